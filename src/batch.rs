@@ -0,0 +1,160 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use crate::pool::BufferPool;
+
+/// Read several parts of a file at once in buffers taken from the pool, they're returned
+/// in the same order.
+pub(crate) trait BatchRead: Send {
+    fn read_batch(
+        &mut self,
+        file: &File,
+        reads: &[(u64, usize)],
+        pool: &BufferPool,
+    ) -> io::Result<Vec<Vec<u8>>>;
+}
+
+fn buffer(pool: &BufferPool, size: usize) -> Vec<u8> {
+    let mut buf = pool.get(size);
+    buf.resize(size, 0);
+    buf
+}
+
+/// Use an io_uring when the feature is enabled and the kernel supports it.
+pub(crate) fn reads() -> Box<dyn BatchRead> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let Ok(reads) = UringReads::new() {
+        return Box::new(reads);
+    }
+    Box::new(SequentialReads)
+}
+
+/// Read the parts one after the other.
+pub(crate) struct SequentialReads;
+
+impl BatchRead for SequentialReads {
+    fn read_batch(
+        &mut self,
+        mut file: &File,
+        reads: &[(u64, usize)],
+        pool: &BufferPool,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        reads
+            .iter()
+            .map(|(offset, size)| {
+                let mut buf = buffer(pool, *size);
+                file.seek(SeekFrom::Start(*offset))?;
+                file.read_exact(&mut buf)?;
+                Ok(buf)
+            })
+            .collect()
+    }
+}
+
+/// Submit all the reads to an io_uring at once and wait for all of them.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub(crate) struct UringReads {
+    ring: io_uring::IoUring,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl UringReads {
+    /// The number of reads submitted at the same time.
+    const QUEUE_DEPTH: u32 = 64;
+
+    pub fn new() -> io::Result<UringReads> {
+        Ok(UringReads {
+            ring: io_uring::IoUring::new(Self::QUEUE_DEPTH)?,
+        })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl BatchRead for UringReads {
+    fn read_batch(
+        &mut self,
+        file: &File,
+        reads: &[(u64, usize)],
+        pool: &BufferPool,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        use std::os::{fd::AsRawFd, unix::fs::FileExt};
+
+        use io_uring::{opcode, types};
+
+        let mut bufs: Vec<Vec<u8>> = reads.iter().map(|(_, size)| buffer(pool, *size)).collect();
+        let fd = types::Fd(file.as_raw_fd());
+
+        for (chunk, bufs) in reads
+            .chunks(Self::QUEUE_DEPTH as usize)
+            .zip(bufs.chunks_mut(Self::QUEUE_DEPTH as usize))
+        {
+            for (i, ((offset, size), buf)) in chunk.iter().zip(bufs.iter_mut()).enumerate() {
+                let read = opcode::Read::new(fd, buf.as_mut_ptr(), *size as u32)
+                    .offset(*offset)
+                    .build()
+                    .user_data(i as u64);
+                // Safety: the buffers and the file outlive the reads since we wait for all of them below
+                unsafe { self.ring.submission().push(&read) }
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+            self.ring.submit_and_wait(chunk.len())?;
+
+            let mut completed = 0;
+            while completed < chunk.len() {
+                let Some(completion) = self.ring.completion().next() else {
+                    self.ring.submit_and_wait(chunk.len() - completed)?;
+                    continue;
+                };
+                completed += 1;
+
+                let i = completion.user_data() as usize;
+                let read = match completion.result() {
+                    read if read < 0 => return Err(io::Error::from_raw_os_error(-read)),
+                    read => read as usize,
+                };
+                // The short reads are completed with a regular read
+                let (offset, _) = chunk[i];
+                file.read_exact_at(&mut bufs[i][read..], offset + read as u64)?;
+            }
+        }
+
+        Ok(bufs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    fn check(reads: &mut dyn BatchRead) {
+        let mut file = tempfile::tempfile().unwrap();
+        let content: Vec<u8> = (0..10_000u32).flat_map(u32::to_be_bytes).collect();
+        file.write_all(&content).unwrap();
+
+        let parts: Vec<_> = (0..100).map(|i| (i * 397, i as usize % 13 + 1)).collect();
+        let pool = BufferPool::default();
+        let read = reads.read_batch(&file, &parts, &pool).unwrap();
+        for ((offset, size), buf) in parts.iter().zip(read) {
+            assert_eq!(buf, &content[*offset as usize..][..*size]);
+        }
+        assert!(reads.read_batch(&file, &[(40_000, 1)], &pool).is_err());
+    }
+
+    #[test]
+    fn sequential_reads() {
+        check(&mut SequentialReads);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn uring_reads() {
+        // The io_uring may be disabled in the sandbox running the tests
+        if let Ok(mut reads) = UringReads::new() {
+            check(&mut reads);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{sync_dir, Layout};
+
+/// The large values stored once whatever the number of entries holding them, see
+/// [`DatabaseBuilder::blob_threshold`](crate::DatabaseBuilder::blob_threshold).
+///
+/// Each value is stored in its own file of the `blobs` directory, named by the hexadecimal
+/// hash of its content. The entries of the clean segments reference the blobs by their hash
+/// and each segment lists the blobs it references, a blob is deleted by the compactions once
+/// no segment references it.
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    layout: Layout,
+}
+
+impl BlobStore {
+    pub fn new(root: &Path, layout: &Layout) -> BlobStore {
+        BlobStore {
+            dir: layout.blobs_dir(root),
+            layout: layout.clone(),
+        }
+    }
+
+    pub fn path(&self, hash: u128) -> PathBuf {
+        self.dir.join(format!("{hash:032x}"))
+    }
+
+    /// Store the value unless it already is, and returns its hash. `None` if another value with
+    /// the same hash is stored, the value must then be stored with its entry.
+    ///
+    /// The blob is durable once it's returned.
+    pub fn put(&self, value: &[u8]) -> io::Result<Option<u128>> {
+        let hash = hash(value);
+        let path = self.path(hash);
+        match fs::read(&path) {
+            Ok(stored) => return Ok((stored == value).then_some(hash)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut file = self.layout.temp_file(&self.dir)?;
+        file.write_all(value)?;
+        file.as_file().sync_all()?;
+        file.persist(&path).map_err(|e| e.error)?;
+        sync_dir(&self.dir)?;
+        Ok(Some(hash))
+    }
+
+    /// Read a blob and check its content against its hash.
+    pub fn get(&self, hash: u128) -> io::Result<Vec<u8>> {
+        let value = fs::read(self.path(hash))?;
+        match self::hash(&value) == hash {
+            true => Ok(value),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted blob {hash:032x}, hash mismatch"),
+            )),
+        }
+    }
+
+    /// Whether any blob was stored.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        match fs::read_dir(&self.dir) {
+            Ok(mut entries) => Ok(entries.next().is_none()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete the blobs that aren't `referenced` along with the temporary files left behind by
+    /// a crash, returns the number of blobs deleted and their size.
+    ///
+    /// No segment must be written meanwhile, its blobs aren't referenced yet.
+    pub fn collect(&self, referenced: &HashSet<u128>) -> io::Result<(usize, u64)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+            Err(e) => return Err(e),
+        };
+        let temp_prefix = self.layout.temp_prefix();
+        let (mut count, mut bytes) = (0, 0);
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with(&temp_prefix) {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            let Ok(hash) = u128::from_str_radix(name, 16) else {
+                continue;
+            };
+            if name.len() == 32 && !referenced.contains(&hash) {
+                bytes += entry.metadata()?.len();
+                fs::remove_file(entry.path())?;
+                count += 1;
+            }
+        }
+        if count > 0 {
+            sync_dir(&self.dir)?;
+        }
+        Ok((count, bytes))
+    }
+}
+
+/// The 128 bits FNV-1a hash of the value. It's not meant to resist collisions crafted on
+/// purpose, [`BlobStore::put`] compares the values sharing a hash.
+fn hash(value: &[u8]) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    value.iter().fold(OFFSET, |hash, byte| {
+        (hash ^ *byte as u128).wrapping_mul(PRIME)
+    })
+}
@@ -0,0 +1,525 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    cache::BlockCache, compat, describe, pool::BufferPool, segment::BlockSizes, Bloom,
+    CompactionFilter, Database, DefaultScheduler, Description, Encoding, FilterPolicy, Follower,
+    Layout, PrefixExtractor, Result, Scheduler, WriteHook,
+};
+
+/// Configure a [`Database`] before opening it.
+pub struct DatabaseBuilder {
+    pub(crate) dirty_thresholds: usize,
+    pub(crate) adaptive_dirty_thresholds: Option<usize>,
+    pub(crate) max_open_files: usize,
+    pub(crate) layout: Layout,
+    pub(crate) log_max_size: u64,
+    pub(crate) log_keep: usize,
+    pub(crate) versions: usize,
+    pub(crate) filter: Option<Arc<dyn FilterPolicy>>,
+    pub(crate) compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    pub(crate) prefix_extractor: Option<PrefixExtractor>,
+    pub(crate) database_filter: bool,
+    pub(crate) uncached_compaction: bool,
+    pub(crate) read_ahead: u64,
+    pub(crate) encoding: Encoding,
+    pub(crate) block_sizes: BlockSizes,
+    pub(crate) wal_max_size: u64,
+    pub(crate) scheduler: Arc<dyn Scheduler>,
+    pub(crate) hooks: Vec<Arc<dyn WriteHook>>,
+    pub(crate) buffer_pool_size: usize,
+    pub(crate) max_key_size: usize,
+    pub(crate) max_value_size: usize,
+    pub(crate) block_cache_size: usize,
+    pub(crate) compressed_block_cache_size: usize,
+    pub(crate) memory_budget: Option<usize>,
+    pub(crate) validate_segments: bool,
+    pub(crate) open_threads: usize,
+    pub(crate) preload_filters: bool,
+    pub(crate) background_scrub: Option<(Duration, u64)>,
+    pub(crate) timestamps: bool,
+    pub(crate) exact_len: bool,
+    pub(crate) blob_threshold: Option<usize>,
+    pub(crate) value_log_threshold: Option<usize>,
+    pub(crate) identity: Option<u128>,
+}
+
+impl Default for DatabaseBuilder {
+    fn default() -> Self {
+        DatabaseBuilder {
+            dirty_thresholds: 1024,
+            adaptive_dirty_thresholds: None,
+            max_open_files: 256,
+            layout: Layout::default(),
+            log_max_size: 1024 * 1024,
+            log_keep: 4,
+            versions: 1,
+            filter: Some(Arc::new(Bloom::default())),
+            compaction_filter: None,
+            prefix_extractor: None,
+            database_filter: false,
+            uncached_compaction: false,
+            read_ahead: 1024 * 1024,
+            encoding: Encoding::default(),
+            block_sizes: BlockSizes::default(),
+            wal_max_size: 64 * 1024 * 1024,
+            scheduler: Arc::new(DefaultScheduler),
+            hooks: Vec::new(),
+            buffer_pool_size: 4 * 1024 * 1024,
+            max_key_size: 64 * 1024,
+            max_value_size: 256 * 1024 * 1024,
+            block_cache_size: 0,
+            compressed_block_cache_size: 0,
+            memory_budget: None,
+            validate_segments: false,
+            open_threads: 4,
+            preload_filters: false,
+            background_scrub: None,
+            timestamps: false,
+            exact_len: true,
+            blob_threshold: None,
+            value_log_threshold: None,
+            identity: None,
+        }
+    }
+}
+
+impl DatabaseBuilder {
+    /// See [`Database::dirty_thresholds`].
+    pub fn dirty_thresholds(mut self, threshold: usize) -> Self {
+        self.dirty_thresholds = threshold;
+        self
+    }
+
+    /// Adjust the dirty threshold to the writes so the memtable holds about `memtable_bytes` of
+    /// keys and values, disabled by default.
+    ///
+    /// The threshold follows a moving average of the size of the entries written, and shrinks
+    /// when the flushes take more than a second since the writes wait for the previous flush
+    /// once the next memtable is full. The [`dirty_thresholds`](Self::dirty_thresholds) is used
+    /// until the first write, and setting it on the open database disables the adjustments.
+    /// The current threshold is reported by [`Stats::dirty_thresholds`](crate::Stats::dirty_thresholds).
+    pub fn adaptive_dirty_thresholds(mut self, memtable_bytes: usize) -> Self {
+        self.adaptive_dirty_thresholds = Some(memtable_bytes);
+        self
+    }
+
+    /// See [`Database::max_open_files`].
+    pub fn max_open_files(mut self, max: usize) -> Self {
+        self.max_open_files = max;
+        self
+    }
+
+    /// Where the files are stored inside the database directory.
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Rotate the events `LOG` once it reaches `max_size` bytes, keeping `keep` old logs around.
+    /// By default the log is rotated every MiB and 4 old logs are kept.
+    pub fn log_rotation(mut self, max_size: u64, keep: usize) -> Self {
+        self.log_max_size = max_size;
+        self.log_keep = keep;
+        self
+    }
+
+    /// How many versions of each key are retained when the dirty segment is flushed
+    /// and when the segments are compacted, only the last one by default.
+    ///
+    /// The retained versions can be read with [`Database::get_at`] and [`Database::versions`].
+    pub fn keep_versions(mut self, versions: usize) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// The filter written in the new segments, a [`Bloom`] filter with 10 bits per key by default.
+    ///
+    /// The filters let the lookups skip the segments that don't contain the key. The segments
+    /// written with another policy are read without their filter.
+    pub fn filter(mut self, policy: impl FilterPolicy + 'static) -> Self {
+        self.filter = Some(Arc::new(policy));
+        self
+    }
+
+    /// Add the prefixes extracted from the keys to the filters of the new segments, none by
+    /// default.
+    ///
+    /// [`Database::prefix`] and [`Database::may_contain_prefix`] skip the segments whose filter
+    /// doesn't contain the prefix the extractor gives for the prefix looked for, if it gives
+    /// one. The segments written without the extractor are always read until they're compacted.
+    pub fn prefix_extractor(mut self, extractor: PrefixExtractor) -> Self {
+        self.prefix_extractor = Some(extractor);
+        self
+    }
+
+    /// Drop or transform the entries while the segments are compacted, none by default.
+    ///
+    /// The keys pinned with [`Database::pin_range`](crate::Database::pin_range) are never given
+    /// to the filter.
+    pub fn compaction_filter(mut self, filter: impl CompactionFilter + 'static) -> Self {
+        self.compaction_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Don't write any filter and ignore the filters of the existing segments.
+    pub fn without_filter(mut self) -> Self {
+        self.filter = None;
+        self
+    }
+
+    /// Keep in memory a filter of the keys of all the segments, disabled by default.
+    ///
+    /// It's meant for the workloads dominated by the lookups of absent keys, which are answered
+    /// without going through the segments. The filter is updated on each flush and rebuilt out of
+    /// all the segments on each compaction, it's created with the [`filter`](Self::filter) policy
+    /// or a [`Bloom`] filter if the segments have no filter.
+    pub fn database_filter(mut self, enabled: bool) -> Self {
+        self.database_filter = enabled;
+        self
+    }
+
+    /// Evict the segments read and written by the compactions from the OS page cache,
+    /// disabled by default.
+    ///
+    /// Otherwise a large compaction fills the page cache and evicts the pages serving the other
+    /// reads. It's only supported on Linux and ignored elsewhere.
+    pub fn uncached_compaction(mut self, enabled: bool) -> Self {
+        self.uncached_compaction = enabled;
+        self
+    }
+
+    /// How many bytes are prefetched once a segment is detected to be read sequentially by an
+    /// iterator or a compaction, 1 MiB by default and `0` disables the prefetching.
+    ///
+    /// It lets the full scans approach the throughput of the disk, it's only supported on Linux
+    /// and ignored elsewhere.
+    pub fn read_ahead(mut self, bytes: u64) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    /// How the entries of the new segments are encoded, [`Encoding::Varint`] by default.
+    ///
+    /// The segments written with another encoding stay readable and are rewritten with this one
+    /// when they're compacted.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// The size after which the data blocks of the new segments are closed, 4 KiB by default.
+    ///
+    /// The lookups read a whole data block, larger blocks suit the scans and make the index
+    /// smaller. The versions of a key are never split so a block can grow past it. The sizes
+    /// aren't needed to read a segment, the segments written with other sizes stay readable.
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_sizes.data = bytes;
+        self
+    }
+
+    /// The size after which the index blocks of the new segments are closed, 4 KiB by default.
+    ///
+    /// The index holds the first key of each data block, smaller index blocks make the lookups
+    /// read less of it at the cost of a larger top-level index, see
+    /// [`block_size`](Self::block_size).
+    pub fn index_block_size(mut self, bytes: usize) -> Self {
+        self.block_sizes.index = bytes;
+        self
+    }
+
+    /// The number of entries between two restart points of the blocks of the new segments, 16
+    /// by default.
+    ///
+    /// The entries share the prefix of their key with the previous one, except on the restart
+    /// points where the lookups start decoding the block. A smaller interval speeds up the
+    /// lookups and grows the blocks, see [`block_size`](Self::block_size).
+    pub fn restart_interval(mut self, entries: usize) -> Self {
+        self.block_sizes.restart_interval = entries;
+        self
+    }
+
+    /// The size after which the dirty segment continues in a new file, 64 MiB by default.
+    ///
+    /// All the files are replayed in order on open, and deleted once their entries are flushed
+    /// to a segment.
+    pub fn wal_max_size(mut self, bytes: u64) -> Self {
+        self.wal_max_size = bytes;
+        self
+    }
+
+    /// Decides when the memtable is flushed and the segments are merged, and gives the time of
+    /// the events log. The [`DefaultScheduler`] by default.
+    ///
+    /// A [`ManualScheduler`](crate::ManualScheduler) leaves all the maintenance to the
+    /// application.
+    pub fn scheduler(mut self, scheduler: impl Scheduler + 'static) -> Self {
+        self.scheduler = Arc::new(scheduler);
+        self
+    }
+
+    /// Tell `hook` about the writes, flushes and compactions, none by default. Several hooks
+    /// can be added, they're called in order.
+    pub fn hook(mut self, hook: impl WriteHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// The maximum number of bytes retained by the pool of the buffers the blocks are read and
+    /// written in, 4 MiB by default.
+    ///
+    /// The lookups, flushes and compactions reuse the buffers of the previous operations instead
+    /// of allocating new ones, `0` disables the reuse.
+    pub fn buffer_pool_size(mut self, bytes: usize) -> Self {
+        self.buffer_pool_size = bytes;
+        self
+    }
+
+    /// The maximum size of the keys accepted by the writes and the imports, 64 KiB by default
+    /// and at most 4 GiB.
+    ///
+    /// The larger keys are rejected with [`Error::KeyTooLarge`](crate::Error::KeyTooLarge). The
+    /// limit is recorded in the manifest, the keys written under a larger limit stay readable.
+    pub fn max_key_size(mut self, bytes: usize) -> Self {
+        self.max_key_size = bytes;
+        self
+    }
+
+    /// The maximum size of the values accepted by the writes and the imports, 256 MiB by
+    /// default and at most 4 GiB.
+    ///
+    /// The larger values are rejected with [`Error::ValueTooLarge`](crate::Error::ValueTooLarge),
+    /// see [`max_key_size`](Self::max_key_size).
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    /// The maximum number of bytes of the blocks kept in memory after the lookups read them,
+    /// disabled by default.
+    ///
+    /// The least recently used blocks are evicted first, or moved to the compressed cache when
+    /// there is one, see [`Stats::block_cache_hit_rate`](crate::Stats::block_cache_hit_rate).
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    /// The maximum number of bytes of the compressed blocks kept behind the
+    /// [block cache](Self::block_cache_size), disabled by default.
+    ///
+    /// The blocks evicted from the block cache are compressed in this one, which thus holds more
+    /// blocks for the same memory at the cost of decompressing them. They move back to the block
+    /// cache when they're read again.
+    pub fn compressed_block_cache_size(mut self, bytes: usize) -> Self {
+        self.compressed_block_cache_size = bytes;
+        self
+    }
+
+    /// The maximum number of bytes kept in memory by the memtables, the block caches and the
+    /// filters, unlimited by default.
+    ///
+    /// The block caches only get the memory left by the memtables and the filters. When a write
+    /// doesn't fit, the memtable is flushed first and the write is rejected with
+    /// [`Error::MemoryBudgetExceeded`](crate::Error::MemoryBudgetExceeded) if it still doesn't
+    /// fit. The usage is reported by [`Stats::memory_bytes`](crate::Stats::memory_bytes).
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Check the footer and the index of each segment on open, disabled by default.
+    ///
+    /// The data blocks aren't read so it stays fast. A segment failing the validation is moved
+    /// to the `quarantine` directory instead of failing the open, the database then opens without
+    /// its entries, see [`Database::quarantined`].
+    pub fn validate_segments(mut self, enabled: bool) -> Self {
+        self.validate_segments = enabled;
+        self
+    }
+
+    /// How many threads read the footers of the segments on open, 4 by default.
+    ///
+    /// The counters and the range deletions of each segment are read from its footer, which
+    /// takes a read per segment and slows down the open of the databases with hundreds of
+    /// segments. With 0 or 1 they're read by the thread opening the database.
+    pub fn open_threads(mut self, threads: usize) -> Self {
+        self.open_threads = threads;
+        self
+    }
+
+    /// Read the filters of the segments on open along with their footers, disabled by default.
+    ///
+    /// By default each filter is read by the first lookup of its segment, which spreads the
+    /// reads over the first lookups rather than delaying the open. The filters loaded count
+    /// toward the [memory budget](Self::memory_budget) either way.
+    pub fn preload_filters(mut self, enabled: bool) -> Self {
+        self.preload_filters = enabled;
+        self
+    }
+
+    /// Record the time of each write along with its entry, disabled by default.
+    ///
+    /// The time is given in milliseconds by the [`scheduler`](Self::scheduler) and never goes
+    /// backward, even across restarts when the clock of the host does: the largest time given is
+    /// recorded in the manifest and in the dirty segment. It's read with
+    /// [`Database::get_with_timestamp`] and [`Entry::timestamp`](crate::Entry::timestamp), the
+    /// entries written while it's disabled have a timestamp of 0.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Keep [`Database::len`] exact, enabled by default.
+    ///
+    /// Each write looks up whether the key it overwrites or deletes had a value, going through
+    /// the memtables and the segments, and each range deletion reads the keys of its range.
+    /// Once disabled the writes only look into the memtables, they assume the other keys they
+    /// write are new and the other keys they delete had a value. [`Database::len`] is then an
+    /// estimate: each overwrite of a key of the segments adds one and each deletion of a missing
+    /// key removes one. It's only counted again when a compaction merges all the segments, which
+    /// the default scheduler may never do.
+    pub fn exact_len(mut self, enabled: bool) -> Self {
+        self.exact_len = enabled;
+        self
+    }
+
+    /// Store the values of at least `bytes` bytes once in the blob store of the database,
+    /// whatever the number of entries holding them, disabled by default.
+    ///
+    /// The flushes, compactions and imports write each large value in its own file of the
+    /// `blobs` directory named by the hash of its content, and the entries of the segments only
+    /// reference it. The values are compared when the hashes match, a value whose hash is taken
+    /// by another one is stored with its entry. The compactions delete the blobs no segment
+    /// references anymore. The segments referencing blobs can't be read without their database,
+    /// e.g. by [`SegmentReader`](crate::SegmentReader).
+    ///
+    /// It can't be enabled along with the [value log](Self::value_log_threshold), the open then
+    /// fails with [`Error::IncompatibleOptions`](crate::Error::IncompatibleOptions). Once the
+    /// database is reopened with the value log instead, the compactions move the blobs written
+    /// before to the value log.
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// Append the values of at least `bytes` bytes to the value log rather than writing them in
+    /// the segments, disabled by default.
+    ///
+    /// The entries of the segments then only hold the position of their value in the log, so
+    /// the compactions move the small entries around and leave the values where they are. The
+    /// compactions only read the values when the [compaction filter](Self::compaction_filter)
+    /// or the [schema](Database::schema) need them. The files of the log no segment references
+    /// are deleted by the compactions, and [`Database::collect_value_log`] reclaims the space
+    /// of the files holding a lot of overwritten or deleted values. The segments pointing to the
+    /// value log can't be read without their database, e.g. by
+    /// [`SegmentReader`](crate::SegmentReader).
+    ///
+    /// It can't be enabled along with the [blob store](Self::blob_threshold), the open then
+    /// fails with [`Error::IncompatibleOptions`](crate::Error::IncompatibleOptions). The values
+    /// written before stay readable once the database is reopened with the blob store instead.
+    pub fn value_log_threshold(mut self, bytes: usize) -> Self {
+        self.value_log_threshold = Some(bytes);
+        self
+    }
+
+    /// Create the database with this identity rather than a random one, so the segments written
+    /// by the tests are the same from one run to the other.
+    #[cfg(test)]
+    pub(crate) fn identity(mut self, identity: u128) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Scrub the segments in the background every `interval`, reading at most `bytes_per_second`,
+    /// disabled by default.
+    ///
+    /// The time is given by the [`scheduler`](Self::scheduler), and the database checks whether
+    /// a scrub is done or due on each write. The damaged filters are then rebuilt and the report
+    /// is available with [`Database::last_scrub`], see [`Database::scrub`].
+    pub fn background_scrub(mut self, interval: Duration, bytes_per_second: u64) -> Self {
+        self.background_scrub = Some((interval, bytes_per_second));
+        self
+    }
+
+    pub fn open(self, dir: impl AsRef<Path>) -> Result<Database> {
+        Database::open(dir.as_ref(), self, &mut |_, _| ())
+    }
+
+    /// Open a database written by the first version of the crate, see [`Database::open_compat`].
+    ///
+    /// The layout, the filter policy and the encoding of the new segments are used to rewrite it.
+    pub fn open_compat(self, dir: impl AsRef<Path>) -> Result<Database> {
+        let dir = dir.as_ref();
+        let upgraded = compat::upgrade(dir, &self)?;
+        let mut database = Database::open(dir, self, &mut |_, _| ())?;
+        if let Some(upgraded) = upgraded {
+            database.events.log(format_args!(
+                "open: database of the first version upgraded, {} segments and {} dirty entries rewritten",
+                upgraded.segments, upgraded.dirty_entries
+            ));
+        }
+        Ok(database)
+    }
+
+    /// Summarize the database stored in `dir`, only the layout is used, see
+    /// [`Database::describe`].
+    pub fn describe(self, dir: impl AsRef<Path>) -> Result<Description> {
+        describe::describe(dir.as_ref(), &self.layout)
+    }
+
+    /// Open a read-only view of a database written by another process, e.g. to spread the reads
+    /// of a database across several processes of the same host.
+    ///
+    /// The layout, the filter policy, the number of open files, the read-ahead and the size of
+    /// the buffer pool and of the block caches are used, the other options only matter to the
+    /// writer. See
+    /// [`Follower::refresh`] to catch up with the writes.
+    pub fn open_follower(self, dir: impl AsRef<Path>) -> Result<Follower> {
+        Follower::open(
+            dir.as_ref(),
+            self.layout,
+            self.max_open_files,
+            self.read_ahead,
+            self.filter,
+            BufferPool::new(self.buffer_pool_size),
+            BlockCache::new(self.block_cache_size, self.compressed_block_cache_size),
+        )
+    }
+
+    /// Open the database and report the progress of the replay of the dirty segment with the
+    /// number of bytes replayed and the total, about every MiB and once done.
+    ///
+    /// The replay can take a while when the dirty segment is large and the database wasn't
+    /// closed cleanly, otherwise the saved memtable is loaded and the progress is reported once.
+    pub fn open_with_progress(
+        self,
+        dir: impl AsRef<Path>,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<Database> {
+        Database::open(dir.as_ref(), self, &mut progress)
+    }
+}
+
+/// An option that can be changed while the database is open, see [`Database::set_option`].
+///
+/// Each option has the meaning of the [`DatabaseBuilder`] method of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opt {
+    /// See [`DatabaseBuilder::dirty_thresholds`].
+    DirtyThresholds(usize),
+    /// See [`DatabaseBuilder::max_open_files`].
+    MaxOpenFiles(usize),
+    /// See [`DatabaseBuilder::read_ahead`], it applies to the iterators and compactions started afterward.
+    ReadAhead(u64),
+    /// See [`DatabaseBuilder::wal_max_size`], the current file of the dirty segment is rotated
+    /// on the next write once it's over the new size.
+    WalMaxSize(u64),
+    /// See [`DatabaseBuilder::block_cache_size`], the blocks evicted by a smaller size move to
+    /// the compressed cache.
+    BlockCacheSize(usize),
+    /// See [`DatabaseBuilder::compressed_block_cache_size`].
+    CompressedBlockCacheSize(usize),
+    /// See [`DatabaseBuilder::background_scrub`], `None` stops the running scrub and disables
+    /// them. The new rate applies right away to the running scrub.
+    BackgroundScrub(Option<(Duration, u64)>),
+}
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::pool::BufferPool;
+
+/// Identifies a block by the id of its segment and its offset, the compactions always write
+/// their segments under a new id.
+type BlockKey = (usize, u64);
+
+/// Keep the most recently read blocks of the segments in memory, in two tiers.
+///
+/// The blocks read by the lookups enter the first tier as they're stored in the segments. The
+/// blocks evicted from it are compressed and moved to the second tier, which holds more blocks
+/// for the same capacity. A block found in the second tier is decompressed and promoted back to
+/// the first one. A tier with a capacity of `0` is disabled.
+///
+/// Both tiers together never hold more than the limit, the blocks of the second tier are
+/// evicted first to respect it.
+pub(crate) struct BlockCache {
+    blocks: Lru,
+    compressed: Lru,
+    limit: usize,
+    hits: u64,
+    misses: u64,
+    compressed_hits: u64,
+    compressed_misses: u64,
+}
+
+/// The hits and misses of each tier of a [`BlockCache`], and the bytes they hold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes: usize,
+    pub compressed_hits: u64,
+    pub compressed_misses: u64,
+    pub compressed_bytes: usize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize, compressed_capacity: usize) -> BlockCache {
+        BlockCache {
+            blocks: Lru::new(capacity),
+            compressed: Lru::new(compressed_capacity),
+            limit: usize::MAX,
+            hits: 0,
+            misses: 0,
+            compressed_hits: 0,
+            compressed_misses: 0,
+        }
+    }
+
+    /// A copy of the block in a buffer taken from the pool, if it's in one of the tiers.
+    pub fn get(&mut self, segment: usize, offset: u64, pool: &BufferPool) -> Option<Vec<u8>> {
+        let key = (segment, offset);
+        if self.blocks.capacity > 0 {
+            if let Some(block) = self.blocks.get(&key) {
+                self.hits += 1;
+                let mut buf = pool.get(block.len());
+                buf.extend_from_slice(block);
+                return Some(buf);
+            }
+            self.misses += 1;
+        }
+        if self.compressed.capacity == 0 {
+            return None;
+        }
+        let block = self
+            .compressed
+            .remove(&key)
+            .and_then(|compressed| lz4_flex::decompress_size_prepended(&compressed).ok());
+        let Some(block) = block else {
+            self.compressed_misses += 1;
+            return None;
+        };
+        self.compressed_hits += 1;
+        let mut buf = pool.get(block.len());
+        buf.extend_from_slice(&block);
+        self.add(key, block);
+        Some(buf)
+    }
+
+    /// Add a block read from a segment.
+    pub fn insert(&mut self, segment: usize, offset: u64, block: &[u8]) {
+        if self.blocks.capacity > 0 || self.compressed.capacity > 0 {
+            self.add((segment, offset), block.to_vec());
+        }
+    }
+
+    /// The blocks evicted from the first tier move to the second one.
+    fn add(&mut self, key: BlockKey, block: Vec<u8>) {
+        for (key, evicted) in self.blocks.insert(key, block) {
+            self.demote(key, &evicted);
+        }
+        self.enforce_limit();
+    }
+
+    fn demote(&mut self, key: BlockKey, block: &[u8]) {
+        if self.compressed.capacity > 0 {
+            self.compressed
+                .insert(key, lz4_flex::compress_prepend_size(block));
+        }
+    }
+
+    /// Change the capacity of the first tier, the blocks it evicts move to the second one.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.blocks.capacity = capacity;
+        for (key, evicted) in self.blocks.shrink() {
+            self.demote(key, &evicted);
+        }
+    }
+
+    pub fn set_compressed_capacity(&mut self, capacity: usize) {
+        self.compressed.capacity = capacity;
+        self.compressed.shrink();
+    }
+
+    /// Limit the bytes held by both tiers, e.g. to fit in the memory budget of the database.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.enforce_limit();
+    }
+
+    /// Evict the least recently used blocks of the second tier and then of the first one until
+    /// both tiers fit in the limit.
+    fn enforce_limit(&mut self) {
+        while self.blocks.bytes + self.compressed.bytes > self.limit {
+            if self.compressed.evict().is_none() {
+                self.blocks.evict();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            bytes: self.blocks.bytes,
+            compressed_hits: self.compressed_hits,
+            compressed_misses: self.compressed_misses,
+            compressed_bytes: self.compressed.bytes,
+        }
+    }
+}
+
+/// The blocks of a tier of the cache, from the least to the most recently used.
+struct Lru {
+    capacity: usize,
+    bytes: usize,
+    // Associate each block to the last time it was used
+    blocks: HashMap<BlockKey, (Vec<u8>, u64)>,
+    order: BTreeMap<u64, BlockKey>,
+    clock: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            bytes: 0,
+            blocks: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &BlockKey) -> Option<&[u8]> {
+        self.clock += 1;
+        let (block, last_used) = self.blocks.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(self.clock, *key);
+        *last_used = self.clock;
+        Some(block)
+    }
+
+    fn remove(&mut self, key: &BlockKey) -> Option<Vec<u8>> {
+        let (block, last_used) = self.blocks.remove(key)?;
+        self.order.remove(&last_used);
+        self.bytes -= block.len();
+        Some(block)
+    }
+
+    /// Add the block and returns the blocks evicted to make room for it, including itself if
+    /// it's larger than the capacity.
+    fn insert(&mut self, key: BlockKey, block: Vec<u8>) -> Vec<(BlockKey, Vec<u8>)> {
+        self.remove(&key);
+        self.clock += 1;
+        self.bytes += block.len();
+        self.blocks.insert(key, (block, self.clock));
+        self.order.insert(self.clock, key);
+        self.shrink()
+    }
+
+    /// Evict the least recently used blocks until they fit in the capacity.
+    fn shrink(&mut self) -> Vec<(BlockKey, Vec<u8>)> {
+        let mut evicted = Vec::new();
+        while self.bytes > self.capacity {
+            evicted.extend(self.evict());
+        }
+        evicted
+    }
+
+    /// Evict the least recently used block, `None` if there is none.
+    fn evict(&mut self) -> Option<(BlockKey, Vec<u8>)> {
+        let (_, key) = self.order.pop_first()?;
+        let (block, _) = self.blocks.remove(&key).unwrap();
+        self.bytes -= block.len();
+        Some((key, block))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tiers() {
+        let pool = BufferPool::new(0);
+        let block = |byte: u8| vec![byte; 100];
+        let mut cache = BlockCache::new(250, 1000);
+        cache.insert(0, 0, &block(0));
+        cache.insert(0, 100, &block(1));
+        assert_eq!(cache.get(0, 0, &pool), Some(block(0)));
+        // The least recently used block is compressed in the second tier
+        cache.insert(1, 0, &block(2));
+        let stats = cache.stats();
+        assert_eq!(stats.bytes, 200);
+        assert!(stats.compressed_bytes > 0 && stats.compressed_bytes < 100);
+
+        // And promoted back on a hit
+        assert_eq!(cache.get(0, 100, &pool), Some(block(1)));
+        assert_eq!(cache.get(0, 100, &pool), Some(block(1)));
+        assert_eq!(cache.get(2, 0, &pool), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!((stats.compressed_hits, stats.compressed_misses), (1, 1));
+        assert_eq!(stats.bytes, 200);
+
+        // Without a second tier the evicted blocks are dropped
+        let mut cache = BlockCache::new(100, 0);
+        cache.insert(0, 0, &block(0));
+        cache.insert(0, 100, &block(1));
+        assert_eq!(cache.get(0, 0, &pool), None);
+        assert_eq!(cache.stats().compressed_misses, 0);
+
+        // Shrinking the first tier moves its blocks to the second one
+        cache.set_compressed_capacity(1000);
+        cache.set_capacity(0);
+        assert_eq!(cache.stats().bytes, 0);
+        assert_eq!(cache.get(0, 100, &pool), Some(block(1)));
+    }
+
+    #[test]
+    fn limit() {
+        let pool = BufferPool::new(0);
+        let block = |byte: u8| vec![byte; 100];
+        let mut cache = BlockCache::new(250, 1000);
+        cache.insert(0, 0, &block(0));
+        cache.insert(0, 100, &block(1));
+        cache.insert(0, 200, &block(2));
+        assert!(cache.stats().compressed_bytes > 0);
+
+        // The compressed blocks are evicted first
+        cache.set_limit(200);
+        let stats = cache.stats();
+        assert_eq!((stats.bytes, stats.compressed_bytes), (200, 0));
+        assert_eq!(cache.get(0, 0, &pool), None);
+
+        // Then the least recently used blocks, even below the capacity
+        assert_eq!(cache.get(0, 100, &pool), Some(block(1)));
+        cache.set_limit(150);
+        assert_eq!(cache.stats().bytes, 100);
+        assert_eq!(cache.get(0, 200, &pool), None);
+        assert_eq!(cache.get(0, 100, &pool), Some(block(1)));
+    }
+}
@@ -0,0 +1,41 @@
+/// What a [`CompactionFilter`] does with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// Write the entry as is.
+    Keep,
+    /// Drop the entry, the key is deleted unless a more recent segment or the memtable holds it.
+    Remove,
+    /// Write the entry with another value.
+    Replace(Vec<u8>),
+}
+
+/// Drop or transform the entries while the segments are compacted, e.g. to expire the entries
+/// by a timestamp stored in their value.
+///
+/// The filter is called by [`Database::merge_segment`](crate::Database::merge_segment) on each
+/// version with a value of the keys of the merged segments, once the versions hidden by the
+/// deletions are dropped. The values are given and taken upgraded by the
+/// [`Schema`](crate::Schema). The flushes don't call it, an entry stays readable until the
+/// compaction of its segment.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8]) -> CompactionDecision;
+}
+
+/// What the next compaction would do, see
+/// [`Database::compaction_plan`](crate::Database::compaction_plan).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionPlan {
+    /// The ids of the segments merged, from the oldest to the most recent one.
+    pub segments: Vec<usize>,
+    /// The size of the segments merged.
+    pub input_bytes: u64,
+    /// An estimation of the size of the merged segment.
+    pub estimated_output_bytes: u64,
+}
+
+impl CompactionPlan {
+    /// The bytes the compaction is expected to release.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.input_bytes.saturating_sub(self.estimated_output_bytes)
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    persist_segment, pool::BufferPool, read_bytes, read_entry_to_vec, read_u32, segment,
+    segment::SegmentWriter, sync_dir, write_entry, DatabaseBuilder, Layout, Result,
+};
+
+/// What was rewritten by [`upgrade`].
+pub(crate) struct Upgraded {
+    pub segments: usize,
+    pub dirty_entries: usize,
+}
+
+/// The files of a database written by the first version of the crate.
+///
+/// They're stored at the root of its directory without prefix: a `dirty` file and the
+/// `segment-{id}` files, both holding the key and value of the entries prefixed by their size.
+/// The segments are sorted by key and have no footer, and there is no manifest.
+struct V0 {
+    dirty: Option<PathBuf>,
+    // From the oldest to the most recent one
+    segments: Vec<(usize, PathBuf)>,
+}
+
+/// Find the files of a database written by the first version of the crate, `None` if the
+/// directory has a manifest or no such file.
+///
+/// The `dirty` file has no header, it's only told apart from the legacy dirty file of the
+/// following versions by the missing manifest, which they write on every open.
+fn find(root: &Path, layout: &Layout) -> io::Result<Option<V0>> {
+    if layout.manifest_path(root).exists() || !root.exists() {
+        return Ok(None);
+    }
+    let v0 = Layout::flat();
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| v0.parse_segment(name));
+        // Written by the current version before a crash, the manifest recovery adopts them
+        if let Some(id) = id {
+            if !segment::has_footer(&path)? {
+                segments.push((id, path));
+            }
+        }
+    }
+    segments.sort_unstable();
+    let dirty = Some(v0.dirty_path(root)).filter(|path| path.exists());
+    match dirty.is_none() && segments.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(V0 { dirty, segments })),
+    }
+}
+
+/// Whether the directory holds segments written by the first version of the crate, which
+/// must be opened with [`Database::open_compat`](crate::Database::open_compat).
+pub(crate) fn has_v0_segments(root: &Path, layout: &Layout) -> io::Result<bool> {
+    Ok(find(root, layout)?.is_some_and(|v0| !v0.segments.is_empty()))
+}
+
+/// Rewrite a database written by the first version of the crate in the current format,
+/// returns `None` if it isn't one.
+///
+/// The entries had no sequence number, they're all given the sequence number 0. Each segment
+/// is rewritten under its id and the entries of the `dirty` file are written to the first
+/// file of the dirty segment, each file is replaced once its new version is durable. An
+/// interrupted upgrade can be run again, the segments already rewritten have a footer and
+/// are adopted by the manifest recovery of the open.
+pub(crate) fn upgrade(root: &Path, builder: &DatabaseBuilder) -> Result<Option<Upgraded>> {
+    let layout = &builder.layout;
+    let Some(v0) = find(root, layout)? else {
+        return Ok(None);
+    };
+    layout.create_dirs(root)?;
+    let pool = Arc::new(BufferPool::new(builder.buffer_pool_size));
+
+    let level_dir = layout.level_dir(root, 0);
+    for (id, path) in &v0.segments {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let new_segment = layout.temp_file(&level_dir)?;
+        let mut writer = SegmentWriter::new(
+            BufWriter::new(new_segment),
+            builder.filter.as_deref(),
+            builder.encoding,
+            pool.clone(),
+        );
+        writer.block_sizes(builder.block_sizes);
+        if let Some(extractor) = builder.prefix_extractor {
+            writer.prefix_extractor(extractor);
+        }
+        while let Some((key, value)) = read_v0_entry(&mut reader)? {
+            writer.add(&key, 0, 0, 0, Some(&value))?;
+        }
+        let new_segment = writer
+            .finish()?
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        let new_path = layout.segment_path(root, 0, *id);
+        if new_path == *path {
+            // The segment of the first version is replaced in place
+            new_segment.as_file().sync_all()?;
+            new_segment.persist(&new_path)?;
+            sync_dir(new_path.parent().unwrap_or(&new_path))?;
+        } else {
+            persist_segment(new_segment, &new_path)?;
+            fs::remove_file(path)?;
+        }
+    }
+
+    let mut dirty_entries = 0;
+    if let Some(path) = &v0.dirty {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let dir = layout.wal_files_dir(root);
+        let mut writer = BufWriter::new(layout.temp_file(&dir)?);
+        while let Some((key, value)) = read_v0_entry(&mut reader)? {
+            write_entry(&mut writer, &key, 0, 0, 0, Some(&value))?;
+            dirty_entries += 1;
+        }
+        writer.flush()?;
+        let file = writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        file.as_file().sync_all()?;
+        file.persist(layout.wal_path(root, 0))?;
+        fs::remove_file(path)?;
+        sync_dir(&dir)?;
+    }
+    sync_dir(root)?;
+    Ok(Some(Upgraded {
+        segments: v0.segments.len(),
+        dirty_entries,
+    }))
+}
+
+/// Read the next entry of a file written by the first version of the crate.
+fn read_v0_entry(reader: &mut impl io::Read) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let size = match read_u32(reader) {
+        Ok(size) => size,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut key = Vec::new();
+    read_bytes(reader, size as usize, &mut key)?;
+    Ok(Some((key, read_entry_to_vec(reader)?)))
+}
@@ -0,0 +1,89 @@
+use std::ops::Bound;
+
+use crate::{Database, Range, Result};
+
+/// A position among the entries of the database that moves in both directions, see
+/// [`Database::cursor`].
+///
+/// When the cursor isn't positioned, [`Cursor::next`] moves to the first entry and
+/// [`Cursor::prev`] to the last one. Moving past either end leaves it unpositioned.
+pub struct Cursor<'a> {
+    database: &'a mut Database,
+    // The entry the cursor is on
+    current: Option<(Vec<u8>, Vec<u8>)>,
+    // Reads the following entries while the cursor moves forward
+    forward: Option<Range>,
+}
+
+impl Cursor<'_> {
+    pub(crate) fn new(database: &mut Database) -> Cursor<'_> {
+        Cursor {
+            database,
+            current: None,
+            forward: None,
+        }
+    }
+
+    /// The entry the cursor is on.
+    pub fn current(&self) -> Option<(&[u8], &[u8])> {
+        self.current
+            .as_ref()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    /// Move to the first entry whose key is greater or equal to `key`.
+    pub fn seek(&mut self, key: impl AsRef<[u8]>) -> Result<Option<(&[u8], &[u8])>> {
+        self.forward_from(Bound::Included(key.as_ref().to_vec()))
+    }
+
+    /// Move to the last entry whose key is lower or equal to `key`.
+    pub fn seek_for_prev(&mut self, key: impl AsRef<[u8]>) -> Result<Option<(&[u8], &[u8])>> {
+        self.backward_from(Bound::Included(key.as_ref()))
+    }
+
+    /// Move to the following entry.
+    // It moves in both directions, it can't be an iterator
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(&[u8], &[u8])>> {
+        match &mut self.forward {
+            Some(forward) => {
+                self.current = forward.next().transpose()?;
+                if self.current.is_none() {
+                    self.forward = None;
+                }
+                Ok(self.current())
+            }
+            None => {
+                let start = match self.current.take() {
+                    Some((key, _)) => Bound::Excluded(key),
+                    None => Bound::Unbounded,
+                };
+                self.forward_from(start)
+            }
+        }
+    }
+
+    /// Move to the previous entry.
+    pub fn prev(&mut self) -> Result<Option<(&[u8], &[u8])>> {
+        let current = self.current.take();
+        let end = match &current {
+            Some((key, _)) => Bound::Excluded(key.as_slice()),
+            None => Bound::Unbounded,
+        };
+        self.backward_from(end)
+    }
+
+    fn forward_from(&mut self, start: Bound<Vec<u8>>) -> Result<Option<(&[u8], &[u8])>> {
+        let mut forward = self.database.range((start, Bound::Unbounded))?;
+        self.current = forward.next().transpose()?;
+        self.forward = self.current.is_some().then_some(forward);
+        Ok(self.current())
+    }
+
+    fn backward_from(&mut self, end: Bound<&[u8]>) -> Result<Option<(&[u8], &[u8])>> {
+        // The segments can only be read forward, each move looks the previous key up again
+        self.forward = None;
+        self.current = self.database.last_entry(end)?;
+        Ok(self.current())
+    }
+}
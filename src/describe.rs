@@ -0,0 +1,150 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{manifest, Layout, Result, SegmentMetadata, SegmentReader};
+
+/// A summary of the files of a database directory, see
+/// [`Database::describe`](crate::Database::describe).
+///
+/// It's formatted as a human-readable report with [`Display`](fmt::Display).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    /// The segments listed in the manifest, from the oldest to the most recent one.
+    pub segments: Vec<SegmentDescription>,
+    /// The number of live keys of the segments recorded in the manifest, `None` when it must be
+    /// counted again on the next open.
+    pub live_keys: Option<u64>,
+    /// The number of files of the dirty segment.
+    pub wal_files: usize,
+    /// The size of the files of the dirty segment.
+    pub wal_bytes: u64,
+}
+
+/// A clean segment of a [`Description`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentDescription {
+    pub id: usize,
+    pub path: PathBuf,
+    /// 1 for the compacted segments when the layout has level directories, 0 otherwise.
+    pub level: u8,
+    /// The size of the file, the holes punched in it included.
+    pub size: u64,
+    /// What the footer records, or why it couldn't be read.
+    pub metadata: std::result::Result<SegmentMetadata, String>,
+}
+
+impl Description {
+    /// The size of the segments and of the files of the dirty segment.
+    pub fn disk_usage(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.size)
+            .sum::<u64>()
+            + self.wal_bytes
+    }
+}
+
+/// Read the manifest, the footers of the segments and the size of the files of the dirty
+/// segment, nothing is written nor replayed.
+pub(crate) fn describe(root: &Path, layout: &Layout) -> Result<Description> {
+    let manifest = manifest::read(root, layout)?;
+    let mut segments = Vec::with_capacity(manifest.listed.len());
+    for (id, path) in manifest.listed {
+        let level = layout.has_level_dirs() && path.starts_with(layout.level_dir(root, 1));
+        let metadata = SegmentReader::open(&path).map(|reader| reader.metadata().clone());
+        segments.push(SegmentDescription {
+            id,
+            level: level as u8,
+            size: fs::metadata(&path)?.len(),
+            metadata: metadata.map_err(|e| e.to_string()),
+            path,
+        });
+    }
+
+    let (mut wal_files, mut wal_bytes) = (0, 0);
+    let dirty = layout.dirty_path(root);
+    match fs::read_dir(layout.wal_files_dir(root)) {
+        Ok(entries) => {
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name();
+                let is_wal = name.to_str().and_then(|name| layout.parse_wal(name));
+                if is_wal.is_some() || entry.path() == dirty {
+                    wal_files += 1;
+                    wal_bytes += entry.metadata()?.len();
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(Description {
+        segments,
+        live_keys: manifest.live_keys,
+        wal_files,
+        wal_bytes,
+    })
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} segments, {} bytes on disk",
+            self.segments.len(),
+            self.disk_usage()
+        )?;
+        match self.live_keys {
+            Some(live_keys) => writeln!(f, ", {live_keys} live keys")?,
+            None => writeln!(f, ", live keys not counted")?,
+        }
+        writeln!(
+            f,
+            "dirty segment: {} files, {} bytes",
+            self.wal_files, self.wal_bytes
+        )?;
+        for level in 0..=1 {
+            let segments = self
+                .segments
+                .iter()
+                .filter(|segment| segment.level == level);
+            let (count, size) = segments.fold((0, 0), |(count, size), segment| {
+                (count + 1, size + segment.size)
+            });
+            if count != 0 {
+                writeln!(f, "level {level}: {count} segments, {size} bytes")?;
+            }
+        }
+        for segment in &self.segments {
+            write!(
+                f,
+                "  segment {} (level {}, {} bytes): ",
+                segment.id, segment.level, segment.size
+            )?;
+            let metadata = match &segment.metadata {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    writeln!(f, "unreadable footer, {e}")?;
+                    continue;
+                }
+            };
+            write!(f, "format version {}", metadata.format_version)?;
+            if let Some(counters) = &metadata.counters {
+                write!(f, ", {} entries", counters.entries)?;
+            }
+            match &metadata.fence {
+                Some((first, last)) => writeln!(
+                    f,
+                    ", keys \"{}\"..=\"{}\"",
+                    first.escape_ascii(),
+                    last.escape_ascii()
+                )?,
+                None => writeln!(f, ", empty")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::io;
+
+use crate::{read_u32, read_u64, TOMBSTONE};
+
+/// The size of value marking a value stored in a blob in the blocks of the segments, the hash
+/// of the blob follows.
+const BLOB: u32 = u32::MAX - 1;
+/// The size of value marking a value stored in the value log, its pointer follows.
+const POINTER: u32 = u32::MAX - 2;
+
+/// The size of a value of the blocks that may reference blobs, see [`Encoding::write_stored_len`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StoredLen {
+    Inline(usize),
+    /// The value is in a blob, its hash follows.
+    Blob,
+    /// The value is in the value log, its pointer follows.
+    Pointer,
+    Deleted,
+}
+
+/// How the lengths and sequence numbers of the entries are stored in the clean segments.
+///
+/// The encoding is recorded in each segment, thus the segments written with another encoding
+/// can still be read and are converted by the compactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Big endian `u32` lengths and `u64` sequence numbers.
+    Fixed,
+    /// Variable length integers, a small entry uses 3 bytes instead of 20 to store its lengths
+    /// and sequence number. A deletion is a single flag byte instead of a 4 bytes length.
+    #[default]
+    Varint,
+}
+
+impl Encoding {
+    /// The segment format version corresponding to the encoding, the footers and blocks of both
+    /// versions have a checksum.
+    pub(crate) fn format_version(self) -> u32 {
+        match self {
+            Encoding::Fixed => 24,
+            Encoding::Varint => 25,
+        }
+    }
+
+    pub(crate) fn write_len(self, buf: &mut Vec<u8>, len: usize) {
+        match self {
+            Encoding::Fixed => buf.extend_from_slice(&(len as u32).to_be_bytes()),
+            Encoding::Varint => write_varint(buf, len as u64),
+        }
+    }
+
+    pub(crate) fn read_len(self, cursor: &mut &[u8]) -> io::Result<usize> {
+        match self {
+            Encoding::Fixed => Ok(read_u32(cursor)? as usize),
+            Encoding::Varint => Ok(read_varint(cursor)? as usize),
+        }
+    }
+
+    pub(crate) fn write_seq(self, buf: &mut Vec<u8>, seq: u64) {
+        match self {
+            Encoding::Fixed => buf.extend_from_slice(&seq.to_be_bytes()),
+            Encoding::Varint => write_varint(buf, seq),
+        }
+    }
+
+    pub(crate) fn read_seq(self, cursor: &mut &[u8]) -> io::Result<u64> {
+        match self {
+            Encoding::Fixed => read_u64(cursor),
+            Encoding::Varint => read_varint(cursor),
+        }
+    }
+
+    /// Write the size of the value, `None` for a deleted entry.
+    pub(crate) fn write_value_len(self, buf: &mut Vec<u8>, len: Option<usize>) {
+        match (self, len) {
+            (Encoding::Fixed, Some(len)) => buf.extend_from_slice(&(len as u32).to_be_bytes()),
+            (Encoding::Fixed, None) => buf.extend_from_slice(&TOMBSTONE.to_be_bytes()),
+            // The deletions are stored as 0 and the sizes are shifted by one
+            (Encoding::Varint, Some(len)) => write_varint(buf, len as u64 + 1),
+            (Encoding::Varint, None) => write_varint(buf, 0),
+        }
+    }
+
+    /// Write the size of a value of the blocks written since the format version 22, which
+    /// may be stored in a blob, or in the value log since the version 24.
+    pub(crate) fn write_stored_len(self, buf: &mut Vec<u8>, len: StoredLen) {
+        match (self, len) {
+            (Encoding::Fixed, StoredLen::Inline(len)) => {
+                buf.extend_from_slice(&(len as u32).to_be_bytes())
+            }
+            (Encoding::Fixed, StoredLen::Blob) => buf.extend_from_slice(&BLOB.to_be_bytes()),
+            (Encoding::Fixed, StoredLen::Pointer) => buf.extend_from_slice(&POINTER.to_be_bytes()),
+            (Encoding::Fixed, StoredLen::Deleted) => {
+                buf.extend_from_slice(&TOMBSTONE.to_be_bytes())
+            }
+            // The sizes are shifted by three
+            (Encoding::Varint, StoredLen::Inline(len)) => write_varint(buf, len as u64 + 3),
+            (Encoding::Varint, StoredLen::Blob) => write_varint(buf, 1),
+            (Encoding::Varint, StoredLen::Pointer) => write_varint(buf, 2),
+            (Encoding::Varint, StoredLen::Deleted) => write_varint(buf, 0),
+        }
+    }
+
+    /// Before the version 24 the values can't be in the value log and the varint sizes are
+    /// shifted by two, `pointers` tells whether the block is more recent.
+    pub(crate) fn read_stored_len(
+        self,
+        cursor: &mut &[u8],
+        pointers: bool,
+    ) -> io::Result<StoredLen> {
+        match self {
+            Encoding::Fixed => match read_u32(cursor)? {
+                TOMBSTONE => Ok(StoredLen::Deleted),
+                BLOB => Ok(StoredLen::Blob),
+                POINTER if pointers => Ok(StoredLen::Pointer),
+                len => Ok(StoredLen::Inline(len as usize)),
+            },
+            Encoding::Varint => match read_varint(cursor)? {
+                0 => Ok(StoredLen::Deleted),
+                1 => Ok(StoredLen::Blob),
+                2 if pointers => Ok(StoredLen::Pointer),
+                len => Ok(StoredLen::Inline(len as usize - 2 - pointers as usize)),
+            },
+        }
+    }
+
+    pub(crate) fn read_value_len(self, cursor: &mut &[u8]) -> io::Result<Option<usize>> {
+        match self {
+            Encoding::Fixed => match read_u32(cursor)? {
+                TOMBSTONE => Ok(None),
+                len => Ok(Some(len as usize)),
+            },
+            Encoding::Varint => match read_varint(cursor)? {
+                0 => Ok(None),
+                len => Ok(Some(len as usize - 1)),
+            },
+        }
+    }
+}
+
+/// LEB128, 7 bits per byte starting with the least significant ones, the high bit is set on
+/// all the bytes but the last one.
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(cursor: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = cursor.split_first().ok_or(io::ErrorKind::UnexpectedEof)?;
+        *cursor = rest;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint too long",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn varint() {
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            let mut cursor = buf.as_slice();
+            assert_eq!(read_varint(&mut cursor).unwrap(), n);
+            assert!(cursor.is_empty());
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+        assert!(read_varint(&mut &buf[..1]).is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use std::{backtrace::Backtrace, io};
+// thiserror provides the fields typed `Backtrace` through the nightly-only
+// `error_generic_member_access`, the alias keeps the crate building on stable and the `nightly`
+// feature opts back in.
+use std::{backtrace::Backtrace as Trace, io, path::PathBuf};
 
 use thiserror::Error;
 
@@ -6,19 +9,241 @@ use thiserror::Error;
 pub enum Error {
     #[error("{source}: {backtrace}")]
     Io {
-        #[from]
         source: io::Error,
-        backtrace: Backtrace,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
     },
     #[error("{source}: {backtrace}")]
     TempFile {
-        #[from]
         source: tempfile::PersistError,
-        backtrace: Backtrace,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
     },
-    #[error("Key too large {0}. Maximum size accepted is {}", u32::MAX)]
-    KeyTooLarge(usize),
+    #[error("Key too large {size}. Maximum size accepted is {max}")]
+    KeyTooLarge { size: usize, max: usize },
 
-    #[error("Value too large {0}. Maximum size accepted is {}", u32::MAX)]
-    ValueTooLarge(usize),
+    #[error("Value too large {size}. Maximum size accepted is {max}")]
+    ValueTooLarge { size: usize, max: usize },
+
+    #[error("The write needs {needed} bytes of memory but the memory budget is {budget} bytes")]
+    MemoryBudgetExceeded { needed: usize, budget: usize },
+
+    #[error("Malformed composite key")]
+    MalformedKey,
+
+    #[error("Value isn't tagged with a schema version")]
+    UntaggedValue,
+
+    #[error("No upgrade registered to migrate values from the schema version {0}")]
+    UnsupportedSchemaVersion(u32),
+
+    #[error("A counter must be stored on 8 bytes but the value is {0} bytes long")]
+    InvalidCounter(usize),
+
+    #[error("The counter overflowed")]
+    CounterOverflow,
+
+    #[error("The entry {key:?} isn't valid UTF-8: {source}")]
+    InvalidUtf8 {
+        key: Vec<u8>,
+        source: std::str::Utf8Error,
+    },
+
+    #[error("Corrupted segment filter")]
+    CorruptedFilter,
+
+    #[error("The record of the write-ahead log at {0} is corrupted")]
+    CorruptedWalRecord(u64),
+
+    #[error("The segment {} listed in the manifest is missing", .0.display())]
+    MissingSegment(PathBuf),
+
+    #[error("A file already exists at {}, where a new segment was to be written", .0.display())]
+    SegmentExists(PathBuf),
+
+    #[error("Invalid line in the manifest: {0:?}")]
+    InvalidManifest(String),
+
+    #[error("Invalid line in the pinned ranges: {0:?}")]
+    InvalidPins(String),
+
+    #[error("Invalid database identity: {0:?}")]
+    InvalidIdentity(String),
+
+    #[error("The segment {} was written by another database", .0.display())]
+    ForeignSegment(PathBuf),
+
+    #[error("The imported keys must be sorted and unique, {0:?} is out of order")]
+    UnsortedImport(Vec<u8>),
+
+    #[error("Invalid imported entry at line {line}: {reason}")]
+    InvalidImportEntry { line: u64, reason: String },
+
+    #[error("The imported file has no column named {0:?}")]
+    MissingColumn(String),
+
+    #[error("The entries following the sequence number {0} were already flushed out of the dirty segment")]
+    WalTruncated(u64),
+
+    #[error("A flush or a compaction panicked, the database must be reopened")]
+    Poisoned,
+
+    #[error("Waiting for the range lock would deadlock")]
+    Deadlock,
+
+    #[error("The options `{0}` and `{1}` can't be enabled together")]
+    IncompatibleOptions(&'static str, &'static str),
+
+    #[error("The database {} was written by the first version of the crate, it must be opened with `Database::open_compat`", .0.display())]
+    LegacyDatabase(PathBuf),
+
+    #[cfg(feature = "sled")]
+    #[error("{source}: {backtrace}")]
+    Sled {
+        source: sled::Error,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
+    },
+    #[cfg(feature = "rocksdb")]
+    #[error("{source}: {backtrace}")]
+    RocksDb {
+        source: rocksdb::Error,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
+    },
+    #[cfg(feature = "csv")]
+    #[error("{source}: {backtrace}")]
+    Csv {
+        source: csv::Error,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
+    },
+    #[cfg(feature = "parquet")]
+    #[error("{source}: {backtrace}")]
+    Arrow {
+        source: arrow_schema::ArrowError,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
+    },
+    #[cfg(feature = "parquet")]
+    #[error("{source}: {backtrace}")]
+    Parquet {
+        source: parquet::errors::ParquetError,
+        #[cfg_attr(feature = "nightly", backtrace)]
+        backtrace: Trace,
+    },
+}
+
+// Captures the backtrace where the error is converted, as the `#[from]` of thiserror does.
+macro_rules! from_source {
+    ($($(#[$cfg:meta])* $variant:ident($source:ty)),*) => {$(
+        $(#[$cfg])*
+        impl From<$source> for Error {
+            fn from(source: $source) -> Self {
+                Error::$variant { source, backtrace: Trace::capture() }
+            }
+        }
+    )*};
+}
+
+from_source!(
+    Io(io::Error),
+    TempFile(tempfile::PersistError),
+    #[cfg(feature = "sled")]
+    Sled(sled::Error),
+    #[cfg(feature = "rocksdb")]
+    RocksDb(rocksdb::Error),
+    #[cfg(feature = "csv")]
+    Csv(csv::Error),
+    #[cfg(feature = "parquet")]
+    Arrow(arrow_schema::ArrowError),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::errors::ParquetError)
+);
+
+/// The category of an [`Error`](enum@Error), see [`Error::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A file of the database doesn't hold what was written, e.g. a checksum mismatch.
+    Corruption,
+    /// A file of the database, or the entries asked for, no longer exist.
+    NotFound,
+    /// A lock can't be taken.
+    Locked,
+    /// The disk is full, the write can be retried once space is freed.
+    DiskFull,
+    /// The files of the database can't be written.
+    ReadOnly,
+    /// The input doesn't have the expected format, e.g. an imported entry or a tagged value.
+    InvalidFormat,
+    /// A key, a value or the memory needed by a write is larger than the limit configured.
+    LimitExceeded,
+    /// A flush or a compaction panicked, the database must be reopened.
+    Poisoned,
+    /// Any other error, like the other I/O errors or the errors of the other formats.
+    Other,
+}
+
+impl Error {
+    /// The category of the error, so it can be matched on without looking into the I/O errors.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io { source, .. } => io_kind(source),
+            Error::TempFile { source, .. } => io_kind(&source.error),
+            Error::KeyTooLarge { .. }
+            | Error::ValueTooLarge { .. }
+            | Error::MemoryBudgetExceeded { .. }
+            | Error::CounterOverflow => ErrorKind::LimitExceeded,
+            Error::MalformedKey
+            | Error::UntaggedValue
+            | Error::UnsupportedSchemaVersion(_)
+            | Error::InvalidCounter(_)
+            | Error::InvalidUtf8 { .. }
+            | Error::UnsortedImport(_)
+            | Error::InvalidImportEntry { .. }
+            | Error::MissingColumn(_)
+            | Error::LegacyDatabase(_) => ErrorKind::InvalidFormat,
+            Error::CorruptedFilter
+            | Error::CorruptedWalRecord(_)
+            | Error::InvalidManifest(_)
+            | Error::InvalidPins(_)
+            | Error::InvalidIdentity(_)
+            | Error::ForeignSegment(_)
+            | Error::SegmentExists(_) => ErrorKind::Corruption,
+            Error::MissingSegment(_) | Error::WalTruncated(_) => ErrorKind::NotFound,
+            Error::Poisoned => ErrorKind::Poisoned,
+            Error::Deadlock => ErrorKind::Locked,
+            Error::IncompatibleOptions(..) => ErrorKind::Other,
+            #[cfg(feature = "sled")]
+            Error::Sled { .. } => ErrorKind::Other,
+            #[cfg(feature = "rocksdb")]
+            Error::RocksDb { .. } => ErrorKind::Other,
+            #[cfg(feature = "csv")]
+            Error::Csv { .. } => ErrorKind::InvalidFormat,
+            #[cfg(feature = "parquet")]
+            Error::Arrow { .. } | Error::Parquet { .. } => ErrorKind::Other,
+        }
+    }
+}
+
+/// The damaged files are reported as invalid data or truncated, see [`Error::kind`].
+fn io_kind(error: &io::Error) -> ErrorKind {
+    match error.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorKind::Corruption,
+        io::ErrorKind::NotFound => ErrorKind::NotFound,
+        io::ErrorKind::WouldBlock => ErrorKind::Locked,
+        io::ErrorKind::StorageFull => ErrorKind::DiskFull,
+        io::ErrorKind::ReadOnlyFilesystem => ErrorKind::ReadOnly,
+        _ => ErrorKind::Other,
+    }
+}
+
+/// Returned by [`Database::compare_and_swap`](crate::Database::compare_and_swap) when the
+/// current value doesn't match the expected one.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("The current value doesn't match the expected one")]
+pub struct CompareAndSwapError {
+    /// The current value of the entry, `None` if it doesn't exist.
+    pub current: Option<Vec<u8>>,
 }
@@ -0,0 +1,122 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use crate::Scheduler;
+
+/// An append-only, human-readable log of the significant events happening in a database.
+///
+/// Each line starts with the number of seconds since the unix epoch. Once the log reaches
+/// its maximum size it's renamed to `LOG.1`, the previous `LOG.1` to `LOG.2` and so on.
+pub(crate) struct EventLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    // How many rotated logs are kept besides the current one
+    keep: usize,
+    // Gives the time of the events
+    clock: Arc<dyn Scheduler>,
+}
+
+impl EventLog {
+    pub fn open(
+        path: PathBuf,
+        max_size: u64,
+        keep: usize,
+        clock: Arc<dyn Scheduler>,
+    ) -> io::Result<EventLog> {
+        let file = File::options().append(true).create(true).open(&path)?;
+        Ok(EventLog {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            max_size,
+            keep,
+            clock,
+        })
+    }
+
+    /// Log an event.
+    ///
+    /// The log is only there to help the operators, failing to write it must not fail
+    /// the operation that generated the event so the errors are ignored.
+    pub fn log(&mut self, event: fmt::Arguments) {
+        let _ = self.try_log(event);
+    }
+
+    fn try_log(&mut self, event: fmt::Arguments) -> io::Result<()> {
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+
+        let now = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!("{}.{:03} {event}\n", now.as_secs(), now.subsec_millis());
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, i);
+                if from.exists() {
+                    std::fs::rename(from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = File::options().append(true).create(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_owned();
+    name.push(format!(".{n}"));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DefaultScheduler;
+
+    #[test]
+    fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("LOG");
+        let mut log = EventLog::open(path.clone(), 10, 2, Arc::new(DefaultScheduler)).unwrap();
+
+        for i in 0..4 {
+            log.log(format_args!("event {i}"));
+        }
+
+        let read = |n| {
+            let path = if n == 0 {
+                path.clone()
+            } else {
+                rotated_path(&path, n)
+            };
+            let content = std::fs::read_to_string(path).unwrap();
+            content.split_once(' ').unwrap().1.to_string()
+        };
+        assert_eq!(read(0), "event 3\n");
+        assert_eq!(read(1), "event 2\n");
+        assert_eq!(read(2), "event 1\n");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
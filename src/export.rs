@@ -0,0 +1,232 @@
+use std::{
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use crate::{segment::Pinned, sync_dir, Result};
+
+/// A clean segment handed to an external reader, see
+/// [`Database::export_segments`](crate::Database::export_segments).
+///
+/// The file is immutable and stays available until the value is dropped, even if the segment is
+/// replaced by a compaction in the meantime.
+pub struct ExportedSegment {
+    pin: Pinned,
+    keys: Option<RangeInclusive<Vec<u8>>>,
+    seqs: Option<RangeInclusive<u64>>,
+}
+
+impl ExportedSegment {
+    pub(crate) fn new(
+        pin: Pinned,
+        keys: Option<RangeInclusive<Vec<u8>>>,
+        seqs: Option<RangeInclusive<u64>>,
+    ) -> ExportedSegment {
+        ExportedSegment { pin, keys, seqs }
+    }
+
+    /// The path of the segment file.
+    pub fn path(&self) -> &Path {
+        &self.pin.path
+    }
+
+    /// The smallest and largest keys of the segment, `None` if it's empty.
+    pub fn key_range(&self) -> Option<&RangeInclusive<Vec<u8>>> {
+        self.keys.as_ref()
+    }
+
+    /// The smallest and largest sequence numbers of the segment, `None` if it's empty.
+    pub fn seq_range(&self) -> Option<&RangeInclusive<u64>> {
+        self.seqs.as_ref()
+    }
+}
+
+impl std::fmt::Debug for ExportedSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExportedSegment")
+            .field("path", &self.pin.path)
+            .field("keys", &self.keys)
+            .field("seqs", &self.seqs)
+            .finish()
+    }
+}
+
+/// The files of a database at a point in time, for the backups copying them while the
+/// database stays online, see [`Database::begin_backup`](crate::Database::begin_backup).
+///
+/// The segments stay available until the value is dropped, even if they're replaced by a
+/// compaction in the meantime. The manifest is the one written when the backup began, the
+/// files of the database directory that it doesn't list must not be copied, besides the
+/// `IDENTITY` file recorded by the segments, the blobs and the files of the value log they
+/// reference.
+pub struct Backup {
+    root: PathBuf,
+    identity_path: PathBuf,
+    manifest_path: PathBuf,
+    manifest: String,
+    segments: Vec<Pinned>,
+    // Kept by the segments referencing them
+    blobs: Vec<PathBuf>,
+    value_log: Vec<PathBuf>,
+}
+
+impl Backup {
+    pub(crate) fn new(
+        root: PathBuf,
+        identity_path: PathBuf,
+        manifest_path: PathBuf,
+        manifest: String,
+        segments: Vec<Pinned>,
+        blobs: Vec<PathBuf>,
+        value_log: Vec<PathBuf>,
+    ) -> Backup {
+        Backup {
+            root,
+            identity_path,
+            manifest_path,
+            manifest,
+            segments,
+            blobs,
+            value_log,
+        }
+    }
+
+    /// The content of the manifest listing the segments of the backup, to be written last.
+    pub fn manifest(&self) -> &str {
+        &self.manifest
+    }
+
+    /// The path of the manifest in the database directory.
+    pub fn manifest_path(&self) -> &Path {
+        &self.manifest_path
+    }
+
+    /// The paths of the segment files, from the oldest to the most recent one.
+    pub fn segments(&self) -> impl Iterator<Item = &Path> {
+        self.segments.iter().map(|pin| pin.path.as_path())
+    }
+
+    /// The paths of the blobs referenced by the segments, see
+    /// [`DatabaseBuilder::blob_threshold`](crate::DatabaseBuilder::blob_threshold).
+    pub fn blobs(&self) -> impl Iterator<Item = &Path> {
+        self.blobs.iter().map(PathBuf::as_path)
+    }
+
+    /// The paths of the files of the value log referenced by the segments, see
+    /// [`DatabaseBuilder::value_log_threshold`](crate::DatabaseBuilder::value_log_threshold).
+    /// The last one may still be appended to, the values the segments reference are already
+    /// in it.
+    pub fn value_log(&self) -> impl Iterator<Item = &Path> {
+        self.value_log.iter().map(PathBuf::as_path)
+    }
+
+    /// The path of the `IDENTITY` file, to be copied along with the segments.
+    pub fn identity_path(&self) -> &Path {
+        &self.identity_path
+    }
+
+    /// Copy the segments, the blobs, the value log, the `IDENTITY` file and then the manifest to
+    /// `dir`, at the
+    /// same paths relative to `dir` as in the database directory. The copy opens with the same
+    /// [`Layout`](crate::Layout).
+    pub fn copy_to(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let target = |path: &Path| dir.join(path.strip_prefix(&self.root).unwrap_or(path));
+        let files = self.segments().chain(self.blobs()).chain(self.value_log());
+        for path in files.chain([self.identity_path.as_path()]) {
+            let to = target(path);
+            let parent = to.parent().unwrap_or(dir);
+            fs::create_dir_all(parent)?;
+            fs::copy(path, &to)?;
+            fs::File::open(&to)?.sync_all()?;
+            sync_dir(parent)?;
+        }
+        let to = target(&self.manifest_path);
+        fs::write(&to, &self.manifest)?;
+        fs::File::open(&to)?.sync_all()?;
+        sync_dir(to.parent().unwrap_or(dir))?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Backup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Backup")
+            .field("manifest_path", &self.manifest_path)
+            .field("segments", &self.segments().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// The number of entries converted at once by [`Database::export_parquet`](crate::Database::export_parquet).
+#[cfg(feature = "parquet")]
+const PARQUET_BATCH_SIZE: usize = 8192;
+
+/// The default mapping of [`Database::export_parquet`](crate::Database::export_parquet), two
+/// binary columns named `key` and `value`.
+#[cfg(feature = "parquet")]
+pub fn key_value_batch(
+    keys: arrow_array::BinaryArray,
+    values: arrow_array::BinaryArray,
+) -> std::result::Result<arrow_array::RecordBatch, arrow_schema::ArrowError> {
+    use std::sync::Arc;
+
+    use arrow_schema::{DataType, Field, Schema};
+
+    let schema = Schema::new(vec![
+        Field::new("key", DataType::Binary, false),
+        Field::new("value", DataType::Binary, false),
+    ]);
+    arrow_array::RecordBatch::try_new(Arc::new(schema), vec![Arc::new(keys), Arc::new(values)])
+}
+
+/// Write the entries to a Parquet file through `schema_fn` and returns their number.
+///
+/// The file is written next to `path` and only moved in place once complete.
+#[cfg(feature = "parquet")]
+pub(crate) fn write_parquet<F>(
+    path: &Path,
+    entries: crate::Range,
+    mut schema_fn: F,
+) -> crate::Result<u64>
+where
+    F: FnMut(
+        arrow_array::BinaryArray,
+        arrow_array::BinaryArray,
+    ) -> std::result::Result<arrow_array::RecordBatch, arrow_schema::ArrowError>,
+{
+    use arrow_array::BinaryArray;
+    use parquet::arrow::ArrowWriter;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file = tempfile::NamedTempFile::new_in(dir)?;
+    // The schema is the one of the mapping of no entries
+    let empty = || BinaryArray::from_iter_values(std::iter::empty::<&[u8]>());
+    let schema = schema_fn(empty(), empty())?.schema();
+    let mut writer = ArrowWriter::try_new(file, schema, None)?;
+
+    let mut entries = entries;
+    let mut rows = 0;
+    loop {
+        let batch: Vec<_> = entries
+            .by_ref()
+            .take(PARQUET_BATCH_SIZE)
+            .collect::<crate::Result<_>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        rows += batch.len() as u64;
+        let keys = BinaryArray::from_iter_values(batch.iter().map(|(key, _)| key));
+        let values = BinaryArray::from_iter_values(batch.iter().map(|(_, value)| value));
+        writer.write(&schema_fn(keys, values)?)?;
+    }
+
+    let file = writer.into_inner()?;
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+    Ok(rows)
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Keep a bounded number of segment files open, the least recently used ones are closed
+/// when the capacity is reached and re-opened on demand.
+pub(crate) struct FilePool {
+    capacity: usize,
+    // Associate each open file to the last time it was used
+    files: HashMap<PathBuf, (File, u64)>,
+    clock: u64,
+}
+
+impl FilePool {
+    pub fn new(capacity: usize) -> FilePool {
+        FilePool {
+            capacity: capacity.max(1),
+            files: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Return the open file corresponding to `path`, opening it if needed.
+    pub fn get(&mut self, path: &Path) -> io::Result<&mut File> {
+        self.clock += 1;
+
+        if !self.files.contains_key(path) {
+            let file = File::open(path)?;
+            self.files.insert(path.to_owned(), (file, self.clock));
+            self.shrink_to(self.capacity);
+        }
+
+        let (file, last_used) = self.files.get_mut(path).unwrap();
+        *last_used = self.clock;
+        Ok(file)
+    }
+
+    /// Close the file corresponding to `path` if it was open.
+    /// Must be called when a file is replaced or removed.
+    pub fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.shrink_to(self.capacity);
+    }
+
+    /// The number of files currently open.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        while self.files.len() > capacity {
+            let lru = self
+                .files
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone())
+                .unwrap();
+            self.files.remove(&lru);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..3).map(|i| dir.path().join(i.to_string())).collect();
+        for path in &paths {
+            File::create(path).unwrap();
+        }
+
+        let mut pool = FilePool::new(2);
+        pool.get(&paths[0]).unwrap();
+        pool.get(&paths[1]).unwrap();
+        pool.get(&paths[0]).unwrap();
+        pool.get(&paths[2]).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(pool.files.contains_key(&paths[0]));
+        assert!(!pool.files.contains_key(&paths[1]));
+
+        pool.set_capacity(1);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.files.contains_key(&paths[2]));
+    }
+}
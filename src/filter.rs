@@ -0,0 +1,240 @@
+use std::{ops::Bound, sync::Arc};
+
+use crate::{Error, Result, Segment};
+
+/// A probabilistic set of the keys stored in a segment.
+///
+/// When a filter doesn't contain a key the segment is skipped without being read, a filter can
+/// return false positives but never false negatives.
+pub trait Filter: Send + Sync {
+    /// Add a key to the filter, it's called once per key in order.
+    fn add(&mut self, key: &[u8]);
+
+    /// Returns `false` only if the key was never added to the filter.
+    fn contains(&self, key: &[u8]) -> bool;
+
+    /// Returns the bytes stored in the segment, they're read back with [`FilterPolicy::read_filter`].
+    fn serialize(&self) -> Vec<u8>;
+}
+
+/// Create the filters of the segments.
+///
+/// The default policy is a [`Bloom`] filter, other kinds of filters such as ribbon or xor filters
+/// can be plugged in with [`DatabaseBuilder::filter`](crate::DatabaseBuilder::filter).
+pub trait FilterPolicy: Send + Sync {
+    /// Stored with the filters, a filter written with another policy is ignored when the
+    /// segment is read. It must change when the format of the serialized filter changes.
+    fn name(&self) -> &str;
+
+    /// Create an empty filter for a new segment.
+    fn new_filter(&self) -> Box<dyn Filter>;
+
+    /// Read a filter serialized by a filter created with [`FilterPolicy::new_filter`].
+    fn read_filter(&self, bytes: &[u8]) -> Result<Box<dyn Filter>>;
+}
+
+/// A bloom filter using `bits_per_key` bits for each key, 10 by default which gives
+/// a false positive rate of about 1%.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bloom {
+    bits_per_key: usize,
+}
+
+impl Bloom {
+    pub fn new(bits_per_key: usize) -> Bloom {
+        Bloom {
+            bits_per_key: bits_per_key.max(1),
+        }
+    }
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Bloom::new(10)
+    }
+}
+
+impl FilterPolicy for Bloom {
+    fn name(&self) -> &str {
+        "bloom"
+    }
+
+    fn new_filter(&self) -> Box<dyn Filter> {
+        Box::new(BloomFilter {
+            bits_per_key: self.bits_per_key,
+            hashes: Vec::new(),
+            bits: Vec::new(),
+            probes: 0,
+        })
+    }
+
+    fn read_filter(&self, bytes: &[u8]) -> Result<Box<dyn Filter>> {
+        let Some((probes, bits)) = bytes.split_last() else {
+            return Err(Error::CorruptedFilter);
+        };
+        if bits.is_empty() || *probes == 0 {
+            return Err(Error::CorruptedFilter);
+        }
+        Ok(Box::new(BloomFilter {
+            bits_per_key: self.bits_per_key,
+            hashes: Vec::new(),
+            bits: bits.to_vec(),
+            probes: *probes,
+        }))
+    }
+}
+
+struct BloomFilter {
+    bits_per_key: usize,
+    // The bits can only be sized once all the keys are known
+    hashes: Vec<u64>,
+    bits: Vec<u8>,
+    // The number of bits set for each key
+    probes: u8,
+}
+
+impl BloomFilter {
+    fn probes(hash: u64, probes: u8, bits: usize) -> impl Iterator<Item = usize> {
+        // Double hashing, the second hash must be odd to go through all the bits
+        let delta = hash.rotate_left(32) | 1;
+        (0..probes as u64)
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % bits as u64) as usize)
+    }
+}
+
+impl Filter for BloomFilter {
+    fn add(&mut self, key: &[u8]) {
+        self.hashes.push(hash(key));
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        let hash = hash(key);
+        if self.hashes.contains(&hash) {
+            return true;
+        }
+        let bits = self.bits.len() * 8;
+        bits != 0
+            && Self::probes(hash, self.probes, bits)
+                .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        // Too few bits would give a very high false positive rate
+        let bits = (self.hashes.len() * self.bits_per_key).max(64).div_ceil(8) * 8;
+        // ln(2) * bits per key minimizes the false positive rate
+        let probes = ((self.bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+
+        let mut buf = vec![0; bits / 8];
+        for hash in &self.hashes {
+            for bit in Self::probes(*hash, probes, bits) {
+                buf[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        buf.push(probes);
+        buf
+    }
+}
+
+/// An in memory filter of the keys of all the segments, so the lookups of absent keys
+/// don't have to go through the segments.
+pub(crate) struct DatabaseFilter {
+    policy: Arc<dyn FilterPolicy>,
+    // Receives the keys of all the segments, the filters can only be queried once read back
+    keys: Box<dyn Filter>,
+    filter: Box<dyn Filter>,
+    // The size of the serialized filter
+    bytes: usize,
+}
+
+impl DatabaseFilter {
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> Result<DatabaseFilter> {
+        let keys = policy.new_filter();
+        let serialized = keys.serialize();
+        let filter = policy.read_filter(&serialized)?;
+        Ok(DatabaseFilter {
+            policy,
+            keys,
+            filter,
+            bytes: serialized.len(),
+        })
+    }
+
+    /// The memory used by the filter, it's kept twice: once to receive the keys and once to be
+    /// queried.
+    pub fn bytes(&self) -> usize {
+        self.bytes * 2
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.filter.contains(key)
+    }
+
+    /// Add the keys of a new segment.
+    pub fn extend<'a>(&mut self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+        for key in keys {
+            self.keys.add(key);
+        }
+        let serialized = self.keys.serialize();
+        self.filter = self.policy.read_filter(&serialized)?;
+        self.bytes = serialized.len();
+        Ok(())
+    }
+
+    /// Rebuild the filter out of the keys of the segments, to forget the keys dropped by a compaction.
+    pub fn rebuild<'a>(
+        &mut self,
+        segments: impl IntoIterator<Item = &'a Segment>,
+        read_ahead: u64,
+    ) -> Result<()> {
+        self.keys = self.policy.new_filter();
+        for segment in segments {
+            let mut last_key = None;
+            let mut entries = segment.iter(Bound::Unbounded, read_ahead)?;
+            entries.keys_only();
+            for entry in entries {
+                let entry = entry?;
+                if last_key.as_ref() != Some(&entry.key) {
+                    self.keys.add(&entry.key);
+                    last_key = Some(entry.key);
+                }
+            }
+        }
+        self.extend([])
+    }
+}
+
+/// FNV-1a followed by the finalizer of splitmix64 to spread the bits of the short keys.
+fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bloom() {
+        let policy = Bloom::default();
+        let mut filter = policy.new_filter();
+        for i in 0..10_000u32 {
+            filter.add(&i.to_be_bytes());
+        }
+        let filter = policy.read_filter(&filter.serialize()).unwrap();
+
+        assert!((0..10_000u32).all(|i| filter.contains(&i.to_be_bytes())));
+        let false_positives = (10_000..20_000u32)
+            .filter(|i| filter.contains(&i.to_be_bytes()))
+            .count();
+        // about 1% with 10 bits per key
+        assert!(false_positives < 200, "{false_positives} false positives");
+
+        assert!(policy.read_filter(&[]).is_err());
+    }
+}
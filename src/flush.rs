@@ -0,0 +1,207 @@
+use std::{
+    io::{self, BufWriter},
+    ops::Bound,
+    panic,
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    blob::BlobStore,
+    iter::{Entry, RangeTombstone},
+    persist_segment,
+    pool::BufferPool,
+    segment::{BlockSizes, SegmentOptions},
+    uncached,
+    vlog::ValueLog,
+    write_segment, Encoding, FilterPolicy, Layout, PrefixExtractor, Result, Schema,
+};
+
+/// A memtable being written to a segment by a background thread.
+///
+/// Its entries stay readable until the segment is added to the database, while the new writes
+/// go to a fresh memtable and file of the dirty segment.
+pub(crate) struct Frozen {
+    job: Arc<FlushJob>,
+    /// The files of the dirty segment holding the entries, to delete once the segment is durable.
+    pub wal_files: Vec<u64>,
+    /// The id of the new segment.
+    pub id: usize,
+    /// The number of keys of the memtable.
+    pub len: usize,
+    /// How many live keys the memtable adds to the segments, negative when it deletes more.
+    pub live_keys: i64,
+    /// The memory used by the entries until the segment is added.
+    pub bytes: usize,
+    // `None` once the flush failed, it's then retried by the next wait
+    flush: Option<JoinHandle<Result<(u64, Duration)>>>,
+}
+
+/// Everything needed to write the segment without borrowing the database.
+pub(crate) struct FlushJob {
+    /// Sorted by key and then from the most recent to the oldest version.
+    pub entries: Vec<Entry>,
+    pub range_tombstones: Vec<RangeTombstone>,
+    pub path: PathBuf,
+    pub layout: Layout,
+    pub level_dir: PathBuf,
+    pub bottommost: bool,
+    pub versions: usize,
+    pub schema: Option<Arc<Schema>>,
+    pub filter: Option<Arc<dyn FilterPolicy>>,
+    pub prefix_extractor: Option<PrefixExtractor>,
+    pub encoding: Encoding,
+    pub block_sizes: BlockSizes,
+    pub pool: Arc<BufferPool>,
+    pub database: u128,
+    pub blobs: Option<(Arc<BlobStore>, usize)>,
+    pub value_log: Option<(Arc<ValueLog>, usize)>,
+}
+
+impl FlushJob {
+    /// Write the segment and returns its size and how long it took.
+    fn run(&self) -> Result<(u64, Duration)> {
+        let start = Instant::now();
+        let new_segment = self.layout.temp_file(&self.level_dir)?;
+        // The keys and values are an upper bound of the size, unless the blocks are compressed
+        let expected: usize = (self.entries.iter())
+            .map(|entry| entry.key.len() + entry.value.as_ref().map_or(0, Vec::len))
+            .sum();
+        uncached::preallocate(new_segment.as_file(), expected as u64)?;
+        let mut writer = BufWriter::new(new_segment);
+        let options = SegmentOptions {
+            versions: self.versions,
+            schema: self.schema.as_deref(),
+            filter: self.filter.as_deref(),
+            compaction_filter: None,
+            pins: None,
+            prefix_extractor: self.prefix_extractor,
+            encoding: self.encoding,
+            block_sizes: self.block_sizes,
+            pool: &self.pool,
+            database: self.database,
+            blobs: (self.blobs.as_ref()).map(|(store, threshold)| (store, *threshold)),
+            value_log: (self.value_log.as_ref()).map(|(log, threshold)| (log, *threshold)),
+            stored_values: false,
+            relocate: None,
+        };
+        let entries = self.entries.iter().cloned().map(Ok);
+        write_segment(
+            &mut writer,
+            entries,
+            &self.range_tombstones,
+            self.bottommost,
+            &options,
+        )?;
+
+        let mut new_segment = writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        // The footer is read from the end of the file
+        uncached::truncate_to_written(new_segment.as_file_mut())?;
+        // The dirty segment can only be deleted once the segment is durable
+        persist_segment(new_segment, &self.path)?;
+        Ok((std::fs::metadata(&self.path)?.len(), start.elapsed()))
+    }
+}
+
+impl Frozen {
+    /// Start writing the segment in the background.
+    pub fn new(
+        job: FlushJob,
+        wal_files: Vec<u64>,
+        id: usize,
+        len: usize,
+        live_keys: i64,
+    ) -> Frozen {
+        let bytes = job.entries.iter().map(Entry::memory_bytes).sum::<usize>()
+            + (job.range_tombstones.iter())
+                .map(RangeTombstone::memory_bytes)
+                .sum::<usize>();
+        let job = Arc::new(job);
+        let flush = thread::spawn({
+            let job = job.clone();
+            move || job.run()
+        });
+        Frozen {
+            job,
+            wal_files,
+            id,
+            len,
+            live_keys,
+            bytes,
+            flush: Some(flush),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.job.path
+    }
+
+    /// Wait for the segment to be written and returns its size and how long it took.
+    pub fn wait(&mut self) -> Result<(u64, Duration)> {
+        match self.flush.take() {
+            Some(flush) => flush.join().unwrap_or_else(|e| panic::resume_unwind(e)),
+            None => self.job.run(),
+        }
+    }
+
+    /// The ranges deleted by the memtable.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.job.range_tombstones
+    }
+
+    /// The most recent version of the key, its value is `None` if it was deleted.
+    pub fn get<'a>(&'a self, key: &'a [u8]) -> Option<&'a Entry> {
+        self.versions(key).next()
+    }
+
+    /// All the versions of the key from the most recent to the oldest one.
+    pub fn versions<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a Entry> {
+        let entries = &self.job.entries;
+        let start = entries.partition_point(|entry| entry.key.as_slice() < key);
+        entries[start..]
+            .iter()
+            .take_while(move |entry| entry.key == key)
+    }
+
+    /// The entries whose key is contained in the bounds.
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Vec<Entry> {
+        let entries = &self.job.entries;
+        let from = match start {
+            Bound::Included(start) => entries.partition_point(|entry| entry.key.as_slice() < start),
+            Bound::Excluded(start) => {
+                entries.partition_point(|entry| entry.key.as_slice() <= start)
+            }
+            Bound::Unbounded => 0,
+        };
+        let to = match end {
+            Bound::Included(end) => entries.partition_point(|entry| entry.key.as_slice() <= end),
+            Bound::Excluded(end) => entries.partition_point(|entry| entry.key.as_slice() < end),
+            Bound::Unbounded => entries.len(),
+        };
+        entries[from..to.max(from)].to_vec()
+    }
+
+    /// The largest key contained in `..end`, including the deleted ones.
+    pub fn last_key(&self, end: Bound<&[u8]>) -> Option<&[u8]> {
+        let entries = &self.job.entries;
+        let to = match end {
+            Bound::Included(end) => entries.partition_point(|entry| entry.key.as_slice() <= end),
+            Bound::Excluded(end) => entries.partition_point(|entry| entry.key.as_slice() < end),
+            Bound::Unbounded => entries.len(),
+        };
+        to.checked_sub(1).map(|last| entries[last].key.as_slice())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        let mut last = None;
+        self.job.entries.iter().filter_map(move |entry| {
+            let new = last != Some(entry.key.as_slice());
+            last = Some(entry.key.as_slice());
+            new.then_some(entry.key.as_slice())
+        })
+    }
+}
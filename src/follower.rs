@@ -0,0 +1,319 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    batch::{self, BatchRead},
+    blob::BlobStore,
+    cache::BlockCache,
+    files::FilePool,
+    iter::{range_deleted, Entry, RangeTombstone, Source},
+    key, manifest,
+    pool::BufferPool,
+    read_wal_record,
+    vlog::ValueLog,
+    Error, FilterPolicy, Layout, Range, Record, Result, Schema, Segment,
+};
+
+/// The number of times the manifest is read again when a compaction removes one of the
+/// segments it lists while it's read.
+const MANIFEST_ATTEMPTS: usize = 3;
+
+/// A read-only view of a database written by another process, see
+/// [`DatabaseBuilder::open_follower`](crate::DatabaseBuilder::open_follower).
+///
+/// Nothing is ever written in the directory. The view only changes when it's refreshed: the
+/// manifest is read again to find the new segments, and the files of the dirty segment are
+/// tailed from where the previous refresh stopped.
+pub struct Follower {
+    path: PathBuf,
+    layout: Layout,
+    segments: VecDeque<Segment>,
+    // The entries of the files of the dirty segment, only the most recent version of each key
+    memtable: BTreeMap<Vec<u8>, Entry>,
+    // The ranges deleted by the files of the dirty segment
+    range_tombstones: Vec<RangeTombstone>,
+    // How far each file of the dirty segment was read
+    wal_offsets: BTreeMap<u64, u64>,
+    read_ahead: u64,
+    files: FilePool,
+    reads: Box<dyn BatchRead>,
+    pool: Arc<BufferPool>,
+    cache: BlockCache,
+    // Reads the values the segments store in blobs and in the value log
+    blob_store: Arc<BlobStore>,
+    value_log: Arc<ValueLog>,
+    filter: Option<Arc<dyn FilterPolicy>>,
+    schema: Option<Arc<Schema>>,
+    // When set, the reads refresh the view once it's older than the interval
+    refresh_interval: Option<Duration>,
+    refreshed: Instant,
+}
+
+impl Follower {
+    pub(crate) fn open(
+        path: &Path,
+        layout: Layout,
+        max_open_files: usize,
+        read_ahead: u64,
+        filter: Option<Arc<dyn FilterPolicy>>,
+        pool: BufferPool,
+        cache: BlockCache,
+    ) -> Result<Follower> {
+        let mut follower = Follower {
+            path: path.to_owned(),
+            layout: layout.clone(),
+            segments: VecDeque::new(),
+            memtable: BTreeMap::new(),
+            range_tombstones: Vec::new(),
+            wal_offsets: BTreeMap::new(),
+            read_ahead,
+            files: FilePool::new(max_open_files),
+            reads: batch::reads(),
+            pool: Arc::new(pool),
+            cache,
+            blob_store: Arc::new(BlobStore::new(path, &layout)),
+            value_log: Arc::new(ValueLog::open(path, &layout)?),
+            filter,
+            schema: None,
+            refresh_interval: None,
+            refreshed: Instant::now(),
+        };
+        follower.refresh()?;
+        Ok(follower)
+    }
+
+    /// Register the schema the values were written with, see [`Database::schema`](crate::Database::schema).
+    pub fn schema(&mut self, schema: Schema) {
+        self.schema = Some(Arc::new(schema));
+    }
+
+    /// Refresh the view before the reads once it's older than `interval`.
+    pub fn refresh_every(&mut self, interval: Duration) {
+        self.refresh_interval = Some(interval);
+    }
+
+    /// Catch up with the writes of the database.
+    pub fn refresh(&mut self) -> Result<()> {
+        // The files of the dirty segment are deleted once their segment is in the manifest,
+        // reading them first means no entry can be missed in between
+        self.tail_wal()?;
+
+        let mut attempts = 0;
+        let manifest = loop {
+            match manifest::read(&self.path, &self.layout) {
+                Ok(manifest) => break manifest,
+                Err(Error::MissingSegment(_)) if attempts + 1 < MANIFEST_ATTEMPTS => attempts += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        // The segments already known keep their filter and fence
+        let mut known: HashMap<PathBuf, Segment> = self
+            .segments
+            .drain(..)
+            .map(|segment| (segment.path.clone(), segment))
+            .collect();
+        let mut holes = manifest.holes;
+        for (id, path) in manifest.listed {
+            let mut segment = known.remove(&path).unwrap_or_else(|| {
+                let mut segment = Segment::new(id, path, self.pool.clone());
+                segment.blob_store = Some(self.blob_store.clone());
+                segment.value_log = Some(self.value_log.clone());
+                segment
+            });
+            segment.holes = holes.remove(&segment.path).unwrap_or_default();
+            self.segments.push_back(segment);
+        }
+        for path in known.into_keys() {
+            self.files.forget(&path);
+        }
+        self.refreshed = Instant::now();
+        Ok(())
+    }
+
+    /// Read the entries appended to the files of the dirty segment since the previous refresh.
+    fn tail_wal(&mut self) -> Result<()> {
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(self.layout.wal_files_dir(&self.path))? {
+            let name = entry?.file_name();
+            if let Some(number) = name.to_str().and_then(|name| self.layout.parse_wal(name)) {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+        // The entries of the deleted files are in the segments now
+        if self
+            .wal_offsets
+            .keys()
+            .any(|number| !numbers.contains(number))
+        {
+            self.wal_offsets.clear();
+            self.memtable.clear();
+            self.range_tombstones.clear();
+        }
+
+        for number in numbers {
+            let offset = self.wal_offsets.entry(number).or_insert(0);
+            let mut file = match fs::File::open(self.layout.wal_path(&self.path, number)) {
+                Ok(file) => file,
+                // Flushed in the meantime
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            file.seek(SeekFrom::Start(*offset))?;
+            let mut appended = Vec::new();
+            file.read_to_end(&mut appended)?;
+
+            let start = *offset;
+            let mut reader = appended.as_slice();
+            loop {
+                let record = match read_wal_record(&mut reader) {
+                    Ok(Some(record)) => record,
+                    // The last entry may still be being written
+                    Ok(None) => break,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e.into()),
+                };
+                *offset = start + (appended.len() - reader.len()) as u64;
+                match record {
+                    Record::Entry(entry) => {
+                        self.memtable.insert(entry.key.clone(), entry);
+                    }
+                    // The previous value is always in the same files
+                    Record::Append { entry, .. } => match self.memtable.get_mut(&entry.key) {
+                        Some(previous) => {
+                            let fragment = entry.value.unwrap_or_default();
+                            let value = previous.value.get_or_insert_with(Vec::new);
+                            value.extend_from_slice(&fragment);
+                            previous.seq = entry.seq;
+                            previous.timestamp = entry.timestamp;
+                        }
+                        None => {
+                            self.memtable.insert(entry.key.clone(), entry);
+                        }
+                    },
+                    Record::DeleteRange(range) => self.range_tombstones.push(range),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn refresh_if_outdated(&mut self) -> Result<()> {
+        match self.refresh_interval {
+            Some(interval) if self.refreshed.elapsed() >= interval => self.refresh(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.refresh_if_outdated()?;
+        let key = key.as_ref();
+        let value = match self.lookup(key) {
+            // A compaction removed a segment since the last refresh
+            Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                self.refresh()?;
+                self.lookup(key)?
+            }
+            value => value?,
+        };
+        match (value, &self.schema) {
+            (Some(value), Some(schema)) => Ok(Some(schema.migrate(value)?)),
+            (value, _) => Ok(value),
+        }
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let range_tombstones = self.collect_range_tombstones()?;
+        if let Some(entry) = self.memtable.get(key) {
+            if range_deleted(&range_tombstones, key, entry.seq) {
+                return Ok(None);
+            }
+            return Ok(entry.value.clone());
+        }
+        for segment in self.segments.iter().rev() {
+            if !segment.in_fence(&mut self.files, key)? {
+                continue;
+            }
+            if let Some(policy) = &self.filter {
+                let filter = segment.filter(&mut self.files, policy.as_ref())?;
+                if filter.is_some_and(|filter| !filter.contains(key)) {
+                    continue;
+                }
+            }
+            let found = segment.multi_get(
+                &mut self.files,
+                &[key],
+                self.reads.as_mut(),
+                &mut self.cache,
+            )?;
+            if let Some(entry) = found.into_iter().next().flatten() {
+                if range_deleted(&range_tombstones, key, entry.seq) {
+                    return Ok(None);
+                }
+                return Ok(entry.value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The ranges deleted by the dirty segment and the segments.
+    fn collect_range_tombstones(&mut self) -> Result<Vec<RangeTombstone>> {
+        let mut range_tombstones = self.range_tombstones.clone();
+        for segment in &self.segments {
+            range_tombstones.extend_from_slice(segment.range_tombstones(&mut self.files)?);
+        }
+        Ok(range_tombstones)
+    }
+
+    /// Iterate over all the entries whose key is contained in `range`, in order.
+    ///
+    /// The segments are opened right away, a compaction can't remove them while they're read.
+    pub fn range<K: AsRef<[u8]>>(&mut self, range: impl RangeBounds<K>) -> Result<Range> {
+        self.refresh_if_outdated()?;
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        if key::is_empty_range(
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        ) {
+            return Range::new(Vec::new(), end, None, Vec::new());
+        }
+        let entries: Vec<_> = self
+            .memtable
+            .range::<[u8], _>((
+                start.as_ref().map(Vec::as_slice),
+                end.as_ref().map(Vec::as_slice),
+            ))
+            .map(|(_, entry)| entry.clone())
+            .collect();
+
+        let range_tombstones = match self.collect_range_tombstones() {
+            Ok(range_tombstones) => range_tombstones,
+            Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                self.refresh()?;
+                return self.range((start, end));
+            }
+            Err(e) => return Err(e),
+        };
+        let mut sources = vec![Source::Memtable(entries.into_iter())];
+        for segment in self.segments.iter().rev() {
+            let iter = match segment.iter(start.clone(), self.read_ahead) {
+                Ok(iter) => iter,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.refresh()?;
+                    return self.range((start, end));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            sources.push(Source::Segment(Box::new(iter)));
+        }
+        Range::new(sources, end, self.schema.clone(), range_tombstones)
+    }
+}
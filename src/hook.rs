@@ -0,0 +1,36 @@
+/// A write of the database, see [`WriteHook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteEvent<'a> {
+    /// The key written, or the start of the range deleted by
+    /// [`Database::delete_range`](crate::Database::delete_range).
+    pub key: &'a [u8],
+    /// The end of the deleted range, `None` for the other writes.
+    pub range_end: Option<&'a [u8]>,
+    /// The size of the value written, or of the bytes given to
+    /// [`Database::append`](crate::Database::append). `None` for the deletions.
+    pub value_size: Option<usize>,
+    /// The sequence number of the write.
+    pub seq: u64,
+}
+
+/// Told about the writes, flushes and compactions of the database, e.g. to maintain caches,
+/// metrics or secondary systems derived from its content.
+///
+/// The hooks are called on the thread of the write and in the order they were added with
+/// [`DatabaseBuilder::hook`](crate::DatabaseBuilder::hook), they should return quickly. The
+/// imports don't call the hooks for each entry, only [`WriteHook::after_flush`] for the segment
+/// they write.
+pub trait WriteHook: Send + Sync {
+    /// Called once the write is accepted, right before it's written to the dirty segment. The
+    /// write can still fail afterward.
+    fn before_write(&self, _event: &WriteEvent) {}
+
+    /// Called once the write is committed to the dirty segment, and synced when it was asked to.
+    fn after_write(&self, _event: &WriteEvent) {}
+
+    /// Called once a flush or an import added a segment of `size` bytes to the database.
+    fn after_flush(&self, _segment: usize, _size: u64) {}
+
+    /// Called once a compaction replaced the oldest segments by a segment of `size` bytes.
+    fn after_compaction(&self, _segment: usize, _size: u64) {}
+}
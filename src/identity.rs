@@ -0,0 +1,87 @@
+use std::{fs, io, path::Path};
+
+use crate::{sync_dir, Error, Layout, Result};
+
+/// Read the identity of the database, `identity` or a new one is written to the `IDENTITY` file
+/// if there is none yet.
+///
+/// The file holds the identity as a UUID. The databases created before it existed get one on
+/// their next open, their segments have no identity and are accepted by any database.
+pub(crate) fn load_or_create(root: &Path, layout: &Layout, identity: Option<u128>) -> Result<u128> {
+    let path = layout.identity_path(root);
+    match fs::read_to_string(&path) {
+        Ok(content) => {
+            let content = content.trim();
+            parse(content).ok_or_else(|| Error::InvalidIdentity(content.to_owned()))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let identity = identity.unwrap_or_else(generate);
+            let mut file = layout.temp_file(root)?;
+            io::Write::write_all(&mut file, format!("{}\n", format(identity)).as_bytes())?;
+            file.as_file().sync_all()?;
+            file.persist(&path)?;
+            sync_dir(root)?;
+            Ok(identity)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A random version 4 UUID, never 0 which marks the segments without identity.
+fn generate() -> u128 {
+    use std::hash::{BuildHasher, RandomState};
+
+    // The randomness only comes from the keys of the `RandomState`, seeded from the system
+    // randomness once per thread and incremented for each new one. The time and the process id
+    // tell apart the identities generated from the same keys, e.g. after a fork
+    let state = RandomState::new();
+    let seed = (std::time::SystemTime::now(), std::process::id());
+    let high = state.hash_one((seed, 0)) as u128;
+    let low = state.hash_one((seed, 1)) as u128;
+    let identity = high << 64 | low;
+    // The version and variant bits
+    identity & !(0xf << 76 | 0x3 << 62) | 0x4 << 76 | 0x2 << 62
+}
+
+/// The UUID in its hyphenated form.
+pub(crate) fn format(identity: u128) -> String {
+    let hex = format!("{identity:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn parse(uuid: &str) -> Option<u128> {
+    let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+    match hex.len() == 32 && uuid.len() == 36 {
+        true => u128::from_str_radix(&hex, 16)
+            .ok()
+            .filter(|identity| *identity != 0),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn random_identity() {
+        let identities: Vec<_> = (0..100).map(|_| generate()).collect();
+        for identity in &identities {
+            let uuid = format(*identity);
+            assert_eq!(&uuid[14..15], "4");
+            assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"), "{uuid}");
+            assert_eq!(parse(&uuid), Some(*identity));
+        }
+        let mut unique = identities.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), identities.len());
+    }
+}
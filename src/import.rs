@@ -0,0 +1,198 @@
+use std::{
+    io::{self, BufWriter},
+    ops::Bound,
+    path::Path,
+    sync::Arc,
+};
+
+use tempfile::NamedTempFile;
+
+use crate::{
+    iter::{Entry, MergeIter, Source},
+    pool::BufferPool,
+    segment::{SegmentIter, SegmentWriter},
+    Encoding, Layout, Result,
+};
+
+/// The size of the keys and values sorted in memory at once by
+/// [`Database::import_unsorted`](crate::Database::import_unsorted).
+pub(crate) const RUN_SIZE: usize = 64 * 1024 * 1024;
+
+/// Sort entries that may not fit in memory.
+///
+/// The entries are sorted by chunks of `run_size` bytes, each chunk is written to a temporary
+/// segment and the segments are merged back once everything was read. The last chunk stays
+/// in memory.
+pub(crate) struct ExternalSort<'a> {
+    /// Where the temporary segments are written.
+    pub dir: &'a Path,
+    pub layout: &'a Layout,
+    pub encoding: Encoding,
+    pub pool: &'a Arc<BufferPool>,
+    pub run_size: usize,
+    pub read_ahead: u64,
+}
+
+impl ExternalSort<'_> {
+    pub fn sort(
+        &self,
+        entries: impl IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    ) -> Result<Sorted> {
+        let mut runs = Vec::new();
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        // The later occurrences of a key have a larger sequence number and replace the previous ones
+        for (seq, entry) in (1..).zip(entries) {
+            let (key, value) = entry?;
+            size += key.len() + value.len();
+            chunk.push(Entry {
+                key,
+                seq,
+                meta: 0,
+                timestamp: 0,
+                value: Some(value),
+            });
+            if size >= self.run_size {
+                runs.push(self.write_run(&mut chunk)?);
+                size = 0;
+            }
+        }
+
+        let mut sources = Vec::with_capacity(runs.len() + 1);
+        for run in &runs {
+            let iter = SegmentIter::open(run.path(), Bound::Unbounded, self.read_ahead)?;
+            sources.push(Source::Segment(Box::new(iter)));
+        }
+        sort_chunk(&mut chunk);
+        sources.push(Source::Memtable(chunk.into_iter()));
+        Ok(Sorted {
+            entries: MergeIter::new(sources)?,
+            last_key: None,
+            _runs: runs,
+        })
+    }
+
+    fn write_run(&self, chunk: &mut Vec<Entry>) -> Result<NamedTempFile> {
+        sort_chunk(chunk);
+        let run = self.layout.temp_file(self.dir)?;
+        let mut writer =
+            SegmentWriter::new(BufWriter::new(run), None, self.encoding, self.pool.clone());
+        for entry in chunk.drain(..) {
+            writer.add(
+                &entry.key,
+                entry.seq,
+                entry.meta,
+                entry.timestamp,
+                entry.value.as_deref(),
+            )?;
+        }
+        let run = writer
+            .finish()?
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        Ok(run)
+    }
+}
+
+/// Sort by key and only keep the last occurrence of each key.
+fn sort_chunk(chunk: &mut Vec<Entry>) {
+    chunk.sort_unstable_by(|a, b| a.key.cmp(&b.key).then(b.seq.cmp(&a.seq)));
+    chunk.dedup_by(|next, kept| next.key == kept.key);
+}
+
+/// The entries sorted by [`ExternalSort`], the temporary segments are deleted once dropped.
+pub(crate) struct Sorted {
+    entries: MergeIter,
+    // The key of the last returned entry, its older occurrences must be skipped
+    last_key: Option<Vec<u8>>,
+    _runs: Vec<NamedTempFile>,
+}
+
+impl Iterator for Sorted {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if self.last_key.as_ref() == Some(&entry.key) {
+                continue;
+            }
+            self.last_key = Some(entry.key.clone());
+            // Only the values are written in the runs
+            return Some(Ok((entry.key, entry.value.unwrap_or_default())));
+        }
+    }
+}
+
+/// Parse a line of a JSON-lines file, an object with a string `key` and a `value`.
+///
+/// The string values are stored as is, the other values are stored as JSON.
+#[cfg(feature = "jsonl")]
+pub(crate) fn parse_json_line(line: u64, text: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    use serde_json::Value;
+
+    let invalid = |reason: String| crate::Error::InvalidImportEntry { line, reason };
+    let mut object = match serde_json::from_str(text) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err(invalid("not an object".to_string())),
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+    let key = match object.remove("key") {
+        Some(Value::String(key)) => key.into_bytes(),
+        Some(_) => return Err(invalid("the key isn't a string".to_string())),
+        None => return Err(invalid("missing key".to_string())),
+    };
+    let value = match object.remove("value") {
+        Some(Value::String(value)) => value.into_bytes(),
+        Some(value) => value.to_string().into_bytes(),
+        None => return Err(invalid("missing value".to_string())),
+    };
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn external_sort() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = Arc::new(BufferPool::default());
+        let sort = ExternalSort {
+            dir: dir.path(),
+            layout: &Layout::default(),
+            encoding: Encoding::Varint,
+            pool: &pool,
+            run_size: 4,
+            read_ahead: 0,
+        };
+        let entries = [
+            ("c", "1"),
+            ("a", "2"),
+            ("c", "3"),
+            ("b", "4"),
+            ("a", "5"),
+            ("d", "6"),
+        ];
+        let entries = entries.map(|(key, value)| Ok((key.into(), value.into())));
+        let sorted = sort.sort(entries).unwrap();
+        // The runs are written next to the segments
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        let sorted: Vec<_> = sorted
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                format!(
+                    "{}={}",
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(value).unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(sorted, ["a=5", "b=4", "c=3", "d=6"]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    cmp::Ordering,
+    io,
+    iter::Fuse,
+    mem,
+    ops::{Bound, RangeBounds},
+    sync::{mpsc, Arc},
+    thread, vec,
+};
+
+use crate::{segment::SegmentIter, Result, Schema};
+
+/// The deletion of all the keys of `start..end` written before it, see
+/// [`Database::delete_range`](crate::Database::delete_range).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    /// The sequence number of the deletion.
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    /// Whether the version of the key written with the sequence number `seq` is deleted.
+    pub fn deletes(&self, key: &[u8], seq: u64) -> bool {
+        seq < self.seq && self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    /// The memory used by the range tombstone, its bounds included.
+    pub(crate) fn memory_bytes(&self) -> usize {
+        mem::size_of::<RangeTombstone>() + self.start.len() + self.end.len()
+    }
+}
+
+/// Whether the version of the key written with the sequence number `seq` is deleted by one of
+/// the range tombstones.
+pub(crate) fn range_deleted(range_tombstones: &[RangeTombstone], key: &[u8], seq: u64) -> bool {
+    range_tombstones.iter().any(|range| range.deletes(key, seq))
+}
+
+/// Decides which keys a filtered range returns, see
+/// [`Database::range_filtered`](crate::Database::range_filtered).
+pub(crate) type KeyFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// An entry as it's stored in the dirty and clean segments, see [`SegmentReader`](crate::SegmentReader).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub seq: u64,
+    /// Left to the application, 0 when none was given, see
+    /// [`Database::add_with_meta`](crate::Database::add_with_meta).
+    pub meta: u8,
+    /// The milliseconds since the Unix epoch when the entry was written, 0 when it wasn't
+    /// recorded, see [`DatabaseBuilder::timestamps`](crate::DatabaseBuilder::timestamps).
+    pub timestamp: u64,
+    /// `None` for the deleted entries.
+    pub value: Option<Vec<u8>>,
+}
+
+impl Entry {
+    /// The memory used by the entry, its key and value included.
+    pub(crate) fn memory_bytes(&self) -> usize {
+        mem::size_of::<Entry>() + self.key.len() + self.value.as_ref().map_or(0, Vec::len)
+    }
+}
+
+/// Merge sources sorted by key into a single sorted stream.
+///
+/// All the versions of the entries are returned, the versions of a key are returned from the
+/// most recent to the oldest one.
+pub(crate) struct MergeIter {
+    sources: Vec<Source>,
+    // The next entry of each source.
+    heads: Vec<Option<Entry>>,
+}
+
+impl MergeIter {
+    pub fn new(sources: Vec<Source>) -> io::Result<MergeIter> {
+        let mut merge = MergeIter {
+            heads: vec![None; sources.len()],
+            sources,
+        };
+        for i in 0..merge.sources.len() {
+            merge.advance(i)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (next, _) = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| Some((i, head.as_ref()?)))
+            .min_by(|(_, a), (_, b)| a.key.cmp(&b.key).then(b.seq.cmp(&a.seq)))?;
+        let entry = self.heads[next].take()?;
+
+        match self.advance(next) {
+            Ok(()) => Some(Ok(entry)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// An iterator over the entries of a range of keys, in order.
+///
+/// It merges the memtable with all the segments, only the most recent value of each key is returned.
+/// The segments are re-opened when the iterator is created so it doesn't borrow the database,
+/// and the segments replaced by a compaction are only deleted once the iterator is dropped.
+pub struct Range {
+    entries: MergeIter,
+    end: Bound<Vec<u8>>,
+    // The key of the last returned entry, its older versions must be skipped
+    last_key: Option<Vec<u8>>,
+    // When set the values are returned untagged and upgraded to the current version
+    schema: Option<Arc<Schema>>,
+    // Hide the entries written before them
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl Range {
+    pub(crate) fn new(
+        sources: Vec<Source>,
+        end: Bound<Vec<u8>>,
+        schema: Option<Arc<Schema>>,
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> Result<Range> {
+        Ok(Range {
+            entries: MergeIter::new(sources)?,
+            end,
+            last_key: None,
+            schema,
+            range_tombstones,
+        })
+    }
+}
+
+impl Iterator for Range {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = loop {
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if self.last_key.as_ref() == Some(&entry.key) {
+                continue;
+            }
+
+            let range = (Bound::Unbounded, self.end.as_ref());
+            if !RangeBounds::<Vec<u8>>::contains(&range, &entry.key) {
+                self.entries.heads.clear();
+                return None;
+            }
+            self.last_key = Some(entry.key.clone());
+
+            // The deleted entries still hide their older versions
+            if range_deleted(&self.range_tombstones, &entry.key, entry.seq) {
+                continue;
+            }
+            if let Some(value) = entry.value {
+                break (entry.key, value);
+            }
+        };
+
+        match &self.schema {
+            Some(schema) => Some(schema.migrate(value).map(|value| (key, value))),
+            None => Some(Ok((key, value))),
+        }
+    }
+}
+
+/// A key whose entry differs between two databases, see [`Database::diff`](crate::Database::diff).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The key only has a value in the database `diff` was called on.
+    OnlyInSelf { key: Vec<u8>, value: Vec<u8> },
+    /// The key only has a value in the other database.
+    OnlyInOther { key: Vec<u8>, value: Vec<u8> },
+    /// The key has a different value in each database.
+    Different {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        other_value: Vec<u8>,
+    },
+}
+
+/// An iterator over the differences between two databases, in the order of the keys.
+///
+/// It walks the entries of both databases side by side, only the current entry of each is
+/// kept in memory.
+pub struct Diff {
+    ours: Fuse<Range>,
+    theirs: Fuse<Range>,
+    // The entries read ahead and not compared yet
+    our_head: Option<(Vec<u8>, Vec<u8>)>,
+    their_head: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Diff {
+    pub(crate) fn new(ours: Range, theirs: Range) -> Diff {
+        Diff {
+            ours: ours.fuse(),
+            theirs: theirs.fuse(),
+            our_head: None,
+            their_head: None,
+        }
+    }
+}
+
+impl Iterator for Diff {
+    type Item = Result<Difference>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.our_head.is_none() {
+                self.our_head = match self.ours.next().transpose() {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+            if self.their_head.is_none() {
+                self.their_head = match self.theirs.next().transpose() {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+
+            let difference = match (self.our_head.take(), self.their_head.take()) {
+                (None, None) => return None,
+                (Some((key, value)), None) => Difference::OnlyInSelf { key, value },
+                (None, Some((key, value))) => Difference::OnlyInOther { key, value },
+                (Some(ours), Some(theirs)) => match ours.0.cmp(&theirs.0) {
+                    Ordering::Less => {
+                        self.their_head = Some(theirs);
+                        let (key, value) = ours;
+                        Difference::OnlyInSelf { key, value }
+                    }
+                    Ordering::Greater => {
+                        self.our_head = Some(ours);
+                        let (key, value) = theirs;
+                        Difference::OnlyInOther { key, value }
+                    }
+                    Ordering::Equal if ours.1 == theirs.1 => continue,
+                    Ordering::Equal => Difference::Different {
+                        key: ours.0,
+                        value: ours.1,
+                        other_value: theirs.1,
+                    },
+                },
+            };
+            return Some(Ok(difference));
+        }
+    }
+}
+
+type Chunk = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An iterator over chunks of entries of a range of keys.
+///
+/// The entries are read by a worker thread that prepares the next chunk while the current one is being processed.
+pub struct Chunks {
+    receiver: mpsc::Receiver<Result<Chunk>>,
+}
+
+impl Chunks {
+    pub(crate) fn new(mut range: Range, chunk_size: usize) -> Chunks {
+        let chunk_size = chunk_size.max(1);
+        // The worker is only allowed to get one chunk ahead of the consumer
+        let (sender, receiver) = mpsc::sync_channel(1);
+
+        thread::spawn(move || loop {
+            let chunk: Result<Chunk> = range.by_ref().take(chunk_size).collect();
+            if matches!(&chunk, Ok(chunk) if chunk.is_empty()) {
+                break;
+            }
+            let last = !matches!(&chunk, Ok(chunk) if chunk.len() == chunk_size);
+            // When the consumer is gone there is no need to continue
+            if sender.send(chunk).is_err() || last {
+                break;
+            }
+        });
+
+        Chunks { receiver }
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// An iterator over the keys of a range, in order, see [`Database::keys`](crate::Database::keys).
+pub struct Keys {
+    range: Range,
+}
+
+impl Keys {
+    pub(crate) fn new(range: Range) -> Keys {
+        Keys { range }
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.range.next()?.map(|(key, _)| key))
+    }
+}
+
+/// An iterator over the entries of a range of UTF-8 keys and values, see
+/// [`Database::range_str`](crate::Database::range_str).
+pub struct StrRange {
+    range: Range,
+}
+
+impl StrRange {
+    pub(crate) fn new(range: Range) -> StrRange {
+        StrRange { range }
+    }
+}
+
+impl Iterator for StrRange {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match self.range.next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(utf8_entry(key, value))
+    }
+}
+
+/// Convert the key and the value to strings, the error holds the key of the invalid entry.
+pub(crate) fn utf8_entry(key: Vec<u8>, value: Vec<u8>) -> Result<(String, String)> {
+    let invalid = |key: Vec<u8>, source| crate::Error::InvalidUtf8 { key, source };
+    let value = match String::from_utf8(value) {
+        Ok(value) => value,
+        Err(e) => return Err(invalid(key, e.utf8_error())),
+    };
+    match String::from_utf8(key) {
+        Ok(key) => Ok((key, value)),
+        Err(e) => {
+            let source = e.utf8_error();
+            Err(invalid(e.into_bytes(), source))
+        }
+    }
+}
+
+pub(crate) enum Source {
+    Memtable(vec::IntoIter<Entry>),
+    Segment(Box<SegmentIter>),
+}
+
+impl Iterator for Source {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Source::Memtable(entries) => entries.next().map(Ok),
+            Source::Segment(segment) => segment.next(),
+        }
+    }
+}
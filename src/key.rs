@@ -0,0 +1,308 @@
+use std::ops::Bound;
+
+use crate::{Error, Result};
+
+/// A composite key whose byte representation sorts the same way its parts do.
+///
+/// ```
+/// use database::Key;
+///
+/// let key = Key::new().push(42_u64).push("tamo").push(-3_i32);
+/// let mut decoder = Key::decode(key.as_bytes());
+/// assert_eq!(decoder.read::<u64>().unwrap(), 42);
+/// assert_eq!(decoder.read::<String>().unwrap(), "tamo");
+/// assert_eq!(decoder.read::<i32>().unwrap(), -3);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key {
+    bytes: Vec<u8>,
+}
+
+impl Key {
+    pub fn new() -> Key {
+        Key::default()
+    }
+
+    /// Append a new part at the end of the key.
+    pub fn push(mut self, part: impl KeyPart) -> Key {
+        part.encode(&mut self.bytes);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Read back the parts of a key previously built with [`Key::push`].
+    pub fn decode(bytes: &[u8]) -> KeyDecoder<'_> {
+        KeyDecoder { bytes }
+    }
+
+    /// The range containing every key starting with all the parts of this key.
+    pub fn prefix_range(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        prefix_range(&self.bytes)
+    }
+}
+
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<Key> for Vec<u8> {
+    fn from(key: Key) -> Vec<u8> {
+        key.bytes
+    }
+}
+
+pub struct KeyDecoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl KeyDecoder<'_> {
+    /// Decode the next part of the key.
+    pub fn read<T: FromKeyPart>(&mut self) -> Result<T> {
+        T::decode(&mut self.bytes).ok_or(Error::MalformedKey)
+    }
+
+    /// The bytes that haven't been decoded yet.
+    pub fn remaining(&self) -> &[u8] {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// A value that can be encoded as part of a [`Key`].
+///
+/// The encoding must preserve the ordering of the values: if `a < b` then
+/// the encoding of `a` must sort before the encoding of `b`.
+pub trait KeyPart {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// A value that can be decoded from a part of a [`Key`].
+pub trait FromKeyPart: Sized {
+    /// Decode the value from the start of `input` and advance it past the consumed bytes.
+    fn decode(input: &mut &[u8]) -> Option<Self>;
+}
+
+impl<T: KeyPart + ?Sized> KeyPart for &T {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out)
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if input.len() < n {
+        return None;
+    }
+    let (head, tail) = input.split_at(n);
+    *input = tail;
+    Some(head)
+}
+
+macro_rules! unsigned_key_part {
+    ($($ty:ty),*) => {$(
+        impl KeyPart for $ty {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+
+        impl FromKeyPart for $ty {
+            fn decode(input: &mut &[u8]) -> Option<Self> {
+                let bytes = take(input, std::mem::size_of::<$ty>())?;
+                Some(<$ty>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+// Flipping the sign bit makes the negative numbers sort before the positive ones.
+macro_rules! signed_key_part {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl KeyPart for $ty {
+            fn encode(&self, out: &mut Vec<u8>) {
+                ((*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1))).encode(out);
+            }
+        }
+
+        impl FromKeyPart for $ty {
+            fn decode(input: &mut &[u8]) -> Option<Self> {
+                let n = <$unsigned>::decode(input)?;
+                Some((n ^ (1 << (<$unsigned>::BITS - 1))) as $ty)
+            }
+        }
+    )*};
+}
+
+unsigned_key_part!(u8, u16, u32, u64, u128);
+signed_key_part!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyPart for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode(out)
+    }
+}
+
+impl FromKeyPart for bool {
+    fn decode(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode(input)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+// Variable length parts are terminated by `0x00 0x01` and every `0x00` they contain
+// is escaped as `0x00 0xff`. That way a shorter part always sorts before a longer
+// one sharing the same prefix, and the parts following it can't leak into the comparison.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+impl KeyPart for [u8] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        for &byte in self {
+            out.push(byte);
+            if byte == ESCAPE {
+                out.push(ESCAPED_ZERO);
+            }
+        }
+        out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+    }
+}
+
+impl KeyPart for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out)
+    }
+}
+
+impl FromKeyPart for Vec<u8> {
+    fn decode(input: &mut &[u8]) -> Option<Self> {
+        let mut bytes = Vec::new();
+        loop {
+            match *take(input, 1)? {
+                [ESCAPE] => match *take(input, 1)? {
+                    [ESCAPED_ZERO] => bytes.push(ESCAPE),
+                    [TERMINATOR] => return Some(bytes),
+                    _ => return None,
+                },
+                [byte] => bytes.push(byte),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl KeyPart for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode(out)
+    }
+}
+
+impl KeyPart for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode(out)
+    }
+}
+
+impl FromKeyPart for String {
+    fn decode(input: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(Vec::decode(input)?).ok()
+    }
+}
+
+/// The range containing every key starting with `prefix`.
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+
+    // The first key that doesn't start with the prefix is the prefix with its last
+    // byte incremented, once all the trailing `0xff` have been removed.
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last != u8::MAX {
+            end.push(last + 1);
+            return (start, Bound::Excluded(end));
+        }
+    }
+    (start, Bound::Unbounded)
+}
+
+/// Whether the bounds contain no key, e.g. when the start is past the end. A `BTreeMap` panics
+/// on such bounds.
+pub fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn preserve_ordering() {
+        let keys = [
+            Key::new().push(-300_i32).push("b"),
+            Key::new().push(-1_i32).push(""),
+            Key::new().push(0_i32).push("a"),
+            Key::new().push(0_i32).push("a\0"),
+            Key::new().push(0_i32).push("ab"),
+            Key::new().push(7_i32).push("a"),
+        ];
+
+        for window in keys.windows(2) {
+            assert!(window[0].as_bytes() < window[1].as_bytes(), "{window:?}");
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let key = Key::new()
+            .push(12_u8)
+            .push(-42_i64)
+            .push(b"a\0b".as_slice())
+            .push(true)
+            .push("tamo");
+
+        let mut decoder = Key::decode(key.as_bytes());
+        assert_eq!(decoder.read::<u8>().unwrap(), 12);
+        assert_eq!(decoder.read::<i64>().unwrap(), -42);
+        assert_eq!(decoder.read::<Vec<u8>>().unwrap(), b"a\0b");
+        assert!(decoder.read::<bool>().unwrap());
+        assert_eq!(decoder.read::<String>().unwrap(), "tamo");
+        assert!(decoder.is_empty());
+        assert!(matches!(decoder.read::<u32>(), Err(Error::MalformedKey)));
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(
+            prefix_range(&[1, 2]),
+            (Bound::Included(vec![1, 2]), Bound::Excluded(vec![1, 3]))
+        );
+        assert_eq!(
+            prefix_range(&[1, 255]),
+            (Bound::Included(vec![1, 255]), Bound::Excluded(vec![2]))
+        );
+        assert_eq!(
+            prefix_range(&[255]),
+            (Bound::Included(vec![255]), Bound::Unbounded)
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use tempfile::NamedTempFile;
+
+/// Where the files of a database are stored inside its directory.
+///
+/// By default everything lives directly in the database directory. The layout makes it
+/// possible to share the directory with other files of an application:
+///
+/// ```
+/// use database::{Database, Layout};
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// // creates `kv-LOG`, `wal/kv-wal-000001`, `segments/L0/kv-segment-0`, `segments/L1/kv-segment-0`…
+/// let database = Database::builder()
+///     .layout(Layout::nested().prefix("kv-"))
+///     .open(dir.path())
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    prefix: String,
+    wal_dir: PathBuf,
+    segments_dir: PathBuf,
+    level_dirs: bool,
+}
+
+impl Layout {
+    /// All the files are stored at the root of the database directory.
+    pub fn flat() -> Layout {
+        Layout::default()
+    }
+
+    /// The dirty segment is stored in `wal/`, the flushed segments in `segments/L0/`
+    /// and the compacted segments in `segments/L1/`.
+    pub fn nested() -> Layout {
+        Layout {
+            prefix: String::new(),
+            wal_dir: PathBuf::from("wal"),
+            segments_dir: PathBuf::from("segments"),
+            level_dirs: true,
+        }
+    }
+
+    /// Prefix all the file names.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Layout {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// The directory holding the files of the dirty segment, relative to the database directory.
+    pub fn wal_dir(mut self, dir: impl Into<PathBuf>) -> Layout {
+        self.wal_dir = dir.into();
+        self
+    }
+
+    /// The directory holding the clean segments, relative to the database directory.
+    pub fn segments_dir(mut self, dir: impl Into<PathBuf>) -> Layout {
+        self.segments_dir = dir.into();
+        self
+    }
+
+    /// Store the segments of each level in their own `L{level}` sub-directory.
+    pub fn level_dirs(mut self, level_dirs: bool) -> Layout {
+        self.level_dirs = level_dirs;
+        self
+    }
+
+    pub(crate) fn create_dirs(&self, root: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(root.join(&self.wal_dir))?;
+        for level in 0..=1 {
+            std::fs::create_dir_all(self.level_dir(root, level))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn log_path(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}LOG", self.prefix))
+    }
+
+    pub(crate) fn manifest_path(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}MANIFEST", self.prefix))
+    }
+
+    /// The ranges of keys pinned with [`Database::pin_range`](crate::Database::pin_range).
+    pub(crate) fn pins_path(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}PINS", self.prefix))
+    }
+
+    /// The identity stamped in the segments, see [`Database::identity`](crate::Database::identity).
+    pub(crate) fn identity_path(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}IDENTITY", self.prefix))
+    }
+
+    /// Where the large values are stored once, see
+    /// [`DatabaseBuilder::blob_threshold`](crate::DatabaseBuilder::blob_threshold).
+    pub(crate) fn blobs_dir(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}blobs", self.prefix))
+    }
+
+    /// Where the large values are appended, see
+    /// [`DatabaseBuilder::value_log_threshold`](crate::DatabaseBuilder::value_log_threshold).
+    pub(crate) fn value_log_dir(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}vlog", self.prefix))
+    }
+
+    /// Where the segments failing their validation on open are moved.
+    pub(crate) fn quarantine_dir(&self, root: &Path) -> PathBuf {
+        root.join(format!("{}quarantine", self.prefix))
+    }
+
+    /// The prefix of the temporary files, so the ones left behind by a crash can be recognized.
+    pub(crate) fn temp_prefix(&self) -> String {
+        format!(".{}tmp-", self.prefix)
+    }
+
+    pub(crate) fn temp_file(&self, dir: &Path) -> io::Result<NamedTempFile> {
+        tempfile::Builder::new()
+            .prefix(&self.temp_prefix())
+            .tempfile_in(dir)
+    }
+
+    /// The single file of the dirty segment used before it was split in several files.
+    pub(crate) fn dirty_path(&self, root: &Path) -> PathBuf {
+        self.wal_files_dir(root)
+            .join(format!("{}dirty", self.prefix))
+    }
+
+    pub(crate) fn wal_files_dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.wal_dir)
+    }
+
+    /// The memtable saved by a clean shutdown, next to the files of the dirty segment.
+    pub(crate) fn memtable_path(&self, root: &Path) -> PathBuf {
+        self.wal_files_dir(root)
+            .join(format!("{}memtable", self.prefix))
+    }
+
+    pub(crate) fn wal_path(&self, root: &Path, number: u64) -> PathBuf {
+        self.wal_files_dir(root)
+            .join(format!("{}wal-{number:06}", self.prefix))
+    }
+
+    /// Returns the number of a file of the dirty segment from its name.
+    pub(crate) fn parse_wal(&self, name: &str) -> Option<u64> {
+        parse_number(name.strip_prefix(&self.prefix)?.strip_prefix("wal-")?)
+    }
+
+    /// Returns the id of a segment from its file name.
+    pub(crate) fn parse_segment(&self, name: &str) -> Option<usize> {
+        parse_number(name.strip_prefix(&self.prefix)?.strip_prefix("segment-")?)
+    }
+
+    /// Whether the segments of the level are stored in a directory of their own.
+    pub(crate) fn has_level_dirs(&self) -> bool {
+        self.level_dirs
+    }
+
+    /// The directory holding the segments of a level, the temporary files must be created there.
+    pub(crate) fn level_dir(&self, root: &Path, level: u8) -> PathBuf {
+        let dir = root.join(&self.segments_dir);
+        if self.level_dirs {
+            dir.join(format!("L{level}"))
+        } else {
+            dir
+        }
+    }
+
+    pub(crate) fn segment_path(&self, root: &Path, level: u8, id: usize) -> PathBuf {
+        self.level_dir(root, level)
+            .join(format!("{}segment-{id}", self.prefix))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(number: &str) -> Option<T> {
+    match number.bytes().all(|byte| byte.is_ascii_digit()) {
+        true => number.parse().ok(),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let root = Path::new("db");
+
+        let flat = Layout::flat();
+        assert_eq!(flat.dirty_path(root), Path::new("db/dirty"));
+        assert_eq!(flat.segment_path(root, 1, 3), Path::new("db/segment-3"));
+
+        let nested = Layout::nested().prefix("kv-");
+        assert_eq!(nested.dirty_path(root), Path::new("db/wal/kv-dirty"));
+        assert_eq!(nested.wal_path(root, 12), Path::new("db/wal/kv-wal-000012"));
+        assert_eq!(nested.parse_wal("kv-wal-000012"), Some(12));
+        assert_eq!(nested.parse_wal("wal-000012"), None);
+        assert_eq!(nested.parse_wal("kv-wal-000012.tmp"), None);
+        assert_eq!(nested.parse_segment("kv-segment-3"), Some(3));
+        assert_eq!(nested.parse_segment("kv-segment-"), None);
+        assert_eq!(
+            nested.segment_path(root, 0, 3),
+            Path::new("db/segments/L0/kv-segment-3")
+        );
+        assert_eq!(
+            nested.segment_path(root, 1, 3),
+            Path::new("db/segments/L1/kv-segment-3")
+        );
+    }
+}
@@ -1,177 +1,1159 @@
-#![feature(error_generic_member_access)]
+#![cfg_attr(feature = "nightly", feature(error_generic_member_access))]
 
+mod batch;
+mod blob;
+mod builder;
+mod cache;
+mod compaction;
+mod compat;
+mod cursor;
+mod describe;
+mod encoding;
 mod error;
+mod events;
+mod export;
+mod files;
+mod filter;
+mod flush;
+mod follower;
+mod hook;
+mod identity;
+mod import;
+mod iter;
+pub mod key;
+mod layout;
+mod lock;
+mod manifest;
+mod memtable;
+mod pin;
+mod poison;
+mod pool;
+mod prefix;
+mod queue;
+mod scheduler;
+mod schema;
+mod scrub;
+mod segment;
+mod stats;
+mod threshold;
+mod uncached;
+mod vlog;
+mod wal;
 
 use std::{
-    collections::{BTreeMap, VecDeque},
-    fs::File,
-    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     mem,
+    ops::{Bound, RangeBounds},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::UNIX_EPOCH,
 };
 
-pub use error::Error;
-use tempfile::NamedTempFile;
+use batch::BatchRead;
+use blob::BlobStore;
+pub use builder::{DatabaseBuilder, Opt};
+use cache::BlockCache;
+pub use compaction::{CompactionDecision, CompactionFilter, CompactionPlan};
+pub use cursor::Cursor;
+pub use describe::{Description, SegmentDescription};
+pub use encoding::Encoding;
+pub use error::{CompareAndSwapError, Error, ErrorKind};
+use events::EventLog;
+#[cfg(feature = "parquet")]
+pub use export::key_value_batch;
+pub use export::{Backup, ExportedSegment};
+use files::FilePool;
+use filter::DatabaseFilter;
+pub use filter::{Bloom, Filter, FilterPolicy};
+use flush::{FlushJob, Frozen};
+pub use follower::Follower;
+pub use hook::{WriteEvent, WriteHook};
+use import::ExternalSort;
+use iter::{range_deleted, KeyFilter, Source};
+pub use iter::{Chunks, Diff, Difference, Entry, Keys, Range, RangeTombstone, StrRange};
+pub use key::Key;
+pub use layout::Layout;
+pub use lock::{RangeGuard, RangeLocks};
+use manifest::{Limits, Recovered};
+use pin::Pins;
+use poison::Poison;
+use pool::BufferPool;
+pub use prefix::PrefixExtractor;
+pub use scheduler::{DefaultScheduler, ManualScheduler, Scheduler, SchedulerState};
+pub use schema::Schema;
+use scrub::BackgroundScrub;
+pub use scrub::ScrubReport;
+use segment::{BlockSizes, RetiredRefs, Segment, SegmentOptions, SegmentWriter};
+pub use segment::{SegmentCounters, SegmentEntries, SegmentMetadata, SegmentReader};
+pub use stats::{PrefixStats, Provenance, SpaceAmplification, Stats};
+use threshold::AdaptiveThreshold;
+use vlog::ValueLog;
+use wal::WalFiles;
+pub use wal::{Wal, WalRecords, WriteOptions};
+#[cfg(feature = "parquet")]
+pub use {arrow_array, arrow_schema};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// A key-value store made of a dirty segment, its memtable, and immutable clean segments.
+///
+/// All the operations take `&mut self`, even the reads which move the cursors of the files. The
+/// database can be sent to another thread and is shared by wrapping it in a `Mutex`, which
+/// serializes the operations. The flushes and the scrubs run in background threads on data
+/// the database doesn't modify meanwhile.
 pub struct Database {
     /// When reached, rewrite the dirty segment as a clean segment
     dirty_thresholds: usize,
+    // When set, the dirty threshold is adjusted to the writes
+    adaptive: Option<AdaptiveThreshold>,
+    // Only shared with the application, see `Database::lock_range`
+    range_locks: RangeLocks,
 
     // The path that holds all the segments
     path: PathBuf,
+    layout: Layout,
+    // Stamped in the segments, those of another database are rejected
+    identity: u128,
+    // Holds the large values referenced by the entries of the segments
+    blob_store: Arc<BlobStore>,
+    // When set, the values of at least this size are written to the blob store
+    blob_threshold: Option<usize>,
+    // Holds the large values the entries of the segments point to
+    value_log: Arc<ValueLog>,
+    // When set, the values of at least this size are appended to the value log
+    value_log_threshold: Option<usize>,
+    // The blobs and the bytes of the value log referenced by the retired segments still read
+    // by an iterator or a backup
+    retired_refs: Vec<RetiredRefs>,
 
-    // An in memory `BTreeMap` of all the keys + their index in the current dirty segment
+    // An in memory `BTreeMap` of all the keys + the index of their last version in the current dirty segment
     memtable: BTreeMap<Vec<u8>, u64>,
-    dirty: File,
+    // The entries of the memtable written without the dirty segment, their index is `UNLOGGED`
+    unlogged: HashMap<Vec<u8>, Entry>,
+    // The memory used by the keys of the memtable and the entries written without the dirty segment
+    memtable_bytes: usize,
+    // The ranges deleted by the current dirty segment
+    range_tombstones: Vec<RangeTombstone>,
+    // The previous memtable while it's written to a segment
+    frozen: Option<Frozen>,
+    // The sequence number of the last write
+    sequence: u64,
+    // When set, the time of each write is recorded along with its entry
+    timestamps: bool,
+    // The largest timestamp given to a write, the following ones can't be smaller
+    clock: u64,
+    // The number of keys with a value in the segments, and how many the memtable adds to them
+    segment_keys: u64,
+    memtable_keys: i64,
+    // When set, the writes look up the keys in the segments to keep the counts exact
+    exact_len: bool,
+    // How many versions of each key are kept when writing clean segments
+    versions: usize,
+    // Don't keep the segments read and written by the compactions in the page cache
+    uncached_compaction: bool,
+    // The number of bytes prefetched by the sequential scans of the segments
+    read_ahead: u64,
+    // How the entries of the new clean segments are encoded
+    encoding: Encoding,
+    block_sizes: BlockSizes,
+    dirty: WalFiles,
     segments: VecDeque<Segment>,
-}
+    // The id of the next flushed segment
+    next_id: usize,
+    // The segments moved away because they failed their validation on open
+    quarantined: Vec<PathBuf>,
+    // The segments only open their file when needed
+    files: FilePool,
+    // Reads the blocks of the segments during the lookups
+    reads: Box<dyn BatchRead>,
+    // The buffers of the blocks read and written by the segments
+    pool: Arc<BufferPool>,
+    // The blocks recently read by the lookups
+    cache: BlockCache,
+    // The maximum memory used by the memtables, the block cache and the filters
+    memory_budget: Option<usize>,
+    // Set when a flush or a compaction panicked, everything then fails until the database is reopened
+    poison: Poison,
+    // The maximum size of the keys and values of the writes
+    limits: Limits,
+    events: EventLog,
+    // Decides when the flushes and the merges happen
+    scheduler: Arc<dyn Scheduler>,
+    // Told about the writes, flushes and compactions, in order
+    hooks: Vec<Arc<dyn WriteHook>>,
+    // When enabled, reads all the segments from time to time to find their damaged blocks
+    background_scrub: Option<BackgroundScrub>,
+    last_scrub: Option<ScrubReport>,
+
+    // When set, all the values are tagged with the version of the schema they were written with
+    schema: Option<Arc<Schema>>,
 
-struct Segment {
-    id: usize,
-    file: File,
+    // Creates the filters of the new segments and reads the filters of the existing ones
+    filter: Option<Arc<dyn FilterPolicy>>,
+    // Called on the entries of the compactions
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    // The ranges of keys the compaction filter never sees
+    pins: Pins,
+    // Extracts the prefixes added to the filters of the new segments
+    prefix_extractor: Option<PrefixExtractor>,
+    // The number of lookups the filters avoided and didn't avoid
+    filter_negatives: u64,
+    filter_positives: u64,
+    filter_false_positives: u64,
+    // The number of lookups skipped because the key was outside of the segment
+    fence_negatives: u64,
+    // When enabled, the keys of all the segments
+    database_filter: Option<DatabaseFilter>,
+    database_filter_negatives: u64,
+    // The size of the keys and values written since the open, and of the segments written by
+    // the flushes and the compactions
+    user_bytes: u64,
+    flush_bytes: u64,
+    compaction_bytes: u64,
 }
 
-impl Segment {
-    pub fn get(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
-        self.file.seek(SeekFrom::Start(0))?;
-        let mut reader = BufReader::new(&mut self.file);
+impl Database {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Database> {
+        Database::builder().open(dir)
+    }
 
-        loop {
-            buf.clear();
-            match read_entry(&mut reader, buf) {
-                Ok(_) => (),
-                // We went through the whole dirty entries, we can move to the next segment
-                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+    pub fn builder() -> DatabaseBuilder {
+        DatabaseBuilder::default()
+    }
+
+    /// Open a database written by the first version of the crate, it had no manifest and its
+    /// segments had no footer.
+    ///
+    /// The segments and the dirty file are rewritten in the current format before the database
+    /// is opened, and only the current format is written afterward. The other databases are
+    /// opened as usual, while [`Database::new`] refuses to open the ones holding segments of
+    /// the first version.
+    pub fn open_compat(dir: impl AsRef<Path>) -> Result<Database> {
+        Database::builder().open_compat(dir)
+    }
+
+    /// Summarize the levels, segments, key ranges, disk usage and format versions of the
+    /// database stored in `dir`, e.g. to triage a database without opening it.
+    ///
+    /// Only the manifest and the footers of the segments are read: the directory isn't locked,
+    /// and the dirty segment is neither replayed nor repaired. See
+    /// [`DatabaseBuilder::describe`] for the databases using another [`Layout`].
+    pub fn describe(dir: impl AsRef<Path>) -> Result<Description> {
+        Database::builder().describe(dir)
+    }
+
+    fn open(
+        dir: &Path,
+        builder: DatabaseBuilder,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Database> {
+        let DatabaseBuilder {
+            dirty_thresholds,
+            adaptive_dirty_thresholds,
+            max_open_files,
+            layout,
+            log_max_size,
+            log_keep,
+            versions,
+            filter,
+            compaction_filter,
+            prefix_extractor,
+            database_filter,
+            uncached_compaction,
+            read_ahead,
+            encoding,
+            block_sizes,
+            wal_max_size,
+            scheduler,
+            hooks,
+            buffer_pool_size,
+            max_key_size,
+            max_value_size,
+            block_cache_size,
+            compressed_block_cache_size,
+            memory_budget,
+            validate_segments,
+            open_threads,
+            preload_filters,
+            background_scrub,
+            timestamps,
+            exact_len,
+            blob_threshold,
+            value_log_threshold,
+            identity,
+        } = builder;
+        // Each large value goes to a single place
+        if blob_threshold.is_some() && value_log_threshold.is_some() {
+            return Err(Error::IncompatibleOptions(
+                "blob_threshold",
+                "value_log_threshold",
+            ));
+        }
+        // Their segments would be deleted as invalid by the manifest recovery
+        if compat::has_v0_segments(dir, &layout)? {
+            return Err(Error::LegacyDatabase(dir.to_owned()));
+        }
+        layout.create_dirs(dir)?;
+        let pool = Arc::new(BufferPool::new(buffer_pool_size));
+
+        let mut events = EventLog::open(
+            layout.log_path(dir),
+            log_max_size,
+            log_keep,
+            scheduler.clone(),
+        )?;
+        let mut dirty = WalFiles::open(dir, &layout, wal_max_size)?;
+
+        let saved = match memtable::load(dir, &layout, &dirty.file_sizes()) {
+            Ok(saved) => saved,
+            Err(e) => {
+                events.log(format_args!("open: saved memtable ignored: {e}"));
+                None
+            }
+        };
+        let (saved, saved_live_keys) = match saved {
+            Some(saved) => {
+                events.log(format_args!(
+                    "open: {} entries loaded from the saved memtable",
+                    saved.memtable.len()
+                ));
+                progress(dirty.len(), dirty.len());
+                let live_keys = saved.live_keys;
+                (saved, Some(live_keys))
+            }
+            None => {
+                let replayed = match Self::init_memtable(&mut dirty, progress) {
+                    Ok(replayed) => replayed,
+                    Err(e) => {
+                        events.log(format_args!("open failed: {e}"));
+                        return Err(e);
+                    }
+                };
+                events.log(format_args!(
+                    "open: {} entries replayed from the dirty segment",
+                    replayed.memtable.len()
+                ));
+                (replayed, None)
+            }
+        };
+        let pins = match Pins::load(dir, &layout) {
+            Ok(pins) => pins,
+            Err(e) => {
+                events.log(format_args!("open failed: {e}"));
+                return Err(e);
+            }
+        };
+        let identity = match identity::load_or_create(dir, &layout, identity) {
+            Ok(identity) => identity,
+            Err(e) => {
+                events.log(format_args!("open failed: {e}"));
+                return Err(e);
+            }
+        };
+        let recovered = manifest::recover(
+            dir,
+            &layout,
+            &pool,
+            validate_segments,
+            identity,
+            &mut events,
+        );
+        let Recovered {
+            mut segments,
+            next_id,
+            quarantined,
+            live_keys,
+            limits: previous_limits,
+            clock,
+            sequence,
+        } = match recovered {
+            Ok(recovered) => recovered,
+            Err(e) => {
+                events.log(format_args!("open failed: {e}"));
+                return Err(e);
+            }
+        };
+        let value_log = match ValueLog::open(dir, &layout) {
+            Ok(value_log) => Arc::new(value_log),
+            Err(e) => {
+                events.log(format_args!("open failed: {e}"));
+                return Err(e.into());
+            }
+        };
+        let blob_store = Arc::new(BlobStore::new(dir, &layout));
+        for segment in &mut segments {
+            segment.blob_store = Some(blob_store.clone());
+            segment.value_log = Some(value_log.clone());
+        }
+
+        let database_filter = match database_filter {
+            true => {
+                let policy = filter.clone().unwrap_or_else(|| Arc::new(Bloom::default()));
+                let mut database_filter = DatabaseFilter::new(policy)?;
+                if !segments.is_empty() {
+                    database_filter.rebuild(&segments, read_ahead)?;
+                }
+                Some(database_filter)
+            }
+            false => None,
+        };
+
+        let live_keys = match live_keys {
+            None if segments.is_empty() => Some(0),
+            live_keys => live_keys,
+        };
+        let mut database = Database {
+            dirty_thresholds,
+            adaptive: adaptive_dirty_thresholds.map(AdaptiveThreshold::new),
+            range_locks: RangeLocks::new(),
+            path: dir.to_owned(),
+            layout,
+            identity,
+            blob_store,
+            blob_threshold,
+            value_log,
+            value_log_threshold,
+            retired_refs: Vec::new(),
+            memtable: saved.memtable,
+            range_tombstones: saved.range_tombstones,
+            unlogged: HashMap::new(),
+            memtable_bytes: 0,
+            frozen: None,
+            // The dirty segment only holds the writes since the last flush
+            sequence: saved.sequence.max(sequence.unwrap_or(0)),
+            timestamps,
+            clock: clock.max(saved.clock),
+            segment_keys: live_keys.unwrap_or(0),
+            memtable_keys: 0,
+            exact_len,
+            versions: versions.max(1),
+            uncached_compaction,
+            read_ahead,
+            encoding,
+            block_sizes,
+            dirty,
+            segments,
+            next_id,
+            quarantined,
+            files: FilePool::new(max_open_files),
+            reads: batch::reads(),
+            pool,
+            cache: BlockCache::new(block_cache_size, compressed_block_cache_size),
+            memory_budget,
+            poison: Poison::default(),
+            limits: Limits {
+                key: max_key_size.min(u32::MAX as usize),
+                // The largest sizes mark the deleted entries and ranges, the appends and the timestamps
+                value: max_value_size.min(TIMESTAMP as usize - 1),
+            },
+            events,
+            background_scrub: background_scrub.map(|(interval, bytes_per_second)| {
+                BackgroundScrub::new(interval, bytes_per_second, scheduler.now())
+            }),
+            last_scrub: None,
+            scheduler,
+            hooks,
+            schema: None,
+            filter,
+            compaction_filter,
+            pins,
+            prefix_extractor,
+            filter_negatives: 0,
+            filter_positives: 0,
+            filter_false_positives: 0,
+            fence_negatives: 0,
+            database_filter,
+            database_filter_negatives: 0,
+            user_bytes: 0,
+            flush_bytes: 0,
+            compaction_bytes: 0,
+        };
+        if live_keys.is_none() {
+            database.segment_keys = database.count_segment_keys()?;
+            database.write_manifest()?;
+            database.events.log(format_args!(
+                "open: {} live keys counted in the segments",
+                database.segment_keys
+            ));
+        }
+        if previous_limits != Some(database.limits) {
+            database.write_manifest()?;
+            // The entries already written over the new limits stay readable
+            if let Some(previous) = previous_limits {
+                database.events.log(format_args!(
+                    "open: size limits changed from {} and {} bytes to {} and {} bytes",
+                    previous.key, previous.value, database.limits.key, database.limits.value
+                ));
+            }
+        }
+        // The count of the saved memtable only holds if the segments didn't change
+        database.memtable_keys = match (saved_live_keys, live_keys) {
+            (Some(saved), Some(_)) => saved,
+            _ => database.memtable_keys_delta()?,
+        };
+        if let Err(e) = database.load_segments(open_threads, preload_filters) {
+            database.events.log(format_args!("open failed: {e}"));
+            return Err(e);
+        }
+        // The manifests written before the sequence was recorded only know the segments
+        if sequence.is_none() {
+            match database.segments_sequence() {
+                Ok(sequence) => database.sequence = database.sequence.max(sequence),
                 Err(e) => {
-                    println!("{e}");
-                    return Err(e.into());
+                    database.events.log(format_args!("open failed: {e}"));
+                    return Err(e);
                 }
-            };
-            if key == buf {
-                // we found the entry
-                read_entry(&mut reader, buf)?;
-                return Ok(Some(buf.to_vec()));
-            } else {
-                skip_entry(&mut reader)?;
             }
         }
+        database.memtable_bytes = (database.memtable.keys())
+            .map(|key| key.len() + MEMTABLE_ENTRY_BYTES)
+            .sum();
+        database.limit_cache();
+        Ok(database)
+    }
 
-        Ok(None)
+    /// The number of keys with a value.
+    ///
+    /// It's kept up to date by the writes, which look up whether the key they overwrite or
+    /// delete had a value, and is saved along with the list of the segments. It's counted again
+    /// by the compactions merging all the segments.
+    ///
+    /// With [`DatabaseBuilder::exact_len`] disabled it's only an estimate, the writes don't look
+    /// up the keys out of the memtables and the count drifts in both directions until the
+    /// segments are all merged. It's never below 0.
+    pub fn len(&self) -> u64 {
+        let frozen = self.frozen.as_ref().map_or(0, |frozen| frozen.live_keys);
+        (self.segment_keys as i64 + frozen + self.memtable_keys).max(0) as u64
     }
 
-    pub fn merge(writer: impl Write, new: &mut Self, old: &mut Self) -> io::Result<()> {
-        let mut new_segment = BufWriter::new(writer);
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        new.file.seek(SeekFrom::Start(0))?;
-        old.file.seek(SeekFrom::Start(0))?;
+    /// The random identity generated on the creation of the database and stored as a UUID in
+    /// its `IDENTITY` file.
+    ///
+    /// It's recorded in the footer of the segments, the open fails with
+    /// [`Error::ForeignSegment`] when a segment of the directory was written by another database.
+    pub fn identity(&self) -> u128 {
+        self.identity
+    }
 
-        let mut new = BufReader::new(&mut new.file);
-        let mut old = BufReader::new(&mut old.file);
+    /// Count the live keys of the segments by reading all of them.
+    fn count_segment_keys(&mut self) -> Result<u64> {
+        let mut sources = Vec::with_capacity(self.segments.len());
+        let mut range_tombstones = Vec::new();
+        for segment in self.segments.iter().rev() {
+            let iter = segment.iter(Bound::Unbounded, self.read_ahead)?;
+            sources.push(Source::Segment(Box::new(iter)));
+            range_tombstones.extend_from_slice(segment.range_tombstones(&mut self.files)?);
+        }
+        let mut count = 0;
+        for entry in Range::new(sources, Bound::Unbounded, None, range_tombstones)? {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
 
-        let mut new_key = read_entry_to_vec(&mut new)?;
-        let mut old_key = read_entry_to_vec(&mut old)?;
+    /// The largest sequence number of the segments, 0 if they're empty.
+    fn segments_sequence(&mut self) -> Result<u64> {
+        let mut sequence = 0;
+        for segment in &self.segments {
+            if let Some(seqs) = segment.seqs(&mut self.files)? {
+                sequence = sequence.max(*seqs.end());
+            }
+        }
+        Ok(sequence)
+    }
 
-        loop {
-            if new_key <= old_key {
-                new_segment.write_all(&(new_key.len() as u32).to_be_bytes())?;
-                new_segment.write_all(&new_key)?;
-
-                let value_size = read_u32(&mut new)?;
-                new_segment.write_all(&value_size.to_be_bytes())?;
-                io::copy(&mut new.by_ref().take(value_size as u64), &mut new_segment)?;
-
-                if new_key == old_key {
-                    // skip the value
-                    skip_entry(&mut old)?;
-                    // update the key
-                    match read_entry(&mut old, &mut old_key) {
-                        Ok(()) => (),
-                        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
-                            new_segment.write_all(&(new_key.len() as u32).to_be_bytes())?;
-                            new_segment.write_all(&new_key)?;
-                            io::copy(&mut new, &mut new_segment)?;
-                            break;
-                        }
-                        Err(e) => return Err(e),
-                    };
-                }
+    /// How many live keys the memtable adds to the segments, negative when it deletes more.
+    fn memtable_keys_delta(&mut self) -> Result<i64> {
+        if !self.range_tombstones.is_empty() {
+            // The deleted ranges can hide any number of keys of the segments
+            let mut count = 0;
+            for entry in self.range::<&[u8]>(..)? {
+                entry?;
+                count += 1;
+            }
+            return Ok(count - self.segment_keys as i64);
+        }
+        let indexes: Vec<_> = self
+            .memtable
+            .iter()
+            .map(|(key, index)| (key.clone(), *index))
+            .collect();
+        let mut delta = 0;
+        for (key, index) in &indexes {
+            delta += self.read_dirty(key, *index)?.value.is_some() as i64;
+        }
+        let keys: Vec<&[u8]> = indexes.iter().map(|(key, _)| key.as_slice()).collect();
+        let range_tombstones = self.memtable_range_tombstones();
+        let found = self.get_from_segments(&keys, &range_tombstones)?;
+        Ok(delta - found.iter().flatten().count() as i64)
+    }
 
-                // read the next key in new_key
-                match read_entry(&mut new, &mut new_key) {
-                    Ok(()) => (),
-                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
-                        new_segment.write_all(&(old_key.len() as u32).to_be_bytes())?;
-                        new_segment.write_all(&old_key)?;
-                        io::copy(&mut old, &mut new_segment)?;
-                        break;
-                    }
-                    Err(e) => return Err(e),
-                };
-            } else {
-                new_segment.write_all(&(old_key.len() as u32).to_be_bytes())?;
-                new_segment.write_all(&old_key)?;
-
-                let value_size = read_u32(&mut old)?;
-                new_segment.write_all(&value_size.to_be_bytes())?;
-                io::copy(&mut old.by_ref().take(value_size as u64), &mut new_segment)?;
-
-                // read the next key in old_key
-                match read_entry(&mut old, &mut old_key) {
-                    Ok(()) => (),
-                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
-                        new_segment.write_all(&(new_key.len() as u32).to_be_bytes())?;
-                        new_segment.write_all(&new_key)?;
-                        io::copy(&mut new, &mut new_segment)?;
-                        break;
+    /// Whether the key has a value, without migrating it.
+    fn is_live(&mut self, key: &[u8]) -> Result<bool> {
+        if let Some(live) = self.is_live_in_memtables(key)? {
+            return Ok(live);
+        }
+        let range_tombstones = self.memtable_range_tombstones();
+        let found = self.get_from_segments(&[key], &range_tombstones)?;
+        Ok(found.into_iter().next().flatten().is_some())
+    }
+
+    /// Whether the key has a value in the memtables, `None` if they don't hold it.
+    fn is_live_in_memtables(&mut self, key: &[u8]) -> Result<Option<bool>> {
+        let (has_value, seq) = match self.memtable.get(key) {
+            Some(index) => {
+                let entry = self.read_dirty(key, *index)?;
+                (entry.value.is_some(), entry.seq)
+            }
+            None => match self.frozen.as_ref().and_then(|frozen| frozen.get(key)) {
+                Some(entry) => (entry.value.is_some(), entry.seq),
+                None => return Ok(None),
+            },
+        };
+        let range_tombstones = self.memtable_range_tombstones();
+        Ok(Some(
+            has_value && !range_deleted(&range_tombstones, key, seq),
+        ))
+    }
+
+    /// The ranges deleted by the memtable and the frozen memtable.
+    fn memtable_range_tombstones(&self) -> Vec<RangeTombstone> {
+        let mut range_tombstones = self.range_tombstones.clone();
+        if let Some(frozen) = &self.frozen {
+            range_tombstones.extend_from_slice(frozen.range_tombstones());
+        }
+        range_tombstones
+    }
+
+    /// The ranges deleted by the memtables and the segments.
+    fn collect_range_tombstones(&mut self) -> Result<Vec<RangeTombstone>> {
+        let mut range_tombstones = self.memtable_range_tombstones();
+        for segment in &self.segments {
+            range_tombstones.extend_from_slice(segment.range_tombstones(&mut self.files)?);
+        }
+        Ok(range_tombstones)
+    }
+
+    /// Whether a flush or a compaction panicked midway, the database must then be reopened and
+    /// all the other methods return [`Error::Poisoned`].
+    ///
+    /// Nothing is lost, the entries not in a segment yet are replayed from the dirty segment.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// The segments that failed their validation when the database was opened and were moved to
+    /// the quarantine directory, see [`DatabaseBuilder::validate_segments`].
+    pub fn quarantined(&self) -> &[PathBuf] {
+        &self.quarantined
+    }
+
+    /// Flush the memtable once it holds more than `threshold` keys, 1024 by default.
+    ///
+    /// It disables the adjustments of [`DatabaseBuilder::adaptive_dirty_thresholds`].
+    pub fn dirty_thresholds(&mut self, threshold: usize) {
+        self.dirty_thresholds = threshold;
+        self.adaptive = None;
+    }
+
+    /// Change an option without reopening the database.
+    pub fn set_option(&mut self, option: Opt) {
+        match option {
+            Opt::DirtyThresholds(threshold) => self.dirty_thresholds(threshold),
+            Opt::MaxOpenFiles(max) => self.max_open_files(max),
+            Opt::ReadAhead(bytes) => self.read_ahead = bytes,
+            Opt::WalMaxSize(bytes) => self.dirty.set_max_size(bytes),
+            Opt::BlockCacheSize(bytes) => self.cache.set_capacity(bytes),
+            Opt::CompressedBlockCacheSize(bytes) => self.cache.set_compressed_capacity(bytes),
+            Opt::BackgroundScrub(Some((interval, bytes_per_second))) => {
+                match &mut self.background_scrub {
+                    Some(scrub) => scrub.set_schedule(interval, bytes_per_second),
+                    None => {
+                        let now = self.scheduler.now();
+                        self.background_scrub =
+                            Some(BackgroundScrub::new(interval, bytes_per_second, now));
                     }
-                    Err(e) => return Err(e),
-                };
+                }
             }
+            // Dropping the scrub stops it
+            Opt::BackgroundScrub(None) => self.background_scrub = None,
         }
-        Ok(())
     }
 
-    pub fn dump(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
-        buf.clear();
-        self.file.seek(SeekFrom::Start(0))?;
-        let mut reader = BufReader::new(&mut self.file);
-        reader.read_to_end(buf)?;
+    fn scheduler_state(&self) -> SchedulerState {
+        SchedulerState {
+            memtable_entries: self.memtable.len(),
+            dirty_thresholds: self.dirty_thresholds,
+            segments: self.segments.len(),
+        }
+    }
+
+    /// The maximum number of segment files kept open at the same time, 256 by default.
+    ///
+    /// The least recently used segments are closed when the limit is reached and re-opened on demand.
+    /// The iterators open their own files and are not accounted for.
+    pub fn max_open_files(&mut self, max: usize) {
+        self.files.set_capacity(max);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let (pool_buffers, pool_bytes) = self.pool.occupancy();
+        let cache = self.cache.stats();
+        let (memtable_bytes, filter_bytes) = (self.memtable_memory(), self.filter_memory());
+        let mut counters = SegmentCounters::default();
+        let mut uncounted_segments = 0;
+        for segment in &self.segments {
+            match segment.loaded_counters() {
+                Some(segment) => {
+                    counters.entries += segment.entries;
+                    counters.key_bytes += segment.key_bytes;
+                    counters.value_bytes += segment.value_bytes;
+                    counters.tombstones += segment.tombstones;
+                }
+                None => uncounted_segments += 1,
+            }
+        }
+        Stats {
+            // the files of the dirty segment are always open
+            open_files: self.files.len() + self.dirty.file_count(),
+            pool_buffers,
+            pool_bytes,
+            filter_negatives: self.filter_negatives,
+            filter_positives: self.filter_positives,
+            filter_false_positives: self.filter_false_positives,
+            fence_negatives: self.fence_negatives,
+            database_filter_negatives: self.database_filter_negatives,
+            block_cache_hits: cache.hits,
+            block_cache_misses: cache.misses,
+            block_cache_bytes: cache.bytes,
+            compressed_block_cache_hits: cache.compressed_hits,
+            compressed_block_cache_misses: cache.compressed_misses,
+            compressed_block_cache_bytes: cache.compressed_bytes,
+            memtable_bytes,
+            filter_bytes,
+            memory_bytes: memtable_bytes + filter_bytes + cache.bytes + cache.compressed_bytes,
+            segment_entries: counters.entries,
+            segment_key_bytes: counters.key_bytes,
+            segment_value_bytes: counters.value_bytes,
+            segment_tombstones: counters.tombstones,
+            uncounted_segments,
+            dirty_thresholds: self.dirty_thresholds,
+            user_bytes: self.user_bytes,
+            flush_bytes: self.flush_bytes,
+            compaction_bytes: self.compaction_bytes,
+        }
+    }
+
+    /// The [stats](Database::stats) in the Prometheus text exposition format, to be served as
+    /// is by a `/metrics` route.
+    ///
+    /// The metrics are prefixed by `database_`, the counters since the database was opened end
+    /// with `_total`. Only available with the `prometheus` feature.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_metrics(&self) -> String {
+        self.stats().prometheus(self.segments.len(), self.len())
+    }
+
+    /// Estimate how many bytes of the clean segments are used by live entries.
+    ///
+    /// The first keys of up to 64 blocks of each segment are looked up in the more recent segments
+    /// and the memtable, and each segment is assumed to be as live as its sampled keys. Since it
+    /// reads the index of all the segments, it's meant to decide when to force a full compaction
+    /// rather than to be called on every request.
+    pub fn space_amplification(&mut self) -> Result<SpaceAmplification> {
+        let mut amplification = SpaceAmplification::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            let size = self.files.get(&segment.path)?.metadata()?.len();
+            amplification.total_bytes += size;
+            let sample = segment.sample_keys(&mut self.files, AMPLIFICATION_SAMPLES)?;
+            if sample.is_empty() {
+                continue;
+            }
+
+            // The keys overwritten in the memtables or deleted in this segment are dead
+            let frozen = |key: &[u8]| self.frozen.as_ref().is_some_and(|f| f.get(key).is_some());
+            let keys: Vec<&[u8]> = sample
+                .iter()
+                .map(Vec::as_slice)
+                .filter(|key| !self.memtable.contains_key(*key) && !frozen(key))
+                .collect();
+            let found =
+                segment.multi_get(&mut self.files, &keys, self.reads.as_mut(), &mut self.cache)?;
+            let mut live: Vec<&[u8]> = keys
+                .into_iter()
+                .zip(found)
+                .filter_map(|(key, entry)| {
+                    matches!(entry, Some(Entry { value: Some(_), .. })).then_some(key)
+                })
+                .collect();
+            for newer in self.segments.iter().skip(i + 1) {
+                if live.is_empty() {
+                    break;
+                }
+                let found = newer.multi_get(
+                    &mut self.files,
+                    &live,
+                    self.reads.as_mut(),
+                    &mut self.cache,
+                )?;
+                live = live
+                    .into_iter()
+                    .zip(found)
+                    .filter_map(|(key, value)| value.is_none().then_some(key))
+                    .collect();
+            }
+            amplification.live_bytes += size * live.len() as u64 / sample.len() as u64;
+        }
+        Ok(amplification)
+    }
+
+    /// Read ahead the blocks of the clean segments holding the keys of `range`, so the first
+    /// requests after an open don't wait for the disk. Returns the number of bytes of blocks
+    /// read.
+    ///
+    /// The filters of the segments are loaded first whatever the budget, every lookup needs
+    /// them. Then the index and data blocks of the range are read through the [block cache](DatabaseBuilder::block_cache_size) from the most
+    /// recent segment to the oldest one, until `budget_bytes` bytes were read. The blocks also
+    /// end up in the page cache of the OS, even without a block cache or once it evicts them.
+    pub fn warm_cache<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        budget_bytes: u64,
+    ) -> Result<u64> {
+        self.poison.check()?;
+        let bounds = (
+            range.start_bound().map(|key| key.as_ref()),
+            range.end_bound().map(|key| key.as_ref()),
+        );
+        let mut read = 0;
+        if let Some(policy) = &self.filter {
+            for segment in &self.segments {
+                segment.filter(&mut self.files, policy.as_ref())?;
+            }
+        }
+        for segment in self.segments.iter().rev() {
+            let budget = budget_bytes.saturating_sub(read);
+            if budget == 0 {
+                break;
+            }
+            read += segment.warm(
+                &mut self.files,
+                bounds,
+                budget,
+                self.reads.as_mut(),
+                &mut self.cache,
+            )?;
+        }
+        self.limit_cache();
+        self.events.log(format_args!(
+            "warm cache: {read} bytes read from {} segments",
+            self.segments.len()
+        ));
+        Ok(read)
+    }
+
+    /// Up to `n` keys splitting the database in `n + 1` parts of about the same size, e.g. to
+    /// choose the split points of shards.
+    ///
+    /// Only the indexes of the clean segments are read: their data blocks have about the same
+    /// size, thus the first keys of all the blocks are sorted and evenly picked. The keys may
+    /// have been deleted since, and the memtables aren't accounted for.
+    pub fn sample_keys(&mut self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for segment in &self.segments {
+            keys.extend(segment.sample_keys(&mut self.files, usize::MAX)?);
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.len() <= n {
+            return Ok(keys);
+        }
+        let len = keys.len();
+        let picked = (1..=n).map(|i| i * len / (n + 1));
+        Ok(picked.map(|i| mem::take(&mut keys[i])).collect())
+    }
+
+    /// Read every block of the clean segments and check their checksum.
+    ///
+    /// The segments whose only damage is in their filter are rewritten, since the filter can be
+    /// rebuilt out of their entries. The other damaged segments are reported, and logged in the
+    /// events log. See [`DatabaseBuilder::background_scrub`] to scrub the segments from time to
+    /// time in the background.
+    pub fn scrub(&mut self) -> Result<ScrubReport> {
+        self.poison.check()?;
+        let results = self
+            .segments
+            .iter()
+            .map(|segment| {
+                (
+                    segment.path.clone(),
+                    Segment::scrub(&segment.path, &segment.holes, |_| Ok(())),
+                )
+            })
+            .collect();
+        self.apply_scrub(results)
+    }
+
+    /// The clean segments with their key and sequence ranges, for the external tools reading the
+    /// segment files directly while the database stays online.
+    ///
+    /// The files are immutable and each of them stays available until its [`ExportedSegment`]
+    /// is dropped, even if it's replaced by a compaction. The entries of the memtable aren't in
+    /// any segment, call [`Database::flush`] first to export them too.
+    pub fn export_segments(&mut self) -> Result<Vec<ExportedSegment>> {
+        self.poison.check()?;
+        let mut exported = Vec::with_capacity(self.segments.len());
+        for segment in &self.segments {
+            let keys = segment
+                .fence(&mut self.files)?
+                .map(|(first, last)| first.clone()..=last.clone());
+            let seqs = segment.seqs(&mut self.files)?;
+            exported.push(ExportedSegment::new(segment.pin(), keys, seqs));
+        }
+        Ok(exported)
+    }
+
+    /// Flush the memtable and keep the segment files from being deleted until the returned
+    /// [`Backup`] is dropped, so a backup can copy them while the database stays online.
+    ///
+    /// The backup is made of the segments and of the manifest listing them, copied last. All
+    /// the writes done before are in the segments, the files of the dirty segment only hold the
+    /// following ones and aren't part of it. The segments replaced by a compaction meanwhile are
+    /// deleted once the backup is dropped.
+    pub fn begin_backup(&mut self) -> Result<Backup> {
+        self.flush()?;
+        self.write_manifest()?;
+        let manifest_path = self.layout.manifest_path(&self.path);
+        let manifest = std::fs::read_to_string(&manifest_path)?;
+        let segments = self.segments.iter().map(Segment::pin).collect();
+        let (mut blobs, mut value_log) = (Vec::new(), Vec::new());
+        for segment in &self.segments {
+            let blob_refs = segment.blob_refs(&mut self.files)?;
+            blobs.extend(blob_refs.iter().map(|hash| self.blob_store.path(*hash)));
+            let value_refs = segment.value_refs(&mut self.files)?;
+            value_log.extend(value_refs.iter().map(|(id, _)| self.value_log.path(*id)));
+        }
+        blobs.sort_unstable();
+        blobs.dedup();
+        value_log.sort_unstable();
+        value_log.dedup();
+        Ok(Backup::new(
+            self.path.clone(),
+            self.layout.identity_path(&self.path),
+            manifest_path,
+            manifest,
+            segments,
+            blobs,
+            value_log,
+        ))
+    }
+
+    /// Write the memtable and the frozen memtable to a standalone segment file at `path`,
+    /// without adding it to the database, and returns the number of keys with a value.
+    ///
+    /// The file is in the format of the clean segments, sorted by key, and can be read by
+    /// another process with [`SegmentReader`]. It holds the deletions and the range deletions
+    /// of the memtables, they hide nothing since the entries of the segments aren't written.
+    /// [`Error::SegmentExists`] is returned if a file is already at `path`.
+    pub fn freeze_memtable_to(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        self.poison.check()?;
+        let path = path.as_ref();
+        let mut entries = self.dirty_entries()?;
+        if let Some(frozen) = &self.frozen {
+            entries.extend(frozen.range(Bound::Unbounded, Bound::Unbounded));
+        }
+        entries.sort_unstable_by(|a, b| a.key.cmp(&b.key).then(b.seq.cmp(&a.seq)));
+        let range_tombstones = self.memtable_range_tombstones();
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        // Not bottommost, the deletions may hide the entries of another dataset
+        let Written { live_keys, .. } = write_segment(
+            &mut file,
+            entries.into_iter().map(Ok),
+            &range_tombstones,
+            false,
+            &self.segment_options(),
+        )?;
+        persist_segment(file, path)?;
+        Ok(live_keys)
+    }
+
+    /// Write the entries of the database to a Parquet file and returns their number.
+    ///
+    /// The entries are given by batches to `schema_fn`, as a column of keys and a column of
+    /// values, to be mapped to the columns of the file. [`key_value_batch`] keeps them as two
+    /// binary columns. The schema of the file is the one returned for an empty batch.
+    ///
+    /// Only available with the `parquet` feature, the [`arrow_array`] and [`arrow_schema`]
+    /// crates are re-exported to write the mapping.
+    #[cfg(feature = "parquet")]
+    pub fn export_parquet<F>(&mut self, path: impl AsRef<Path>, schema_fn: F) -> Result<u64>
+    where
+        F: FnMut(
+            arrow_array::BinaryArray,
+            arrow_array::BinaryArray,
+        )
+            -> std::result::Result<arrow_array::RecordBatch, arrow_schema::ArrowError>,
+    {
+        export::write_parquet(path.as_ref(), self.range::<&[u8]>(..)?, schema_fn)
+    }
+
+    /// The report of the last scrub done in the background.
+    pub fn last_scrub(&self) -> Option<&ScrubReport> {
+        self.last_scrub.as_ref()
+    }
+
+    /// Collect the background scrub once it's done, and start the next one when it's time.
+    fn poll_scrub(&mut self) -> Result<()> {
+        let Some(scrub) = &mut self.background_scrub else {
+            return Ok(());
+        };
+        if let Some(results) = scrub.finished() {
+            let report = self.apply_scrub(results)?;
+            self.last_scrub = Some(report);
+            return Ok(());
+        }
+        let now = self.scheduler.now();
+        let due = scrub.last + scrub.interval <= now;
+        if due && !scrub.is_running() {
+            scrub.start(self.segments.iter().map(Segment::pin).collect(), now);
+        }
         Ok(())
     }
-}
 
-impl Database {
-    pub fn new(dir: impl AsRef<Path>) -> Result<Database> {
-        let dir = dir.as_ref();
-        std::fs::create_dir_all(dir)?;
+    fn apply_scrub(&mut self, results: scrub::Results) -> Result<ScrubReport> {
+        let mut report = ScrubReport::default();
+        for (path, scrubbed) in results {
+            report.segments += 1;
+            match scrubbed {
+                Ok(scrubbed) => {
+                    report.blocks += scrubbed.blocks;
+                    report.bytes += scrubbed.bytes;
+                    let Some(e) = scrubbed.damaged_filter else {
+                        continue;
+                    };
+                    // The segment may have been compacted since
+                    let Some(position) = self.segments.iter().position(|s| s.path == path) else {
+                        continue;
+                    };
+                    let id = self.rewrite_segment(position, None)?;
+                    self.events.log(format_args!(
+                        "scrub: segment {} rewritten as segment {id}: {e}",
+                        path.display()
+                    ));
+                    report.repaired.push(path);
+                }
+                Err(e) => {
+                    self.events.log(format_args!(
+                        "scrub: segment {} is damaged: {e}",
+                        path.display()
+                    ));
+                    report.corrupted.push((path, e.to_string()));
+                }
+            }
+        }
+        self.events.log(format_args!(
+            "scrub: {} blocks of {} segments checked, {} repaired, {} damaged",
+            report.blocks,
+            report.segments,
+            report.repaired.len(),
+            report.corrupted.len()
+        ));
+        Ok(report)
+    }
 
-        let mut dirty = File::options()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(dir.join("dirty"))?;
+    /// Write the entries of the segment to a new segment taking its place, returns its id.
+    ///
+    /// The values stay where they are, except the ones of the `relocated` files of the value
+    /// log which are written again.
+    fn rewrite_segment(
+        &mut self,
+        position: usize,
+        relocated: Option<&HashSet<u32>>,
+    ) -> Result<usize> {
+        let _guard = self.poison.guard()?;
+        let old = &self.segments[position];
+        let level = match self.layout.has_level_dirs() {
+            true => old.path.starts_with(self.layout.level_dir(&self.path, 1)) as u8,
+            false => 0,
+        };
+        let level_dir = self.layout.level_dir(&self.path, level);
+        let id = self.next_id;
+        self.next_id += 1;
 
-        Ok(Database {
-            dirty_thresholds: 1024,
-            path: dir.to_owned(),
-            memtable: Self::init_memtable(&mut dirty)?,
-            dirty,
-            segments: VecDeque::new(),
-        })
+        let mut new_segment = self.layout.temp_file(&level_dir)?;
+        let mut entries = old.iter_uncached(self.read_ahead)?;
+        entries.stored_values();
+        let range_tombstones = old.range_tombstones(&mut self.files)?.to_vec();
+        let options = SegmentOptions {
+            // Everything is copied as is
+            versions: usize::MAX,
+            schema: None,
+            stored_values: true,
+            relocate: relocated.map(|files| (&self.value_log, files)),
+            ..self.segment_options()
+        };
+        write_segment(
+            &mut new_segment,
+            entries,
+            &range_tombstones,
+            false,
+            &options,
+        )?;
+        let path = self.layout.segment_path(&self.path, level, id);
+        persist_segment(new_segment, &path)?;
+
+        let new = self.new_segment(id, path);
+        let old = mem::replace(&mut self.segments[position], new);
+        self.write_manifest()?;
+        self.load_counters();
+        self.retire(old)?;
+        Ok(id)
     }
 
-    pub fn dirty_thresholds(&mut self, threshold: usize) {
-        self.dirty_thresholds = threshold;
+    /// Register the schema of the values.
+    ///
+    /// From now on the values are written tagged with the version of the schema, and the values
+    /// written with an older version are upgraded when they're read or compacted.
+    /// Thus the schema must be registered before any value is written in the database.
+    pub fn schema(&mut self, schema: Schema) {
+        self.schema = Some(Arc::new(schema));
     }
 
-    fn init_memtable(dirty: &mut File) -> Result<BTreeMap<Vec<u8>, u64>> {
+    /// Returns the memtable, range tombstones and last sequence number found in the dirty
+    /// segment, `progress` is called with the number of bytes replayed after each MiB. The
+    /// number of live keys isn't known yet.
+    fn init_memtable(
+        dirty: &mut WalFiles,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<memtable::Saved> {
         let mut memtable = BTreeMap::new();
-        let mut reader = BufReader::new(dirty);
+        let mut range_tombstones = Vec::new();
+        let total = dirty.len();
+        // The entries are small, they're read by large batches
+        let mut reader = BufReader::with_capacity(REPLAY_BATCH as usize, dirty);
+        let mut reported = 0;
 
         let mut current_position = 0;
         let mut key_buf = Vec::new();
+        let mut sequence = 0;
+        let mut clock = 0;
 
         loop {
             let key_size = match read_u32(&mut reader) {
@@ -185,350 +1167,6724 @@ impl Database {
             };
 
             read_bytes(&mut reader, key_size as usize, &mut key_buf)?;
-            memtable.insert(key_buf.clone(), current_position);
+            let (seq, _, deleted, checksummed) = read_seq_and_meta(&mut reader)?;
+            sequence = sequence.max(seq);
+            let (timestamp, size) = read_value_size(&mut reader, deleted)?;
+            clock = clock.max(timestamp);
+            // The compact deletions have no size of value
+            let size_size = match deleted {
+                true => 0,
+                false => mem::size_of::<u32>() as u64,
+            };
+            // The mark and the timestamp precede the size of the value
+            let timestamp_size = match timestamp {
+                0 => 0,
+                _ => (mem::size_of::<u32>() + mem::size_of::<u64>()) as u64,
+            };
+            // The checksum follows the value or the fragment
+            let checksum_size = match checksummed && size != TOMBSTONE {
+                true => mem::size_of::<u32>() as u64,
+                false => 0,
+            };
 
-            let value_size = read_u32(&mut reader)?;
-            io::copy(
-                &mut reader.by_ref().take(value_size as u64),
-                &mut io::sink(),
-            )?;
+            let value_size = match size {
+                RANGE_TOMBSTONE => {
+                    let end = read_entry_to_vec(&mut reader)?;
+                    // The end follows its size like a value
+                    let size = mem::size_of::<u32>() + end.len();
+                    range_tombstones.push(RangeTombstone {
+                        start: key_buf.clone(),
+                        end,
+                        seq,
+                    });
+                    size as u64
+                }
+                APPEND => {
+                    memtable.insert(key_buf.clone(), current_position);
+                    // The position of the previous value and the size of the whole value
+                    let chain = (mem::size_of::<u64>() + mem::size_of::<u32>()) as u64;
+                    skip_bytes(&mut reader, chain)?;
+                    let size = read_u32(&mut reader)?;
+                    skip_bytes(&mut reader, size as u64)?;
+                    chain + mem::size_of::<u32>() as u64 + size as u64
+                }
+                size => {
+                    memtable.insert(key_buf.clone(), current_position);
+                    let size = if size == TOMBSTONE { 0 } else { size as u64 };
+                    skip_bytes(&mut reader, size)?;
+                    size
+                }
+            };
+            skip_bytes(&mut reader, checksum_size)?;
 
             // increase the current position by the size of the entry
-            // aka: the size _of the size_ of the key and value + the size of the key + the size of the value
-            current_position +=
-                mem::size_of::<u32>() as u64 * 2 + key_size as u64 + value_size as u64;
+            // aka: the size _of the size_ of the key and value + the size of the key + the
+            // size of the sequence number + the size of the timestamp + the size of the value and
+            // of its checksum
+            current_position += mem::size_of::<u32>() as u64
+                + size_size
+                + key_size as u64
+                + mem::size_of::<u64>() as u64
+                + timestamp_size
+                + value_size
+                + checksum_size;
+            if current_position - reported >= REPLAY_BATCH {
+                reported = current_position;
+                progress(current_position, total);
+            }
         }
+        progress(total, total);
+
+        Ok(memtable::Saved {
+            memtable,
+            sequence,
+            live_keys: 0,
+            clock,
+            range_tombstones,
+        })
+    }
 
-        Ok(memtable)
+    /// The sequence number of the last write, 0 if nothing was ever written.
+    ///
+    /// Each write is assigned the next sequence number, it can be used to read the
+    /// previous versions of the entries with [`Database::get_at`].
+    pub fn sequence(&self) -> u64 {
+        self.sequence
     }
 
     pub fn add(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
-        let (key, value) = (key.as_ref(), value.as_ref());
+        self.write(key.as_ref(), Some(value.as_ref()), 0)
+    }
+
+    /// Write the entry with its own durability, e.g. to sync the critical metadata right away
+    /// or to skip the dirty segment for the data that can be computed again.
+    ///
+    /// The entries written without the dirty segment are kept in memory until the next flush,
+    /// they're written to the dirty segment when the database is closed.
+    pub fn add_opt(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        options: WriteOptions,
+    ) -> Result<()> {
+        self.write_opt(key.as_ref(), Some(value.as_ref()), 0, options)
+    }
+
+    /// Write the entry along with a metadata byte returned by [`Database::get_with_meta`].
+    ///
+    /// The metadata is left to the application, e.g. to mark the values it compressed itself.
+    /// It's kept by the flushes and compactions, the entries written without it have a
+    /// metadata of 0.
+    pub fn add_with_meta(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        meta: u8,
+    ) -> Result<()> {
+        self.write(key.as_ref(), Some(value.as_ref()), meta)
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.write(key.as_ref(), None, 0)
+    }
 
-        if key.len() > u32::MAX as usize {
-            return Err(Error::KeyTooLarge(key.len()));
+    /// Delete all the keys contained in `start..end` with a single write.
+    ///
+    /// A range tombstone is written instead of a deletion for each key: it hides the older
+    /// entries of the range from the reads, and the compactions drop the entries it covers. The
+    /// keys written afterward in the range aren't affected. The live keys of the range are
+    /// still read once to keep [`Database::len`] exact, unless [`DatabaseBuilder::exact_len`] is
+    /// disabled.
+    pub fn delete_range(&mut self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<()> {
+        self.poison.check()?;
+        let (start, end) = (start.as_ref(), end.as_ref());
+        self.check_limits(start, 0)?;
+        self.check_limits(end, 0)?;
+        if start >= end {
+            return Ok(());
         }
-        if value.len() > u32::MAX as usize {
-            return Err(Error::KeyTooLarge(key.len()));
+        self.reserve_memory(mem::size_of::<RangeTombstone>() + start.len() + end.len())?;
+        let mut deleted = 0;
+        if self.exact_len {
+            for entry in self.range(start..end)? {
+                entry?;
+                deleted += 1;
+            }
         }
 
-        self.prepare_to_add()?;
-        let pos = self.dirty.stream_position()?;
-
-        // First we need to write everything on disk in case a crash happens
-        write_entry(&mut self.dirty, key, value)?;
-        // Then we can add it in the memtable
-        self.memtable.insert(key.to_vec(), pos);
+        let event = WriteEvent {
+            key: start,
+            range_end: Some(end),
+            value_size: None,
+            seq: self.sequence + 1,
+        };
+        self.hooks.iter().for_each(|hook| hook.before_write(&event));
+        self.dirty.rotate_if_full()?;
+        let range = RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq: self.sequence + 1,
+        };
+        write_range_tombstone(&mut self.dirty, &range)?;
+        self.sequence += 1;
+        self.user_bytes += (start.len() + end.len()) as u64;
+        self.range_tombstones.push(range);
+        self.memtable_keys -= deleted;
+        self.hooks.iter().for_each(|hook| hook.after_write(&event));
 
-        if self.memtable.len() > self.dirty_thresholds {
-            self.flush_dirty()?;
+        if self.scheduler.should_flush(&self.scheduler_state()) {
+            self.freeze()?;
         }
+        self.poll_scrub()?;
 
         Ok(())
     }
 
-    pub fn flush_dirty(&mut self) -> Result<()> {
-        // We need to dump the dirty entries in a new segment
-
-        // 1. Get a tempfile that'll be droped if something happens during the dumping operation
-        let new_segment = NamedTempFile::new_in(&self.path)?;
-        let mut writer = BufWriter::new(new_segment);
-
-        // 1. Write all entries ordered by keys in a new file
-        for (key, value) in self.memtable.iter() {
-            self.dirty.seek(SeekFrom::Start(
-                value + mem::size_of::<u32>() as u64 + key.len() as u64,
-            ))?;
-            let value = read_entry_to_vec(&mut self.dirty)?;
-
-            write_entry(&mut writer, key, &value)?;
+    /// Delete the keys of `range` whose entry is accepted by `predicate`, called with the key
+    /// and the value. Returns the number of keys deleted, or that would be deleted when
+    /// `dry_run` is set, in which case nothing is written.
+    ///
+    /// The range is scanned once before any deletion is written, the deletions are then written
+    /// one after the other and aren't atomic: a crash can leave some of the keys undeleted.
+    pub fn delete_where<K, P>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        mut predicate: P,
+        dry_run: bool,
+    ) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        P: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.poison.check()?;
+        let mut matching = Vec::new();
+        for entry in self.range(range)? {
+            let (key, value) = entry?;
+            if predicate(&key, &value) {
+                matching.push(key);
+            }
         }
-        writer.flush()?;
+        if !dry_run {
+            for key in &matching {
+                self.delete(key)?;
+            }
+        }
+        Ok(matching.len() as u64)
+    }
 
-        // 2. Clean the dirty segment
-        self.memtable.clear();
-        let next_id = self.segments.back().map_or(0, |segment| segment.id + 1);
-        let new_segment = writer
-            .into_inner()
-            .unwrap()
-            .persist(self.path.join(format!("segment-{next_id}")))?;
-        self.dirty.set_len(0)?;
+    /// Append `bytes` to the value of the key, a missing key is created with them.
+    ///
+    /// When the value is in the memtable only the bytes are written to the dirty segment,
+    /// chained to the previous value: the reads concatenate the chain and the flush writes the
+    /// whole value to the segment. Building a large value by small appends thus writes it once
+    /// per flush instead of on every append. Otherwise the value is read and written again.
+    pub fn append(&mut self, key: impl AsRef<[u8]>, bytes: impl AsRef<[u8]>) -> Result<()> {
+        self.poison.check()?;
+        let (key, bytes) = (key.as_ref(), bytes.as_ref());
+        let prev = self.memtable.get(key).copied();
+        let Some(prev) = prev.filter(|index| *index != UNLOGGED) else {
+            return self.append_to_older(key, bytes);
+        };
+        let (seq, meta, _, size, _) = self.seek_dirty(key, prev)?;
+        let size = match size {
+            TOMBSTONE => None,
+            APPEND => {
+                let _prev = read_u64(&mut self.dirty)?;
+                Some(read_u32(&mut self.dirty)?)
+            }
+            size => Some(size),
+        };
+        let size = match size {
+            Some(size) if !range_deleted(&self.range_tombstones, key, seq) => size as usize,
+            _ => return self.append_to_older(key, bytes),
+        };
+        self.check_limits(key, size + bytes.len())?;
+        self.reserve_memory(key.len() + MEMTABLE_ENTRY_BYTES)?;
 
-        // 3. Push the new file to the segment list
-        self.segments.push_back(Segment {
-            id: next_id,
-            file: new_segment,
-        });
+        let event = WriteEvent {
+            key,
+            range_end: None,
+            value_size: Some(bytes.len()),
+            seq: self.sequence + 1,
+        };
+        self.hooks.iter().for_each(|hook| hook.before_write(&event));
+        self.dirty.rotate_if_full()?;
+        let pos = self.dirty.len();
+        let entry = Entry {
+            key: key.to_vec(),
+            seq: self.sequence + 1,
+            meta,
+            timestamp: self.next_timestamp(),
+            value: Some(bytes.to_vec()),
+        };
+        write_append(&mut self.dirty, &entry, prev, (size + bytes.len()) as u32)?;
+        self.sequence += 1;
+        self.user_bytes += (key.len() + bytes.len()) as u64;
+        self.memtable_insert(entry.key, pos);
+        self.hooks.iter().for_each(|hook| hook.after_write(&event));
 
-        if self.segments.len() > 10 {
-            self.merge_segment()?;
+        if self.scheduler.should_flush(&self.scheduler_state()) {
+            self.freeze()?;
         }
+        self.poll_scrub()?;
+
         Ok(())
     }
 
-    pub fn merge_segment(&mut self) -> Result<()> {
-        // merge the first two segments
-        let mut old = self.segments.pop_front().unwrap();
-        let mut new = self.segments.pop_front().unwrap();
-        let mut new_segment = NamedTempFile::new_in(&self.path)?;
-        Segment::merge(&mut new_segment, &mut new, &mut old)?;
-        let file = new_segment.persist(self.path.join(format!("segment-{}", old.id)))?;
-
-        self.segments.push_front(Segment { id: old.id, file });
+    /// Write the whole value, the previous one isn't in the memtable.
+    fn append_to_older(&mut self, key: &[u8], bytes: &[u8]) -> Result<()> {
+        let (mut value, meta) = self.get_with_meta(key)?.unwrap_or_default();
+        value.extend_from_slice(bytes);
+        self.write(key, Some(&value), meta)
+    }
 
-        Ok(())
+    /// Replace the value of the key by `new` only if its current value is `expected`.
+    /// A `None` stands for a missing entry, thus it can be used to create or delete an entry.
+    ///
+    /// If the current value doesn't match, nothing is written and it's returned in the error.
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), CompareAndSwapError>> {
+        let key = key.as_ref();
+        // The database is borrowed mutably, nothing can be written between the read and the write
+        let current = self.get(key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+        self.write(key, new, 0)?;
+        Ok(Ok(()))
     }
 
-    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+    /// Add `delta` to the counter stored in the key and return its new value.
+    ///
+    /// The counters are stored as 8 bytes big endian integers, a missing counter starts at 0.
+    pub fn increment(&mut self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64> {
         let key = key.as_ref();
-        let index = match self.memtable.get(key) {
-            Some(index) => *index,
-            None => return self.get_from_segments(key),
+        let current = match self.get(key)? {
+            Some(value) => {
+                let bytes = value
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| Error::InvalidCounter(value.len()))?;
+                i64::from_be_bytes(bytes)
+            }
+            None => 0,
         };
-        self.dirty.seek(SeekFrom::Start(
-            // the index + skip the key
-            index + mem::size_of::<u32>() as u64 + key.len() as u64,
-        ))?;
-        // and get the value
-        let value = read_entry_to_vec(&mut self.dirty)?;
-
-        Ok(Some(value))
+        let new = current.checked_add(delta).ok_or(Error::CounterOverflow)?;
+        // Storing the total rather than the delta means there is nothing left to fold during the compactions
+        self.write(key, Some(&new.to_be_bytes()), 0)?;
+        Ok(new)
     }
 
-    fn get_from_segments(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let mut buf = Vec::new();
-        // We want to go from the most recent segment to the most outdated one
-        for segment in self.segments.iter_mut().rev() {
-            if let Some(value) = segment.get(key, &mut buf)? {
-                return Ok(Some(value));
-            }
+    /// Return the value of the key, or write and return the value given by `default` when the
+    /// key is missing.
+    ///
+    /// `default` is only called for a missing key, and the existing value is never rewritten.
+    pub fn get_or_insert_with<V: AsRef<[u8]>>(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        default: impl FnOnce() -> V,
+    ) -> Result<Vec<u8>> {
+        let key = key.as_ref();
+        // The database is borrowed mutably, nothing can be written between the read and the write
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
         }
-
-        Ok(None)
+        let value = default().as_ref().to_vec();
+        self.write(key, Some(&value), 0)?;
+        Ok(value)
     }
 
-    fn prepare_to_add(&mut self) -> io::Result<()> {
-        self.dirty.seek(SeekFrom::End(0))?;
-        Ok(())
+    fn write(&mut self, key: &[u8], value: Option<&[u8]>, meta: u8) -> Result<()> {
+        self.write_opt(key, value, meta, WriteOptions::default())
     }
 
-    fn prepare_to_read(&mut self) -> io::Result<()> {
-        self.dirty.seek(SeekFrom::Start(0))?;
-        Ok(())
-    }
+    fn write_opt(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        meta: u8,
+        options: WriteOptions,
+    ) -> Result<()> {
+        self.poison.check()?;
+        self.check_limits(key, value.map_or(0, <[u8]>::len))?;
+        let unlogged = match options.disable_wal {
+            true => mem::size_of::<Entry>() + key.len() + value.map_or(0, <[u8]>::len),
+            false => 0,
+        };
+        self.reserve_memory(key.len() + MEMTABLE_ENTRY_BYTES + unlogged)?;
 
-    #[cfg(test)]
-    fn dump(&mut self) -> io::Result<String> {
-        let mut buf = String::new();
-        buf.push_str(&format!("memtable:\n{:?}\n", self.memtable));
+        let tagged;
+        let value = match (value, &self.schema) {
+            (Some(value), Some(schema)) => {
+                tagged = schema.tag(value);
+                Some(tagged.as_slice())
+            }
+            (value, _) => value,
+        };
 
-        let mut dirty_buf = Vec::new();
-        self.prepare_to_read()?;
-        self.dirty.read_to_end(&mut dirty_buf)?;
+        let was_live = match self.exact_len {
+            true => self.is_live(key)?,
+            // The keys out of the memtables are assumed to be new, or deleted while they had a
+            // value in the segments
+            false => (self.is_live_in_memtables(key)?)
+                .unwrap_or(value.is_none() && !self.segments.is_empty()),
+        };
+        let timestamp = self.next_timestamp();
+        let event = WriteEvent {
+            key,
+            range_end: None,
+            value_size: value.map(<[u8]>::len),
+            seq: self.sequence + 1,
+        };
+        self.hooks.iter().for_each(|hook| hook.before_write(&event));
 
-        buf.push_str(&format!("dirty segment:\n{dirty_buf:?}\n"));
+        if options.disable_wal {
+            self.sequence += 1;
+            let entry = Entry {
+                key: key.to_vec(),
+                seq: self.sequence,
+                meta,
+                timestamp,
+                value: value.map(<[u8]>::to_vec),
+            };
+            self.memtable_bytes += entry.memory_bytes();
+            if let Some(previous) = self.unlogged.insert(key.to_vec(), entry) {
+                self.memtable_bytes -= previous.memory_bytes();
+            }
+            self.memtable_insert(key.to_vec(), UNLOGGED);
+        } else {
+            // The reads of the dirty segment move its position but not its end
+            self.dirty.rotate_if_full()?;
+            let pos = self.dirty.len();
 
-        for (i, segment) in self.segments.iter_mut().enumerate() {
-            segment.dump(&mut dirty_buf)?;
-            buf.push_str(&format!("segment {i}:\n{dirty_buf:?}\n"));
+            // First we need to write everything on disk in case a crash happens
+            write_entry(
+                &mut self.dirty,
+                key,
+                self.sequence + 1,
+                meta,
+                timestamp,
+                value,
+            )?;
+            self.sequence += 1;
+            // Then we can add it in the memtable
+            self.memtable_insert(key.to_vec(), pos);
+            if let Some(previous) = self.unlogged.remove(key) {
+                self.memtable_bytes -= previous.memory_bytes();
+            }
+        }
+        self.memtable_keys += value.is_some() as i64 - was_live as i64;
+        self.user_bytes += (key.len() + value.map_or(0, <[u8]>::len)) as u64;
+        if options.sync && !options.disable_wal {
+            self.dirty.sync()?;
+        }
+        self.hooks.iter().for_each(|hook| hook.after_write(&event));
+        if let Some(adaptive) = &mut self.adaptive {
+            adaptive.record_entry(key.len() + value.map_or(0, <[u8]>::len));
+            self.dirty_thresholds = adaptive.threshold().unwrap_or(self.dirty_thresholds);
         }
 
-        Ok(buf)
+        if self.scheduler.should_flush(&self.scheduler_state()) {
+            self.freeze()?;
+        }
+        self.poll_scrub()?;
+
+        Ok(())
     }
-}
 
-fn write_entry(mut writer: impl Write, key: &[u8], value: &[u8]) -> io::Result<()> {
+    /// The timestamp of a new write, 0 when they aren't recorded.
+    fn next_timestamp(&mut self) -> u64 {
+        if !self.timestamps {
+            return 0;
+        }
+        let now = self.scheduler.now().duration_since(UNIX_EPOCH);
+        let now = now.unwrap_or_default().as_millis() as u64;
+        // 0 stands for the entries written without timestamp
+        self.clock = self.clock.max(now).max(1);
+        self.clock
+    }
+
+    fn check_limits(&self, key: &[u8], value_size: usize) -> Result<()> {
+        let Limits {
+            key: max_key,
+            value: max_value,
+        } = self.limits;
+        if key.len() > max_key {
+            return Err(Error::KeyTooLarge {
+                size: key.len(),
+                max: max_key,
+            });
+        }
+        if value_size > max_value {
+            return Err(Error::ValueTooLarge {
+                size: value_size,
+                max: max_value,
+            });
+        }
+        Ok(())
+    }
+
+    /// Insert the index of the last version of the key in the memtable, the new keys are
+    /// accounted for in the memory budget.
+    fn memtable_insert(&mut self, key: Vec<u8>, index: u64) {
+        let bytes = key.len() + MEMTABLE_ENTRY_BYTES;
+        if self.memtable.insert(key, index).is_none() {
+            self.memtable_bytes += bytes;
+        }
+    }
+
+    /// The memory used by the memtables, see [`Stats::memtable_bytes`].
+    fn memtable_memory(&self) -> usize {
+        let ranges = self
+            .range_tombstones
+            .iter()
+            .map(RangeTombstone::memory_bytes);
+        self.memtable_bytes
+            + ranges.sum::<usize>()
+            + self.frozen.as_ref().map_or(0, |frozen| frozen.bytes)
+    }
+
+    /// The memory used by the filters, see [`Stats::filter_bytes`].
+    fn filter_memory(&self) -> usize {
+        let segments = self.segments.iter().map(Segment::filter_bytes);
+        segments.sum::<usize>()
+            + self
+                .database_filter
+                .as_ref()
+                .map_or(0, DatabaseFilter::bytes)
+    }
+
+    /// Give the block cache the memory of the budget left by the memtables and the filters.
+    fn limit_cache(&mut self) {
+        if let Some(budget) = self.memory_budget {
+            let used = self.memtable_memory() + self.filter_memory();
+            self.cache.set_limit(budget.saturating_sub(used));
+        }
+    }
+
+    /// Make room in the memory budget for a write using `bytes` more: the block cache gives
+    /// back its memory first, then the memtable is flushed.
+    fn reserve_memory(&mut self, bytes: usize) -> Result<()> {
+        let Some(budget) = self.memory_budget else {
+            return Ok(());
+        };
+        let needed =
+            |database: &Database| database.memtable_memory() + database.filter_memory() + bytes;
+        if needed(self) > budget {
+            if self.memtable.is_empty() && self.range_tombstones.is_empty() {
+                self.finish_flush()?;
+            } else {
+                self.flush()?;
+            }
+            let needed = needed(self);
+            if needed > budget {
+                return Err(Error::MemoryBudgetExceeded { needed, budget });
+            }
+        }
+        self.cache.set_limit(budget - needed(self));
+        Ok(())
+    }
+
+    /// Read the counters of the segments that were just added, so [`Database::stats`] doesn't
+    /// need to read the footers.
+    fn load_counters(&mut self) {
+        for segment in &self.segments {
+            // A damaged footer is reported by the lookups and the scrubs, the segment is left
+            // out of the counters
+            let _ = segment.counters(&mut self.files);
+        }
+    }
+
+    /// Read the footers of the segments, and their filters when `filters` is set, with up to
+    /// `threads` threads pulling the segments one by one.
+    fn load_segments(&self, threads: usize, filters: bool) -> Result<()> {
+        let (segments, policy) = (&self.segments, self.filter.as_deref().filter(|_| filters));
+        let identity = self.identity;
+        let next = AtomicUsize::new(0);
+        let foreign = Mutex::new(None);
+        let load = || {
+            while let Some(segment) = segments.get(next.fetch_add(1, Ordering::Relaxed)) {
+                // A damaged footer is reported by the lookups and the scrubs, the segment is
+                // left out of the counters
+                if let Err(e @ Error::ForeignSegment(_)) = segment.preload(policy, identity) {
+                    foreign.lock().unwrap().get_or_insert(e);
+                }
+            }
+        };
+        match threads.min(segments.len()) {
+            0 | 1 => load(),
+            threads => thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(load);
+                }
+            }),
+        }
+        match foreign.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the manifest with the current segments.
+    fn write_manifest(&self) -> io::Result<()> {
+        self.write_manifest_with(None)
+    }
+
+    /// Replace the manifest with the current segments, recording the id of the segment the
+    /// compaction about to start writes.
+    fn write_manifest_with(&self, pending_compaction: Option<usize>) -> io::Result<()> {
+        manifest::write(
+            &self.path,
+            &self.layout,
+            &self.segments,
+            Some(self.segment_keys),
+            Some(self.limits),
+            self.clock,
+            self.sequence,
+            self.next_id,
+            pending_compaction,
+        )
+    }
+
+    /// Bulk-load entries sorted by key in a new segment without going through the dirty segment,
+    /// returns the number of entries imported.
+    ///
+    /// The memtable is flushed first, the imported entries are newer than everything already
+    /// written. Nothing is added if a key is out of order or if `entries` fails. The
+    /// `import_from_sled` and `import_from_rocksdb` methods, behind the features of the same
+    /// name, migrate the content of other stores.
+    pub fn import<K, V, E>(
+        &mut self,
+        entries: impl IntoIterator<Item = std::result::Result<(K, V), E>>,
+    ) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        Error: From<E>,
+    {
+        if !self.memtable.is_empty() {
+            self.freeze()?;
+        }
+        self.finish_flush()?;
+        let _guard = self.poison.guard()?;
+
+        let level_dir = self.layout.level_dir(&self.path, 0);
+        let new_segment = self.layout.temp_file(&level_dir)?;
+        // The keys are looked up while the segment is written
+        let filter = self.filter.clone();
+        let mut writer = SegmentWriter::new(
+            BufWriter::new(new_segment),
+            filter.as_deref(),
+            self.encoding,
+            self.pool.clone(),
+        );
+        writer.block_sizes(self.block_sizes);
+        writer.database(self.identity);
+        if let Some(threshold) = self.blob_threshold {
+            writer.blobs(self.blob_store.clone(), threshold);
+        }
+        if let Some(threshold) = self.value_log_threshold {
+            writer.value_log(self.value_log.clone(), threshold);
+        }
+        if let Some(extractor) = self.prefix_extractor {
+            writer.prefix_extractor(extractor);
+        }
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut sequence = self.sequence;
+        let timestamp = self.next_timestamp();
+        // Everything imported in an empty database is new
+        let lookup = self.exact_len && !self.segments.is_empty();
+        let mut new_keys = 0;
+        let mut imported_bytes = 0;
+        for entry in entries {
+            let (key, value) = entry?;
+            let (key, value) = (key.as_ref(), value.as_ref());
+            if last_key.as_deref().is_some_and(|last| last >= key) {
+                return Err(Error::UnsortedImport(key.to_vec()));
+            }
+            self.check_limits(key, value.len())?;
+            if !lookup || !self.is_live(key)? {
+                new_keys += 1;
+            }
+
+            let tagged;
+            let value = match &self.schema {
+                Some(schema) => {
+                    tagged = schema.tag(value);
+                    tagged.as_slice()
+                }
+                None => value,
+            };
+            sequence += 1;
+            writer.add(key, sequence, 0, timestamp, Some(value))?;
+            imported_bytes += (key.len() + value.len()) as u64;
+            let last_key = last_key.get_or_insert_with(Vec::new);
+            last_key.clear();
+            last_key.extend_from_slice(key);
+        }
+        let imported = sequence - self.sequence;
+        if imported == 0 {
+            return Ok(0);
+        }
+
+        let new_segment = writer
+            .finish()?
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let path = self.layout.segment_path(&self.path, 0, id);
+        persist_segment(new_segment, &path)?;
+
+        let size = std::fs::metadata(&path)?.len();
+        let segment = self.new_segment(id, path);
+        self.segments.push_back(segment);
+        self.segment_keys += new_keys;
+        self.write_manifest()?;
+        self.load_counters();
+        self.sequence = sequence;
+        self.user_bytes += imported_bytes;
+        self.flush_bytes += size;
+        if let Some(filter) = &mut self.database_filter {
+            filter.rebuild(&self.segments, self.read_ahead)?;
+        }
+        self.events.log(format_args!(
+            "import: {imported} entries written to segment {id} ({size} bytes)"
+        ));
+        self.hooks
+            .iter()
+            .for_each(|hook| hook.after_flush(id, size));
+        Ok(imported)
+    }
+
+    /// Bulk-load entries in any order, returns the number of keys imported.
+    ///
+    /// The entries are sorted by chunks written to temporary files next to the segments and
+    /// merged back, thus they don't need to fit in memory. When a key appears several times the
+    /// last occurrence wins. See [`Database::import`] for the entries already sorted.
+    pub fn import_unsorted<K, V, E>(
+        &mut self,
+        entries: impl IntoIterator<Item = std::result::Result<(K, V), E>>,
+    ) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        Error: From<E>,
+    {
+        let sort = ExternalSort {
+            dir: &self.layout.level_dir(&self.path, 0),
+            layout: &self.layout,
+            encoding: self.encoding,
+            pool: &self.pool,
+            run_size: import::RUN_SIZE,
+            read_ahead: self.read_ahead,
+        };
+        let sorted = sort.sort(entries.into_iter().map(|entry| match entry {
+            Ok((key, value)) => Ok((key.as_ref().to_vec(), value.as_ref().to_vec())),
+            Err(e) => Err(Error::from(e)),
+        }))?;
+        // The bound on `E` would be picked for the errors of the sort
+        self.import::<_, _, Error>(sorted)
+    }
+
+    /// Import a JSON-lines file, each line is an object with a string `key` and a `value`, see
+    /// [`Database::import_unsorted`].
+    ///
+    /// The string values are stored as is while the other values are stored as JSON. The empty
+    /// lines are ignored. Only available with the `jsonl` feature.
+    #[cfg(feature = "jsonl")]
+    pub fn import_jsonl(&mut self, reader: impl io::BufRead) -> Result<u64> {
+        let entries = (1..)
+            .zip(reader.lines())
+            .filter_map(|(line, text)| match text {
+                Ok(text) if text.trim().is_empty() => None,
+                Ok(text) => Some(import::parse_json_line(line, &text)),
+                Err(e) => Some(Err(e.into())),
+            });
+        self.import_unsorted(entries)
+    }
+
+    /// Import the `key_col` and `value_col` columns of a CSV file starting with a header, see
+    /// [`Database::import_unsorted`].
+    ///
+    /// The fields are stored as is, they don't need to be UTF-8. Only available with the `csv`
+    /// feature.
+    #[cfg(feature = "csv")]
+    pub fn import_csv(
+        &mut self,
+        reader: impl io::Read,
+        key_col: &str,
+        value_col: &str,
+    ) -> Result<u64> {
+        let mut reader = csv::Reader::from_reader(reader);
+        let headers = reader.byte_headers()?;
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header == name.as_bytes())
+                .ok_or_else(|| Error::MissingColumn(name.to_string()))
+        };
+        let (key_col, value_col) = (column(key_col)?, column(value_col)?);
+        let entries = reader.into_byte_records().map(|record| {
+            let record = record?;
+            let field = |column: usize| {
+                record.get(column).ok_or_else(|| Error::InvalidImportEntry {
+                    line: record.position().map_or(0, |position| position.line()),
+                    reason: format!("missing field {column}"),
+                })
+            };
+            Ok::<_, Error>((field(key_col)?.to_vec(), field(value_col)?.to_vec()))
+        });
+        self.import_unsorted(entries)
+    }
+
+    /// Import the entries of the default tree of a sled database, see [`Database::import`].
+    ///
+    /// Only available with the `sled` feature. The other trees aren't imported.
+    #[cfg(feature = "sled")]
+    pub fn import_from_sled(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref();
+        // Opening a missing sled database would create it
+        if !path.exists() {
+            return Err(io::Error::from(io::ErrorKind::NotFound).into());
+        }
+        let source = sled::open(path)?;
+        self.import(source.iter())
+    }
+
+    /// Import the entries of the default column family of a RocksDB database opened in read-only
+    /// mode, see [`Database::import`].
+    ///
+    /// Only available with the `rocksdb` feature. The database must use the default bytewise
+    /// comparator, the other column families aren't imported.
+    #[cfg(feature = "rocksdb")]
+    pub fn import_from_rocksdb(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let options = rocksdb::Options::default();
+        let source = rocksdb::DB::open_for_read_only(&options, path, false)?;
+        self.import(source.iterator(rocksdb::IteratorMode::Start))
+    }
+
+    /// Shut the database down, returning the errors dropping it would ignore.
+    ///
+    /// The background scrub is collected if it's done and interrupted otherwise, the background
+    /// flush is waited for, then the memtable is saved and the dirty segment synced so the next
+    /// open doesn't replay it. Call [`Database::flush`] first to write the memtable to a
+    /// segment instead.
+    pub fn close(mut self) -> Result<()> {
+        if let Some(results) = self.background_scrub.take().and_then(|mut s| s.finished()) {
+            let report = self.apply_scrub(results)?;
+            self.last_scrub = Some(report);
+        }
+        self.finish_flush()?;
+        let entries = self.memtable.len();
+        self.save_memtable()?;
+        // A saved memtable torn by a crash fails its checksum, the dirty segment is then replayed
+        self.dirty.sync()?;
+        self.events.log(format_args!(
+            "close: {entries} entries saved from the memtable"
+        ));
+        Ok(())
+    }
+
+    /// Save the memtable next to the dirty segment, it's empty afterward.
+    ///
+    /// The entries written without the dirty segment are written to it first.
+    fn save_memtable(&mut self) -> io::Result<()> {
+        if self.memtable.is_empty() && self.range_tombstones.is_empty() {
+            return Ok(());
+        }
+        for (key, entry) in mem::take(&mut self.unlogged) {
+            self.dirty.rotate_if_full()?;
+            let pos = self.dirty.len();
+            let value = entry.value.as_deref();
+            write_entry(
+                &mut self.dirty,
+                &key,
+                entry.seq,
+                entry.meta,
+                entry.timestamp,
+                value,
+            )?;
+            self.memtable.insert(key, pos);
+        }
+        let saved = memtable::Saved {
+            memtable: mem::take(&mut self.memtable),
+            sequence: self.sequence,
+            live_keys: self.memtable_keys,
+            clock: self.clock,
+            range_tombstones: mem::take(&mut self.range_tombstones),
+        };
+        memtable::save(&self.path, &self.layout, &self.dirty.file_sizes(), &saved)
+    }
+
+    /// Make all the writes durable by syncing the dirty segment, without writing a segment.
+    ///
+    /// The entries written with [`WriteOptions::disable_wal`] stay in memory.
+    pub fn persist(&mut self) -> Result<()> {
+        self.poison.check()?;
+        self.dirty.sync()?;
+        Ok(())
+    }
+
+    /// Write the memtable to a new segment and wait for the segment to be added.
+    ///
+    /// The writes are durable afterward, [`Database::persist`] is enough when that's all
+    /// that's needed.
+    pub fn flush(&mut self) -> Result<()> {
+        self.freeze()?;
+        self.finish_flush()?;
+        if self.scheduler.should_merge(&self.scheduler_state()) {
+            self.merge_segment()?;
+        }
+        Ok(())
+    }
+
+    /// Start writing the memtable to a new segment in the background.
+    ///
+    /// The memtable is frozen and stays readable until the segment is added by
+    /// [`Database::finish_flush`], meanwhile the writes go to a new memtable. A previous
+    /// flush is waited for first.
+    fn freeze(&mut self) -> Result<()> {
+        self.finish_flush()?;
+        if self.scheduler.should_merge(&self.scheduler_state()) {
+            self.merge_segment()?;
+        }
+        let _guard = self.poison.guard()?;
+
+        // 1. Read all the entries ordered by keys
+        let entries = if self.versions == 1 {
+            // The memtable already points to the last version of each entry
+            let indexes: Vec<_> = self
+                .memtable
+                .iter()
+                .map(|(key, index)| (key.clone(), *index))
+                .collect();
+            let mut entries = Vec::with_capacity(indexes.len());
+            for (key, index) in indexes {
+                entries.push(self.read_dirty(&key, index)?);
+            }
+            entries
+        } else {
+            let mut entries = self.dirty_entries()?;
+            entries.sort_unstable_by(|a, b| a.key.cmp(&b.key).then(b.seq.cmp(&a.seq)));
+            entries
+        };
+
+        // 2. The new writes go to a new file of the dirty segment
+        let wal_files = self.dirty.seal()?;
+        let len = self.memtable.len();
+        self.memtable.clear();
+        self.unlogged.clear();
+        self.memtable_bytes = 0;
+        let live_keys = mem::take(&mut self.memtable_keys);
+
+        // 3. Write the segment in the background
+        // The ids are never reused, the file of a compacted segment may still be read by an iterator
+        let id = self.next_id;
+        self.next_id += 1;
+        let job = FlushJob {
+            entries,
+            range_tombstones: mem::take(&mut self.range_tombstones),
+            path: self.layout.segment_path(&self.path, 0, id),
+            layout: self.layout.clone(),
+            level_dir: self.layout.level_dir(&self.path, 0),
+            // When there is no other segment the deleted entries can be dropped
+            bottommost: self.segments.is_empty(),
+            versions: self.versions,
+            schema: self.schema.clone(),
+            filter: self.filter.clone(),
+            prefix_extractor: self.prefix_extractor,
+            encoding: self.encoding,
+            block_sizes: self.block_sizes,
+            pool: self.pool.clone(),
+            database: self.identity,
+            blobs: (self.blob_threshold).map(|threshold| (self.blob_store.clone(), threshold)),
+            value_log: (self.value_log_threshold)
+                .map(|threshold| (self.value_log.clone(), threshold)),
+        };
+        self.frozen = Some(Frozen::new(job, wal_files, id, len, live_keys));
+        Ok(())
+    }
+
+    /// Wait for the flush of the frozen memtable and add its segment to the database.
+    fn finish_flush(&mut self) -> Result<()> {
+        let _guard = self.poison.guard()?;
+        let Some(frozen) = &mut self.frozen else {
+            return Ok(());
+        };
+        let size = match frozen.wait() {
+            Ok((size, duration)) => {
+                if let Some(adaptive) = &mut self.adaptive {
+                    adaptive.record_flush(duration);
+                    self.dirty_thresholds = adaptive.threshold().unwrap_or(self.dirty_thresholds);
+                }
+                size
+            }
+            Err(e) => {
+                self.events.log(format_args!("flush failed: {e}"));
+                return Err(e);
+            }
+        };
+        let frozen = self.frozen.take().unwrap();
+
+        let segment = self.new_segment(frozen.id, frozen.path().clone());
+        self.segments.push_back(segment);
+        self.segment_keys = (self.segment_keys as i64 + frozen.live_keys).max(0) as u64;
+        self.flush_bytes += size;
+        self.write_manifest()?;
+        self.load_counters();
+        self.dirty.remove(&frozen.wal_files)?;
+        if let Some(filter) = &mut self.database_filter {
+            filter.extend(frozen.keys())?;
+        }
+        self.events.log(format_args!(
+            "flush: {} entries written to segment {} ({size} bytes)",
+            frozen.len, frozen.id
+        ));
+        self.hooks
+            .iter()
+            .for_each(|hook| hook.after_flush(frozen.id, size));
+        Ok(())
+    }
+
+    /// Never give the keys of `start..end` to the [`DatabaseBuilder::compaction_filter`], so
+    /// the compactions keep them whatever it would decide, e.g. to exempt them from an
+    /// expiration. Returns `false` if the range was already pinned.
+    ///
+    /// The pinned ranges are written to the `PINS` file and survive the restarts. The keys can
+    /// still be overwritten and deleted.
+    pub fn pin_range(&mut self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<bool> {
+        self.poison.check()?;
+        let range = start.as_ref().to_vec()..end.as_ref().to_vec();
+        if range.start >= range.end || !self.pins.insert(range.clone()) {
+            return Ok(false);
+        }
+        if let Err(e) = self.pins.write(&self.path, &self.layout) {
+            self.pins.remove(&range);
+            return Err(e.into());
+        }
+        Ok(true)
+    }
+
+    /// Pin a single key, see [`Database::pin_range`].
+    pub fn pin(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let (start, end) = key_range(key.as_ref());
+        self.pin_range(start, end)
+    }
+
+    /// Remove a range pinned by [`Database::pin_range`], the ranges overlapping it stay pinned.
+    /// Returns `false` if the range wasn't pinned.
+    pub fn unpin_range(&mut self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<bool> {
+        self.poison.check()?;
+        let range = start.as_ref().to_vec()..end.as_ref().to_vec();
+        if !self.pins.remove(&range) {
+            return Ok(false);
+        }
+        if let Err(e) = self.pins.write(&self.path, &self.layout) {
+            self.pins.insert(range);
+            return Err(e.into());
+        }
+        Ok(true)
+    }
+
+    /// Remove a key pinned by [`Database::pin`].
+    pub fn unpin(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let (start, end) = key_range(key.as_ref());
+        self.unpin_range(start, end)
+    }
+
+    /// The pinned ranges, sorted by their start.
+    pub fn pinned_ranges(&self) -> &[std::ops::Range<Vec<u8>>] {
+        self.pins.ranges()
+    }
+
+    /// Whether a pinned range holds the key.
+    pub fn is_pinned(&self, key: impl AsRef<[u8]>) -> bool {
+        self.pins.contains(key.as_ref())
+    }
+
+    /// Merge the two oldest segments into one.
+    ///
+    /// The segments aren't partitioned by key range, each of them can hold any key, so there
+    /// are no disjoint compactions to run in parallel: one compaction runs at a time.
+    ///
+    /// Nothing is done when there are less than two segments.
+    pub fn merge_segment(&mut self) -> Result<()> {
+        self.poison.check()?;
+        if self.segments.len() < 2 {
+            return Ok(());
+        }
+        let sizes: Vec<_> = self
+            .segments
+            .iter()
+            .take(2)
+            .map(|segment| {
+                let size = std::fs::metadata(&segment.path).map_or(0, |m| m.len());
+                format!("{} ({size} bytes)", segment.id)
+            })
+            .collect();
+
+        match self.merge_oldest_segments() {
+            Ok((id, size)) => {
+                self.compaction_bytes += size;
+                self.events.log(format_args!(
+                    "compaction: segments {} merged into segment {id} ({size} bytes), \
+                     write amplification {:.2}",
+                    sizes.join(", "),
+                    self.stats().write_amplification(),
+                ));
+                self.hooks
+                    .iter()
+                    .for_each(|hook| hook.after_compaction(id, size));
+                self.collect_unreferenced()
+            }
+            Err(e) => {
+                self.events.log(format_args!(
+                    "compaction of segments {} failed: {e}",
+                    sizes.join(", ")
+                ));
+                Err(e)
+            }
+        }
+    }
+
+    /// What the next [`Database::merge_segment`] would merge, without running it, e.g. to
+    /// preview a heavy compaction. `None` when there are less than two segments.
+    ///
+    /// The size of the merged segment is estimated like [`Database::space_amplification`]: the
+    /// first keys of up to 64 blocks of the oldest segment are looked up in the second one, and
+    /// the versions it overwrote or deleted are assumed to be dropped in the same proportion. The
+    /// range tombstones and the compaction filter aren't accounted for.
+    pub fn compaction_plan(&mut self) -> Result<Option<CompactionPlan>> {
+        self.poison.check()?;
+        let (Some(old), Some(new)) = (self.segments.front(), self.segments.get(1)) else {
+            return Ok(None);
+        };
+        let old_bytes = self.files.get(&old.path)?.metadata()?.len();
+        let new_bytes = self.files.get(&new.path)?.metadata()?.len();
+
+        // The older versions are only dropped when a single version is kept
+        let mut kept_bytes = old_bytes;
+        let sample = match self.versions {
+            1 => old.sample_keys(&mut self.files, AMPLIFICATION_SAMPLES)?,
+            _ => Vec::new(),
+        };
+        if !sample.is_empty() {
+            let keys: Vec<&[u8]> = sample.iter().map(Vec::as_slice).collect();
+            let found =
+                new.multi_get(&mut self.files, &keys, self.reads.as_mut(), &mut self.cache)?;
+            let kept = found.iter().filter(|entry| entry.is_none()).count();
+            kept_bytes = old_bytes * kept as u64 / sample.len() as u64;
+        }
+
+        Ok(Some(CompactionPlan {
+            segments: vec![old.id, new.id],
+            input_bytes: old_bytes + new_bytes,
+            estimated_output_bytes: new_bytes + kept_bytes,
+        }))
+    }
+
+    /// Release the space of the data blocks of the segments of at least `min_segment_size` bytes
+    /// whose entries are all deleted by [`Database::delete_range`], without waiting for a
+    /// compaction to rewrite them. Returns the number of bytes released.
+    ///
+    /// The blocks are punched out of the files, whose size doesn't change, and recorded in the
+    /// manifest so the reads skip them. Like a compaction it drops the versions hidden by the
+    /// deletions, [`Database::versions`] doesn't return them anymore. Only the whole pages are
+    /// released, and only on Linux with a file system supporting it.
+    ///
+    /// The segments read by an iterator or exported are skipped. A [`Follower`] or a tool
+    /// reading the files directly can't tell the punched blocks apart from damaged ones.
+    pub fn punch_holes(&mut self, min_segment_size: u64) -> Result<u64> {
+        let _guard = self.poison.guard()?;
+        let mut range_tombstones = Vec::new();
+        for segment in &self.segments {
+            range_tombstones.extend_from_slice(segment.range_tombstones(&mut self.files)?);
+        }
+        if range_tombstones.is_empty() {
+            return Ok(0);
+        }
+
+        let mut punched = Vec::new();
+        for segment in &mut self.segments {
+            let size = self.files.get(&segment.path)?.metadata()?.len();
+            if size < min_segment_size || segment.in_use() {
+                continue;
+            }
+            let runs = segment.dead_runs(&mut self.files, &range_tombstones)?;
+            if !runs.is_empty() {
+                segment.holes.extend(runs.iter().cloned());
+                punched.push((segment.path.clone(), runs));
+            }
+        }
+        if punched.is_empty() {
+            return Ok(0);
+        }
+        // The blocks are skipped before they're punched, a crash can't expose them
+        self.write_manifest()?;
+
+        let mut released = 0;
+        for (path, runs) in &punched {
+            let file = OpenOptions::new().write(true).open(path)?;
+            for run in runs {
+                released += uncached::punch_hole(&file, run)?;
+            }
+        }
+        self.events.log(format_args!(
+            "punch holes: {released} bytes released in {} segments",
+            punched.len()
+        ));
+        Ok(released)
+    }
+
+    /// Rewrite the dirty segment without the overwritten versions of the keys, and returns the
+    /// number of bytes released. No segment is written.
+    ///
+    /// The [`keep_versions`](DatabaseBuilder::keep_versions) most recent versions of each key
+    /// are kept along with the deletions and the range deletions, which hide the entries of the
+    /// segments, and the appended fragments are written as whole values. The entries dropped
+    /// can't be streamed by [`Database::stream_wal_since`] anymore. The files of the frozen
+    /// memtable aren't rewritten.
+    ///
+    /// The previous files are deleted once the new one is durable, a crash in between replays
+    /// both which yields the same entries.
+    pub fn compact_wal(&mut self) -> Result<u64> {
+        let _guard = self.poison.guard()?;
+        let before = self.dirty.len();
+        let records = self.dirty_records()?;
+        let range_tombstones: Vec<_> = (records.iter())
+            .filter_map(|record| match record {
+                Record::DeleteRange(range) => Some(range.clone()),
+                _ => None,
+            })
+            .collect();
+        // The most recent versions are found from the end
+        let mut versions = HashMap::new();
+        let mut records: Vec<_> = (resolve_appends(records).into_iter().rev())
+            .filter(|entry| {
+                let count = versions.entry(entry.key.clone()).or_insert(0);
+                *count += 1;
+                *count <= self.versions
+            })
+            .map(Record::Entry)
+            .chain(range_tombstones.into_iter().map(Record::DeleteRange))
+            .collect();
+        records.sort_unstable_by_key(Record::seq);
+
+        let sealed = self.dirty.seal()?;
+        for record in &records {
+            self.dirty.rotate_if_full()?;
+            let pos = self.dirty.len();
+            match record {
+                Record::Entry(entry) => {
+                    write_entry(
+                        &mut self.dirty,
+                        &entry.key,
+                        entry.seq,
+                        entry.meta,
+                        entry.timestamp,
+                        entry.value.as_deref(),
+                    )?;
+                    // The entries are in the order they were written, the last one is the most recent
+                    match self.memtable.get_mut(&entry.key) {
+                        Some(index) if *index != UNLOGGED => *index = pos,
+                        _ => (),
+                    }
+                }
+                Record::DeleteRange(range) => write_range_tombstone(&mut self.dirty, range)?,
+                Record::Append { .. } => unreachable!("the appends are resolved"),
+            }
+        }
+        self.dirty.sync()?;
+        self.dirty.remove(&sealed)?;
+
+        let released = before.saturating_sub(self.dirty.len());
+        self.events.log(format_args!(
+            "compact wal: {released} bytes released, {} records kept",
+            records.len()
+        ));
+        Ok(released)
+    }
+
+    /// Reclaim the space of the value log, see [`DatabaseBuilder::value_log_threshold`], and
+    /// returns the number of bytes released.
+    ///
+    /// The file appended to is sealed first. Then the segments referencing the files whose
+    /// share of garbage is at least `min_garbage`, between 0 and 1, are rewritten with the
+    /// values of those files, which are appended to the value log again or written in the
+    /// segments without threshold. Finally the files no segment references are deleted, the
+    /// ones still read by an iterator or a backup are deleted by a following collection or
+    /// compaction.
+    pub fn collect_value_log(&mut self, min_garbage: f64) -> Result<u64> {
+        self.finish_flush()?;
+        self.poison.check()?;
+        self.value_log.seal()?;
+        let live = self.live_value_bytes()?;
+        let relocated: HashSet<u32> = (self.value_log.sealed_files()?.into_iter())
+            .filter(|(id, size)| {
+                live.get(id)
+                    .is_some_and(|live| 1.0 - *live as f64 / *size as f64 >= min_garbage)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        let mut rewritten = 0;
+        if !relocated.is_empty() {
+            for position in 0..self.segments.len() {
+                let value_refs = self.segments[position].value_refs(&mut self.files)?;
+                if value_refs.iter().any(|(id, _)| relocated.contains(id)) {
+                    self.rewrite_segment(position, Some(&relocated))?;
+                    rewritten += 1;
+                }
+            }
+        }
+        let (count, bytes) = self.delete_value_log_files()?;
+        self.events.log(format_args!(
+            "value log: {} files relocated by rewriting {rewritten} segments, {count} files \
+             deleted ({bytes} bytes)",
+            relocated.len()
+        ));
+        Ok(bytes)
+    }
+
+    /// A segment of the database, it reads its values stored in blobs and in the value log.
+    fn new_segment(&self, id: usize, path: PathBuf) -> Segment {
+        let mut segment = Segment::new(id, path, self.pool.clone());
+        segment.blob_store = Some(self.blob_store.clone());
+        segment.value_log = Some(self.value_log.clone());
+        segment
+    }
+
+    /// Delete the file of a segment replaced by a compaction, the blobs and the files of the
+    /// value log it references are kept while its file is still read.
+    fn retire(&mut self, segment: Segment) -> Result<()> {
+        let blob_refs = segment.blob_refs(&mut self.files)?.to_vec();
+        let value_refs = segment.value_refs(&mut self.files)?.to_vec();
+        let watch = segment.watch();
+        // The handles still point to the replaced files
+        self.files.forget(&segment.path);
+        segment.retire()?;
+        if watch.in_use() && !(blob_refs.is_empty() && value_refs.is_empty()) {
+            self.retired_refs.push(RetiredRefs {
+                watch,
+                blob_refs,
+                value_refs,
+            });
+        }
+        Ok(())
+    }
+
+    /// Delete the blobs and the files of the value log no segment references anymore.
+    fn collect_unreferenced(&mut self) -> Result<()> {
+        self.collect_blobs()?;
+        let (count, bytes) = self.delete_value_log_files()?;
+        if count > 0 {
+            self.events.log(format_args!(
+                "compaction: {count} unreferenced value log files deleted ({bytes} bytes)"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The number of bytes of each file of the value log referenced by the segments, including
+    /// the retired ones still read.
+    fn live_value_bytes(&mut self) -> Result<HashMap<u32, u64>> {
+        self.retired_refs.retain(|retired| retired.watch.in_use());
+        let mut live = HashMap::new();
+        let retired = self
+            .retired_refs
+            .iter()
+            .flat_map(|retired| &retired.value_refs);
+        for (id, bytes) in retired.copied() {
+            *live.entry(id).or_default() += bytes;
+        }
+        for segment in &self.segments {
+            for (id, bytes) in segment.value_refs(&mut self.files)? {
+                *live.entry(*id).or_default() += bytes;
+            }
+        }
+        Ok(live)
+    }
+
+    /// Delete the sealed files of the value log no segment references anymore, returns how
+    /// many were deleted and their size.
+    fn delete_value_log_files(&mut self) -> Result<(usize, u64)> {
+        // The segment being flushed references values it doesn't list yet
+        if self.frozen.is_some() {
+            return Ok((0, 0));
+        }
+        let live = self.live_value_bytes()?;
+        let (mut count, mut bytes) = (0, 0);
+        for (id, size) in self.value_log.sealed_files()? {
+            if !live.contains_key(&id) {
+                self.value_log.delete(id)?;
+                count += 1;
+                bytes += size;
+            }
+        }
+        Ok((count, bytes))
+    }
+
+    /// Delete the blobs no segment references anymore, see [`DatabaseBuilder::blob_threshold`].
+    fn collect_blobs(&mut self) -> Result<()> {
+        // The segment being flushed references blobs it doesn't list yet
+        if self.frozen.is_some() || self.blob_store.is_empty()? {
+            return Ok(());
+        }
+        self.retired_refs.retain(|retired| retired.watch.in_use());
+        let mut referenced: HashSet<u128> = (self.retired_refs.iter())
+            .flat_map(|retired| retired.blob_refs.iter().copied())
+            .collect();
+        for segment in &self.segments {
+            referenced.extend(segment.blob_refs(&mut self.files)?);
+        }
+        let (count, bytes) = self.blob_store.collect(&referenced)?;
+        if count > 0 {
+            self.events.log(format_args!(
+                "compaction: {count} unreferenced blobs deleted ({bytes} bytes)"
+            ));
+        }
+        Ok(())
+    }
+
+    fn segment_options(&self) -> SegmentOptions<'_> {
+        SegmentOptions {
+            versions: self.versions,
+            schema: self.schema.as_deref(),
+            filter: self.filter.as_deref(),
+            compaction_filter: None,
+            pins: None,
+            prefix_extractor: self.prefix_extractor,
+            encoding: self.encoding,
+            block_sizes: self.block_sizes,
+            pool: &self.pool,
+            database: self.identity,
+            blobs: (self.blob_threshold).map(|threshold| (&self.blob_store, threshold)),
+            value_log: (self.value_log_threshold).map(|threshold| (&self.value_log, threshold)),
+            stored_values: false,
+            relocate: None,
+        }
+    }
+
+    /// Returns the id and size of the new segment.
+    fn merge_oldest_segments(&mut self) -> Result<(usize, u64)> {
+        let _guard = self.poison.guard()?;
+        // merge the first two segments
+        // A new id so the files of the merged segments are never overwritten while exported,
+        // recorded first so the recovery can tell the merged segment apart from a flushed one
+        let id = self.next_id;
+        self.next_id += 1;
+        self.write_manifest_with(Some(id))?;
+        let (old, new) = (&self.segments[0], &self.segments[1]);
+        let mut range_tombstones = new.range_tombstones(&mut self.files)?.to_vec();
+        range_tombstones.extend_from_slice(old.range_tombstones(&mut self.files)?);
+        let level_dir = self.layout.level_dir(&self.path, 1);
+        let mut new_segment = self.layout.temp_file(&level_dir)?;
+        // The merged segment is at most as large as both of them
+        let expected = self.files.get(&old.path)?.metadata()?.len()
+            + self.files.get(&new.path)?.metadata()?.len();
+        uncached::preallocate(new_segment.as_file(), expected)?;
+        let options = SegmentOptions {
+            compaction_filter: self.compaction_filter.as_deref(),
+            pins: Some(&self.pins),
+            ..self.segment_options()
+        };
+        let Written { live_keys, removed } = Segment::merge(
+            &mut new_segment,
+            new,
+            old,
+            &range_tombstones,
+            &options,
+            self.uncached_compaction,
+            self.read_ahead,
+        )?;
+        // The footer is read from the end of the file
+        uncached::truncate_to_written(new_segment.as_file_mut())?;
+        let path = self.layout.segment_path(&self.path, 1, id);
+        // The compacted segments can only be deleted once the new one is durable
+        let file = persist_segment(new_segment, &path)?;
+        if self.uncached_compaction {
+            // Only the clean pages can be evicted
+            uncached::drop_cache(&file, 0, 0);
+        }
+
+        // The compacted segments go to the level 1
+        let old = self.segments.pop_front().unwrap();
+        let new = self.segments.pop_front().unwrap();
+        let size = std::fs::metadata(&path)?.len();
+        let segment = self.new_segment(id, path.clone());
+        self.segments.push_front(segment);
+        // The keys whose value the compaction filter removed were live, unless a more recent
+        // segment or the memtables wrote them again
+        for key in removed {
+            if self.versions(&key)?.is_empty() {
+                self.segment_keys = self.segment_keys.saturating_sub(1);
+            }
+        }
+        // All the segments were merged, their live keys were just counted
+        if self.segments.len() == 1 && live_keys != self.segment_keys {
+            self.events.log(format_args!(
+                "compaction: live keys count reconciled from {} to {live_keys}",
+                self.segment_keys
+            ));
+            self.segment_keys = live_keys;
+        }
+        self.write_manifest()?;
+        self.load_counters();
+        for segment in [old, new] {
+            self.retire(segment)?;
+        }
+
+        if let Some(filter) = &mut self.database_filter {
+            filter.rebuild(&self.segments, self.read_ahead)?;
+        }
+        Ok((id, size))
+    }
+
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.multi_get(&[key])?.pop().flatten())
+    }
+
+    /// The value of the key along with its metadata, see [`Database::add_with_meta`].
+    pub fn get_with_meta(&mut self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u8)>> {
+        let value = self.lookup(&[key])?.pop().flatten();
+        Ok(value.map(|(value, meta, _)| (value, meta)))
+    }
+
+    /// The value of the key along with the milliseconds since the Unix epoch when it was
+    /// written, 0 if it was written without timestamp, see [`DatabaseBuilder::timestamps`].
+    pub fn get_with_timestamp(&mut self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>> {
+        let value = self.lookup(&[key])?.pop().flatten();
+        Ok(value.map(|(value, _, timestamp)| (value, timestamp)))
+    }
+
+    /// The value of the key along with its CRC-32 (IEEE), for the applications keeping the
+    /// values in a cache of their own to check them later without reading them again.
+    ///
+    /// The value is compared with the checksum stored along with it before it's returned: the
+    /// one following it in the dirty segment, of the block of the segment holding it, of its
+    /// record in the value log or its hash in the blob store. A mismatch is returned as an
+    /// error of kind [`ErrorKind::Corruption`]. The values written with
+    /// [`WriteOptions::disable_wal`] are only kept in memory and the ones written by the older
+    /// versions, before the checksums existed, are returned unchecked.
+    pub fn get_with_checksum(&mut self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u32)>> {
+        let value = self.get(key)?;
+        Ok(value.map(|value| {
+            let checksum = crc32fast::hash(&value);
+            (value, checksum)
+        }))
+    }
+
+    /// The value of the key along with where it was found, `None` if it's in none of the
+    /// segments. The value is `None` too when the key was deleted.
+    ///
+    /// Meant to debug unexpected values and to tune the filters and the block cache, the lookup
+    /// skips the database filter and isn't counted in the [`Stats`].
+    pub fn get_traced(
+        &mut self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<(Option<Vec<u8>>, Option<Provenance>)> {
+        self.poison.check()?;
+        let key = key.as_ref();
+        let mut range_tombstones = self.memtable_range_tombstones();
+        let mut found = match self.memtable.get(key) {
+            Some(index) => {
+                let entry = self.read_dirty(key, *index)?;
+                Some((entry.seq, entry.value, Provenance::Memtable))
+            }
+            None => self
+                .frozen
+                .as_ref()
+                .and_then(|frozen| frozen.get(key))
+                .map(|entry| (entry.seq, entry.value.clone(), Provenance::Memtable)),
+        };
+        let mut blocks_read = 0;
+        for segment in self.segments.iter().rev() {
+            if found.is_some() {
+                break;
+            }
+            range_tombstones.extend_from_slice(segment.range_tombstones(&mut self.files)?);
+            if !segment.in_fence(&mut self.files, key)? {
+                continue;
+            }
+            let filter = match &self.filter {
+                Some(policy) => segment.filter(&mut self.files, policy.as_ref())?,
+                None => None,
+            };
+            if filter.is_some_and(|filter| !filter.contains(key)) {
+                continue;
+            }
+            let (entry, trace) =
+                segment.traced_get(&mut self.files, key, self.reads.as_mut(), &mut self.cache)?;
+            blocks_read += trace.blocks_read;
+            found = entry.map(|entry| {
+                let provenance = Provenance::Segment {
+                    id: segment.id,
+                    offset: trace.block.unwrap_or_default(),
+                    bloom_checked: filter.is_some(),
+                    blocks_read,
+                };
+                (entry.seq, entry.value, provenance)
+            });
+        }
+
+        let Some((seq, value, provenance)) = found else {
+            return Ok((None, None));
+        };
+        // The entry was found but a more recent range deletes it
+        let value = value.filter(|_| !range_deleted(&range_tombstones, key, seq));
+        let value = match (value, &self.schema) {
+            (Some(value), Some(schema)) => Some(schema.migrate(value)?),
+            (value, _) => value,
+        };
+        Ok((value, Some(provenance)))
+    }
+
+    /// Get the values of several keys, in the same order.
+    ///
+    /// The reads of the keys missing from the dirty segment are issued together for each segment,
+    /// with the `io-uring` feature on Linux they're submitted at once to the kernel.
+    pub fn multi_get<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let values = self.lookup(keys)?;
+        Ok(values
+            .into_iter()
+            .map(|value| value.map(|(value, _, _)| value))
+            .collect())
+    }
+
+    /// The values of the keys along with their metadata.
+    fn lookup<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Vec<Option<MetaValue>>> {
+        self.poison.check()?;
+        // Without any live key the memtables and the segments don't need to be looked into. The
+        // number of live keys is only estimated without `DatabaseBuilder::exact_len`, then
+        // there must be nothing at all
+        let empty = match self.exact_len {
+            true => self.is_empty(),
+            false => self.memtable.is_empty() && self.frozen.is_none() && self.segments.is_empty(),
+        };
+        if empty {
+            return Ok(vec![None; keys.len()]);
+        }
+        let range_tombstones = self.memtable_range_tombstones();
+        let mut values = vec![None; keys.len()];
+        let mut missing = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let key = key.as_ref();
+            match self.memtable.get(key) {
+                Some(index) => {
+                    let entry = self.read_dirty(key, *index)?;
+                    if !range_deleted(&range_tombstones, key, entry.seq) {
+                        values[i] = entry
+                            .value
+                            .map(|value| (value, entry.meta, entry.timestamp));
+                    }
+                }
+                None => match self.frozen.as_ref().and_then(|frozen| frozen.get(key)) {
+                    Some(entry) if range_deleted(&range_tombstones, key, entry.seq) => (),
+                    Some(entry) => {
+                        values[i] = entry
+                            .value
+                            .clone()
+                            .map(|value| (value, entry.meta, entry.timestamp))
+                    }
+                    None => missing.push(i),
+                },
+            }
+        }
+
+        let missing_keys: Vec<_> = missing.iter().map(|i| keys[*i].as_ref()).collect();
+        for (i, value) in missing
+            .into_iter()
+            .zip(self.get_from_segments(&missing_keys, &range_tombstones)?)
+        {
+            values[i] = value;
+        }
+
+        if let Some(schema) = &self.schema {
+            for (value, _, _) in values.iter_mut().filter_map(Option::as_mut) {
+                *value = schema.migrate(mem::take(value))?;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Returns the entry stored at `index` in the dirty segment.
+    ///
+    /// The fragments written by [`Database::append`] are concatenated to the previous values
+    /// they're chained to.
+    fn read_dirty(&mut self, key: &[u8], index: u64) -> io::Result<Entry> {
+        if index == UNLOGGED {
+            return Ok(self.unlogged[key].clone());
+        }
+        let (seq, meta, timestamp, mut size, mut checksummed) = self.seek_dirty(key, index)?;
+        let mut fragments = Vec::new();
+        let mut value = loop {
+            match size {
+                TOMBSTONE => break None,
+                APPEND => {
+                    let prev = read_u64(&mut self.dirty)?;
+                    let _size = read_u32(&mut self.dirty)?;
+                    let fragment = read_entry_to_vec(&mut self.dirty)?;
+                    if checksummed {
+                        verify_value(&mut self.dirty, &fragment)?;
+                    }
+                    fragments.push(fragment);
+                    (_, _, _, size, checksummed) = self.seek_dirty(key, prev)?;
+                }
+                size => {
+                    let mut buf = Vec::new();
+                    read_bytes(&mut self.dirty, size as usize, &mut buf)?;
+                    if checksummed {
+                        verify_value(&mut self.dirty, &buf)?;
+                    }
+                    break Some(buf);
+                }
+            }
+        };
+        if let Some(value) = &mut value {
+            for fragment in fragments.iter().rev() {
+                value.extend_from_slice(fragment);
+            }
+        }
+        Ok(Entry {
+            key: key.to_vec(),
+            seq,
+            meta,
+            timestamp,
+            value,
+        })
+    }
+
+    /// Move past the size of the value of the entry stored at `index` in the dirty segment and
+    /// returns its sequence number, metadata, timestamp, the size of its value and whether the
+    /// value is followed by its checksum.
+    fn seek_dirty(&mut self, key: &[u8], index: u64) -> io::Result<(u64, u8, u64, u32, bool)> {
+        self.dirty.seek(SeekFrom::Start(
+            // the index + skip the key
+            index + mem::size_of::<u32>() as u64 + key.len() as u64,
+        ))?;
+        let (seq, meta, deleted, checksummed) = read_seq_and_meta(&mut self.dirty)?;
+        let (timestamp, size) = read_value_size(&mut self.dirty, deleted)?;
+        Ok((seq, meta, timestamp, size, checksummed))
+    }
+
+    /// Read all the entries of the dirty segment, including the outdated versions, in the order they were written.
+    fn dirty_entries(&mut self) -> io::Result<Vec<Entry>> {
+        let mut entries = resolve_appends(self.dirty_records()?);
+        if !self.unlogged.is_empty() {
+            entries.extend(self.unlogged.values().cloned());
+            entries.sort_by_key(|entry| entry.seq);
+        }
+        Ok(entries)
+    }
+
+    /// Read all the records of the dirty segment in the order they were written.
+    fn dirty_records(&mut self) -> io::Result<Vec<Record>> {
+        self.dirty.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&mut self.dirty);
+        let mut records = Vec::new();
+        while let Some(record) = read_wal_record(&mut reader)? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Write to `writer` the entries of the dirty segment written after the sequence number
+    /// `since`, returns the sequence number of the last one written.
+    ///
+    /// The entries and the range deletions are written in the order they were written, in the
+    /// format of the dirty segment, and can be applied to another database with
+    /// [`Database::ingest_wal`], e.g. by a process tailing the writes through a pipe. The values
+    /// are upgraded to the current version of the schema. Only the entries that weren't flushed
+    /// to a clean segment yet are available, [`Error::WalTruncated`] is returned when some of
+    /// the entries following `since` are gone.
+    pub fn stream_wal_since(&mut self, since: u64, mut writer: impl Write) -> Result<u64> {
+        self.poison.check()?;
+        let mut records = Vec::new();
+        // The files of the frozen memtable are only deleted once its segment is added
+        if let Some(frozen) = &self.frozen {
+            for number in &frozen.wal_files {
+                let file = File::open(self.layout.wal_path(&self.path, *number))?;
+                let mut reader = BufReader::new(file);
+                while let Some(record) = read_wal_record(&mut reader)? {
+                    records.push(record);
+                }
+            }
+        }
+        records.extend(self.dirty_records()?);
+
+        let oldest = records.first().map_or(self.sequence + 1, Record::seq);
+        if since + 1 < oldest {
+            return Err(Error::WalTruncated(since));
+        }
+        let mut last = since;
+        for record in records.into_iter().filter(|record| record.seq() > since) {
+            last = record.seq();
+            let entry = match record {
+                Record::Entry(entry) => entry,
+                // The fragments are appended as is, they don't hold a tag
+                Record::Append { entry, prev, size } => {
+                    write_append(&mut writer, &entry, prev, size)?;
+                    continue;
+                }
+                Record::DeleteRange(range) => {
+                    write_range_tombstone(&mut writer, &range)?;
+                    continue;
+                }
+            };
+            let value = match (entry.value, &self.schema) {
+                (Some(value), Some(schema)) => Some(schema.migrate(value)?),
+                (value, _) => value,
+            };
+            write_entry(
+                &mut writer,
+                &entry.key,
+                entry.seq,
+                entry.meta,
+                entry.timestamp,
+                value.as_deref(),
+            )?;
+        }
+        writer.flush()?;
+        Ok(last)
+    }
+
+    /// Apply the entries written by [`Database::stream_wal_since`] until the end of `reader`,
+    /// returns the number of entries applied.
+    ///
+    /// Each entry is a new write of this database, with its own sequence numbers.
+    pub fn ingest_wal(&mut self, reader: impl Read) -> Result<u64> {
+        let mut reader = BufReader::new(reader);
+        let mut count = 0;
+        while let Some(record) = read_wal_record(&mut reader)? {
+            match record {
+                Record::Entry(entry) => {
+                    self.write(&entry.key, entry.value.as_deref(), entry.meta)?
+                }
+                Record::Append { entry, .. } => {
+                    self.append(&entry.key, entry.value.unwrap_or_default())?
+                }
+                Record::DeleteRange(range) => self.delete_range(range.start, range.end)?,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Return the value the key had once the write with the sequence number `seq` was done.
+    ///
+    /// Only the versions retained by the database can be returned, see [`DatabaseBuilder::keep_versions`].
+    pub fn get_at(&mut self, key: impl AsRef<[u8]>, seq: u64) -> Result<Option<Vec<u8>>> {
+        let versions = self.versions(key)?;
+        Ok(versions
+            .into_iter()
+            .find(|(version, _)| *version <= seq)
+            .and_then(|(_, value)| value))
+    }
+
+    /// All the versions of the key still stored in the database with their sequence number,
+    /// from the most recent to the oldest one. The deletions are returned as `None`.
+    pub fn versions(&mut self, key: impl AsRef<[u8]>) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        self.poison.check()?;
+        let key = key.as_ref();
+        let mut versions = Vec::new();
+
+        if self.memtable.contains_key(key) {
+            let entries = self.dirty_entries()?;
+            let dirty = entries.into_iter().filter(|entry| entry.key == key).rev();
+            versions.extend(dirty.map(|entry| (entry.seq, entry.value)));
+        }
+        if let Some(frozen) = &self.frozen {
+            let frozen = frozen.versions(key);
+            versions.extend(frozen.map(|entry| (entry.seq, entry.value.clone())));
+        }
+        // We want to go from the most recent segment to the most outdated one
+        for segment in self.segments.iter().rev() {
+            versions.extend(segment.versions(&mut self.files, key, usize::MAX)?);
+        }
+        let range_tombstones = self.collect_range_tombstones()?;
+        let deleted = range_tombstones
+            .iter()
+            .filter(|range| range.start.as_slice() <= key && key < range.end.as_slice());
+        if deleted.clone().next().is_some() {
+            versions.extend(deleted.map(|range| (range.seq, None)));
+            versions.sort_by_key(|(seq, _)| Reverse(*seq));
+        }
+
+        if let Some(schema) = &self.schema {
+            for value in versions.iter_mut().filter_map(|(_, value)| value.as_mut()) {
+                *value = schema.migrate(mem::take(value))?;
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Iterate over all the entries whose key is contained in `range`, in order.
+    pub fn range<K: AsRef<[u8]>>(&mut self, range: impl RangeBounds<K>) -> Result<Range> {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        self.range_with(start, end, None, &[], false)
+    }
+
+    /// Iterate over the entries whose key is contained in `range` and accepted by `filter`, in
+    /// order.
+    ///
+    /// The keys are filtered while the memtables and the segments are scanned, the values of
+    /// the rejected entries are never read nor allocated. `filter` must only depend on the key,
+    /// it's called on all the versions of the keys of the range.
+    pub fn range_filtered<K, F>(&mut self, range: impl RangeBounds<K>, filter: F) -> Result<Range>
+    where
+        K: AsRef<[u8]>,
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        // The keys past the range are kept, the first one tells the iterator the range is over
+        // instead of letting the segments scan their keys until their end
+        let past = end.clone();
+        let filter: KeyFilter = Arc::new(move |key: &[u8]| {
+            let range = (Bound::Unbounded, past.as_ref().map(Vec::as_slice));
+            !RangeBounds::<[u8]>::contains(&range, key) || filter(key)
+        });
+        self.range_with(start, end, Some(filter), &[], false)
+    }
+
+    /// Iterate over the keys contained in `range`, in order.
+    ///
+    /// The values of the segments are skipped without being copied, and the schema doesn't
+    /// upgrade them, e.g. to build an index or audit the keys of a large range.
+    pub fn keys<K: AsRef<[u8]>>(&mut self, range: impl RangeBounds<K>) -> Result<Keys> {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        self.range_with(start, end, None, &[], true).map(Keys::new)
+    }
+
+    /// The number of keys with a value contained in `range`.
+    ///
+    /// The count is exact: the keys of the range are merged across the memtables and the
+    /// segments like [`Database::keys`] does, the deleted keys and the keys covered by a range
+    /// deletion aren't counted. The values aren't read, but all the keys of the range are, see
+    /// [`Database::approximate_count_range`] to only read the indexes.
+    pub fn count_range<K: AsRef<[u8]>>(&mut self, range: impl RangeBounds<K>) -> Result<u64> {
+        let mut count = 0;
+        for key in self.keys(range)? {
+            key?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The segments listed in `skipped` hold no key of the range, they aren't iterated but
+    /// their range tombstones still apply. With `keys_only` the values are returned empty.
+    fn range_with(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        filter: Option<KeyFilter>,
+        skipped: &[usize],
+        keys_only: bool,
+    ) -> Result<Range> {
+        self.poison.check()?;
+        if key::is_empty_range(
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        ) {
+            return Range::new(Vec::new(), end, None, Vec::new());
+        }
+        let accepts = |key: &[u8]| filter.as_ref().is_none_or(|filter| filter(key));
+
+        // The memtable is bounded by the dirty threshold, we can load its part of the range right away
+        let indexes: Vec<_> = self
+            .memtable
+            .range::<[u8], _>((
+                start.as_ref().map(Vec::as_slice),
+                end.as_ref().map(Vec::as_slice),
+            ))
+            .filter(|(key, _)| accepts(key))
+            .map(|(key, index)| (key.clone(), *index))
+            .collect();
+        let mut entries = Vec::with_capacity(indexes.len());
+        for (key, index) in indexes {
+            entries.push(self.read_dirty(&key, index)?);
+        }
+
+        let mut sources = vec![Source::Memtable(entries.into_iter())];
+        if let Some(frozen) = &self.frozen {
+            let mut entries = frozen.range(
+                start.as_ref().map(Vec::as_slice),
+                end.as_ref().map(Vec::as_slice),
+            );
+            entries.retain(|entry| accepts(&entry.key));
+            sources.push(Source::Memtable(entries.into_iter()));
+        }
+        // We want to go from the most recent segment to the most outdated one
+        for segment in self.segments.iter().rev() {
+            if skipped.contains(&segment.id) {
+                continue;
+            }
+            let mut iter = segment.iter(start.clone(), self.read_ahead)?;
+            if let Some(filter) = &filter {
+                iter.filter_keys(filter.clone());
+            }
+            if keys_only {
+                iter.keys_only();
+            }
+            sources.push(Source::Segment(Box::new(iter)));
+        }
+        let range_tombstones = self.collect_range_tombstones()?;
+
+        // The empty values can't be upgraded
+        let schema = self.schema.clone().filter(|_| !keys_only);
+        Range::new(sources, end, schema, range_tombstones)
+    }
+
+    /// A cursor to move through the entries in both directions, e.g. to paginate or to find the
+    /// neighbors of a key.
+    ///
+    /// Moving forward reads the entries like [`Database::range`], while each move backward looks
+    /// up the previous key in the memtables and in the last blocks of every segment.
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(self)
+    }
+
+    /// The entry with the largest key contained in `..end`.
+    fn last_entry(&mut self, end: Bound<&[u8]>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.poison.check()?;
+        let mut end = end.map(<[u8]>::to_vec);
+        loop {
+            let bounds = (Bound::Unbounded, end.as_ref().map(Vec::as_slice));
+            let mut last = self
+                .memtable
+                .range::<[u8], _>(bounds)
+                .next_back()
+                .map(|(key, _)| key.clone());
+            if let Some(frozen) = &self.frozen {
+                last = last.max(frozen.last_key(bounds.1).map(<[u8]>::to_vec));
+            }
+            for segment in &self.segments {
+                last = last.max(segment.last_key(&mut self.files, bounds.1)?);
+            }
+
+            let Some(key) = last else {
+                return Ok(None);
+            };
+            // The most recent version of the key may be a deletion
+            match self.get(&key)? {
+                Some(value) => return Ok(Some((key, value))),
+                None => end = Bound::Excluded(key),
+            }
+        }
+    }
+
+    /// Iterate over the entries whose key is contained in `range` by chunks of `chunk_size` entries.
+    ///
+    /// The entries are read on a worker thread that prefetches the next chunk while the current
+    /// one is processed, which speeds up the scans of large ranges.
+    pub fn scan_chunks<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        chunk_size: usize,
+    ) -> Result<Chunks> {
+        Ok(Chunks::new(self.range(range)?, chunk_size))
+    }
+
+    /// A checksum of the keys and values of the database, to check that two databases hold the
+    /// same entries whatever the history of their writes and their segments.
+    ///
+    /// All the entries are read. The sequence numbers and metadata aren't part of it, and the
+    /// values are hashed once upgraded by the schema.
+    pub fn content_hash(&mut self) -> Result<u32> {
+        let mut hasher = crc32fast::Hasher::new();
+        for entry in self.range::<&[u8]>(..)? {
+            let (key, value) = entry?;
+            // The sizes keep the boundaries between the keys and the values
+            hasher.update(&(key.len() as u32).to_be_bytes());
+            hasher.update(&key);
+            hasher.update(&(value.len() as u32).to_be_bytes());
+            hasher.update(&value);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Compare the entries of the two databases, in the order of the keys.
+    ///
+    /// Both databases are iterated side by side, neither is loaded in memory. The values are
+    /// compared once upgraded by the schema of their database.
+    pub fn diff(&mut self, other: &mut Database) -> Result<Diff> {
+        Ok(Diff::new(
+            self.range::<&[u8]>(..)?,
+            other.range::<&[u8]>(..)?,
+        ))
+    }
+
+    /// Iterate over all the entries whose key starts with `prefix`, in order.
+    ///
+    /// Combined with a [`Key`] it returns all the entries sharing their first parts. The
+    /// segments whose filter doesn't contain the prefix are skipped, see
+    /// [`DatabaseBuilder::prefix_extractor`].
+    pub fn prefix(&mut self, prefix: impl AsRef<[u8]>) -> Result<Range> {
+        let prefix = prefix.as_ref();
+        let skipped = self.segments_without_prefix(prefix)?;
+        let (start, end) = key::prefix_range(prefix);
+        self.range_with(start, end, None, &skipped, false)
+    }
+
+    /// Estimate the entries of the clean segments whose key starts with `prefix`, e.g. to report
+    /// the usage of each tenant of a database without scanning their keys.
+    ///
+    /// The segments whose fence doesn't overlap the prefix are skipped. In the others only the
+    /// index is read: each segment is assumed to store its entries evenly across its data blocks,
+    /// its [`SegmentCounters`] are shared out by the size of the blocks that may hold the
+    /// prefix. The estimation is thus rougher when the prefix holds less than a few blocks. The
+    /// memtables and the segments written before the counters were recorded aren't accounted for.
+    pub fn prefix_stats(&mut self, prefix: impl AsRef<[u8]>) -> Result<PrefixStats> {
+        self.poison.check()?;
+        let prefix = prefix.as_ref();
+        let (start, end) = key::prefix_range(prefix);
+        let bounds = (
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        );
+        let mut stats = PrefixStats::default();
+        for segment in &self.segments {
+            let overlaps = match segment.fence(&mut self.files)? {
+                Some((first, last)) => {
+                    let before_end = match &end {
+                        Bound::Excluded(end) => first < end,
+                        _ => true,
+                    };
+                    last.as_slice() >= prefix && before_end
+                }
+                None => false,
+            };
+            if !overlaps {
+                continue;
+            }
+            let (in_range, total) = segment.block_bytes(&mut self.files, bounds)?;
+            stats.disk_bytes += in_range;
+            let Some(counters) = segment.counters(&mut self.files)? else {
+                continue;
+            };
+            let share =
+                |count: u64| (count as u128 * in_range as u128 / total.max(1) as u128) as u64;
+            stats.entries += share(counters.entries);
+            stats.key_bytes += share(counters.key_bytes);
+            stats.value_bytes += share(counters.value_bytes);
+        }
+        Ok(stats)
+    }
+
+    /// Estimate the number of keys with a value contained in `range`, without reading the keys
+    /// of the segments.
+    ///
+    /// Like [`Database::prefix_stats`], only the indexes of the segments overlapping the range
+    /// are read and their [`SegmentCounters`] are shared out by the size of the data blocks that
+    /// may hold the range, their tombstones excluded. The entries of the memtables in the range
+    /// are added. The versions hidden by more recent ones are counted in each segment holding
+    /// them, and the deletions of the memtables and the range deletions aren't subtracted, see
+    /// [`Database::count_range`] for an exact count.
+    pub fn approximate_count_range<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<u64> {
+        self.poison.check()?;
+        let bounds = (
+            range.start_bound().map(|key| key.as_ref()),
+            range.end_bound().map(|key| key.as_ref()),
+        );
+        if key::is_empty_range(bounds.0, bounds.1) {
+            return Ok(0);
+        }
+        let mut count = self.memtable.range::<[u8], _>(bounds).count() as u64;
+        if let Some(frozen) = &self.frozen {
+            count += frozen.range(bounds.0, bounds.1).len() as u64;
+        }
+        for segment in &self.segments {
+            let overlaps = match segment.fence(&mut self.files)? {
+                Some((first, last)) => {
+                    let after_start = match bounds.0 {
+                        Bound::Included(start) => last.as_slice() >= start,
+                        Bound::Excluded(start) => last.as_slice() > start,
+                        Bound::Unbounded => true,
+                    };
+                    let before_end = match bounds.1 {
+                        Bound::Included(end) => first.as_slice() <= end,
+                        Bound::Excluded(end) => first.as_slice() < end,
+                        Bound::Unbounded => true,
+                    };
+                    after_start && before_end
+                }
+                None => false,
+            };
+            if !overlaps {
+                continue;
+            }
+            let Some(counters) = segment.counters(&mut self.files)? else {
+                continue;
+            };
+            let (in_range, total) = segment.block_bytes(&mut self.files, bounds)?;
+            let live = counters.entries.saturating_sub(counters.tombstones);
+            count += (live as u128 * in_range as u128 / total.max(1) as u128) as u64;
+        }
+        Ok(count)
+    }
+
+    /// Whether a key starting with `prefix` may exist, `false` only if there is none.
+    ///
+    /// The memtables are looked into, then the filters of the segments holding the prefixes of
+    /// the [`DatabaseBuilder::prefix_extractor`]. The other segments, and all of them when the
+    /// extractor gives no prefix for `prefix`, are assumed to hold such a key. The deleted keys
+    /// may still be counted.
+    pub fn may_contain_prefix(&mut self, prefix: impl AsRef<[u8]>) -> Result<bool> {
+        self.poison.check()?;
+        let prefix = prefix.as_ref();
+        let (start, end) = key::prefix_range(prefix);
+        let bounds = (
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        );
+        if self.memtable.range::<[u8], _>(bounds).next().is_some() {
+            return Ok(true);
+        }
+        if let Some(frozen) = &self.frozen {
+            if !frozen.range(bounds.0, bounds.1).is_empty() {
+                return Ok(true);
+            }
+        }
+        let skipped = self.segments_without_prefix(prefix)?;
+        Ok(skipped.len() < self.segments.len())
+    }
+
+    /// The ids of the segments whose filter rules out the keys starting with `prefix`.
+    fn segments_without_prefix(&mut self, prefix: &[u8]) -> Result<Vec<usize>> {
+        let (Some(policy), Some(extractor)) = (&self.filter, &self.prefix_extractor) else {
+            return Ok(Vec::new());
+        };
+        // All the keys starting with `prefix` share its own prefix
+        let Some(extracted) = extractor.extract(prefix) else {
+            return Ok(Vec::new());
+        };
+        let mut skipped = Vec::new();
+        for segment in &self.segments {
+            let filter = segment.prefix_filter(&mut self.files, policy.as_ref(), extractor)?;
+            if filter.is_some_and(|filter| !filter.contains(extracted)) {
+                skipped.push(segment.id);
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Write a UTF-8 entry, see [`Database::get_str`].
+    pub fn put_str(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Result<()> {
+        self.add(key.as_ref(), value.as_ref())
+    }
+
+    /// The value of the key as a string, an [`Error::InvalidUtf8`] is returned if it isn't valid
+    /// UTF-8.
+    pub fn get_str(&mut self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = key.as_ref();
+        match self.get(key)? {
+            Some(value) => Ok(Some(iter::utf8_entry(key.into(), value)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    /// The value of the key as a string, the invalid UTF-8 sequences are replaced by `�`.
+    pub fn get_str_lossy(&mut self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let value = self.get(key.as_ref())?;
+        Ok(value.map(|value| String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    /// Iterate over the entries whose key is contained in `range` as strings, in order.
+    ///
+    /// The iteration returns an [`Error::InvalidUtf8`] on the first key or value that isn't valid
+    /// UTF-8 and can be continued past it.
+    pub fn range_str<K: AsRef<[u8]>>(&mut self, range: impl RangeBounds<K>) -> Result<StrRange> {
+        Ok(StrRange::new(self.range(range)?))
+    }
+
+    /// Look up the keys in the segments, the lookups of all the keys in a segment are done at once.
+    ///
+    /// The entries deleted by the `range_tombstones` of the memtables, or by the ones of the
+    /// more recent segments, are returned as `None`.
+    fn get_from_segments(
+        &mut self,
+        keys: &[&[u8]],
+        range_tombstones: &[RangeTombstone],
+    ) -> Result<Vec<Option<MetaValue>>> {
+        let mut range_tombstones = range_tombstones.to_vec();
+        let mut values = vec![None; keys.len()];
+        // The keys that weren't found yet
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        if let Some(filter) = &self.database_filter {
+            pending.retain(|i| filter.contains(keys[*i]));
+            self.database_filter_negatives += (keys.len() - pending.len()) as u64;
+        }
+
+        // We want to go from the most recent segment to the most outdated one
+        for segment in self.segments.iter().rev() {
+            if pending.is_empty() {
+                break;
+            }
+            // Its own ranges are harmless, the segment doesn't hold the entries they delete
+            range_tombstones.extend_from_slice(segment.range_tombstones(&mut self.files)?);
+            // The keys outside of the segment are skipped before reading its filter
+            let mut candidates = Vec::with_capacity(pending.len());
+            for i in pending.iter().copied() {
+                if segment.in_fence(&mut self.files, keys[i])? {
+                    candidates.push(i);
+                }
+            }
+            self.fence_negatives += (pending.len() - candidates.len()) as u64;
+            if candidates.is_empty() {
+                continue;
+            }
+            let filter = match &self.filter {
+                Some(policy) => segment.filter(&mut self.files, policy.as_ref())?,
+                None => None,
+            };
+            let lookups: Vec<usize> = match filter {
+                Some(filter) => {
+                    let lookups: Vec<_> = candidates
+                        .iter()
+                        .copied()
+                        .filter(|i| filter.contains(keys[*i]))
+                        .collect();
+                    self.filter_negatives += (candidates.len() - lookups.len()) as u64;
+                    lookups
+                }
+                None => candidates,
+            };
+            if lookups.is_empty() {
+                continue;
+            }
+
+            let lookup_keys: Vec<_> = lookups.iter().map(|i| keys[*i]).collect();
+            let found = segment.multi_get(
+                &mut self.files,
+                &lookup_keys,
+                self.reads.as_mut(),
+                &mut self.cache,
+            )?;
+            for (i, entry) in lookups.into_iter().zip(found) {
+                if filter.is_some() {
+                    match entry {
+                        Some(_) => self.filter_positives += 1,
+                        None => self.filter_false_positives += 1,
+                    }
+                }
+                if let Some(entry) = entry {
+                    if !range_deleted(&range_tombstones, keys[i], entry.seq) {
+                        values[i] = entry
+                            .value
+                            .map(|value| (value, entry.meta, entry.timestamp));
+                    }
+                    pending.retain(|pending| *pending != i);
+                }
+            }
+        }
+        // The filters loaded by the lookups take their memory from the block cache
+        self.limit_cache();
+
+        Ok(values)
+    }
+
+    #[cfg(test)]
+    fn prepare_to_read(&mut self) -> io::Result<()> {
+        self.dirty.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn dump(&mut self) -> Result<String> {
+        self.finish_flush()?;
+        let mut buf = String::new();
+        buf.push_str(&format!("memtable:\n{:?}\n", self.memtable));
+
+        let mut dirty_buf = Vec::new();
+        self.prepare_to_read()?;
+        self.dirty.read_to_end(&mut dirty_buf)?;
+
+        buf.push_str(&format!("dirty segment:\n{dirty_buf:?}\n"));
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            segment.dump(&mut self.files, &mut dirty_buf)?;
+            buf.push_str(&format!("segment {i}:\n{dirty_buf:?}\n"));
+        }
+
+        Ok(buf)
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // Otherwise the memtable would be replayed and flushed again on the next open, the
+        // memtable of a poisoned database may not match the dirty segment anymore
+        if self.finish_flush().is_ok() {
+            // The dirty segment is replayed if the memtable can't be saved
+            let _ = self.save_memtable();
+        }
+    }
+}
+
+/// The size of value used to mark a deleted entry, no value follows.
+const TOMBSTONE: u32 = u32::MAX;
+/// The size of value used to mark a range tombstone in the dirty segment, its key is the start
+/// of the range and the end follows prefixed by its size.
+const RANGE_TOMBSTONE: u32 = u32::MAX - 1;
+/// The size of value used to mark a fragment appended to the previous value of the key in the
+/// dirty segment. The position of the record holding the previous value follows, then the size
+/// of the whole value and the fragment prefixed by its size.
+const APPEND: u32 = u32::MAX - 2;
+/// The size of value used to mark an entry of the dirty segment written with a timestamp, the
+/// timestamp follows and then the size of the value or one of the other marks.
+const TIMESTAMP: u32 = u32::MAX - 3;
+/// The index of the entries of the memtable written without the dirty segment.
+const UNLOGGED: u64 = u64::MAX;
+/// The memory used by a key of the memtable besides its bytes, the nodes of the tree aren't
+/// accounted for.
+const MEMTABLE_ENTRY_BYTES: usize = mem::size_of::<Vec<u8>>() + mem::size_of::<u64>();
+/// The metadata of the entries is stored in the most significant byte of their sequence number
+/// in the dirty segment, thus the entries written before it existed have a metadata of 0.
+const META_SHIFT: u32 = 56;
+/// The flag set in the sequence number of a deletion written without a timestamp in the dirty
+/// segment, nothing follows it. The other deletions are written with the [`TOMBSTONE`] mark.
+const DELETED: u64 = 1 << (META_SHIFT - 1);
+/// The flag set in the sequence number of an entry of the dirty segment whose value, or
+/// appended fragment, is followed by its CRC-32. The entries written before it existed aren't
+/// checked.
+const CHECKSUMMED: u64 = 1 << (META_SHIFT - 2);
+
+/// A value along with its metadata and timestamp.
+type MetaValue = (Vec<u8>, u8, u64);
+/// The number of bytes of the dirty segment read at once when it's replayed, the progress is
+/// reported after each of them.
+const REPLAY_BATCH: u64 = 1024 * 1024;
+/// The number of keys sampled in each segment to estimate the space amplification.
+const AMPLIFICATION_SAMPLES: usize = 64;
+
+/// The range holding only `key`, the following key being `key` followed by a zero byte.
+fn key_range(key: &[u8]) -> (&[u8], Vec<u8>) {
+    let mut end = key.to_vec();
+    end.push(0);
+    (key, end)
+}
+
+/// Make the creation of the files of a directory durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    // The directories can't be opened as files on Windows
+    if cfg!(unix) {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Move a fully written segment to `path` once its content is durable, then sync its directory
+/// so the segment can't vanish after a crash. It must be called before the segment is recorded
+/// in the manifest or replaces the files it was written from.
+///
+/// A file already at `path` is an error rather than overwritten, the ids of the segments are
+/// never reused.
+fn persist_segment(file: tempfile::NamedTempFile, path: &Path) -> Result<File> {
+    file.as_file().sync_all()?;
+    let file = file
+        .persist_noclobber(path)
+        .map_err(|e| match e.error.kind() {
+            io::ErrorKind::AlreadyExists => Error::SegmentExists(path.to_owned()),
+            _ => e.into(),
+        })?;
+    sync_dir(path.parent().unwrap_or(path))?;
+    Ok(file)
+}
+
+fn write_entry(
+    mut writer: impl Write,
+    key: &[u8],
+    seq: u64,
+    meta: u8,
+    timestamp: u64,
+    value: Option<&[u8]>,
+) -> io::Result<()> {
     writer.write_all(&(key.len() as u32).to_be_bytes())?;
     writer.write_all(key)?;
-    writer.write_all(&(value.len() as u32).to_be_bytes())?;
-    writer.write_all(value)?;
+    if value.is_none() && timestamp == 0 {
+        // A deletion only needs its flag
+        writer.write_all(&(seq | DELETED | (meta as u64) << META_SHIFT).to_be_bytes())?;
+        return Ok(());
+    }
+    let checksummed = if value.is_some() { CHECKSUMMED } else { 0 };
+    writer.write_all(&(seq | checksummed | (meta as u64) << META_SHIFT).to_be_bytes())?;
+    write_timestamp(&mut writer, timestamp)?;
+    match value {
+        Some(value) => {
+            writer.write_all(&(value.len() as u32).to_be_bytes())?;
+            writer.write_all(value)?;
+            writer.write_all(&crc32fast::hash(value).to_be_bytes())?;
+        }
+        None => writer.write_all(&TOMBSTONE.to_be_bytes())?,
+    }
+    Ok(())
+}
+
+/// Write a range tombstone in the dirty segment.
+fn write_range_tombstone(mut writer: impl Write, range: &RangeTombstone) -> io::Result<()> {
+    writer.write_all(&(range.start.len() as u32).to_be_bytes())?;
+    writer.write_all(&range.start)?;
+    writer.write_all(&range.seq.to_be_bytes())?;
+    writer.write_all(&RANGE_TOMBSTONE.to_be_bytes())?;
+    writer.write_all(&(range.end.len() as u32).to_be_bytes())?;
+    writer.write_all(&range.end)?;
+    Ok(())
+}
+
+/// Write a fragment appended to the value stored at `prev` in the dirty segment, `size` is the
+/// size of the whole value.
+fn write_append(mut writer: impl Write, entry: &Entry, prev: u64, size: u32) -> io::Result<()> {
+    let fragment = entry.value.as_deref().unwrap_or_default();
+    writer.write_all(&(entry.key.len() as u32).to_be_bytes())?;
+    writer.write_all(&entry.key)?;
+    writer
+        .write_all(&(entry.seq | CHECKSUMMED | (entry.meta as u64) << META_SHIFT).to_be_bytes())?;
+    write_timestamp(&mut writer, entry.timestamp)?;
+    writer.write_all(&APPEND.to_be_bytes())?;
+    writer.write_all(&prev.to_be_bytes())?;
+    writer.write_all(&size.to_be_bytes())?;
+    writer.write_all(&(fragment.len() as u32).to_be_bytes())?;
+    writer.write_all(fragment)?;
+    writer.write_all(&crc32fast::hash(fragment).to_be_bytes())?;
+    Ok(())
+}
+
+/// Write the timestamp of an entry of the dirty segment before the size of its value, nothing
+/// is written when it wasn't recorded.
+fn write_timestamp(mut writer: impl Write, timestamp: u64) -> io::Result<()> {
+    if timestamp != 0 {
+        writer.write_all(&TIMESTAMP.to_be_bytes())?;
+        writer.write_all(&timestamp.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read the timestamp of an entry of the dirty segment, 0 if it wasn't recorded, and the size
+/// of its value or the mark replacing it. Nothing is read for the compact deletions, they're
+/// returned as a [`TOMBSTONE`].
+fn read_value_size(reader: &mut impl Read, deleted: bool) -> io::Result<(u64, u32)> {
+    if deleted {
+        return Ok((0, TOMBSTONE));
+    }
+    match read_u32(reader)? {
+        TIMESTAMP => Ok((read_u64(reader)?, read_u32(reader)?)),
+        size => Ok((0, size)),
+    }
+}
+
+/// Write a clean segment out of entries sorted by key and then from the most recent to the
+/// oldest version, keeping only the `versions` most recent versions of each key.
+///
+/// The entries deleted by the range tombstones are dropped, the range tombstones are written
+/// along with the entries to hide the older segments. If there is no older segment the
+/// deletions don't need to hide anything and can be dropped.
+fn write_segment(
+    writer: impl Write,
+    entries: impl Iterator<Item = io::Result<Entry>>,
+    range_tombstones: &[RangeTombstone],
+    bottommost: bool,
+    options: &SegmentOptions,
+) -> Result<Written> {
+    let SegmentOptions {
+        versions,
+        schema,
+        filter,
+        compaction_filter,
+        pins,
+        prefix_extractor,
+        encoding,
+        block_sizes,
+        pool,
+        database,
+        blobs,
+        value_log,
+        stored_values,
+        relocate,
+    } = *options;
+    let mut writer = SegmentWriter::new(BufWriter::new(writer), filter, encoding, pool.clone());
+    writer.block_sizes(block_sizes);
+    writer.database(database);
+    if let Some((store, threshold)) = blobs {
+        writer.blobs(store.clone(), threshold);
+    }
+    if let Some((log, threshold)) = value_log {
+        writer.value_log(log.clone(), threshold);
+    }
+    if let Some((log, files)) = relocate {
+        writer.relocate(log.clone(), files.clone());
+    }
+    if let Some(extractor) = prefix_extractor {
+        writer.prefix_extractor(extractor);
+    }
+    // The retained versions of the current key
+    let mut kept: Vec<Entry> = Vec::new();
+    let mut live_keys = 0;
+    let mut removed = Vec::new();
+
+    let mut write_versions = |kept: &mut Vec<Entry>| -> Result<()> {
+        // The key is removed if the compaction filter drops its value
+        let live_key = match compaction_filter {
+            Some(_) => kept.first().filter(|entry| entry.value.is_some()),
+            None => None,
+        };
+        let live_key = live_key.map(|entry| entry.key.clone());
+        // The pinned keys are kept whatever the compaction filter would decide
+        let pinned = live_key
+            .as_ref()
+            .is_some_and(|key| pins.is_some_and(|pins| pins.contains(key)));
+        let compaction_filter = compaction_filter.filter(|_| !pinned);
+        if let Some(compaction_filter) = compaction_filter {
+            let mut filtered = Vec::with_capacity(kept.len());
+            for mut entry in kept.drain(..) {
+                let Some(stored) = entry.value.take() else {
+                    filtered.push(entry);
+                    continue;
+                };
+                let decision = match schema {
+                    Some(schema) => {
+                        compaction_filter.filter(&entry.key, &schema.migrate(stored.clone())?)
+                    }
+                    None => compaction_filter.filter(&entry.key, &stored),
+                };
+                entry.value = match (decision, schema) {
+                    (CompactionDecision::Keep, _) => Some(stored),
+                    (CompactionDecision::Remove, _) => continue,
+                    (CompactionDecision::Replace(value), Some(schema)) => Some(schema.tag(&value)),
+                    (CompactionDecision::Replace(value), None) => Some(value),
+                };
+                filtered.push(entry);
+            }
+            *kept = filtered;
+        }
+        if bottommost {
+            // A deletion still matters if older versions are retained behind it
+            while kept.last().is_some_and(|entry| entry.value.is_none()) {
+                kept.pop();
+            }
+        }
+        match kept.first() {
+            Some(entry) if entry.value.is_some() => live_keys += 1,
+            _ => removed.extend(live_key),
+        }
+        for Entry {
+            key,
+            seq,
+            meta,
+            timestamp,
+            value,
+        } in kept.drain(..)
+        {
+            // That's the right time to rewrite the values stored in an old version
+            let value = match (value, schema) {
+                (Some(value), Some(schema)) => Some(schema.retag(value)?),
+                (value, _) => value,
+            };
+            match stored_values {
+                true => writer.add_stored(&key, seq, meta, timestamp, value.as_deref())?,
+                false => writer.add(&key, seq, meta, timestamp, value.as_deref())?,
+            }
+        }
+        Ok(())
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if iter::range_deleted(range_tombstones, &entry.key, entry.seq) {
+            continue;
+        }
+        if kept.first().is_some_and(|first| first.key != entry.key) {
+            write_versions(&mut kept)?;
+        }
+        if kept.len() < versions {
+            kept.push(entry);
+        }
+    }
+    write_versions(&mut kept)?;
+    if !bottommost {
+        for range in range_tombstones {
+            writer.delete_range(range.clone());
+        }
+    }
+    writer.finish()?.flush()?;
+
+    Ok(Written { live_keys, removed })
+}
+
+/// What [`write_segment`] wrote.
+pub(crate) struct Written {
+    /// The number of keys whose most recent version has a value.
+    pub live_keys: u64,
+    /// The keys whose value was removed by the compaction filter.
+    pub removed: Vec<Vec<u8>>,
+}
+
+/// What the dirty segment holds.
+pub(crate) enum Record {
+    Entry(Entry),
+    /// The value of the entry is the fragment appended to the previous value of the key, stored
+    /// at `prev`. The whole value is `size` bytes long.
+    Append {
+        entry: Entry,
+        prev: u64,
+        size: u32,
+    },
+    DeleteRange(RangeTombstone),
+}
+
+impl Record {
+    fn seq(&self) -> u64 {
+        match self {
+            Record::Entry(entry) | Record::Append { entry, .. } => entry.seq,
+            Record::DeleteRange(range) => range.seq,
+        }
+    }
+}
+
+/// Concatenate the fragments of the records to the previous value of their key, the records
+/// must be in the order they were written.
+fn resolve_appends(records: Vec<Record>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::with_capacity(records.len());
+    // The position of the most recent entry of each key
+    let mut last = HashMap::new();
+    for record in records {
+        let entry = match record {
+            Record::Entry(entry) => entry,
+            Record::Append { mut entry, .. } => {
+                let previous = last.get(&entry.key).map(|i: &usize| &entries[*i].value);
+                let mut value = previous.cloned().flatten().unwrap_or_default();
+                value.extend_from_slice(entry.value.as_deref().unwrap_or_default());
+                entry.value = Some(value);
+                entry
+            }
+            Record::DeleteRange(_) => continue,
+        };
+        last.insert(entry.key.clone(), entries.len());
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Read the next record of the dirty segment, `None` once it's over.
+fn read_wal_record(reader: &mut impl Read) -> io::Result<Option<Record>> {
+    let key = match read_entry_to_vec(reader) {
+        Ok(key) => key,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (seq, meta, deleted, checksummed) = read_seq_and_meta(reader)?;
+    let (timestamp, size) = read_value_size(reader, deleted)?;
+    let value = match size {
+        TOMBSTONE => None,
+        APPEND => {
+            let prev = read_u64(reader)?;
+            let size = read_u32(reader)?;
+            let fragment = read_entry_to_vec(reader)?;
+            if checksummed {
+                verify_value(reader, &fragment)?;
+            }
+            let entry = Entry {
+                key,
+                seq,
+                meta,
+                timestamp,
+                value: Some(fragment),
+            };
+            return Ok(Some(Record::Append { entry, prev, size }));
+        }
+        RANGE_TOMBSTONE => {
+            let end = read_entry_to_vec(reader)?;
+            let range = RangeTombstone {
+                start: key,
+                end,
+                seq,
+            };
+            return Ok(Some(Record::DeleteRange(range)));
+        }
+        size => {
+            let mut buf = Vec::new();
+            read_bytes(reader, size as usize, &mut buf)?;
+            if checksummed {
+                verify_value(reader, &buf)?;
+            }
+            Some(buf)
+        }
+    };
+    Ok(Some(Record::Entry(Entry {
+        key,
+        seq,
+        meta,
+        timestamp,
+        value,
+    })))
+}
+
+fn read_entry(reader: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<()> {
+    let size = read_u32(reader)?;
+    read_bytes(reader, size as usize, buf)?;
+    Ok(())
+}
+
+fn read_entry_to_vec(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    read_entry(reader, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_bytes(reader: &mut impl Read, size: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.resize(size, 0);
+    reader.read_exact(buf)?;
+    Ok(())
+}
+
+fn skip_bytes(reader: &mut impl Read, size: u64) -> io::Result<()> {
+    // we can't Seek thus we're throw away everything we've read
+    let skipped = io::copy(&mut reader.by_ref().take(size), &mut io::sink())?;
+    if skipped < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut u64_buf = [0; 8];
+    reader.read_exact(&mut u64_buf)?;
+    Ok(u64::from_be_bytes(u64_buf))
+}
+
+/// Read the sequence number and the metadata of an entry of the dirty segment, whether it's a
+/// compact deletion and whether its value is followed by its checksum.
+fn read_seq_and_meta(reader: &mut impl Read) -> io::Result<(u64, u8, bool, bool)> {
+    let stored = read_u64(reader)?;
+    Ok((
+        stored & (CHECKSUMMED - 1),
+        (stored >> META_SHIFT) as u8,
+        stored & DELETED != 0,
+        stored & CHECKSUMMED != 0,
+    ))
+}
+
+/// Read the CRC-32 following a value or a fragment of the dirty segment and compare it with
+/// the one of `value`.
+fn verify_value(reader: &mut impl Read, value: &[u8]) -> io::Result<()> {
+    if read_u32(reader)? != crc32fast::hash(value) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted value in the dirty segment, checksum mismatch",
+        ));
+    }
     Ok(())
 }
 
-fn read_entry(reader: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<()> {
-    let size = read_u32(reader)?;
-    read_bytes(reader, size as usize, buf)?;
-    Ok(())
-}
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut u8_buf = [0; 1];
+    reader.read_exact(&mut u8_buf)?;
+    Ok(u8_buf[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut u32_buf = [0; 4];
+    reader.read_exact(&mut u32_buf)?;
+    let n = u32::from_be_bytes(u32_buf);
+    Ok(n)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// The identity of the databases whose segments are compared to snapshots.
+    const IDENTITY: u128 = 0x6a1b3c5d_7e9f_4a2b_8c4d_5e6f7a8b9c0d;
+
+    #[test]
+    fn insert_and_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.add(b"hello", b"world").unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {[104, 101, 108, 108, 111]: 0}
+        dirty segment:
+        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67]
+        ");
+
+        let v = database.get(b"hello").map_err(|e| println!("{e}")).unwrap();
+        assert_eq!(v.as_deref(), Some(&b"world"[..]));
+        let v = database.get(b"hemlo").map_err(|e| println!("{e}")).unwrap();
+        assert_eq!(v.as_deref(), None);
+        assert_eq!(v.as_deref(), None);
+    }
+
+    #[test]
+    fn empty_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.add(b"", b"riengue").unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {[]: 0}
+        dirty segment:
+        [0, 0, 0, 0, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 7, 114, 105, 101, 110, 103, 117, 101, 97, 67, 253, 34]
+        ");
+
+        let v = database.get(b"").map_err(|e| println!("{e}")).unwrap();
+        assert_eq!(v.as_deref(), Some(&b"riengue"[..]));
+    }
+
+    #[test]
+    fn size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .max_key_size(4)
+            .max_value_size(8)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"key", b"value").unwrap();
+        let error = database.add(b"large", b"value").unwrap_err();
+        insta::assert_snapshot!(error, @"Key too large 5. Maximum size accepted is 4");
+        let error = database.add(b"key", b"large value").unwrap_err();
+        insta::assert_snapshot!(error, @"Value too large 11. Maximum size accepted is 8");
+        assert!(database.delete(b"large").is_err());
+        let entries = [(b"large", b"value")].map(Ok::<_, Error>);
+        assert!(matches!(
+            database.import(entries),
+            Err(Error::KeyTooLarge { size: 5, max: 4 })
+        ));
+        assert_eq!(error.kind(), ErrorKind::LimitExceeded);
+        drop(database);
+
+        let manifest = std::fs::read_to_string(dir.path().join("MANIFEST")).unwrap();
+        insta::assert_snapshot!(manifest, @"
+        # live keys: 1
+        # max key size: 4
+        # max value size: 8
+        # sequence: 1
+        # next id: 1
+        segment-0
+        ");
+        // The entries written under the previous limits stay readable
+        let mut database = Database::builder()
+            .max_key_size(2)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(
+            database.get(b"key").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        assert!(database.add(b"key", b"value").is_err());
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        assert!(log.contains("size limits changed from 4 and 8 bytes to 2 and 268435456 bytes"));
+    }
+
+    #[test]
+    fn write_amplification() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(database.stats().write_amplification(), 0.0);
+        for i in 0..100u32 {
+            database.add(i.to_be_bytes(), [0; 96]).unwrap();
+        }
+        database.delete_range([0; 4], [0, 0, 0, 10]).unwrap();
+        database.flush().unwrap();
+        let size = |database: &Database, i: usize| {
+            std::fs::metadata(&database.segments[i].path).unwrap().len()
+        };
+        let flushed = size(&database, 0);
+        let stats = database.stats();
+        assert_eq!(stats.user_bytes, 100 * 100 + 8);
+        assert_eq!(stats.flush_bytes, flushed);
+        assert_eq!(stats.compaction_bytes, 0);
+
+        database.append(1u32.to_be_bytes(), b"more").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        let stats = database.stats();
+        assert_eq!(stats.user_bytes, 100 * 100 + 8 + 8);
+        assert_eq!(stats.compaction_bytes, size(&database, 0));
+        let amplification = (stats.flush_bytes + stats.compaction_bytes) as f64 / 10016.0;
+        assert_eq!(stats.write_amplification(), amplification);
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        assert!(log.contains(&format!("write amplification {amplification:.2}")));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.flush().unwrap();
+        database.add(b"patou", b"patou").unwrap();
+
+        let metrics = database.prometheus_metrics();
+        let lines: Vec<_> = metrics
+            .lines()
+            .filter(|line| line.contains("user_bytes") || line.contains("live_keys"))
+            .collect();
+        insta::assert_debug_snapshot!(lines, @r##"
+        [
+            "# HELP database_user_bytes_total Bytes written by the application.",
+            "# TYPE database_user_bytes_total counter",
+            "database_user_bytes_total 19",
+            "# HELP database_live_keys Keys with a value.",
+            "# TYPE database_live_keys gauge",
+            "database_live_keys 2",
+        ]
+        "##);
+        // Each metric has a help, a type and a value
+        assert_eq!(metrics.lines().count() % 3, 0);
+        assert!(metrics
+            .lines()
+            .all(|line| line.starts_with("# HELP database_")
+                || line.starts_with("# TYPE database_")
+                || line.starts_with("database_")));
+    }
+
+    #[test]
+    fn segment_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.flush().unwrap();
+        database.delete(b"tamo").unwrap();
+        database.flush().unwrap();
+
+        let stats = database.stats();
+        assert_eq!(stats.segment_entries, 3);
+        assert_eq!(stats.segment_key_bytes, 13);
+        assert_eq!(stats.segment_value_bytes, 10);
+        assert_eq!(stats.segment_tombstones, 1);
+        assert_eq!(stats.uncounted_segments, 0);
+
+        // The counters are read back from the footers
+        drop(database);
+        let database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.stats().segment_entries, 3);
+        assert_eq!(database.stats().segment_tombstones, 1);
+    }
+
+    #[test]
+    fn empty_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.add(b"riengue", b"").unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {[114, 105, 101, 110, 103, 117, 101]: 0}
+        dirty segment:
+        [0, 0, 0, 7, 114, 105, 101, 110, 103, 117, 101, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]
+        ");
+
+        let v = database
+            .get(b"riengue")
+            .map_err(|e| println!("{e}"))
+            .unwrap();
+        assert_eq!(v.as_deref(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .identity(IDENTITY)
+            .open(dir.path())
+            .unwrap();
+
+        // make a first segment
+        database.add(b"hello", b"world").unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.add(b"a", b"b").unwrap();
+        database.flush().unwrap();
+
+        // make a second clean segment out of the memtable
+        database.add(b"hello", b"tamo").unwrap();
+        database.add(b"b", b"c").unwrap();
+        database.flush().unwrap();
+
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {}
+        dirty segment:
+        []
+        segment 0:
+        [232, 183, 190, 67, 0, 1, 97, 3, 0, 0, 4, 98, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 1, 0, 0, 8, 119, 111, 114, 108, 100, 129, 82, 32, 227, 0, 4, 116, 97, 109, 111, 2, 0, 0, 8, 107, 101, 102, 105, 114, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 8, 192, 138, 0, 4, 32, 253, 1, 6, 232, 183, 190, 67, 0, 1, 97, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 59, 146, 41, 126, 47, 0, 0, 0, 0, 0, 0, 0, 1, 232, 183, 190, 67, 0, 1, 97, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 77, 0, 0, 0, 35, 175, 243, 249, 217, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 112, 0, 0, 0, 35, 136, 76, 101, 53, 0, 0, 0, 0, 0, 0, 0, 59, 0, 0, 0, 18, 229, 91, 106, 58, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 106, 27, 60, 93, 126, 159, 74, 43, 140, 77, 94, 111, 122, 139, 156, 13, 110, 225, 136, 70, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]
+        segment 1:
+        [113, 190, 239, 249, 0, 1, 98, 5, 0, 0, 4, 99, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 4, 0, 0, 7, 116, 97, 109, 111, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 32, 144, 74, 0, 1, 0, 23, 8, 6, 113, 190, 239, 249, 0, 1, 98, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 39, 67, 116, 123, 180, 0, 0, 0, 0, 0, 0, 0, 1, 113, 190, 239, 249, 0, 1, 98, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 57, 0, 0, 0, 35, 85, 27, 27, 112, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 92, 0, 0, 0, 35, 217, 35, 34, 106, 0, 0, 0, 0, 0, 0, 0, 39, 0, 0, 0, 18, 88, 44, 232, 189, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 106, 27, 60, 93, 126, 159, 74, 43, 140, 77, 94, 111, 122, 139, 156, 13, 24, 227, 98, 178, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]
+        ");
+
+        database.merge_segment().unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {}
+        dirty segment:
+        []
+        segment 0:
+        [232, 183, 190, 67, 0, 1, 97, 3, 0, 0, 4, 98, 113, 190, 239, 249, 0, 1, 98, 5, 0, 0, 4, 99, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 4, 0, 0, 7, 116, 97, 109, 111, 129, 82, 32, 227, 0, 4, 116, 97, 109, 111, 2, 0, 0, 8, 107, 101, 102, 105, 114, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 40, 208, 202, 0, 5, 32, 255, 9, 6, 232, 183, 190, 67, 0, 1, 97, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 70, 33, 32, 69, 14, 0, 0, 0, 0, 0, 0, 0, 1, 232, 183, 190, 67, 0, 1, 97, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 88, 0, 0, 0, 35, 47, 143, 191, 157, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 123, 0, 0, 0, 35, 239, 150, 154, 252, 0, 0, 0, 0, 0, 0, 0, 70, 0, 0, 0, 18, 169, 39, 219, 29, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 106, 27, 60, 93, 126, 159, 74, 43, 140, 77, 94, 111, 122, 139, 156, 13, 186, 99, 28, 123, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]
+        ");
+    }
+
+    #[test]
+    fn adaptive_dirty_thresholds() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(10)
+            .adaptive_dirty_thresholds(100 * 1000)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(database.stats().dirty_thresholds, 10);
+        database.add("0000", [0; 996]).unwrap();
+        assert_eq!(database.stats().dirty_thresholds, 100);
+        // The memtable was flushed once it held more than 100 entries
+        for i in 1..150 {
+            database.add(format!("{i:04}"), [0; 996]).unwrap();
+        }
+        database.flush().unwrap();
+        assert!(database.segments.len() >= 2);
+
+        // A fixed threshold replaces it
+        database.dirty_thresholds(5);
+        database.add("0000", [0; 10]).unwrap();
+        assert_eq!(database.stats().dirty_thresholds, 5);
+    }
+
+    #[test]
+    fn create_and_get_in_clean_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .identity(IDENTITY)
+            .open(dir.path())
+            .unwrap();
+        database.dirty_thresholds(2);
+
+        database.add(b"hello", b"world").unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {[104, 101, 108, 108, 111]: 0}
+        dirty segment:
+        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67]
+        ");
+        database.add(b"tamo", b"world").unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {[104, 101, 108, 108, 111]: 0, [116, 97, 109, 111]: 30}
+        dirty segment:
+        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67, 0, 0, 0, 4, 116, 97, 109, 111, 0, 64, 0, 0, 0, 0, 0, 2, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67]
+        ");
+        database.add(b"patou", b"world").unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {}
+        dirty segment:
+        []
+        segment 0:
+        [54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 1, 0, 0, 8, 119, 111, 114, 108, 100, 45, 152, 181, 227, 0, 5, 112, 97, 116, 111, 117, 3, 0, 0, 8, 119, 111, 114, 108, 100, 129, 82, 32, 227, 0, 4, 116, 97, 109, 111, 2, 0, 0, 8, 119, 111, 114, 108, 100, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 136, 224, 138, 0, 20, 36, 21, 1, 6, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 67, 99, 52, 84, 185, 0, 0, 0, 0, 0, 0, 0, 1, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 85, 0, 0, 0, 39, 201, 103, 76, 122, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 124, 0, 0, 0, 39, 127, 58, 245, 122, 0, 0, 0, 0, 0, 0, 0, 67, 0, 0, 0, 18, 50, 11, 137, 238, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 0, 106, 27, 60, 93, 126, 159, 74, 43, 140, 77, 94, 111, 122, 139, 156, 13, 22, 27, 238, 182, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]
+        ");
+        let v = database.get(b"hello").map_err(|e| println!("{e}")).unwrap();
+        assert_eq!(v.as_deref(), Some(&b"world"[..]));
+    }
+
+    #[test]
+    fn reload_memtable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.add(b"hello", b"world").unwrap();
+        database.add(b"tamo", b"world").unwrap();
+
+        drop(database);
+        // dropping the previous database and opening a new one in the same dir
+        let mut database = Database::new(dir.path()).unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {[104, 101, 108, 108, 111]: 0, [116, 97, 109, 111]: 30}
+        dirty segment:
+        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67, 0, 0, 0, 4, 116, 97, 109, 111, 0, 64, 0, 0, 0, 0, 0, 2, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67]
+        ");
+    }
+
+    #[test]
+    fn close() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .background_scrub(Duration::ZERO, u64::MAX)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.close().unwrap();
+
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        assert!(log.contains("close: 1 entries saved from the memtable"));
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get(b"tamo").unwrap(), Some(b"kefir".to_vec()));
+        assert_eq!(database.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn get_traced() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .block_cache_size(1 << 20)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.flush().unwrap();
+        database.delete(b"tamo").unwrap();
+
+        let traced = database.get_traced(b"tamo").unwrap();
+        assert_eq!(traced, (None, Some(Provenance::Memtable)));
+        assert_eq!(database.get_traced(b"missing").unwrap(), (None, None));
+
+        // The segment holding tamo is skipped, then the top index, the index and the data block
+        // are read and kept in the block cache
+        let (value, provenance) = database.get_traced(b"hello").unwrap();
+        assert_eq!(value.as_deref(), Some(&b"world"[..]));
+        insta::assert_debug_snapshot!(provenance, @"
+        Some(
+            Segment {
+                id: 0,
+                offset: 0,
+                bloom_checked: true,
+                blocks_read: 3,
+            },
+        )
+        ");
+        let (_, provenance) = database.get_traced(b"hello").unwrap();
+        assert!(matches!(
+            provenance,
+            Some(Provenance::Segment { blocks_read: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn diff() {
+        let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut database = Database::new(dir.path()).unwrap();
+        let mut other = Database::new(other_dir.path()).unwrap();
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3"), ("e", "5")] {
+            database.add(key, value).unwrap();
+            other.add(key, value).unwrap();
+        }
+        database.flush().unwrap();
+        database.add("b", "two").unwrap();
+        database.delete("c").unwrap();
+        other.add("d", "4").unwrap();
+        other.flush().unwrap();
+
+        let s = |bytes: Vec<u8>| String::from_utf8(bytes).unwrap();
+        let diff: Vec<_> = database
+            .diff(&mut other)
+            .unwrap()
+            .map(|difference| match difference.unwrap() {
+                Difference::OnlyInSelf { key, value } => format!("< {} {}", s(key), s(value)),
+                Difference::OnlyInOther { key, value } => format!("> {} {}", s(key), s(value)),
+                Difference::Different {
+                    key,
+                    value,
+                    other_value,
+                } => format!("! {} {} {}", s(key), s(value), s(other_value)),
+            })
+            .collect();
+        insta::assert_debug_snapshot!(diff, @r#"
+        [
+            "! b two 2",
+            "> c 3",
+            "> d 4",
+        ]
+        "#);
+
+        other.add("b", "two").unwrap();
+        other.delete("c").unwrap();
+        database.add("d", "4").unwrap();
+        assert_eq!(database.diff(&mut other).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn deterministic_segments() {
+        let write = |dir: &Path| {
+            let mut database = Database::builder()
+                .scheduler(ManualScheduler::new())
+                .identity(IDENTITY)
+                .open(dir)
+                .unwrap();
+            for i in 0..1000_u32 {
+                database.add(i.to_be_bytes(), i.to_string()).unwrap();
+            }
+            database.flush().unwrap();
+            for i in (0..1000_u32).step_by(3) {
+                database.delete(i.to_be_bytes()).unwrap();
+            }
+            database.flush().unwrap();
+            database.merge_segment().unwrap();
+            let segment = std::fs::read(&database.segments[0].path).unwrap();
+            (database, segment)
+        };
+        let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (mut database, segment) = write(dir.path());
+        let (mut other, other_segment) = write(other_dir.path());
+        assert!(segment == other_segment);
+        let hash = database.content_hash().unwrap();
+        assert_eq!(hash, other.content_hash().unwrap());
+
+        // The hash only depends on the entries
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in (0..1000_u32).rev().filter(|i| i % 3 != 0) {
+            database.add(i.to_be_bytes(), "overwritten").unwrap();
+            database.add(i.to_be_bytes(), i.to_string()).unwrap();
+        }
+        assert_eq!(database.content_hash().unwrap(), hash);
+        database.delete(1_u32.to_be_bytes()).unwrap();
+        assert_ne!(database.content_hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.persist().unwrap();
+        assert!(database.segments.is_empty());
+        database.flush().unwrap();
+        assert_eq!(database.segments.len(), 1);
+        database.add(b"tamo", b"kefir").unwrap();
+        database.persist().unwrap();
+        assert_eq!(database.segments.len(), 1);
+        assert_eq!(database.get(b"tamo").unwrap(), Some(b"kefir".to_vec()));
+    }
+
+    #[test]
+    fn sequence_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..5 {
+            database.add(b"a", format!("old{i}")).unwrap();
+        }
+        database.flush().unwrap();
+        drop(database);
+
+        // The dirty segment is empty, the sequence is restored from the manifest
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.sequence(), 5);
+        database.add(b"a", b"new").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments.len(), 1);
+        assert_eq!(database.get(b"a").unwrap(), Some(b"new".to_vec()));
+        let entries: Vec<_> = database.range::<&[u8]>(..).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].as_ref().unwrap().1, b"new");
+        drop(database);
+
+        // The manifests written before it was recorded fall back to the segments
+        let path = dir.path().join("MANIFEST");
+        let manifest = std::fs::read_to_string(&path).unwrap();
+        assert!(manifest.contains("# sequence: 6"), "{manifest}");
+        let manifest: String = manifest
+            .lines()
+            .filter(|line| !line.starts_with("# sequence: "))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        std::fs::write(&path, manifest).unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.sequence(), 6);
+        database.add(b"a", b"newer").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        assert_eq!(database.get(b"a").unwrap(), Some(b"newer".to_vec()));
+    }
+
+    #[test]
+    fn delete_where() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..10_u8 {
+            database.add([i], [i % 3]).unwrap();
+        }
+        database.flush().unwrap();
+        let multiple_of_3 = |_: &[u8], value: &[u8]| value == [0];
+
+        let sequence = database.sequence();
+        assert_eq!(
+            database.delete_where(..[8], multiple_of_3, true).unwrap(),
+            3
+        );
+        assert_eq!(database.sequence(), sequence);
+        assert_eq!(database.len(), 10);
+
+        assert_eq!(
+            database.delete_where(..[8], multiple_of_3, false).unwrap(),
+            3
+        );
+        assert_eq!(database.len(), 7);
+        let keys: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap().0[0])
+            .collect();
+        assert_eq!(keys, [1, 2, 4, 5, 7, 8, 9]);
+        assert_eq!(
+            database.delete_where(..[8], multiple_of_3, false).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn delete_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || {
+            Database::builder()
+                .keep_versions(3)
+                .open(dir.path())
+                .unwrap()
+        };
+        let mut database = open();
+        for key in ["a", "b", "c"] {
+            database.add(key, key).unwrap();
+        }
+        database.flush().unwrap();
+        database.add("d", "d").unwrap();
+        database.add("e", "e").unwrap();
+        database.delete_range("b", "e").unwrap();
+        // Only the older entries are deleted
+        database.add("c", "c2").unwrap();
+        // Nothing to delete
+        database.delete_range("e", "b").unwrap();
+
+        let check = |database: &mut Database| {
+            let keys: Vec<_> = database
+                .range::<&[u8]>(..)
+                .unwrap()
+                .map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+                .collect();
+            assert_eq!(keys, ["a", "c", "e"]);
+            assert_eq!(database.len(), 3);
+            assert_eq!(database.get("b").unwrap(), None);
+            assert_eq!(database.get("d").unwrap(), None);
+            assert_eq!(database.get("c").unwrap(), Some(b"c2".to_vec()));
+            assert_eq!(
+                database.cursor().seek_for_prev("d").unwrap().unwrap().0,
+                b"c"
+            );
+        };
+        check(&mut database);
+        assert_eq!(
+            database.versions("c").unwrap(),
+            [
+                (7, Some(b"c2".to_vec())),
+                (6, None),
+                (3, Some(b"c".to_vec()))
+            ]
+        );
+        assert_eq!(database.get_at("b", 5).unwrap(), Some(b"b".to_vec()));
+
+        // From the saved memtable and then from the dirty segment
+        drop(database);
+        let mut database = open();
+        check(&mut database);
+        drop(database);
+        std::fs::remove_file(dir.path().join("memtable")).unwrap();
+        let mut database = open();
+        check(&mut database);
+
+        // The range is written in the segment to hide the older one
+        database.flush().unwrap();
+        check(&mut database);
+        assert_eq!(database.get_at("b", 5).unwrap(), Some(b"b".to_vec()));
+        // Then the compaction drops it along with the entries it deletes
+        database.merge_segment().unwrap();
+        check(&mut database);
+        assert_eq!(database.versions("b").unwrap(), []);
+        assert_eq!(database.versions("c").unwrap(), [(7, Some(b"c2".to_vec()))]);
+    }
+
+    #[test]
+    fn compact_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .open(dir.path())
+            .unwrap();
+        for i in 0..100u32 {
+            database.add(b"counter", i.to_be_bytes()).unwrap();
+        }
+        database.add(b"log", b"a").unwrap();
+        database.append(b"log", b"b").unwrap();
+        database.add(b"gone", b"gone").unwrap();
+        database.delete(b"gone").unwrap();
+        database.add(b"range/1", b"1").unwrap();
+        database.delete_range(b"range/", b"range0").unwrap();
+        let options = WriteOptions {
+            disable_wal: true,
+            ..WriteOptions::default()
+        };
+        database.add_opt(b"log", b"unlogged", options).unwrap();
+
+        let before = database.dirty.len();
+        let released = database.compact_wal().unwrap();
+        assert_eq!(released, before - database.dirty.len());
+        assert!(released > 0);
+        assert_eq!(database.dirty.file_count(), 1);
+        let check = |database: &mut Database| {
+            let counter = database.get(b"counter").unwrap();
+            assert_eq!(counter, Some(99u32.to_be_bytes().to_vec()));
+            assert_eq!(database.get(b"gone").unwrap(), None);
+            assert_eq!(database.get(b"range/1").unwrap(), None);
+            assert_eq!(database.len(), 2);
+        };
+        check(&mut database);
+        assert_eq!(
+            database.get(b"log").unwrap().as_deref(),
+            Some(&b"unlogged"[..])
+        );
+        // Nothing is left to drop
+        assert_eq!(database.compact_wal().unwrap(), 0);
+        drop(database);
+
+        // The memtable saved on close points to the new positions
+        let mut database = Database::new(dir.path()).unwrap();
+        check(&mut database);
+        assert_eq!(
+            database.get(b"log").unwrap().as_deref(),
+            Some(&b"unlogged"[..])
+        );
+        drop(database);
+
+        // The unlogged write was logged on close, the new file is replayed without the saved memtable
+        std::fs::remove_file(Layout::default().memtable_path(dir.path())).unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        check(&mut database);
+        assert_eq!(
+            database.get(b"log").unwrap().as_deref(),
+            Some(&b"unlogged"[..])
+        );
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        assert!(log.contains(&format!(
+            "compact wal: {released} bytes released, 5 records kept"
+        )));
+    }
+
+    #[test]
+    fn punch_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder().open(dir.path()).unwrap();
+        for i in 0..2000 {
+            database.add(format!("{i:05}"), [b'v'; 100]).unwrap();
+        }
+        database.flush().unwrap();
+        database.delete_range("00500", "01500").unwrap();
+        database.flush().unwrap();
+
+        let holes = |database: &Database| -> Vec<_> {
+            database.segments.iter().map(|s| s.holes.clone()).collect()
+        };
+        let check = |database: &mut Database| {
+            assert_eq!(database.get("00499").unwrap(), Some(vec![b'v'; 100]));
+            assert_eq!(database.get("01000").unwrap(), None);
+            assert_eq!(database.get("01500").unwrap(), Some(vec![b'v'; 100]));
+            assert_eq!(database.range::<&[u8]>(..).unwrap().count(), 1000);
+            let mut cursor = database.cursor();
+            assert_eq!(cursor.seek_for_prev("01200").unwrap().unwrap().0, b"00499");
+        };
+        // The segment read by an iterator is skipped
+        let range = database.range::<&[u8]>(..).unwrap();
+        assert_eq!(database.punch_holes(0).unwrap(), 0);
+        drop(range);
+
+        // Only the blocks whose entries are all deleted are punched
+        let released = database.punch_holes(0).unwrap();
+        assert_eq!(released % 4096, 0);
+        let punched = holes(&database);
+        assert!(punched.iter().any(|holes| !holes.is_empty()));
+        check(&mut database);
+        // Nothing more to punch
+        assert_eq!(database.punch_holes(0).unwrap(), 0);
+        assert_eq!(holes(&database), punched);
+
+        drop(database);
+        let mut database = Database::builder().open(dir.path()).unwrap();
+        assert_eq!(holes(&database), punched);
+        check(&mut database);
+        assert!(database.scrub().unwrap().corrupted.is_empty());
+        database.merge_segment().unwrap();
+        check(&mut database);
+    }
+
+    #[test]
+    fn append() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || {
+            Database::builder()
+                .keep_versions(2)
+                .max_value_size(8)
+                .open(dir.path())
+                .unwrap()
+        };
+        let mut database = open();
+        database.add("a", "1").unwrap();
+        database.flush().unwrap();
+        // Read from the segment and written again
+        database.append("a", "2").unwrap();
+        // Chained to the previous value
+        database.append("a", "3").unwrap();
+        database.append("b", "1").unwrap();
+        database.append("b", "2").unwrap();
+        assert!(matches!(
+            database.append("a", "456789"),
+            Err(Error::ValueTooLarge { size: 9, max: 8 })
+        ));
+        let mut follower = Database::builder().open_follower(dir.path()).unwrap();
+
+        let check = |database: &mut Database| {
+            assert_eq!(database.get("a").unwrap(), Some(b"123".to_vec()));
+            assert_eq!(database.get("b").unwrap(), Some(b"12".to_vec()));
+            assert_eq!(database.len(), 2);
+        };
+        check(&mut database);
+        assert_eq!(follower.get("a").unwrap(), Some(b"123".to_vec()));
+        assert_eq!(
+            database.versions("a").unwrap(),
+            [
+                (3, Some(b"123".to_vec())),
+                (2, Some(b"12".to_vec())),
+                (1, Some(b"1".to_vec()))
+            ]
+        );
+
+        drop(database);
+        let mut database = open();
+        check(&mut database);
+        drop(database);
+        std::fs::remove_file(dir.path().join("memtable")).unwrap();
+        let mut database = open();
+        check(&mut database);
+        database.append("b", "3").unwrap();
+
+        // The flush writes the whole values
+        database.flush().unwrap();
+        assert_eq!(database.get("b").unwrap(), Some(b"123".to_vec()));
+        assert_eq!(
+            database.versions("a").unwrap(),
+            [
+                (3, Some(b"123".to_vec())),
+                (2, Some(b"12".to_vec())),
+                (1, Some(b"1".to_vec()))
+            ]
+        );
+    }
+
+    #[test]
+    fn add_opt() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        let sync = WriteOptions {
+            sync: true,
+            ..WriteOptions::default()
+        };
+        let disable_wal = WriteOptions {
+            disable_wal: true,
+            ..WriteOptions::default()
+        };
+        database.add_opt("a", "synced", sync).unwrap();
+        database.add_opt("b", "cached", disable_wal).unwrap();
+        database.add_opt("a", "cached", disable_wal).unwrap();
+        assert_eq!(database.get("a").unwrap(), Some(b"cached".to_vec()));
+        assert_eq!(database.get("b").unwrap(), Some(b"cached".to_vec()));
+        assert_eq!(database.len(), 2);
+        assert_eq!(database.stream_wal_since(0, io::sink()).unwrap(), 1);
+
+        // Lost by a crash
+        std::mem::forget(database);
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get("a").unwrap(), Some(b"synced".to_vec()));
+        assert_eq!(database.get("b").unwrap(), None);
+        assert_eq!(database.len(), 1);
+
+        // But written to the dirty segment by a clean shutdown
+        database.add_opt("b", "cached", disable_wal).unwrap();
+        drop(database);
+        std::fs::remove_file(dir.path().join("memtable")).unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get("b").unwrap(), Some(b"cached".to_vec()));
+
+        // And by the flushes
+        database.add_opt("c", "cached", disable_wal).unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.get("c").unwrap(), Some(b"cached".to_vec()));
+        assert_eq!(database.len(), 3);
+    }
+
+    #[test]
+    fn get_with_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.add(b"doggo", b"tamo").unwrap();
+        database.flush().unwrap();
+        database.add(b"doggo", b"kefir").unwrap();
+
+        let expected = crc32fast::hash(b"kefir");
+        for key in [&b"tamo"[..], b"doggo"] {
+            let (value, checksum) = database.get_with_checksum(key).unwrap().unwrap();
+            assert_eq!(value, b"kefir");
+            assert_eq!(checksum, expected);
+        }
+        assert_eq!(database.get_with_checksum(b"kefir").unwrap(), None);
+        drop(database);
+
+        // Corrupt the value of the dirty segment
+        let path = dir.path().join("wal-000002");
+        let mut wal = std::fs::read(&path).unwrap();
+        let start = wal.windows(5).position(|w| w == b"kefir").unwrap();
+        wal[start] ^= 1;
+        std::fs::write(&path, wal).unwrap();
+
+        let mut database = Database::new(dir.path()).unwrap();
+        let error = database.get_with_checksum(b"doggo").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Corruption);
+        // The value of the segment is still fine
+        let (value, checksum) = database.get_with_checksum(b"tamo").unwrap().unwrap();
+        assert_eq!(value, b"kefir");
+        assert_eq!(checksum, expected);
+    }
+
+    #[test]
+    fn timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = ManualScheduler::new();
+        let open = || {
+            Database::builder()
+                .scheduler(scheduler.clone())
+                .timestamps(true)
+                .open(dir.path())
+                .unwrap()
+        };
+        let at = |secs| scheduler.set_time(UNIX_EPOCH + Duration::from_secs(secs));
+        let mut database = open();
+        at(1000);
+        database.add("a", "1").unwrap();
+        // The clock of the host went backward
+        at(500);
+        database.add("b", "2").unwrap();
+        assert_eq!(
+            database.get_with_timestamp("b").unwrap(),
+            Some((b"2".to_vec(), 1_000_000))
+        );
+        at(2000);
+        database.append("a", "1").unwrap();
+        assert_eq!(
+            database.get_with_timestamp("a").unwrap(),
+            Some((b"11".to_vec(), 2_000_000))
+        );
+
+        // Through the flushes and the merges
+        database.flush().unwrap();
+        database.add("c", "3").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        let timestamps: Vec<_> = ["a", "b", "c"]
+            .map(|key| database.get_with_timestamp(key).unwrap().unwrap().1)
+            .into();
+        assert_eq!(timestamps, [2_000_000, 1_000_000, 2_000_000]);
+
+        // The clock is restored by the manifest, the saved memtable and the dirty segment
+        std::mem::forget(database);
+        at(0);
+        let mut database = open();
+        database.add("d", "4").unwrap();
+        assert_eq!(
+            database.get_with_timestamp("d").unwrap().unwrap().1,
+            2_000_000
+        );
+        at(3000);
+        database.add("e", "5").unwrap();
+        drop(database);
+        at(0);
+        let mut database = open();
+        database.add("f", "6").unwrap();
+        assert_eq!(
+            database.get_with_timestamp("f").unwrap().unwrap().1,
+            3_000_000
+        );
+        std::mem::forget(database);
+        let mut database = open();
+        assert_eq!(
+            database.get_with_timestamp("e").unwrap().unwrap().1,
+            3_000_000
+        );
+        database.add("g", "7").unwrap();
+        assert_eq!(
+            database.get_with_timestamp("g").unwrap().unwrap().1,
+            3_000_000
+        );
+        drop(database);
+
+        // The entries written without timestamps have a timestamp of 0
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add("h", "8").unwrap();
+        assert_eq!(
+            database.get_with_timestamp("h").unwrap(),
+            Some((b"8".to_vec(), 0))
+        );
+        assert_eq!(
+            database.get_with_timestamp("g").unwrap().unwrap().1,
+            3_000_000
+        );
+    }
+
+    #[test]
+    fn open_compat() {
+        let dir = tempfile::tempdir().unwrap();
+        // The files of the first version, the entries are the sizes and content of the keys and values
+        let v0 = |name: &str, entries: &[(&str, &str)]| {
+            let mut content = Vec::new();
+            for (key, value) in entries {
+                for bytes in [key, value] {
+                    content.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                    content.extend_from_slice(bytes.as_bytes());
+                }
+            }
+            std::fs::write(dir.path().join(name), content).unwrap();
+        };
+        v0("segment-0", &[("a", "old"), ("b", "b")]);
+        v0("segment-1", &[("a", "new"), ("c", "c")]);
+        v0("dirty", &[("c", "dirty"), ("d", "d"), ("c", "latest")]);
+
+        assert!(matches!(
+            Database::new(dir.path()),
+            Err(Error::LegacyDatabase(_))
+        ));
+        let mut database = Database::open_compat(dir.path()).unwrap();
+        let entries: Vec<_> = database
+            .range_str::<&str>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [("a", "new"), ("b", "b"), ("c", "latest"), ("d", "d")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+        assert_eq!(database.len(), 4);
+        assert!(!dir.path().join("dirty").exists());
+
+        // Everything is written in the current format afterward
+        database.add("e", "e").unwrap();
+        database.flush().unwrap();
+        drop(database);
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get("a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(database.get("e").unwrap(), Some(b"e".to_vec()));
+        assert_eq!(database.len(), 5);
+        drop(database);
+        // And the current databases open as usual
+        let mut database = Database::open_compat(dir.path()).unwrap();
+        assert_eq!(database.get("c").unwrap(), Some(b"latest".to_vec()));
+    }
+
+    #[test]
+    fn saved_memtable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.delete(b"tamo").unwrap();
+        drop(database);
+        let saved = dir.path().join("memtable");
+        let stale = std::fs::read(&saved).unwrap();
+
+        let last_event = || {
+            let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+            let line = log.lines().rfind(|line| line.contains("open: ")).unwrap();
+            line.split_once(' ').unwrap().1.to_string()
+        };
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(
+            last_event(),
+            "open: 2 entries loaded from the saved memtable"
+        );
+        assert!(!saved.exists());
+        assert_eq!(database.get(b"hello").unwrap(), Some(b"world".to_vec()));
+        assert_eq!((database.sequence(), database.len()), (2, 1));
+        database.add(b"tamo", b"kefir").unwrap();
+        drop(database);
+
+        // The saved memtable doesn't match the dirty segment anymore
+        std::fs::write(&saved, stale).unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(
+            last_event(),
+            "open: 2 entries replayed from the dirty segment"
+        );
+        assert_eq!(database.get(b"tamo").unwrap(), Some(b"kefir".to_vec()));
+        assert_eq!((database.sequence(), database.len()), (3, 2));
+        drop(database);
+
+        let mut corrupted = std::fs::read(&saved).unwrap();
+        corrupted[20] ^= 1;
+        std::fs::write(&saved, corrupted).unwrap();
+        let database = Database::new(dir.path()).unwrap();
+        assert_eq!(
+            last_event(),
+            "open: 2 entries replayed from the dirty segment"
+        );
+        assert_eq!(database.len(), 2);
+    }
+
+    #[test]
+    fn replay_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(usize::MAX)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..3000_u32 {
+            database.add(i.to_be_bytes(), [0; 1024]).unwrap();
+        }
+        drop(database);
+        // As if the database wasn't closed cleanly
+        std::fs::remove_file(dir.path().join("memtable")).unwrap();
+
+        let mut reports = Vec::new();
+        let database = Database::builder()
+            .open_with_progress(dir.path(), |replayed, total| {
+                reports.push((replayed, total))
+            })
+            .unwrap();
+        let total = 3000 * (4 + 4 + 8 + 4 + 1024 + 4);
+        assert_eq!(reports.len(), 3);
+        assert!(reports.is_sorted());
+        assert!(reports
+            .iter()
+            .all(|(replayed, t)| *t == total && *replayed > 1 << 20));
+        assert_eq!(reports.last(), Some(&(total, total)));
+        assert_eq!(database.len(), 3000);
+    }
+
+    #[test]
+    fn wal_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal_files = || {
+            let files = std::fs::read_dir(dir.path()).unwrap();
+            files
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_str()
+                        .unwrap()
+                        .starts_with("wal-")
+                })
+                .count()
+        };
+        let open = || {
+            Database::builder()
+                .wal_max_size(100)
+                .open(dir.path())
+                .unwrap()
+        };
+
+        let mut database = open();
+        for i in 0..20_u32 {
+            database.add(i.to_be_bytes(), [i as u8; 10]).unwrap();
+        }
+        database.delete(3_u32.to_be_bytes()).unwrap();
+        assert!(wal_files() > 1);
+
+        drop(database);
+        let mut database = open();
+        assert_eq!(database.sequence(), 21);
+        assert_eq!(database.stats().open_files, wal_files());
+        for i in 0..20_u32 {
+            let value = database.get(i.to_be_bytes()).unwrap();
+            assert_eq!(value, (i != 3).then_some(vec![i as u8; 10]));
+        }
+
+        // The flushed entries don't need to be replayed anymore
+        database.flush().unwrap();
+        assert_eq!(wal_files(), 1);
+    }
+
+    #[test]
+    fn recover_orphaned_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"old").unwrap();
+        database.flush().unwrap();
+        let manifest = std::fs::read_to_string(dir.path().join("MANIFEST")).unwrap();
+        database.add(b"a", b"new").unwrap();
+        database.add(b"b", b"new").unwrap();
+        database.flush().unwrap();
+        drop(database);
+
+        // Crash before the manifest was updated, while writing a segment and a compaction
+        std::fs::write(dir.path().join("MANIFEST"), manifest).unwrap();
+        std::fs::write(dir.path().join("segment-7"), b"truncated").unwrap();
+        std::fs::write(dir.path().join(".tmp-abcdef"), b"temporary").unwrap();
+
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.segments.len(), 2);
+        assert_eq!(database.get(b"a").unwrap().as_deref(), Some(&b"new"[..]));
+        assert_eq!(database.get(b"b").unwrap().as_deref(), Some(&b"new"[..]));
+        // The writes of the adopted segments are more recent than the manifest
+        assert_eq!(database.sequence(), 3);
+        // The ids of the adopted segments aren't reused
+        database.add(b"c", b"c").unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.segments[2].id, 2);
+        drop(database);
+
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        let mut recovery: Vec<_> = log
+            .lines()
+            .filter_map(|line| line.split_once("recovery: "))
+            .map(|(_, event)| event.replace(&dir.path().display().to_string(), "{dir}"))
+            .collect();
+        recovery.sort();
+        insta::assert_debug_snapshot!(recovery, @r#"
+        [
+            "invalid orphaned segment {dir}/segment-7 deleted: failed to fill whole buffer",
+            "orphaned segment {dir}/segment-1 adopted",
+            "temporary file {dir}/.tmp-abcdef deleted",
+        ]
+        "#);
+
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.segments.len(), 3);
+        assert_eq!(database.get(b"c").unwrap().as_deref(), Some(&b"c"[..]));
+
+        std::fs::remove_file(dir.path().join("segment-0")).unwrap();
+        let error = Database::new(dir.path()).err().unwrap();
+        assert!(matches!(error, Error::MissingSegment(_)));
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn error_backtrace() {
+        let error = Error::from(io::Error::from(io::ErrorKind::NotFound));
+        let backtrace = std::error::request_ref::<std::backtrace::Backtrace>(&error);
+        assert!(backtrace.is_some());
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn segment_ids_never_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for key in [b"a", b"b"] {
+            database.add(key, key).unwrap();
+            database.flush().unwrap();
+        }
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments[0].id, 2);
+        drop(database);
+
+        // The manifest records the next id even if its segment is gone
+        let path = dir.path().join("MANIFEST");
+        let manifest = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, manifest.replace("# next id: 3", "# next id: 10")).unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"c", b"c").unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.segments[1].id, 10);
+
+        // A file in the way of a new segment isn't overwritten
+        let stray = dir.path().join("segment-11");
+        std::fs::write(&stray, "stray").unwrap();
+        database.add(b"d", b"d").unwrap();
+        let error = database.flush().unwrap_err();
+        assert!(matches!(&error, Error::SegmentExists(path) if *path == stray));
+        assert_eq!(error.kind(), ErrorKind::Corruption);
+        assert_eq!(std::fs::read_to_string(&stray).unwrap(), "stray");
+    }
+
+    #[test]
+    fn unfinished_compaction() {
+        // Reads the manifest while the compaction writes its segment
+        struct ReadManifest(PathBuf, Arc<std::sync::Mutex<Option<String>>>);
+
+        impl CompactionFilter for ReadManifest {
+            fn filter(&self, _key: &[u8], _value: &[u8]) -> CompactionDecision {
+                let mut manifest = self.1.lock().unwrap();
+                manifest.get_or_insert_with(|| std::fs::read_to_string(&self.0).unwrap());
+                CompactionDecision::Keep
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = Arc::default();
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .compaction_filter(ReadManifest(
+                dir.path().join("MANIFEST"),
+                Arc::clone(&manifest),
+            ))
+            .open(dir.path())
+            .unwrap();
+        let mut merged = Vec::new();
+        for key in [b"a", b"b"] {
+            database.add(key, key).unwrap();
+            database.flush().unwrap();
+            let path = database.segments.back().unwrap().path.clone();
+            merged.push((std::fs::read(&path).unwrap(), path));
+        }
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments[0].id, 2);
+        let output = database.segments[0].path.clone();
+        drop(database);
+        let pending = manifest.lock().unwrap().take().unwrap();
+        assert!(pending.contains("# pending compaction: 2"));
+        let manifest = std::fs::read_to_string(dir.path().join("MANIFEST")).unwrap();
+        assert!(!manifest.contains("# pending compaction"));
+
+        // Crash before the merged segments were replaced
+        std::fs::write(dir.path().join("MANIFEST"), pending).unwrap();
+        for (content, path) in merged {
+            std::fs::write(path, content).unwrap();
+        }
+        let mut database = Database::new(dir.path()).unwrap();
+        let ids: Vec<_> = database.segments.iter().map(|segment| segment.id).collect();
+        assert_eq!(ids, [0, 1]);
+        assert!(!output.exists());
+        assert_eq!(database.get(b"a").unwrap().as_deref(), Some(&b"a"[..]));
+        assert_eq!(database.len(), 2);
+        // The id of the deleted segment isn't reused
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments[0].id, 3);
+        drop(database);
+
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        let message = format!(
+            "recovery: segment {} of an unfinished compaction deleted",
+            output.display()
+        );
+        assert!(log.contains(&message));
+    }
+
+    #[test]
+    fn foreign_segment() {
+        let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"a").unwrap();
+        database.flush().unwrap();
+        let segment = database.segments[0].path.clone();
+        let orphan = database.layout.segment_path(dir.path(), 0, 5);
+        drop(database);
+
+        std::fs::write(
+            other_dir.path().join("IDENTITY"),
+            "00000000-0000-4000-8000-000000000001\n",
+        )
+        .unwrap();
+        let mut other = Database::new(other_dir.path()).unwrap();
+        assert_eq!(other.identity(), 0x4000_8000_0000_0000_0001);
+        other.add(b"b", b"b").unwrap();
+        other.flush().unwrap();
+        let other_segment = other.segments[0].path.clone();
+        let metadata = SegmentReader::open(&other_segment)
+            .unwrap()
+            .metadata()
+            .clone();
+        assert_eq!(metadata.database, Some(other.identity()));
+
+        // A segment copied by mistake is neither adopted nor deleted
+        std::fs::copy(&other_segment, &orphan).unwrap();
+        let error = Database::new(dir.path()).err().unwrap();
+        assert!(matches!(&error, Error::ForeignSegment(path) if *path == orphan));
+        assert!(orphan.exists());
+        std::fs::remove_file(&orphan).unwrap();
+
+        // Nor opened in place of a segment of the database
+        let copy = segment.with_extension("bak");
+        std::fs::rename(&segment, &copy).unwrap();
+        std::fs::copy(&other_segment, &segment).unwrap();
+        let error = Database::new(dir.path()).err().unwrap();
+        assert!(matches!(&error, Error::ForeignSegment(path) if *path == segment));
+
+        std::fs::rename(&copy, &segment).unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get(b"a").unwrap(), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::builder()
+            .dirty_thresholds(16)
+            .open(dir.path())
+            .unwrap();
+        let database = Arc::new(std::sync::Mutex::new(database));
+
+        let threads: Vec<_> = (0..4u8)
+            .map(|thread| {
+                let database = Arc::clone(&database);
+                std::thread::spawn(move || {
+                    for i in 0..100u8 {
+                        let key = [thread, i];
+                        let mut database = database.lock().unwrap();
+                        database.add(key, [i]).unwrap();
+                        assert_eq!(database.get(key).unwrap(), Some(vec![i]));
+                        match i % 25 {
+                            0 => database.flush().unwrap(),
+                            10 if database.segments.len() > 1 => database.merge_segment().unwrap(),
+                            20 => database.delete(key).unwrap(),
+                            _ => (),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut database = Arc::into_inner(database).unwrap().into_inner().unwrap();
+        assert_eq!(database.len(), 4 * 96);
+        let keys: Vec<_> = database
+            .keys::<&[u8]>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected: Vec<_> = (0..4u8)
+            .flat_map(|thread| (0..100u8).map(move |i| (thread, i)))
+            .filter(|(_, i)| i % 25 != 20)
+            .map(|(thread, i)| vec![thread, i])
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn open_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .open(dir.path())
+            .unwrap();
+        for i in 0..12u32 {
+            database.add(i.to_be_bytes(), b"value").unwrap();
+            database.flush().unwrap();
+        }
+        database
+            .delete_range(0u32.to_be_bytes(), 2u32.to_be_bytes())
+            .unwrap();
+        database.flush().unwrap();
+        let expected = database.stats();
+        drop(database);
+
+        for threads in [0, 1, 4, 64] {
+            let database = Database::builder()
+                .scheduler(ManualScheduler::new())
+                .open_threads(threads)
+                .open(dir.path())
+                .unwrap();
+            let stats = database.stats();
+            assert_eq!(stats.segment_entries, expected.segment_entries);
+            assert_eq!(stats.uncounted_segments, 0);
+            // The filters are loaded by the lookups
+            assert_eq!(stats.filter_bytes, 0);
+        }
+
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .preload_filters(true)
+            .open(dir.path())
+            .unwrap();
+        let filter_bytes = database.stats().filter_bytes;
+        assert!(filter_bytes > 0);
+        assert_eq!(database.get(1u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(
+            database.get(5u32.to_be_bytes()).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        assert_eq!(database.stats().filter_bytes, filter_bytes);
+    }
+
+    #[test]
+    fn quarantine_invalid_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            database.add(key, key).unwrap();
+            database.flush().unwrap();
+        }
+        drop(database);
+
+        // Corrupt the footer of the second segment
+        let path = dir.path().join("segment-1");
+        let mut segment = std::fs::read(&path).unwrap();
+        let len = segment.len();
+        segment[len - 30] ^= 1;
+        std::fs::write(&path, segment).unwrap();
+
+        // The corruption is only found by the lookups going through the segment
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get(b"c").unwrap().as_deref(), Some(&b"c"[..]));
+        let error = database.get(b"b").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Corruption);
+        drop(database);
+
+        let mut database = Database::builder()
+            .validate_segments(true)
+            .open(dir.path())
+            .unwrap();
+        let quarantined = dir.path().join("quarantine").join("segment-1");
+        assert_eq!(database.quarantined(), std::slice::from_ref(&quarantined));
+        assert!(quarantined.exists());
+        assert_eq!(database.segments.len(), 2);
+        assert_eq!(database.get(b"a").unwrap().as_deref(), Some(&b"a"[..]));
+        assert_eq!(database.get(b"b").unwrap(), None);
+        // The id of the quarantined segment isn't reused
+        database.add(b"d", b"d").unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.segments[2].id, 3);
+        drop(database);
+
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        let recovery: Vec<_> = log
+            .lines()
+            .filter_map(|line| line.split_once("recovery: "))
+            .map(|(_, event)| event.replace(&dir.path().display().to_string(), "{dir}"))
+            .collect();
+        insta::assert_debug_snapshot!(recovery, @r#"
+        [
+            "invalid segment {dir}/segment-1 quarantined to {dir}/quarantine/segment-1: corrupted segment footer, checksum mismatch",
+        ]
+        "#);
+
+        // The manifest doesn't list it anymore
+        let database = Database::new(dir.path()).unwrap();
+        assert!(database.quarantined().is_empty());
+        assert_eq!(database.segments.len(), 3);
+    }
+
+    #[test]
+    fn scrub() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            database.add(key, key).unwrap();
+            database.flush().unwrap();
+        }
+        assert!(database.scrub().unwrap().is_clean());
+        drop(database);
+
+        // Damage a data block of the first segment and the filter of the second one
+        let corrupt = |name: &str, position: fn(&[u8]) -> usize| {
+            let path = dir.path().join(name);
+            let mut segment = std::fs::read(&path).unwrap();
+            let position = position(&segment);
+            segment[position] ^= 1;
+            std::fs::write(&path, segment).unwrap();
+        };
+        corrupt("segment-0", |_| 2);
+        corrupt("segment-1", |segment| {
+            segment
+                .windows(5)
+                .position(|name| name == b"bloom")
+                .unwrap()
+                + 6
+        });
+
+        let mut database = Database::new(dir.path()).unwrap();
+        let report = database.scrub().unwrap();
+        let report = format!("{report:#?}").replace(&dir.path().display().to_string(), "{dir}");
+        insta::assert_snapshot!(report, @r#"
+        ScrubReport {
+            segments: 3,
+            blocks: 8,
+            bytes: 216,
+            repaired: [
+                "{dir}/segment-1",
+            ],
+            corrupted: [
+                (
+                    "{dir}/segment-0",
+                    "corrupted segment block at offset 0, checksum mismatch",
+                ),
+            ],
+        }
+        "#);
+        assert_eq!(database.segments[1].id, 3);
+        assert_eq!(database.get(b"b").unwrap().as_deref(), Some(&b"b"[..]));
+        assert!(database.get(b"a").is_err());
+        assert!(!dir.path().join("segment-1").exists());
+
+        // Only the damaged data block is still reported
+        let report = database.scrub().unwrap();
+        assert!(report.repaired.is_empty());
+        assert_eq!(report.corrupted.len(), 1);
+    }
+
+    #[test]
+    fn background_scrub() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = ManualScheduler::new();
+        let mut database = Database::builder()
+            .scheduler(scheduler.clone())
+            .background_scrub(Duration::from_secs(60), u64::MAX)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..10_u32 {
+            database.add(i.to_be_bytes(), b"value").unwrap();
+            database.flush().unwrap();
+        }
+        assert!(!database.background_scrub.as_ref().unwrap().is_running());
+
+        scheduler.advance(Duration::from_secs(60));
+        database.add(b"start", b"scrub").unwrap();
+        let report = loop {
+            database.add(b"poll", b"scrub").unwrap();
+            if let Some(report) = database.last_scrub() {
+                break report;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(report.segments, 10);
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn set_option() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = ManualScheduler::new();
+        let mut database = Database::builder()
+            .scheduler(scheduler.clone())
+            .open(dir.path())
+            .unwrap();
+        for i in 0..10_u32 {
+            database.add(i.to_be_bytes(), b"value").unwrap();
+            database.flush().unwrap();
+        }
+
+        database.set_option(Opt::BlockCacheSize(1024 * 1024));
+        database.get(0_u32.to_be_bytes()).unwrap();
+        database.get(0_u32.to_be_bytes()).unwrap();
+        assert!(database.stats().block_cache_hits > 0);
+        database.set_option(Opt::BlockCacheSize(0));
+        assert_eq!(database.stats().block_cache_bytes, 0);
+
+        database.set_option(Opt::WalMaxSize(1));
+        database.add(b"a", b"b").unwrap();
+        database.add(b"c", b"d").unwrap();
+        assert_eq!(database.dirty.file_count(), 2);
+
+        // The scrub would take hours at the first rate
+        database.set_option(Opt::BackgroundScrub(Some((Duration::from_secs(60), 1))));
+        scheduler.advance(Duration::from_secs(60));
+        database.add(b"start", b"scrub").unwrap();
+        assert!(database.background_scrub.as_ref().unwrap().is_running());
+        database.set_option(Opt::BackgroundScrub(Some((
+            Duration::from_secs(60),
+            u64::MAX,
+        ))));
+        let report = loop {
+            database.add(b"poll", b"scrub").unwrap();
+            if let Some(report) = database.last_scrub() {
+                break report;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(report.segments, 10);
+        database.set_option(Opt::BackgroundScrub(None));
+        assert!(database.background_scrub.is_none());
+    }
+
+    #[test]
+    fn export_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for keys in [[b"b", b"d"], [b"a", b"c"]] {
+            for key in keys {
+                database.add(key, key).unwrap();
+            }
+            database.flush().unwrap();
+        }
+        let exported = database.export_segments().unwrap();
+        let ranges: Vec<_> = exported
+            .iter()
+            .map(|segment| {
+                let keys = segment.key_range().unwrap();
+                let keys = (keys.start().as_slice(), keys.end().as_slice());
+                (keys, segment.seq_range().unwrap().clone())
+            })
+            .collect();
+        insta::assert_debug_snapshot!(ranges, @"
+        [
+            (
+                (
+                    [
+                        98,
+                    ],
+                    [
+                        100,
+                    ],
+                ),
+                1..=2,
+            ),
+            (
+                (
+                    [
+                        97,
+                    ],
+                    [
+                        99,
+                    ],
+                ),
+                3..=4,
+            ),
+        ]
+        ");
+
+        // The files stay available while they're exported even once they're merged
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments.len(), 1);
+        let paths: Vec<_> = exported.iter().map(|s| s.path().to_owned()).collect();
+        assert!(paths.iter().all(|path| path.exists()));
+        drop(exported);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn begin_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"a").unwrap();
+        database.flush().unwrap();
+        database.add(b"b", b"b").unwrap();
+        let backup = database.begin_backup().unwrap();
+        let paths: Vec<_> = backup.segments().map(Path::to_owned).collect();
+        assert_eq!(paths.len(), 2);
+
+        // The segments replaced meanwhile stay on disk until the backup is dropped
+        database.add(b"c", b"c").unwrap();
+        database.delete(b"a").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        assert!(paths.iter().all(|path| path.exists()));
+
+        let copy = tempfile::tempdir().unwrap();
+        backup.copy_to(copy.path()).unwrap();
+        drop(backup);
+        assert!(paths.iter().all(|path| !path.exists()));
+
+        let mut restored = Database::new(copy.path()).unwrap();
+        let entries: Vec<_> = restored
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), b"a".to_vec()),
+                (b"b".to_vec(), b"b".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn blob_dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = |dir: &Path| match std::fs::read_dir(dir.join("blobs")) {
+            Ok(entries) => entries.count(),
+            Err(_) => 0,
+        };
+        let mut database = Database::builder()
+            .blob_threshold(64)
+            .open(dir.path())
+            .unwrap();
+        let value = vec![7; 1000];
+        for key in [b"a", b"b", b"c"] {
+            database.add(key, &value).unwrap();
+        }
+        database.add(b"d", b"small").unwrap();
+        database.flush().unwrap();
+        assert_eq!(blobs(dir.path()), 1);
+        assert_eq!(database.get(b"b").unwrap(), Some(value.clone()));
+        assert_eq!(database.get(b"d").unwrap(), Some(b"small".to_vec()));
+        let keys: Vec<_> = (database.keys::<&[u8]>(..).unwrap())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(keys, [b"a", b"b", b"c", b"d"]);
+
+        // The blob stays while the backup reads the segment referencing it
+        let backup = database.begin_backup().unwrap();
+        assert_eq!(backup.blobs().count(), 1);
+        for key in [b"a", b"b", b"c"] {
+            database.delete(key).unwrap();
+        }
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        assert_eq!(blobs(dir.path()), 1);
+        let copy = tempfile::tempdir().unwrap();
+        backup.copy_to(copy.path()).unwrap();
+        drop(backup);
+        database.add(b"e", b"e").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        assert_eq!(blobs(dir.path()), 0);
+        assert_eq!(database.get(b"a").unwrap(), None);
+
+        let mut restored = Database::new(copy.path()).unwrap();
+        assert_eq!(restored.get(b"c").unwrap(), Some(value));
+    }
+
+    #[test]
+    fn value_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = |dir: &Path| -> Vec<String> {
+            let mut files: Vec<_> = std::fs::read_dir(dir.join("vlog"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        };
+        let builder = || Database::builder().value_log_threshold(64);
+        let mut database = builder().open(dir.path()).unwrap();
+        for (key, byte) in [(b"a", 1), (b"b", 2), (b"c", 3)] {
+            database.add(key, vec![byte; 1000]).unwrap();
+        }
+        database.add(b"d", b"small").unwrap();
+        database.flush().unwrap();
+        assert_eq!(files(dir.path()), ["000001"]);
+        assert_eq!(database.get(b"b").unwrap(), Some(vec![2; 1000]));
+        assert_eq!(database.get(b"d").unwrap(), Some(b"small".to_vec()));
+
+        // The compaction only moves the pointers
+        database.add(b"a", vec![4; 1000]).unwrap();
+        database.add(b"b", vec![5; 1000]).unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        let size = std::fs::metadata(&database.segments[0].path).unwrap().len();
+        assert!(size < 1000, "{size}");
+        assert_eq!(files(dir.path()), ["000001"]);
+
+        // Two of the five values are garbage
+        assert_eq!(database.collect_value_log(0.5).unwrap(), 0);
+        assert_eq!(files(dir.path()), ["000001"]);
+        assert_eq!(database.collect_value_log(0.3).unwrap(), 5 * 1008);
+        assert_eq!(files(dir.path()), ["000002"]);
+        drop(database);
+
+        let mut database = builder().open(dir.path()).unwrap();
+        let entries: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), vec![4; 1000]),
+                (b"b".to_vec(), vec![5; 1000]),
+                (b"c".to_vec(), vec![3; 1000]),
+                (b"d".to_vec(), b"small".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_values_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = Database::builder()
+            .blob_threshold(64)
+            .value_log_threshold(64)
+            .open(dir.path())
+            .err()
+            .unwrap();
+        insta::assert_snapshot!(error, @"The options `blob_threshold` and `value_log_threshold` can't be enabled together");
+        assert_eq!(error.kind(), ErrorKind::Other);
+
+        // Switching from one to the other keeps the values written before readable
+        let mut database = Database::builder()
+            .blob_threshold(64)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"a", vec![1; 1000]).unwrap();
+        database.flush().unwrap();
+        drop(database);
+        let mut database = Database::builder()
+            .value_log_threshold(64)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"b", vec![2; 1000]).unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.get(b"a").unwrap(), Some(vec![1; 1000]));
+        // The compactions move the blobs to the value log
+        database.merge_segment().unwrap();
+        assert_eq!(dir.path().join("blobs").read_dir().unwrap().count(), 0);
+        assert_eq!(database.get(b"a").unwrap(), Some(vec![1; 1000]));
+        drop(database);
+        let mut database = Database::builder()
+            .blob_threshold(64)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(database.get(b"a").unwrap(), Some(vec![1; 1000]));
+        assert_eq!(database.get(b"b").unwrap(), Some(vec![2; 1000]));
+    }
+
+    #[test]
+    fn import() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"b", b"old").unwrap();
+        let entries = [("a", "a"), ("b", "new"), ("c", "c")];
+        let imported = database.import(entries.map(Ok::<_, Error>)).unwrap();
+        assert_eq!(imported, 3);
+        assert_eq!(database.sequence(), 4);
+        assert_eq!(database.segments.len(), 2);
+        let entries: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), b"a".to_vec()),
+                (b"b".to_vec(), b"new".to_vec()),
+                (b"c".to_vec(), b"c".to_vec())
+            ]
+        );
+
+        // Nothing is added when the keys are out of order
+        let entries = [("d", "d"), ("d", "d")];
+        let error = database.import(entries.map(Ok::<_, Error>)).unwrap_err();
+        insta::assert_snapshot!(error, @"The imported keys must be sorted and unique, [100] is out of order");
+        assert_eq!(database.segments.len(), 2);
+        assert_eq!(database.get(b"d").unwrap(), None);
+    }
+
+    #[cfg(feature = "jsonl")]
+    #[test]
+    fn import_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        let lines = r#"{"key": "b", "value": "text"}
+{"key": "a", "value": {"nested": [1, 2]}}
+
+{"key": "b", "value": 3}
+"#;
+        assert_eq!(database.import_jsonl(lines.as_bytes()).unwrap(), 2);
+        assert_eq!(database.get(b"a").unwrap().unwrap(), br#"{"nested":[1,2]}"#);
+        assert_eq!(database.get(b"b").unwrap().unwrap(), b"3");
+
+        let error = database
+            .import_jsonl(&b"{\"key\": \"c\", \"value\": 1}\n{\"key\": 1}"[..])
+            .unwrap_err();
+        insta::assert_snapshot!(error, @"Invalid imported entry at line 2: the key isn't a string");
+        assert_eq!(database.get(b"c").unwrap(), None);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn import_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        let csv = "id,name,age\n2,bob,30\n1,alice,25\n";
+        assert_eq!(
+            database.import_csv(csv.as_bytes(), "name", "age").unwrap(),
+            2
+        );
+        let entries: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"alice".to_vec(), b"25".to_vec()),
+                (b"bob".to_vec(), b"30".to_vec())
+            ]
+        );
+
+        let error = database
+            .import_csv(csv.as_bytes(), "name", "email")
+            .unwrap_err();
+        insta::assert_snapshot!(error, @r#"The imported file has no column named "email""#);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn import_from_sled() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = sled::open(dir.path().join("sled")).unwrap();
+        for i in (0..100_u32).rev() {
+            source.insert(i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        drop(source);
+
+        let mut database = Database::new(dir.path().join("database")).unwrap();
+        // sled releases its lock once its background threads are done, a failed import adds nothing
+        let mut attempts = 0;
+        let imported = loop {
+            match database.import_from_sled(dir.path().join("sled")) {
+                Ok(imported) => break imported,
+                Err(e) if attempts == 500 => panic!("{e}"),
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+            attempts += 1;
+        };
+        assert_eq!(imported, 100);
+        let value = database.get(42_u32.to_be_bytes()).unwrap();
+        assert_eq!(value.as_deref(), Some(&42_u32.to_le_bytes()[..]));
+        assert!(database
+            .import_from_sled(dir.path().join("missing"))
+            .is_err());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn export_parquet() {
+        use arrow_array::{cast::AsArray, types::UInt32Type, RecordBatch, UInt32Array};
+        use arrow_schema::{DataType, Field, Schema};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..3_u32 {
+            database.add(i.to_be_bytes(), format!("value {i}")).unwrap();
+        }
+        database.flush().unwrap();
+        database.add(3_u32.to_be_bytes(), "value 3").unwrap();
+        database.delete(0_u32.to_be_bytes()).unwrap();
+
+        let read = |path: &Path| -> Vec<RecordBatch> {
+            let file = File::open(path).unwrap();
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+            reader.build().unwrap().map(Result::unwrap).collect()
+        };
+
+        let path = dir.path().join("export.parquet");
+        assert_eq!(database.export_parquet(&path, key_value_batch).unwrap(), 3);
+        let batch = &read(&path)[0];
+        assert_eq!(batch.schema().field(0).name(), "key");
+        let values: Vec<_> = batch
+            .column(1)
+            .as_binary::<i32>()
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(values, [&b"value 1"[..], b"value 2", b"value 3"]);
+
+        // The keys are mapped to integers and the values to strings
+        let mapped = dir.path().join("mapped.parquet");
+        let rows = database
+            .export_parquet(&mapped, |keys, values| {
+                let schema = Schema::new(vec![
+                    Field::new("id", DataType::UInt32, false),
+                    Field::new("name", DataType::Utf8, false),
+                ]);
+                let ids: UInt32Array = keys
+                    .iter()
+                    .map(|key| u32::from_be_bytes(key.unwrap().try_into().unwrap()))
+                    .collect();
+                let names = arrow_array::StringArray::try_from_binary(values)?;
+                RecordBatch::try_new(Arc::new(schema), vec![Arc::new(ids), Arc::new(names)])
+            })
+            .unwrap();
+        assert_eq!(rows, 3);
+        let batch = &read(&mapped)[0];
+        let ids: Vec<_> = batch
+            .column(0)
+            .as_primitive::<UInt32Type>()
+            .values()
+            .to_vec();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(batch.column(1).as_string::<i32>().value(2), "value 3");
+    }
+
+    #[test]
+    fn read_frozen_memtable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(1)
+            .keep_versions(2)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"a", b"old").unwrap();
+        database.add(b"a", b"new").unwrap();
+        database.add(b"b", b"b").unwrap();
+        // The memtable is being flushed in the background
+        assert!(database.frozen.is_some());
+        assert!(database.memtable.is_empty());
+        database.delete(b"b").unwrap();
+        // Waits for the previous flush and freezes the new memtable
+        database.add(b"c", b"c").unwrap();
+        assert_eq!(database.segments.len(), 1);
+
+        let check = |database: &mut Database| {
+            assert_eq!(database.get(b"a").unwrap().as_deref(), Some(&b"new"[..]));
+            assert_eq!(database.get(b"b").unwrap(), None);
+            let versions = database.versions(b"a").unwrap();
+            assert_eq!(
+                versions,
+                [(2, Some(b"new".to_vec())), (1, Some(b"old".to_vec()))]
+            );
+            let entries: Vec<_> = database
+                .range::<&[u8]>(..)
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect();
+            assert_eq!(entries, [b"a", b"c"]);
+        };
+        check(&mut database);
+
+        database.finish_flush().unwrap();
+        assert!(database.frozen.is_none());
+        assert_eq!(database.segments.len(), 2);
+        check(&mut database);
+    }
+
+    #[test]
+    fn freeze_memtable_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"a").unwrap();
+        database.add(b"b", b"b").unwrap();
+        // The first memtable is frozen, the second one holds the new writes
+        database.freeze().unwrap();
+        database.delete(b"a").unwrap();
+        database.add(b"c", b"c").unwrap();
+
+        let path = dir.path().join("memtable");
+        assert_eq!(database.freeze_memtable_to(&path).unwrap(), 2);
+        let reader = SegmentReader::open(&path).unwrap();
+        let entries: Vec<_> = reader
+            .iter(Bound::Unbounded)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.key, entry.seq, entry.value)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), 3, None),
+                (b"b".to_vec(), 2, Some(b"b".to_vec())),
+                (b"c".to_vec(), 4, Some(b"c".to_vec())),
+            ]
+        );
+        // The file isn't a segment of the database
+        database.flush().unwrap();
+        assert!(database.segments.iter().all(|segment| segment.path != path));
+        assert!(matches!(
+            database.freeze_memtable_to(&path),
+            Err(Error::SegmentExists(_))
+        ));
+    }
+
+    #[test]
+    fn range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.add(b"a", b"old").unwrap();
+        database.add(b"c", b"old").unwrap();
+        database.add(b"e", b"old").unwrap();
+        database.flush().unwrap();
+        database.add(b"b", b"segment").unwrap();
+        database.add(b"c", b"segment").unwrap();
+        database.flush().unwrap();
+        database.add(b"d", b"memtable").unwrap();
+        database.add(b"e", b"memtable").unwrap();
+
+        let entries: Vec<_> = database
+            .range(b"b".as_slice()..=b"e".as_slice())
+            .unwrap()
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                format!(
+                    "{}: {}",
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(value).unwrap()
+                )
+            })
+            .collect();
+        insta::assert_debug_snapshot!(entries, @r#"
+        [
+            "b: segment",
+            "c: segment",
+            "d: memtable",
+            "e: memtable",
+        ]
+        "#);
+
+        let all = database.range::<&[u8]>(..).unwrap().count();
+        assert_eq!(all, 5);
+    }
+
+    #[test]
+    fn empty_range_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"segment").unwrap();
+        database.flush().unwrap();
+        database.add(b"b", b"memtable").unwrap();
+
+        let (a, b) = (b"a".as_slice(), b"b".as_slice());
+        assert_eq!(database.range(b..a).unwrap().count(), 0);
+        assert_eq!(database.range(b..=a).unwrap().count(), 0);
+        let excluded = (Bound::Excluded(a.to_vec()), Bound::Excluded(a.to_vec()));
+        assert_eq!(database.range(excluded.clone()).unwrap().count(), 0);
+        assert_eq!(database.keys(b..a).unwrap().count(), 0);
+        assert_eq!(database.count_range(b..a).unwrap(), 0);
+        assert_eq!(database.count_range(excluded.clone()).unwrap(), 0);
+        assert_eq!(database.approximate_count_range(b..a).unwrap(), 0);
+        assert_eq!(
+            database.approximate_count_range(excluded.clone()).unwrap(),
+            0
+        );
+        assert_eq!(database.range(a..=a).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.schema(Schema::new(1));
+        for i in 0..100_u32 {
+            database.add(i.to_be_bytes(), [0; 1024]).unwrap();
+        }
+        database.flush().unwrap();
+        database.delete(10_u32.to_be_bytes()).unwrap();
+        database
+            .delete_range(12_u32.to_be_bytes(), 14_u32.to_be_bytes())
+            .unwrap();
+        database.add(13_u32.to_be_bytes(), b"memtable").unwrap();
+        database.add(100_u32.to_be_bytes(), b"memtable").unwrap();
+        // The values aren't upgraded, the keys are returned whatever their version
+        database.schema(Schema::new(2));
+
+        let keys: Vec<_> = database
+            .keys(8_u32.to_be_bytes()..16_u32.to_be_bytes())
+            .unwrap()
+            .map(|key| u32::from_be_bytes(key.unwrap().try_into().unwrap()))
+            .collect();
+        assert_eq!(keys, [8, 9, 11, 13, 14, 15]);
+        assert_eq!(database.keys::<&[u8]>(..).unwrap().count(), 99);
+    }
+
+    #[test]
+    fn range_filtered() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..1000_u32 {
+            database.add(i.to_be_bytes(), i.to_string()).unwrap();
+        }
+        database.flush().unwrap();
+        database.delete(10_u32.to_be_bytes()).unwrap();
+        database.add(12_u32.to_be_bytes(), b"memtable").unwrap();
+        database.add(13_u32.to_be_bytes(), b"memtable").unwrap();
+
+        let checked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let even = {
+            let checked = checked.clone();
+            move |key: &[u8]| {
+                let i = u32::from_be_bytes(key.try_into().unwrap());
+                checked.lock().unwrap().push(i);
+                i % 2 == 0
+            }
+        };
+        let entries: Vec<_> = database
+            .range_filtered(8_u32.to_be_bytes()..16_u32.to_be_bytes(), even)
+            .unwrap()
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                let i = u32::from_be_bytes(key.try_into().unwrap());
+                (i, String::from_utf8(value).unwrap())
+            })
+            .collect();
+        insta::assert_debug_snapshot!(entries, @r#"
+        [
+            (
+                8,
+                "8",
+            ),
+            (
+                12,
+                "memtable",
+            ),
+            (
+                14,
+                "14",
+            ),
+        ]
+        "#);
+        // The keys past the range aren't given to the filter
+        let checked = checked.lock().unwrap();
+        assert!(checked.iter().all(|i| (8..16).contains(i)));
+    }
+
+    #[test]
+    fn prefix_extractor() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"items/legacy", b"0").unwrap();
+        database.flush().unwrap();
+        database.add(b"users/0", b"0").unwrap();
+        database.flush().unwrap();
+        drop(database);
+
+        let mut database = Database::builder()
+            .prefix_extractor(PrefixExtractor::Delimiter(b'/'))
+            .open(dir.path())
+            .unwrap();
+        // The segment written without the extractor can't rule out any prefix
+        assert!(database.may_contain_prefix(b"orders/").unwrap());
+        database.merge_segment().unwrap();
+        assert!(!database.may_contain_prefix(b"orders/").unwrap());
+
+        database.add(b"items/1", b"1").unwrap();
+        database.add(b"items/2", b"2").unwrap();
+        database.flush().unwrap();
+        database.delete_range(b"items/", b"items/2").unwrap();
+        database.add(b"users/1", b"kefir").unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.segments.len(), 3);
+
+        assert!(database.may_contain_prefix(b"items/").unwrap());
+        assert!(database.may_contain_prefix(b"users/1").unwrap());
+        assert!(!database.may_contain_prefix(b"orders/").unwrap());
+        assert!(!database.may_contain_prefix(b"orders/42").unwrap());
+        // Without delimiter there is no prefix to look for
+        assert!(database.may_contain_prefix(b"orders").unwrap());
+        database.add(b"orders/1", b"1").unwrap();
+        assert!(database.may_contain_prefix(b"orders/").unwrap());
+
+        // The last segment is skipped but its range tombstone still applies
+        assert_eq!(
+            database.segments_without_prefix(b"items/").unwrap(),
+            [database.segments[2].id]
+        );
+        let keys: Vec<_> = database
+            .prefix(b"items/")
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys, [&b"items/2"[..], b"items/legacy"]);
+        let reader = SegmentReader::open(&database.segments[2].path).unwrap();
+        assert_eq!(
+            reader.metadata().filter.as_deref(),
+            Some("bloom+delimiter:47")
+        );
+    }
 
-fn read_entry_to_vec(reader: &mut impl Read) -> io::Result<Vec<u8>> {
-    let mut buf = Vec::new();
-    read_entry(reader, &mut buf)?;
-    Ok(buf)
-}
+    #[test]
+    fn prefix_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        for user_id in [1_u32, 2, 3] {
+            for timestamp in [10_u64, 20] {
+                let key = Key::new().push(user_id).push(timestamp);
+                database.add(key, format!("{user_id}-{timestamp}")).unwrap();
+            }
+            if user_id == 2 {
+                database.flush().unwrap();
+            }
+        }
+
+        let entries: Vec<_> = database
+            .prefix(Key::new().push(2_u32))
+            .unwrap()
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                let mut key = Key::decode(&key);
+                let (user_id, timestamp) = (key.read::<u32>().unwrap(), key.read::<u64>().unwrap());
+                (user_id, timestamp, String::from_utf8(value).unwrap())
+            })
+            .collect();
+        insta::assert_debug_snapshot!(entries, @r#"
+        [
+            (
+                2,
+                10,
+                "2-10",
+            ),
+            (
+                2,
+                20,
+                "2-20",
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn schema_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .identity(IDENTITY)
+            .open(dir.path())
+            .unwrap();
+        database.schema(Schema::new(1));
+
+        database.add(b"flushed", b"v1").unwrap();
+        database.flush().unwrap();
+        database.add(b"dirty", b"v1").unwrap();
+
+        // the application now stores its values in uppercase
+        database.schema(Schema::new(2).upgrade(1, |value| value.to_ascii_uppercase()));
+        database.add(b"new", b"V2").unwrap();
+        database.flush().unwrap();
+
+        assert_eq!(
+            database.get(b"flushed").unwrap().as_deref(),
+            Some(&b"V1"[..])
+        );
+        assert_eq!(database.get(b"dirty").unwrap().as_deref(), Some(&b"V1"[..]));
+        let values: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap().1)
+            .collect();
+        assert_eq!(values, [b"V1", b"V1", b"V2"]);
+
+        // the old segment still contains a value in the version 1 until it's compacted
+        database.merge_segment().unwrap();
+        insta::assert_snapshot!(database.dump().unwrap(), @"
+        memtable:
+        {}
+        dirty segment:
+        []
+        segment 0:
+        [217, 65, 87, 77, 0, 5, 100, 105, 114, 116, 121, 2, 0, 0, 9, 0, 0, 0, 2, 86, 49, 86, 21, 169, 118, 0, 7, 102, 108, 117, 115, 104, 101, 100, 1, 0, 0, 9, 0, 0, 0, 2, 86, 49, 107, 227, 68, 69, 0, 3, 110, 101, 119, 3, 0, 0, 9, 0, 0, 0, 2, 86, 50, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 48, 9, 48, 3, 232, 0, 198, 64, 6, 217, 65, 87, 77, 0, 5, 100, 105, 114, 116, 121, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 71, 183, 139, 176, 101, 0, 0, 0, 0, 0, 0, 0, 1, 217, 65, 87, 77, 0, 5, 100, 105, 114, 116, 121, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 89, 0, 0, 0, 39, 70, 230, 138, 191, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 128, 0, 0, 0, 39, 217, 36, 136, 167, 0, 0, 0, 0, 0, 0, 0, 71, 0, 0, 0, 18, 155, 128, 199, 121, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 18, 0, 0, 0, 0, 0, 0, 0, 0, 106, 27, 60, 93, 126, 159, 74, 43, 140, 77, 94, 111, 122, 139, 156, 13, 150, 39, 209, 228, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]
+        ");
+    }
+
+    #[test]
+    fn bounded_open_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.max_open_files(2);
+
+        for i in 0..5_u8 {
+            database.add([i], [i]).unwrap();
+            database.flush().unwrap();
+        }
+        for i in 0..5_u8 {
+            assert_eq!(database.get([i]).unwrap(), Some(vec![i]));
+        }
+        // two segments + the dirty segment
+        assert_eq!(database.stats().open_files, 3);
+
+        database.max_open_files(1);
+        assert_eq!(database.stats().open_files, 2);
+    }
+
+    #[test]
+    fn nested_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .layout(Layout::nested().prefix("kv-"))
+            .open(dir.path())
+            .unwrap();
+
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        database.add(b"a", b"b").unwrap();
+        database.flush().unwrap();
+
+        let mut files: Vec<_> = walk(dir.path())
+            .map(|path| path.strip_prefix(dir.path()).unwrap().display().to_string())
+            .collect();
+        files.sort();
+        insta::assert_debug_snapshot!(files, @r#"
+        [
+            "kv-IDENTITY",
+            "kv-LOG",
+            "kv-MANIFEST",
+            "segments/L0/kv-segment-3",
+            "segments/L1/kv-segment-2",
+            "wal/kv-wal-000004",
+        ]
+        "#);
+        assert_eq!(
+            database.get(b"tamo").unwrap().as_deref(),
+            Some(&b"kefir"[..])
+        );
+    }
+
+    fn walk(dir: &Path) -> Box<dyn Iterator<Item = PathBuf>> {
+        Box::new(std::fs::read_dir(dir).unwrap().flat_map(|entry| {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(&path)
+            } else {
+                Box::new(std::iter::once(path))
+            }
+        }))
+    }
+
+    #[test]
+    fn compaction_filter() {
+        struct Expire;
+
+        impl CompactionFilter for Expire {
+            fn filter(&self, _key: &[u8], value: &[u8]) -> CompactionDecision {
+                match value {
+                    b"expired" => CompactionDecision::Remove,
+                    b"lower" => CompactionDecision::Replace(b"LOWER".to_vec()),
+                    _ => CompactionDecision::Keep,
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .compaction_filter(Expire)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"a", b"expired").unwrap();
+        database.add(b"b", b"expired").unwrap();
+        database.add(b"c", b"lower").unwrap();
+        database.flush().unwrap();
+        database.add(b"d", b"expired").unwrap();
+        database.add(b"e", b"kept").unwrap();
+        database.flush().unwrap();
+        // More recent than the compacted segments
+        database.add(b"b", b"written again").unwrap();
+        database.flush().unwrap();
+        database.add(b"d", b"written again").unwrap();
+        // The flushes don't filter the entries
+        assert_eq!(
+            database.get(b"a").unwrap().as_deref(),
+            Some(&b"expired"[..])
+        );
+        assert_eq!(database.len(), 5);
+
+        database.merge_segment().unwrap();
+        let entries: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                format!("{}: {}", key.escape_ascii(), value.escape_ascii())
+            })
+            .collect();
+        insta::assert_debug_snapshot!(entries, @r#"
+        [
+            "b: written again",
+            "c: LOWER",
+            "d: written again",
+            "e: kept",
+        ]
+        "#);
+        assert_eq!(database.len(), 4);
+
+        // The count stays exact once the remaining segments are merged
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        database.merge_segment().unwrap();
+        assert_eq!(database.len(), 4);
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        assert!(!log.contains("reconciled"), "{log}");
+    }
+
+    #[test]
+    fn pinned_keys() {
+        struct Expire;
+
+        impl CompactionFilter for Expire {
+            fn filter(&self, _key: &[u8], value: &[u8]) -> CompactionDecision {
+                match value {
+                    b"expired" => CompactionDecision::Remove,
+                    _ => CompactionDecision::Keep,
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let builder = || {
+            Database::builder()
+                .scheduler(ManualScheduler::new())
+                .compaction_filter(Expire)
+        };
+        let mut database = builder().open(dir.path()).unwrap();
+        assert!(database.pin(b"a").unwrap());
+        assert!(!database.pin(b"a").unwrap());
+        assert!(database.pin_range(b"c", b"e").unwrap());
+        for key in [&b"a"[..], b"aa", b"b", b"c", b"d", b"e"] {
+            database.add(key, b"expired").unwrap();
+            database.flush().unwrap();
+        }
+        drop(database);
+
+        // The pins survive the restarts
+        let mut database = builder().open(dir.path()).unwrap();
+        let pinned: Vec<_> = (database.pinned_ranges().iter())
+            .map(|range| {
+                format!(
+                    "{}..{}",
+                    range.start.escape_ascii(),
+                    range.end.escape_ascii()
+                )
+            })
+            .collect();
+        assert_eq!(pinned, ["a..a\\x00", "c..e"]);
+        assert!(database.is_pinned(b"d"));
+        assert!(!database.is_pinned(b"aa"));
+        while database.segments.len() > 1 {
+            database.merge_segment().unwrap();
+        }
+        let keys: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys, [&b"a"[..], b"c", b"d"]);
+        assert_eq!(database.len(), 3);
+
+        assert!(database.unpin_range(b"c", b"e").unwrap());
+        assert!(!database.unpin(b"c").unwrap());
+        database.add(b"f", b"kept").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        let keys: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys, [&b"a"[..], b"f"]);
+    }
+
+    #[test]
+    fn write_hooks() {
+        #[derive(Default, Clone)]
+        struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl WriteHook for Recorder {
+            fn before_write(&self, event: &WriteEvent) {
+                let key = event.key.escape_ascii();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("before {key} {}", event.seq));
+            }
+
+            fn after_write(&self, event: &WriteEvent) {
+                let (key, end) = (event.key.escape_ascii(), event.range_end);
+                let end = end.map(|end| end.escape_ascii().to_string());
+                let size = event.value_size;
+                let event = format!("after {key} {end:?} {size:?} {}", event.seq);
+                self.0.lock().unwrap().push(event);
+            }
+
+            fn after_flush(&self, segment: usize, _size: u64) {
+                self.0.lock().unwrap().push(format!("flush {segment}"));
+            }
+
+            fn after_compaction(&self, segment: usize, _size: u64) {
+                self.0.lock().unwrap().push(format!("compaction {segment}"));
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder::default();
+        let mut database = Database::builder()
+            .scheduler(ManualScheduler::new())
+            .hook(recorder.clone())
+            .open(dir.path())
+            .unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.append(b"tamo", b"!").unwrap();
+        database.delete(b"doggo").unwrap();
+        database.flush().unwrap();
+        database.delete_range(b"a", b"b").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        // A rejected write isn't told to the hooks
+        database.limits.key = 2;
+        database.add(b"tamo", b"kefir").unwrap_err();
+
+        let events = recorder.0.lock().unwrap().clone();
+        insta::assert_debug_snapshot!(events, @r#"
+        [
+            "before tamo 1",
+            "after tamo None Some(5) 1",
+            "before tamo 2",
+            "after tamo None Some(1) 2",
+            "before doggo 3",
+            "after doggo None None 3",
+            "flush 0",
+            "before a 4",
+            "after a Some(\"b\") None 4",
+            "flush 1",
+            "compaction 2",
+        ]
+        "#);
+    }
+
+    #[test]
+    fn describe() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layout::nested().prefix("kv-");
+        let mut database = Database::builder()
+            .layout(layout.clone())
+            .open(dir.path())
+            .unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        database.add(b"doggo", b"tamo").unwrap();
+        database.flush().unwrap();
+        database.add(b"kefir", b"tamo").unwrap();
+
+        let description = Database::builder()
+            .layout(layout)
+            .describe(dir.path())
+            .unwrap();
+        assert_eq!(description.segments.len(), 2);
+        let metadata = description.segments[0].metadata.as_ref().unwrap();
+        assert_eq!(metadata.format_version, 25);
+        insta::assert_snapshot!(description, @r#"
+        2 segments, 615 bytes on disk, 3 live keys
+        dirty segment: 1 files, 29 bytes
+        level 0: 1 segments, 283 bytes
+        level 1: 1 segments, 303 bytes
+          segment 2 (level 1, 303 bytes): format version 25, 2 entries, keys "hello"..="tamo"
+          segment 3 (level 0, 283 bytes): format version 25, 1 entries, keys "doggo"..="doggo"
+        "#);
+
+        // The default layout finds no segment in the nested directories
+        let description = Database::describe(dir.path()).unwrap();
+        assert!(description.segments.is_empty());
+    }
+
+    #[test]
+    fn events_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        // remove the timestamps
+        let log: Vec<_> = log
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        insta::assert_debug_snapshot!(log, @r#"
+        [
+            "open: 0 entries replayed from the dirty segment",
+            "flush: 1 entries written to segment 0 (284 bytes)",
+            "flush: 1 entries written to segment 1 (281 bytes)",
+            "compaction: segments 0 (284 bytes), 1 (281 bytes) merged into segment 2 (303 bytes), write amplification 45.68",
+        ]
+        "#);
+    }
+
+    #[test]
+    fn filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in (0..=2000_u32).step_by(2) {
+            database.add(i.to_be_bytes(), b"value").unwrap();
+        }
+        database.flush().unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+
+        assert_eq!(
+            database.get(b"hello").unwrap().as_deref(),
+            Some(&b"world"[..])
+        );
+        for i in (1..2000_u32).step_by(2) {
+            assert_eq!(database.get(i.to_be_bytes()).unwrap(), None);
+        }
+        let stats = database.stats();
+        assert_eq!(stats.filter_positives, 1);
+        // The absent keys are outside of the last segment and only go through the filter of the first one,
+        // the write of `hello` looked it up outside of the first segment
+        assert_eq!(stats.fence_negatives, 1000 + 1);
+        assert_eq!(stats.filter_negatives + stats.filter_false_positives, 1000);
+        assert!(stats.filter_false_positive_rate() < 0.05);
+
+        // Without filter all the segments are read
+        drop(database);
+        let mut database = Database::builder()
+            .without_filter()
+            .open(dir.path())
+            .unwrap();
+        database.add(b"hello", b"world").unwrap();
+        database.flush().unwrap();
+        assert_eq!(
+            database.get(b"hello").unwrap().as_deref(),
+            Some(&b"world"[..])
+        );
+        assert_eq!(database.get(b"absent").unwrap(), None);
+        assert_eq!(database.stats().filter_negatives, 0);
+    }
+
+    #[test]
+    fn database_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .database_filter(true)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..20_u32 {
+            database.add(i.to_be_bytes(), b"value").unwrap();
+            database.flush().unwrap();
+        }
+        database.delete(0_u32.to_be_bytes()).unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+
+        for i in 0..20_u32 {
+            let expected = (i != 0).then(|| b"value".to_vec());
+            assert_eq!(database.get(i.to_be_bytes()).unwrap(), expected);
+        }
+        for i in 20..1000_u32 {
+            assert_eq!(database.get(i.to_be_bytes()).unwrap(), None);
+        }
+        let stats = database.stats();
+        assert!(stats.database_filter_negatives > 950);
+        // Only the false positives of the database filter reach the segments filters
+        assert!(stats.filter_negatives + stats.filter_false_positives < 1000);
+    }
+
+    #[test]
+    fn block_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .block_cache_size(64 * 1024)
+            .compressed_block_cache_size(1024 * 1024)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..10_000_u32 {
+            database.add(i.to_be_bytes(), [i as u8; 100]).unwrap();
+        }
+        database.flush().unwrap();
+
+        // The segment doesn't fit in the block cache, the second scan is served by the compressed cache
+        for _ in 0..2 {
+            for i in 0..10_000_u32 {
+                let value = database.get(i.to_be_bytes()).unwrap();
+                assert_eq!(value, Some(vec![i as u8; 100]));
+            }
+        }
+        let stats = database.stats();
+        assert!(stats.block_cache_bytes <= 64 * 1024);
+        assert!(stats.compressed_block_cache_bytes < 1024 * 1024);
+        assert!(stats.compressed_block_cache_hit_rate() > 0.4);
+        let compressed_hits = stats.compressed_block_cache_hits;
+
+        // The hot blocks stay in the block cache
+        for _ in 0..10 {
+            for i in 0..100_u32 {
+                database.get(i.to_be_bytes()).unwrap();
+            }
+        }
+        let stats = database.stats();
+        assert!(stats.block_cache_hit_rate() > 0.5);
+        assert!(stats.compressed_block_cache_hits - compressed_hits < 10);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(usize::MAX)
+            .block_cache_size(1024 * 1024)
+            .memory_budget(64 * 1024)
+            .open(dir.path())
+            .unwrap();
+        // The memtable is flushed when it would exceed the budget
+        for i in 0..10_000_u32 {
+            database.add(i.to_be_bytes(), [i as u8; 100]).unwrap();
+            assert!(database.stats().memory_bytes <= 64 * 1024);
+        }
+        assert!(database.segments.len() > 1);
+
+        // The block cache only gets what's left by the memtable and the filters
+        for i in 0..10_000_u32 {
+            let value = database.get(i.to_be_bytes()).unwrap();
+            assert_eq!(value, Some(vec![i as u8; 100]));
+        }
+        let stats = database.stats();
+        assert!(stats.filter_bytes > 0 && stats.block_cache_bytes > 0);
+        assert_eq!(
+            stats.memory_bytes,
+            stats.memtable_bytes + stats.filter_bytes + stats.block_cache_bytes
+        );
+        assert!(stats.memory_bytes <= 64 * 1024);
+        drop(database);
+
+        // The write is rejected once there is nothing left to flush
+        let mut database = Database::builder()
+            .memory_budget(10)
+            .open(dir.path())
+            .unwrap();
+        let err = database.add(b"hello", b"world").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::MemoryBudgetExceeded { budget: 10, .. }
+        ));
+        assert_eq!(database.get(b"hello").unwrap(), None);
+    }
+
+    #[test]
+    fn uncached_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .uncached_compaction(true)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..3_u32 {
+            database.add(i.to_be_bytes(), i.to_le_bytes()).unwrap();
+            database.flush().unwrap();
+        }
+        database.merge_segment().unwrap();
+        database.merge_segment().unwrap();
+
+        for i in 0..3_u32 {
+            let value = database.get(i.to_be_bytes()).unwrap();
+            assert_eq!(value.as_deref(), Some(&i.to_le_bytes()[..]));
+        }
+    }
+
+    #[test]
+    fn convert_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .encoding(Encoding::Fixed)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..2_u32 {
+            database.add(i.to_be_bytes(), i.to_le_bytes()).unwrap();
+            database.flush().unwrap();
+        }
+        let fixed = std::fs::metadata(&database.segments[1].path).unwrap().len();
+
+        // The compaction rewrites the fixed segments with varints
+        database.encoding = Encoding::Varint;
+        database.merge_segment().unwrap();
+        let segment = std::fs::read(&database.segments[0].path).unwrap();
+        let version = &segment[segment.len() - 12..segment.len() - 8];
+        assert_eq!(version, 25_u32.to_be_bytes());
+        assert!((segment.len() as u64) < 2 * fixed);
+
+        for i in 0..2_u32 {
+            let value = database.get(i.to_be_bytes()).unwrap();
+            assert_eq!(value.as_deref(), Some(&i.to_le_bytes()[..]));
+        }
+    }
+
+    #[test]
+    fn block_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(2000)
+            .block_size(64)
+            .index_block_size(64)
+            .restart_interval(1)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..1000_u32 {
+            database.add(i.to_be_bytes(), i.to_le_bytes()).unwrap();
+        }
+        database.flush().unwrap();
+        let small = std::fs::metadata(&database.segments[0].path).unwrap().len();
+        drop(database);
+
+        // The segment stays readable with the default sizes
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..1000_u32 {
+            let value = database.get(i.to_be_bytes()).unwrap();
+            assert_eq!(value.as_deref(), Some(&i.to_le_bytes()[..]));
+        }
+        assert_eq!(database.range::<&[u8]>(..).unwrap().count(), 1000);
+
+        // And the compaction rewrites it with larger blocks and fewer restart points
+        database.add(b"other", b"segment").unwrap();
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        let default = std::fs::metadata(&database.segments[0].path).unwrap().len();
+        assert!(default < small, "{default} {small}");
+    }
+
+    #[test]
+    fn space_amplification() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(20_000)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(database.space_amplification().unwrap().ratio(), 1.0);
+
+        for value in [b"old", b"new"] {
+            for i in 0..10_000_u32 {
+                database.add(i.to_be_bytes(), value).unwrap();
+            }
+            database.flush().unwrap();
+        }
+        // The first segment is fully overwritten by the second one
+        let amplification = database.space_amplification().unwrap();
+        let ratio = amplification.ratio();
+        assert!((1.8..2.2).contains(&ratio), "{amplification:?}");
+
+        database.merge_segment().unwrap();
+        let amplification = database.space_amplification().unwrap();
+        assert_eq!(amplification.live_bytes, amplification.total_bytes);
+
+        // Half the keys are deleted
+        for i in 0..5_000_u32 {
+            database.delete(i.to_be_bytes()).unwrap();
+        }
+        let ratio = database.space_amplification().unwrap().ratio();
+        assert!((1.6..2.4).contains(&ratio), "{ratio}");
+    }
+
+    #[test]
+    fn merge_single_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.merge_segment().unwrap();
+        database.add(b"a", b"a").unwrap();
+        database.flush().unwrap();
+        let manifest = std::fs::read_to_string(dir.path().join("MANIFEST")).unwrap();
+
+        // Nothing to merge, no compaction is recorded
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments.len(), 1);
+        let unchanged = std::fs::read_to_string(dir.path().join("MANIFEST")).unwrap();
+        assert_eq!(unchanged, manifest);
+        assert_eq!(database.get(b"a").unwrap().as_deref(), Some(&b"a"[..]));
+        database.add(b"b", b"b").unwrap();
+        database.merge_segment().unwrap();
+    }
+
+    #[test]
+    fn compaction_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(20_000)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..10_000_u32 {
+            database.add(i.to_be_bytes(), b"old").unwrap();
+        }
+        database.flush().unwrap();
+        assert_eq!(database.compaction_plan().unwrap(), None);
+
+        // Half the keys of the first segment are overwritten by the second one
+        for i in 5_000..15_000_u32 {
+            database.add(i.to_be_bytes(), b"new").unwrap();
+        }
+        database.flush().unwrap();
+        let plan = database.compaction_plan().unwrap().unwrap();
+        assert_eq!(plan.segments, [0, 1]);
+        let sizes: u64 = (database.segments.iter())
+            .map(|segment| std::fs::metadata(&segment.path).unwrap().len())
+            .sum();
+        assert_eq!(plan.input_bytes, sizes);
+
+        // Nothing is merged by the plan
+        assert_eq!(database.segments.len(), 2);
+        database.merge_segment().unwrap();
+        let merged = std::fs::metadata(&database.segments[0].path).unwrap().len();
+        let ratio = plan.estimated_output_bytes as f64 / merged as f64;
+        assert!((0.9..1.1).contains(&ratio), "{plan:?} {merged}");
+        let reclaimed = plan.reclaimed_bytes() as f64 / (sizes - merged) as f64;
+        assert!((0.8..1.2).contains(&reclaimed), "{plan:?} {merged}");
+    }
+
+    #[test]
+    fn warm_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(20_000)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..10_000_u32 {
+            database.add(i.to_be_bytes(), b"value").unwrap();
+        }
+        database.flush().unwrap();
+        drop(database);
+
+        let builder = || Database::builder().block_cache_size(1024 * 1024);
+        let mut database = builder().open(dir.path()).unwrap();
+        let read = database
+            .warm_cache(
+                1_000_u32.to_be_bytes()..2_000_u32.to_be_bytes(),
+                1024 * 1024,
+            )
+            .unwrap();
+        assert!(read > 0);
+        let misses = database.stats().block_cache_misses;
+        for i in 1_000..2_000_u32 {
+            database.get(i.to_be_bytes()).unwrap().unwrap();
+        }
+        assert_eq!(database.stats().block_cache_misses, misses);
+        // The blocks out of the range weren't read
+        database.get(5_000_u32.to_be_bytes()).unwrap().unwrap();
+        assert!(database.stats().block_cache_misses > misses);
+
+        // Nothing is read past the budget
+        let mut database = builder().open(dir.path()).unwrap();
+        let read = database.warm_cache::<&[u8]>(.., 10_000).unwrap();
+        assert!(read > 0 && read <= 10_000, "{read}");
+    }
+
+    #[test]
+    fn prefix_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .dirty_thresholds(20_000)
+            .open(dir.path())
+            .unwrap();
+        for (tenant, count) in [("a/", 2_000), ("b/", 6_000)] {
+            for i in 0..count {
+                database
+                    .add(format!("{tenant}{i:05}"), b"0123456789")
+                    .unwrap();
+            }
+        }
+        database.flush().unwrap();
+        for i in 0..2_000 {
+            database.add(format!("b/{i:05}"), b"0123456789").unwrap();
+        }
+        database.flush().unwrap();
 
-fn read_bytes(reader: &mut impl Read, size: usize, buf: &mut Vec<u8>) -> io::Result<()> {
-    buf.reserve(size);
-    unsafe {
-        // TODO: probably not safe since I didn't initialize the u8 in it
-        buf.set_len(size);
+        let stats = database.prefix_stats("a/").unwrap();
+        assert!((1_800..2_200).contains(&stats.entries), "{stats:?}");
+        assert!((18_000..22_000).contains(&stats.value_bytes), "{stats:?}");
+        // Both versions of the keys overwritten are counted
+        let stats = database.prefix_stats("b/").unwrap();
+        assert!((7_200..8_800).contains(&stats.entries), "{stats:?}");
+        assert!(stats.disk_bytes > 0);
+        assert_eq!(database.prefix_stats("c/").unwrap(), PrefixStats::default());
     }
-    reader.read_exact(buf)?;
-    Ok(())
-}
 
-fn skip_entry(reader: &mut impl Read) -> io::Result<()> {
-    let size = read_u32(reader)?;
-    // we can't Seek thus we're throw away everything we've read
-    io::copy(&mut reader.by_ref().take(size as u64), &mut io::sink())?;
-    Ok(())
-}
+    #[test]
+    fn count_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..10_000_u32 {
+            database.add(i.to_be_bytes(), b"0123456789").unwrap();
+        }
+        database.flush().unwrap();
+        for i in (0..1_000_u32).step_by(2) {
+            database.delete(i.to_be_bytes()).unwrap();
+        }
+        database
+            .delete_range(2_000_u32.to_be_bytes(), 3_000_u32.to_be_bytes())
+            .unwrap();
+        database.add(2_500_u32.to_be_bytes(), b"again").unwrap();
 
-fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
-    let mut u32_buf = [0; 4];
-    reader.read_exact(&mut u32_buf)?;
-    let n = u32::from_be_bytes(u32_buf);
-    Ok(n)
-}
+        let (start, end) = (0_u32.to_be_bytes(), 5_000_u32.to_be_bytes());
+        assert_eq!(database.count_range(start..end).unwrap(), 3_501);
+        assert_eq!(database.count_range(start..=start).unwrap(), 0);
+        assert_eq!(database.count_range::<&[u8]>(..).unwrap(), database.len());
+        database.flush().unwrap();
+        assert_eq!(database.count_range(start..end).unwrap(), 3_501);
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        let (start, end) = (5_000_u32.to_be_bytes(), 9_000_u32.to_be_bytes());
+        let approximate = database.approximate_count_range(start..end).unwrap();
+        assert!((3_600..4_400).contains(&approximate), "{approximate}");
+        let past = 20_000_u32.to_be_bytes();
+        assert_eq!(database.approximate_count_range(past..).unwrap(), 0);
+    }
 
     #[test]
-    fn insert_and_get() {
+    fn range_across_compaction() {
         let dir = tempfile::tempdir().unwrap();
         let mut database = Database::new(dir.path()).unwrap();
+        for i in 0..2_u32 {
+            database.add(i.to_be_bytes(), b"old").unwrap();
+            database.flush().unwrap();
+        }
+        let replaced = database.segments[1].path.clone();
+        let range = database.range::<&[u8]>(..).unwrap();
 
-        database.add(b"hello", b"world").unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {[104, 101, 108, 108, 111]: 0}
-        dirty segment:
-        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100]
-        "###);
+        database.merge_segment().unwrap();
+        // The id of the replaced segment isn't reused
+        database.add(0_u32.to_be_bytes(), b"new").unwrap();
+        database.flush().unwrap();
+        assert!(replaced.exists());
 
-        let v = database.get(b"hello").map_err(|e| println!("{e}")).unwrap();
-        assert_eq!(v.as_deref(), Some(&b"world"[..]));
-        let v = database.get(b"hemlo").map_err(|e| println!("{e}")).unwrap();
-        assert_eq!(v.as_deref(), None);
-        assert_eq!(v.as_deref(), None);
+        let entries: Vec<_> = range.map(Result::unwrap).collect();
+        assert_eq!(
+            entries,
+            [
+                (0_u32.to_be_bytes().to_vec(), b"old".to_vec()),
+                (1_u32.to_be_bytes().to_vec(), b"old".to_vec()),
+            ]
+        );
+        assert!(!replaced.exists());
     }
 
     #[test]
-    fn empty_key() {
+    fn multi_get() {
         let dir = tempfile::tempdir().unwrap();
         let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"old").unwrap();
+        database.add(b"b", b"b").unwrap();
+        database.flush().unwrap();
+        database.add(b"a", b"new").unwrap();
+        database.delete(b"b").unwrap();
+        database.add(b"c", b"c").unwrap();
+        database.flush().unwrap();
+        database.add(b"d", b"d").unwrap();
 
-        database.add(b"", b"riengue").unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {[]: 0}
-        dirty segment:
-        [0, 0, 0, 0, 0, 0, 0, 7, 114, 105, 101, 110, 103, 117, 101]
-        "###);
+        let values = database
+            .multi_get(&[&b"d"[..], b"c", b"b", b"a", b"e"])
+            .unwrap();
+        let values: Vec<_> = values.iter().map(Option::as_deref).collect();
+        assert_eq!(
+            values,
+            [Some(&b"d"[..]), Some(b"c"), None, Some(b"new"), None]
+        );
+    }
 
-        let v = database.get(b"").map_err(|e| println!("{e}")).unwrap();
-        assert_eq!(v.as_deref(), Some(&b"riengue"[..]));
+    #[test]
+    fn empty_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get(b"a").unwrap(), None);
+
+        database.add(b"a", b"a").unwrap();
+        database.add(b"b", b"b").unwrap();
+        database.flush().unwrap();
+        database.delete(b"a").unwrap();
+        database.delete(b"b").unwrap();
+        database.flush().unwrap();
+        assert!(database.is_empty());
+
+        // The segments aren't looked into
+        let stats = database.stats();
+        let values = database.multi_get(&[b"a", b"b", b"c"]).unwrap();
+        assert_eq!(values, [None, None, None]);
+        assert_eq!(database.stats(), stats);
+
+        database.add(b"c", b"c").unwrap();
+        assert_eq!(database.get(b"c").unwrap().as_deref(), Some(&b"c"[..]));
+        assert_eq!(database.get(b"a").unwrap(), None);
     }
 
     #[test]
-    fn empty_value() {
+    fn len() {
         let dir = tempfile::tempdir().unwrap();
         let mut database = Database::new(dir.path()).unwrap();
+        assert!(database.is_empty());
+        database.add(b"a", b"a").unwrap();
+        database.add(b"b", b"b").unwrap();
+        database.add(b"a", b"new").unwrap();
+        database.delete(b"missing").unwrap();
+        assert_eq!(database.len(), 2);
+        database.flush().unwrap();
 
-        database.add(b"riengue", b"").unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {[114, 105, 101, 110, 103, 117, 101]: 0}
-        dirty segment:
-        [0, 0, 0, 7, 114, 105, 101, 110, 103, 117, 101, 0, 0, 0, 0]
-        "###);
+        // The keys are looked up in the frozen memtable and the segments
+        database.delete(b"a").unwrap();
+        database.add(b"c", b"c").unwrap();
+        database.freeze().unwrap();
+        assert_eq!(database.len(), 2);
+        database.add(b"a", b"again").unwrap();
+        database.delete(b"c").unwrap();
+        assert_eq!(database.len(), 2);
+        database.flush().unwrap();
+        database
+            .import([(b"b", b"b"), (b"d", b"d")].map(Ok::<_, Error>))
+            .unwrap();
+        assert_eq!(database.len(), 3);
+        database.add(b"e", b"e").unwrap();
+        drop(database);
 
-        let v = database
-            .get(b"riengue")
-            .map_err(|e| println!("{e}"))
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.len(), 4);
+        while database.segments.len() > 1 {
+            database.merge_segment().unwrap();
+        }
+        assert_eq!(database.len(), 4);
+        drop(database);
+
+        // The manifests written before the keys were counted
+        let manifest = std::fs::read_to_string(dir.path().join("MANIFEST")).unwrap();
+        let manifest: Vec<_> = manifest
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        std::fs::write(dir.path().join("MANIFEST"), manifest.join("\n")).unwrap();
+        let database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.len(), 4);
+    }
+
+    #[test]
+    fn approximate_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .exact_len(false)
+            .open(dir.path())
             .unwrap();
-        assert_eq!(v.as_deref(), Some(&b""[..]));
+        database.add(b"a", b"a").unwrap();
+        database.add(b"b", b"b").unwrap();
+        database.add(b"a", b"new").unwrap();
+        // Nothing is out of the memtable yet
+        database.delete(b"missing").unwrap();
+        assert_eq!(database.len(), 2);
+        database.flush().unwrap();
+
+        // The writes don't look into the segments, the keys out of the memtable are assumed
+        // to be new or to have had a value
+        let lookups = |database: &Database| {
+            let stats = database.stats();
+            stats.fence_negatives + stats.filter_negatives + stats.filter_positives
+        };
+        let before = lookups(&database);
+        database.add(b"a", b"overwritten").unwrap();
+        database.delete(b"missing").unwrap();
+        database.add(b"c", b"c").unwrap();
+        database.add(b"c", b"again").unwrap();
+        database.delete(b"c").unwrap();
+        database.delete(b"c").unwrap();
+        assert_eq!(lookups(&database), before);
+        assert_eq!(database.len(), 2);
+        database.add(b"b", b"overwritten").unwrap();
+        assert_eq!(database.len(), 3);
+
+        // Reconciled once all the segments are merged
+        database.flush().unwrap();
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments.len(), 1);
+        assert_eq!(database.len(), 2);
     }
 
     #[test]
-    fn merge() {
+    fn approximate_len_lookups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .exact_len(false)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"a", b"a").unwrap();
+        database.flush().unwrap();
+        // The deletion of a key out of the memtable is assumed to delete a value
+        database.delete(b"missing").unwrap();
+        assert!(database.is_empty());
+
+        // The lookups don't trust the estimate
+        assert_eq!(database.get(b"a").unwrap().as_deref(), Some(&b"a"[..]));
+        let range: Result<Vec<_>> = database.range::<&[u8]>(..).unwrap().collect();
+        assert_eq!(range.unwrap(), [(b"a".to_vec(), b"a".to_vec())]);
+    }
+
+    #[test]
+    fn value_metadata() {
         let dir = tempfile::tempdir().unwrap();
         let mut database = Database::new(dir.path()).unwrap();
+        database.add_with_meta(b"a", b"compressed", 1).unwrap();
+        database.add(b"b", b"plain").unwrap();
+        assert_eq!(
+            database.get_with_meta(b"a").unwrap(),
+            Some((b"compressed".to_vec(), 1))
+        );
+        database.flush().unwrap();
+        database.add_with_meta(b"c", b"c", 255).unwrap();
+        database.flush().unwrap();
+        database.add_with_meta(b"d", b"d", 7).unwrap();
+        database.merge_segment().unwrap();
+        drop(database);
+
+        // The metadata is kept by the dirty segment, the flushes and the merges
+        let mut database = Database::new(dir.path()).unwrap();
+        let metas: Vec<_> = [&b"a"[..], b"b", b"c", b"d", b"e"]
+            .iter()
+            .map(|key| database.get_with_meta(key).unwrap().map(|(_, meta)| meta))
+            .collect();
+        assert_eq!(metas, [Some(1), Some(0), Some(255), Some(7), None]);
+        assert_eq!(database.get(b"a").unwrap(), Some(b"compressed".to_vec()));
+    }
+
+    #[test]
+    fn multi_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .keep_versions(2)
+            .open(dir.path())
+            .unwrap();
+
+        database.add(b"tamo", b"1").unwrap();
+        database.add(b"tamo", b"2").unwrap();
+        database.add(b"kefir", b"1").unwrap();
+        let before_third = database.sequence();
+        database.add(b"tamo", b"3").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"4").unwrap();
+        database.flush().unwrap();
+        database.add(b"tamo", b"5").unwrap();
+
+        let versions = |database: &mut Database| {
+            database
+                .versions(b"tamo")
+                .unwrap()
+                .into_iter()
+                .map(|(seq, value)| {
+                    format!("{seq}: {}", String::from_utf8(value.unwrap()).unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+        insta::assert_debug_snapshot!(versions(&mut database), @r#"
+        [
+            "6: 5",
+            "5: 4",
+            "4: 3",
+            "2: 2",
+        ]
+        "#);
+
+        assert_eq!(database.get(b"tamo").unwrap().as_deref(), Some(&b"5"[..]));
+        assert_eq!(
+            database.get_at(b"tamo", before_third).unwrap().as_deref(),
+            Some(&b"2"[..])
+        );
+        assert_eq!(database.get_at(b"tamo", 1).unwrap(), None);
+        assert_eq!(database.get_at(b"kefir", 2).unwrap(), None);
+
+        // once merged only two versions are kept
+        database.merge_segment().unwrap();
+        insta::assert_debug_snapshot!(versions(&mut database), @r#"
+        [
+            "6: 5",
+            "5: 4",
+            "4: 3",
+        ]
+        "#);
+        let all: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect();
+        assert_eq!(
+            all,
+            [
+                (b"kefir".to_vec(), b"1".to_vec()),
+                (b"tamo".to_vec(), b"5".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .identity(IDENTITY)
+            .open(dir.path())
+            .unwrap();
 
-        // make a first segment
         database.add(b"hello", b"world").unwrap();
         database.add(b"tamo", b"kefir").unwrap();
-        database.add(b"a", b"b").unwrap();
-        database.flush_dirty().unwrap();
-
-        // make a second clean segment out of the memtable
-        database.add(b"hello", b"tamo").unwrap();
-        database.add(b"b", b"c").unwrap();
-        database.flush_dirty().unwrap();
+        database.flush().unwrap();
+        database.delete(b"hello").unwrap();
+        assert_eq!(database.get(b"hello").unwrap(), None);
 
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {}
-        dirty segment:
-        []
-        segment 0:
-        [0, 0, 0, 1, 97, 0, 0, 0, 1, 98, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100, 0, 0, 0, 4, 116, 97, 109, 111, 0, 0, 0, 5, 107, 101, 102, 105, 114]
-        segment 1:
-        [0, 0, 0, 1, 98, 0, 0, 0, 1, 99, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 4, 116, 97, 109, 111]
-        "###);
+        database.flush().unwrap();
+        assert_eq!(database.get(b"hello").unwrap(), None);
+        let keys: Vec<_> = database
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys, [b"tamo"]);
 
+        // the deletion isn't needed anymore once it reached the oldest segment
         database.merge_segment().unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
+        insta::assert_snapshot!(database.dump().unwrap(), @"
         memtable:
         {}
         dirty segment:
         []
         segment 0:
-        [0, 0, 0, 1, 97, 0, 0, 0, 1, 98, 0, 0, 0, 1, 98, 0, 0, 0, 1, 99, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 4, 116, 97, 109, 111, 0, 0, 0, 4, 116, 97, 109, 111, 0, 0, 0, 5, 107, 101, 102, 105, 114]
-        "###);
+        [129, 82, 32, 227, 0, 4, 116, 97, 109, 111, 2, 0, 0, 8, 107, 101, 102, 105, 114, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 8, 64, 128, 0, 4, 32, 0, 1, 6, 129, 82, 32, 227, 0, 4, 116, 97, 109, 111, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 27, 15, 11, 96, 242, 0, 0, 0, 0, 0, 0, 0, 1, 129, 82, 32, 227, 0, 4, 116, 97, 109, 111, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 45, 0, 0, 0, 38, 215, 42, 102, 110, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 83, 0, 0, 0, 38, 151, 127, 168, 191, 0, 0, 0, 0, 0, 0, 0, 27, 0, 0, 0, 18, 175, 12, 80, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 106, 27, 60, 93, 126, 159, 74, 43, 140, 77, 94, 111, 122, 139, 156, 13, 30, 15, 181, 238, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]
+        ");
     }
 
     #[test]
-    fn create_and_get_in_clean_segment() {
+    fn compact_deletion() {
         let dir = tempfile::tempdir().unwrap();
         let mut database = Database::new(dir.path()).unwrap();
-        database.dirty_thresholds(2);
 
         database.add(b"hello", b"world").unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {[104, 101, 108, 108, 111]: 0}
-        dirty segment:
-        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100]
-        "###);
-        database.add(b"tamo", b"world").unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {[104, 101, 108, 108, 111]: 0, [116, 97, 109, 111]: 18}
-        dirty segment:
-        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100, 0, 0, 0, 4, 116, 97, 109, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100]
-        "###);
-        database.add(b"patou", b"world").unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
+        database.delete(b"hello").unwrap();
+        database.add(b"tamo", b"kefir").unwrap();
+        // the deletion is the key and its flagged sequence number
+        insta::assert_snapshot!(database.dump().unwrap(), @"
         memtable:
-        {}
+        {[104, 101, 108, 108, 111]: 30, [116, 97, 109, 111]: 47}
         dirty segment:
-        []
-        segment 0:
-        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100, 0, 0, 0, 5, 112, 97, 116, 111, 117, 0, 0, 0, 5, 119, 111, 114, 108, 100, 0, 0, 0, 4, 116, 97, 109, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100]
-        "###);
-        let v = database.get(b"hello").map_err(|e| println!("{e}")).unwrap();
-        assert_eq!(v.as_deref(), Some(&b"world"[..]));
+        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 64, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 119, 111, 114, 108, 100, 58, 119, 17, 67, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 128, 0, 0, 0, 0, 0, 2, 0, 0, 0, 4, 116, 97, 109, 111, 0, 64, 0, 0, 0, 0, 0, 3, 0, 0, 0, 5, 107, 101, 102, 105, 114, 98, 86, 193, 28]
+        ");
+        drop(database);
+
+        // the entries following the deletion are still found once replayed
+        let mut database = Database::new(dir.path()).unwrap();
+        assert_eq!(database.get(b"hello").unwrap(), None);
+        assert_eq!(
+            database.get(b"tamo").unwrap().as_deref(),
+            Some(&b"kefir"[..])
+        );
+        assert_eq!(database.sequence(), 3);
+        let versions = database.versions(b"hello").unwrap();
+        assert_eq!(versions, [(2, None), (1, Some(b"world".to_vec()))]);
     }
 
     #[test]
-    fn reload_memtable() {
+    fn compare_and_swap() {
         let dir = tempfile::tempdir().unwrap();
         let mut database = Database::new(dir.path()).unwrap();
 
-        database.add(b"hello", b"world").unwrap();
-        database.add(b"tamo", b"world").unwrap();
+        // create
+        database
+            .compare_and_swap(b"tamo", None, Some(b"kefir"))
+            .unwrap()
+            .unwrap();
+        let err = database
+            .compare_and_swap(b"tamo", None, Some(b"patou"))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.current.as_deref(), Some(&b"kefir"[..]));
+
+        // update
+        database
+            .compare_and_swap(b"tamo", Some(b"kefir"), Some(b"patou"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            database.get(b"tamo").unwrap().as_deref(),
+            Some(&b"patou"[..])
+        );
+
+        // delete
+        database.flush().unwrap();
+        let err = database
+            .compare_and_swap(b"tamo", Some(b"kefir"), None)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.current.as_deref(), Some(&b"patou"[..]));
+        database
+            .compare_and_swap(b"tamo", Some(b"patou"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(database.get(b"tamo").unwrap(), None);
+    }
+
+    #[test]
+    fn increment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        assert_eq!(database.increment(b"counter", 3).unwrap(), 3);
+        database.flush().unwrap();
+        assert_eq!(database.increment(b"counter", -5).unwrap(), -2);
+        assert_eq!(
+            database.get(b"counter").unwrap(),
+            Some((-2_i64).to_be_bytes().to_vec())
+        );
+
+        assert!(matches!(
+            database.increment(b"counter", i64::MIN),
+            Err(Error::CounterOverflow)
+        ));
+        database.add(b"tamo", b"kefir").unwrap();
+        assert!(matches!(
+            database.increment(b"tamo", 1),
+            Err(Error::InvalidCounter(5))
+        ));
+    }
+
+    #[test]
+    fn get_or_insert_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        let value = database.get_or_insert_with(b"tamo", || b"kefir").unwrap();
+        assert_eq!(value, b"kefir");
+        let seq = database.sequence();
+        database.flush().unwrap();
+        // The existing value is returned without calling the default nor writing anything
+        let value = database
+            .get_or_insert_with(b"tamo", || -> &[u8] { unreachable!() })
+            .unwrap();
+        assert_eq!(value, b"kefir");
+        assert_eq!(database.sequence(), seq);
+
+        database.delete(b"tamo").unwrap();
+        let value = database.get_or_insert_with(b"tamo", || b"patou").unwrap();
+        assert_eq!(value, b"patou");
+        assert_eq!(
+            database.get(b"tamo").unwrap().as_deref(),
+            Some(&b"patou"[..])
+        );
+    }
+
+    #[test]
+    fn cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        for key in ["a", "c", "e", "g"] {
+            database.add(key, key).unwrap();
+        }
+        database.flush().unwrap();
+        database.add(b"b", b"b").unwrap();
+        database.delete(b"c").unwrap();
+        database.freeze().unwrap();
+        database.add(b"f", b"f").unwrap();
+        database.delete(b"g").unwrap();
+
+        let mut cursor = database.cursor();
+        let keys = |moved: Option<(&[u8], &[u8])>| moved.map(|(key, _)| key.to_vec());
+        assert_eq!(keys(cursor.seek(b"c").unwrap()), Some(b"e".to_vec()));
+        assert_eq!(cursor.current(), Some((&b"e"[..], &b"e"[..])));
+        assert_eq!(keys(cursor.next().unwrap()), Some(b"f".to_vec()));
+        assert_eq!(keys(cursor.next().unwrap()), None);
+        // Past the end, the cursor starts again from the first entry
+        assert_eq!(keys(cursor.next().unwrap()), Some(b"a".to_vec()));
+        assert_eq!(keys(cursor.prev().unwrap()), None);
+        assert_eq!(keys(cursor.prev().unwrap()), Some(b"f".to_vec()));
+        assert_eq!(keys(cursor.prev().unwrap()), Some(b"e".to_vec()));
+        // The deleted keys are skipped in both directions
+        assert_eq!(keys(cursor.prev().unwrap()), Some(b"b".to_vec()));
+        assert_eq!(keys(cursor.next().unwrap()), Some(b"e".to_vec()));
+        assert_eq!(
+            keys(cursor.seek_for_prev(b"d").unwrap()),
+            Some(b"b".to_vec())
+        );
+        assert_eq!(
+            keys(cursor.seek_for_prev(b"e").unwrap()),
+            Some(b"e".to_vec())
+        );
+        assert_eq!(keys(cursor.seek_for_prev(b"0").unwrap()), None);
+        assert_eq!(keys(cursor.seek(b"z").unwrap()), None);
+    }
+
+    #[test]
+    fn sample_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        assert!(database.sample_keys(3).unwrap().is_empty());
+        // Two segments holding the even and the odd keys
+        for step in [0, 1] {
+            for i in (step..20_000u32).step_by(2) {
+                database.add(i.to_be_bytes(), [0; 32]).unwrap();
+            }
+            database.flush().unwrap();
+        }
+
+        let keys = database.sample_keys(3).unwrap();
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|key| u32::from_be_bytes(key.as_slice().try_into().unwrap()))
+            .collect();
+        assert_eq!(keys.len(), 3);
+        for (key, expected) in keys.iter().zip([5000, 10_000, 15_000]) {
+            assert!(key.abs_diff(expected) < 500, "{keys:?}");
+        }
+        assert!(database.sample_keys(1_000_000).unwrap().len() < 20_000);
+    }
+
+    #[test]
+    fn utf8_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.put_str("café", "crème").unwrap();
+        database.add("invalid", b"caf\xe9").unwrap();
+        database.put_str("tamo", "kefir").unwrap();
+        database.add(b"z\xff", "z").unwrap();
+
+        assert_eq!(database.get_str("café").unwrap().as_deref(), Some("crème"));
+        assert_eq!(database.get_str("missing").unwrap(), None);
+        assert!(matches!(
+            database.get_str("invalid"),
+            Err(Error::InvalidUtf8 { key, .. }) if key == b"invalid"
+        ));
+        let lossy = database.get_str_lossy("invalid").unwrap();
+        assert_eq!(lossy.as_deref(), Some("caf\u{FFFD}"));
+
+        let entries: Vec<_> = database
+            .range_str("c"..)
+            .unwrap()
+            .map(|entry| entry.map_err(|e| e.to_string()))
+            .collect();
+        insta::assert_debug_snapshot!(entries, @r#"
+        [
+            Ok(
+                (
+                    "café",
+                    "crème",
+                ),
+            ),
+            Err(
+                "The entry [105, 110, 118, 97, 108, 105, 100] isn't valid UTF-8: incomplete utf-8 byte sequence from index 3",
+            ),
+            Ok(
+                (
+                    "tamo",
+                    "kefir",
+                ),
+            ),
+            Err(
+                "The entry [122, 255] isn't valid UTF-8: invalid utf-8 sequence of 1 bytes from index 1",
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn stream_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = Database::new(dir.path().join("source")).unwrap();
+        let mut follower = Database::new(dir.path().join("follower")).unwrap();
+        source.add(b"a", b"a").unwrap();
+        source.add_with_meta(b"b", b"b", 3).unwrap();
+        source.delete(b"a").unwrap();
+
+        let mut pipe = Vec::new();
+        let last = source.stream_wal_since(0, &mut pipe).unwrap();
+        assert_eq!(last, 3);
+        assert_eq!(follower.ingest_wal(pipe.as_slice()).unwrap(), 3);
+        assert_eq!(follower.get(b"a").unwrap(), None);
+        assert_eq!(
+            follower.get_with_meta(b"b").unwrap(),
+            Some((b"b".to_vec(), 3))
+        );
+
+        // The entries of the frozen memtable are still available
+        source.add(b"c", b"c").unwrap();
+        source.freeze().unwrap();
+        source.add(b"d", b"d").unwrap();
+        let mut pipe = Vec::new();
+        assert_eq!(source.stream_wal_since(last, &mut pipe).unwrap(), 5);
+        assert_eq!(follower.ingest_wal(pipe.as_slice()).unwrap(), 2);
+        assert_eq!(follower.get(b"c").unwrap(), Some(b"c".to_vec()));
+        assert_eq!(source.stream_wal_since(5, io::sink()).unwrap(), 5);
+
+        source.finish_flush().unwrap();
+        assert!(matches!(
+            source.stream_wal_since(last, io::sink()),
+            Err(Error::WalTruncated(3))
+        ));
+        // A truncated entry is an error
+        let mut pipe = Vec::new();
+        source.stream_wal_since(4, &mut pipe).unwrap();
+        pipe.pop();
+        assert!(follower.ingest_wal(pipe.as_slice()).is_err());
+    }
+
+    #[test]
+    fn follower() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+        database.add(b"a", b"old").unwrap();
+        database.flush().unwrap();
+        database.add(b"b", b"b").unwrap();
+
+        let mut follower = Database::builder().open_follower(dir.path()).unwrap();
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(follower.get(b"b").unwrap(), Some(b"b".to_vec()));
+
+        // The view only changes once refreshed
+        database.add(b"a", b"new").unwrap();
+        database.delete(b"b").unwrap();
+        database.flush().unwrap();
+        database.add(b"c", b"c").unwrap();
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"old".to_vec()));
+        follower.refresh().unwrap();
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(follower.get(b"b").unwrap(), None);
+        assert_eq!(follower.get(b"c").unwrap(), Some(b"c".to_vec()));
+
+        // The segments it knows about were compacted, it refreshes on its own
+        database.merge_segment().unwrap();
+        database.flush().unwrap();
+        let keys: Vec<_> = follower
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys, [b"a", b"c"]);
+        assert_eq!(follower.get(b"a").unwrap(), Some(b"new".to_vec()));
+
+        follower.refresh_every(Duration::ZERO);
+        database.add(b"d", b"d").unwrap();
+        assert_eq!(follower.get(b"d").unwrap(), Some(b"d".to_vec()));
+        // Nothing was written by the follower
+        drop(follower);
+        assert_eq!(database.get(b"d").unwrap(), Some(b"d".to_vec()));
+    }
+
+    #[test]
+    fn scan_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        for i in 0..25_u32 {
+            database.add(i.to_be_bytes(), i.to_string()).unwrap();
+            if i % 10 == 0 {
+                database.flush().unwrap();
+            }
+        }
+
+        let chunks: Vec<_> = database
+            .scan_chunks(5_u32.to_be_bytes()..20_u32.to_be_bytes(), 4)
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect();
+        let sizes: Vec<_> = chunks.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [4, 4, 4, 3]);
+
+        let entries: Vec<_> = chunks.into_iter().flatten().collect();
+        let expected: Vec<_> = database
+            .range(5_u32.to_be_bytes()..20_u32.to_be_bytes())
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect();
+        assert_eq!(entries, expected);
+
+        // an exact multiple of the chunk size doesn't end with an empty chunk
+        let chunks = database.scan_chunks::<&[u8]>(.., 5).unwrap().count();
+        assert_eq!(chunks, 5);
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .buffer_pool_size(64 * 1024)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..1000_u32 {
+            database.add(i.to_be_bytes(), [0; 100]).unwrap();
+        }
+        database.flush().unwrap();
+        // The buffer of the flush is kept for the next operations
+        let stats = database.stats();
+        assert_eq!(stats.pool_buffers, 1);
+
+        for i in 0..1000_u32 {
+            assert!(database.get(i.to_be_bytes()).unwrap().is_some());
+        }
+        assert_eq!(database.range::<&[u8]>(..).unwrap().count(), 1000);
+        let stats = database.stats();
+        assert!(stats.pool_buffers > 1);
+        assert!(stats.pool_bytes <= 64 * 1024);
 
         drop(database);
-        // dropping the previous database and opening a new one in the same dir
+        let mut database = Database::builder()
+            .buffer_pool_size(0)
+            .open(dir.path())
+            .unwrap();
+        assert!(database.get(0_u32.to_be_bytes()).unwrap().is_some());
+        assert_eq!(database.stats().pool_buffers, 0);
+    }
+
+    #[test]
+    fn poisoned() {
+        // Fails the flush in the background
+        struct Panicking;
+        impl FilterPolicy for Panicking {
+            fn name(&self) -> &str {
+                "panicking"
+            }
+            fn new_filter(&self) -> Box<dyn Filter> {
+                panic!("no filter")
+            }
+            fn read_filter(&self, _bytes: &[u8]) -> Result<Box<dyn Filter>> {
+                Err(Error::CorruptedFilter)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::builder()
+            .filter(Panicking)
+            .open(dir.path())
+            .unwrap();
+        database.add(b"hello", b"world").unwrap();
+        let flush = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            database.flush().unwrap();
+        }));
+        assert!(flush.is_err());
+        assert!(database.is_poisoned());
+        assert!(matches!(database.get(b"hello"), Err(Error::Poisoned)));
+        assert!(matches!(database.add(b"a", b"b"), Err(Error::Poisoned)));
+        assert!(matches!(database.flush(), Err(Error::Poisoned)));
+        assert!(matches!(database.close(), Err(Error::Poisoned)));
+
+        // Nothing was lost
         let mut database = Database::new(dir.path()).unwrap();
-        insta::assert_display_snapshot!(database.dump().unwrap(), @r###"
-        memtable:
-        {[104, 101, 108, 108, 111]: 0, [116, 97, 109, 111]: 18}
-        dirty segment:
-        [0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100, 0, 0, 0, 4, 116, 97, 109, 111, 0, 0, 0, 5, 119, 111, 114, 108, 100]
-        "###);
+        assert_eq!(
+            database.get(b"hello").unwrap().as_deref(),
+            Some(&b"world"[..])
+        );
+    }
+
+    #[test]
+    fn manual_scheduler() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = ManualScheduler::new();
+        scheduler.set_time(UNIX_EPOCH + Duration::from_secs(1_000));
+        let mut database = Database::builder()
+            .dirty_thresholds(1)
+            .scheduler(scheduler.clone())
+            .open(dir.path())
+            .unwrap();
+        for i in 0..12u8 {
+            database.add([i], [i]).unwrap();
+            // Nothing happens until the application asks for it
+            assert!(database.frozen.is_none());
+            scheduler.advance(Duration::from_millis(500));
+            database.flush().unwrap();
+        }
+        assert_eq!(database.segments.len(), 12);
+        database.merge_segment().unwrap();
+        assert_eq!(database.segments.len(), 11);
+        drop(database);
+
+        let log = std::fs::read_to_string(dir.path().join("LOG")).unwrap();
+        let times: Vec<_> = log
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().0)
+            .collect();
+        insta::assert_debug_snapshot!(times.first().zip(times.last()), @r#"
+        Some(
+            (
+                "1000.000",
+                "1006.000",
+            ),
+        )
+        "#);
     }
 }
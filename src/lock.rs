@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, ThreadId},
+};
+
+use crate::{Database, Error, Result};
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Advisory locks over ranges of keys, for the applications serializing their
+/// read-modify-write cycles, see [`Database::lock_range`].
+///
+/// The locks don't prevent any read or write of the database, they only exclude each other:
+/// a range can only be locked once none of its keys is in a locked range. The clones share the
+/// same locks, so the threads can wait for a lock without holding the database.
+#[derive(Clone, Default)]
+pub struct RangeLocks {
+    inner: Arc<(Mutex<Locks>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Locks {
+    next_id: u64,
+    held: HashMap<u64, (KeyRange, ThreadId)>,
+    // The range each blocked thread waits for
+    waiting: HashMap<ThreadId, KeyRange>,
+}
+
+/// A locked range of keys, it's unlocked when the guard is dropped.
+///
+/// The guard belongs to the thread that locked the range, it can't be sent to another thread
+/// since the deadlocks are detected between the threads.
+pub struct RangeGuard {
+    locks: RangeLocks,
+    id: u64,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl RangeLocks {
+    pub fn new() -> RangeLocks {
+        RangeLocks::default()
+    }
+
+    /// Lock the range, waiting for the overlapping locked ranges to be unlocked.
+    ///
+    /// Returns [`Error::Deadlock`] instead of waiting when the holder of an overlapping range is
+    /// waiting for a range locked by this thread, directly or through other threads. A thread
+    /// locking a range overlapping a range it already holds is a deadlock too.
+    pub fn lock<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<RangeGuard> {
+        let range = owned(&range);
+        let thread = thread::current().id();
+        let (locks, unlocked) = &*self.inner;
+        let mut locks = locks.lock().unwrap();
+        loop {
+            if !locks.conflicts(&range) {
+                locks.waiting.remove(&thread);
+                return Ok(self.insert(&mut locks, range, thread));
+            }
+            if locks.would_deadlock(thread, &range) {
+                locks.waiting.remove(&thread);
+                return Err(Error::Deadlock);
+            }
+            locks.waiting.insert(thread, range.clone());
+            locks = unlocked.wait(locks).unwrap();
+        }
+    }
+
+    /// Lock the range if none of its keys is in a locked range, `None` otherwise.
+    pub fn try_lock<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Option<RangeGuard> {
+        let range = owned(&range);
+        let mut locks = self.inner.0.lock().unwrap();
+        match locks.conflicts(&range) {
+            true => None,
+            false => Some(self.insert(&mut locks, range, thread::current().id())),
+        }
+    }
+
+    fn insert(&self, locks: &mut Locks, range: KeyRange, thread: ThreadId) -> RangeGuard {
+        let id = locks.next_id;
+        locks.next_id += 1;
+        locks.held.insert(id, (range, thread));
+        RangeGuard {
+            locks: self.clone(),
+            id,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Locks {
+    fn conflicts(&self, range: &KeyRange) -> bool {
+        self.held.values().any(|(held, _)| overlaps(held, range))
+    }
+
+    /// Whether one of the holders of the ranges overlapping `range` waits, through any number
+    /// of other holders, for a range held by `thread`.
+    fn would_deadlock(&self, thread: ThreadId, range: &KeyRange) -> bool {
+        let mut visited = Vec::new();
+        let mut ranges = vec![range];
+        while let Some(range) = ranges.pop() {
+            for (held, holder) in self.held.values() {
+                if !overlaps(held, range) || visited.contains(holder) {
+                    continue;
+                }
+                if *holder == thread {
+                    return true;
+                }
+                visited.push(*holder);
+                ranges.extend(self.waiting.get(holder));
+            }
+        }
+        false
+    }
+}
+
+impl Drop for RangeGuard {
+    fn drop(&mut self) {
+        let (locks, unlocked) = &*self.locks.inner;
+        locks.lock().unwrap().held.remove(&self.id);
+        unlocked.notify_all();
+    }
+}
+
+impl Database {
+    /// Lock a range of keys, see [`RangeLocks::lock`].
+    pub fn lock_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<RangeGuard> {
+        self.range_locks.lock(range)
+    }
+
+    /// Lock a range of keys if it's free, see [`RangeLocks::try_lock`].
+    pub fn try_lock_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Option<RangeGuard> {
+        self.range_locks.try_lock(range)
+    }
+
+    /// The locks of [`Database::lock_range`], to wait for a range without holding the database.
+    pub fn range_locks(&self) -> RangeLocks {
+        self.range_locks.clone()
+    }
+}
+
+fn owned<K: AsRef<[u8]>>(range: &impl RangeBounds<K>) -> KeyRange {
+    (
+        range.start_bound().map(|key| key.as_ref().to_vec()),
+        range.end_bound().map(|key| key.as_ref().to_vec()),
+    )
+}
+
+/// Whether a key can be contained in both ranges.
+fn overlaps(a: &KeyRange, b: &KeyRange) -> bool {
+    // Whether the range ending at `end` ends before the start of the range starting at `start`
+    let before = |end: &Bound<Vec<u8>>, start: &Bound<Vec<u8>>| match (end, start) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(end), Bound::Included(start)) => end < start,
+        (Bound::Included(end) | Bound::Excluded(end), Bound::Excluded(start))
+        | (Bound::Excluded(end), Bound::Included(start)) => end <= start,
+    };
+    !before(&a.1, &b.0) && !before(&b.1, &a.0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn overlapping_ranges() {
+        let locks = RangeLocks::new();
+        let guard = locks.lock("b".."d").unwrap();
+        assert!(locks.try_lock("a".."b").is_some());
+        assert!(locks.try_lock("d"..).is_some());
+        assert!(locks.try_lock("a"..="b").is_none());
+        assert!(locks.try_lock("c".."z").is_none());
+        assert!(locks.try_lock::<&str>(..).is_none());
+        // Locking it again from the same thread would never return
+        assert!(matches!(locks.lock("c"..), Err(Error::Deadlock)));
+
+        // The lock waits for the guard to be dropped
+        let waiter = thread::spawn({
+            let locks = locks.clone();
+            move || locks.lock("a".."c").map(drop).is_ok()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(guard);
+        assert!(waiter.join().unwrap());
+        assert!(locks.try_lock::<&str>(..).is_some());
+    }
+
+    #[test]
+    fn deadlock() {
+        let locks = RangeLocks::new();
+        let a = locks.lock("a"..="a").unwrap();
+        let (locked, wait) = mpsc::channel();
+        let other = thread::spawn({
+            let locks = locks.clone();
+            move || {
+                let b = locks.lock("b"..="b").unwrap();
+                locked.send(()).unwrap();
+                let a = locks.lock("a"..="a");
+                drop(b);
+                a.map(drop).is_err()
+            }
+        });
+        wait.recv().unwrap();
+        // Whichever waits first, the second one is told about the deadlock
+        let b = locks.lock("b"..="b");
+        let deadlock = b.is_err();
+        drop((a, b));
+        assert_ne!(other.join().unwrap(), deadlock);
+    }
+}
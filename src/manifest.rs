@@ -0,0 +1,412 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, Write},
+    ops::{Bound, Range},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    events::EventLog,
+    pool::BufferPool,
+    segment::{self, Holes, SegmentIter},
+    sync_dir, Error, Layout, Result, Segment,
+};
+
+/// The prefix of the line holding the number of live keys of the segments, it's missing from
+/// the manifests written before it was counted.
+const LIVE_KEYS: &str = "# live keys: ";
+/// The prefixes of the lines holding the size limits of the writes, they're missing from the
+/// manifests written before they were configurable.
+const MAX_KEY_SIZE: &str = "# max key size: ";
+const MAX_VALUE_SIZE: &str = "# max value size: ";
+/// The prefix of the line holding the largest timestamp given to a write when the manifest was
+/// written, it's missing from the manifests written before the writes were timestamped.
+const CLOCK: &str = "# clock: ";
+/// The prefix of the line holding the sequence number of the last write when the manifest was
+/// written, it's missing from the manifests written before it was recorded.
+const SEQUENCE: &str = "# sequence: ";
+/// The prefix of the line holding the id given to the next segment, it's missing from the
+/// manifests written before it was recorded.
+const NEXT_ID: &str = "# next id: ";
+/// The prefix of the line holding the id of the segment written by a compaction that didn't
+/// replace its segments yet, its file is deleted by the recovery rather than adopted.
+const PENDING_COMPACTION: &str = "# pending compaction: ";
+/// The prefix of the lines holding the path of a segment followed by the runs of data blocks
+/// punched out of its file, as `start..end` offsets.
+const HOLES: &str = "# holes: ";
+
+/// The maximum size of the keys and values accepted by the writes, see
+/// [`DatabaseBuilder::max_key_size`](crate::DatabaseBuilder::max_key_size).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Limits {
+    pub key: usize,
+    pub value: usize,
+}
+
+/// The list of the clean segments of a database from the oldest to the most recent one,
+/// one path relative to the database directory per line, preceded by the number of live keys
+/// they hold when it's known, by the size limits of the writes, by the largest timestamp given
+/// to a write, by the sequence number of the last write, by the id of the next segment and by
+/// the id of the segment of the pending compaction, and followed by the holes punched in the
+/// segments.
+///
+/// It's atomically replaced after each flush and compaction, once the new segment is durable,
+/// and before a compaction writes its segment.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write(
+    root: &Path,
+    layout: &Layout,
+    segments: &VecDeque<Segment>,
+    live_keys: Option<u64>,
+    limits: Option<Limits>,
+    clock: u64,
+    sequence: u64,
+    next_id: usize,
+    pending_compaction: Option<usize>,
+) -> io::Result<()> {
+    let mut manifest = layout.temp_file(root)?;
+    if let Some(live_keys) = live_keys {
+        writeln!(manifest, "{LIVE_KEYS}{live_keys}")?;
+    }
+    if let Some(limits) = limits {
+        writeln!(manifest, "{MAX_KEY_SIZE}{}", limits.key)?;
+        writeln!(manifest, "{MAX_VALUE_SIZE}{}", limits.value)?;
+    }
+    if clock != 0 {
+        writeln!(manifest, "{CLOCK}{clock}")?;
+    }
+    if sequence != 0 {
+        writeln!(manifest, "{SEQUENCE}{sequence}")?;
+    }
+    if next_id != 0 {
+        writeln!(manifest, "{NEXT_ID}{next_id}")?;
+    }
+    if let Some(id) = pending_compaction {
+        writeln!(manifest, "{PENDING_COMPACTION}{id}")?;
+    }
+    for segment in segments {
+        let path = segment.path.strip_prefix(root).unwrap_or(&segment.path);
+        writeln!(manifest, "{}", path.display())?;
+    }
+    for segment in segments.iter().filter(|segment| !segment.holes.is_empty()) {
+        let path = segment.path.strip_prefix(root).unwrap_or(&segment.path);
+        write!(manifest, "{HOLES}{}", path.display())?;
+        for run in segment.holes.runs() {
+            write!(manifest, " {}..{}", run.start, run.end)?;
+        }
+        writeln!(manifest)?;
+    }
+    manifest.as_file().sync_all()?;
+    manifest.persist(layout.manifest_path(root))?;
+    sync_dir(root)
+}
+
+/// The segments of the database and the id of the next one.
+pub(crate) struct Recovered {
+    pub segments: VecDeque<Segment>,
+    pub next_id: usize,
+    /// Where the segments that failed their validation were moved.
+    pub quarantined: Vec<PathBuf>,
+    /// The number of live keys of the segments, `None` when they must be counted again.
+    pub live_keys: Option<u64>,
+    /// The limits the database was last opened with.
+    pub limits: Option<Limits>,
+    /// The largest timestamp given to a write before the manifest was written.
+    pub clock: u64,
+    /// The sequence number of the last write before the manifest was written, `None` when the
+    /// manifest doesn't record it.
+    pub sequence: Option<u64>,
+}
+
+/// The content of the manifest.
+pub(crate) struct Manifest {
+    /// The id and path of the segments from the oldest to the most recent one.
+    pub listed: Vec<(usize, PathBuf)>,
+    pub live_keys: Option<u64>,
+    pub limits: Option<Limits>,
+    pub clock: u64,
+    pub sequence: Option<u64>,
+    /// The id given to the next segment, the ids are never reused even once their segment
+    /// is compacted.
+    pub next_id: usize,
+    /// The holes punched in the segments, by path.
+    pub holes: HashMap<PathBuf, Holes>,
+    /// The id of the segment written by a compaction that may not have replaced its segments.
+    pub pending_compaction: Option<usize>,
+}
+
+/// Read the manifest, a database created by a version without manifest has no segment listed.
+pub(crate) fn read(root: &Path, layout: &Layout) -> Result<Manifest> {
+    let manifest = match fs::read_to_string(layout.manifest_path(root)) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut listed = Vec::new();
+    let mut live_keys = None;
+    let mut clock = 0;
+    let mut sequence = None;
+    let mut next_id = 0;
+    let mut pending_compaction = None;
+    let mut holes = HashMap::new();
+    let (mut max_key, mut max_value) = (None, None);
+    for line in manifest.lines().filter(|line| !line.is_empty()) {
+        if let Some(count) = line.strip_prefix(LIVE_KEYS) {
+            live_keys = count.parse().ok();
+            continue;
+        }
+        if let Some(size) = line.strip_prefix(MAX_KEY_SIZE) {
+            max_key = size.parse().ok();
+            continue;
+        }
+        if let Some(size) = line.strip_prefix(MAX_VALUE_SIZE) {
+            max_value = size.parse().ok();
+            continue;
+        }
+        if let Some(time) = line.strip_prefix(CLOCK) {
+            clock = time.parse().unwrap_or_default();
+            continue;
+        }
+        if let Some(seq) = line.strip_prefix(SEQUENCE) {
+            sequence = seq.parse().ok();
+            continue;
+        }
+        if let Some(id) = line.strip_prefix(NEXT_ID) {
+            next_id = id.parse().unwrap_or_default();
+            continue;
+        }
+        if let Some(id) = line.strip_prefix(PENDING_COMPACTION) {
+            pending_compaction = id.parse().ok();
+            continue;
+        }
+        if let Some(runs) = line.strip_prefix(HOLES) {
+            let (path, runs) =
+                parse_holes(runs).ok_or_else(|| Error::InvalidManifest(line.to_owned()))?;
+            holes.insert(root.join(path), Holes::new(runs));
+            continue;
+        }
+        let path = root.join(line);
+        let id = file_name(&path).and_then(|name| layout.parse_segment(name));
+        match id {
+            Some(id) if path.exists() => listed.push((id, path)),
+            _ => return Err(Error::MissingSegment(path)),
+        }
+    }
+    let limits = match (max_key, max_value) {
+        (Some(key), Some(value)) => Some(Limits { key, value }),
+        _ => None,
+    };
+    Ok(Manifest {
+        listed,
+        live_keys,
+        limits,
+        clock,
+        sequence,
+        next_id,
+        holes,
+        pending_compaction,
+    })
+}
+
+/// Load the segments listed in the manifest and reconcile them with the files of the segment
+/// directories.
+///
+/// A crash between the creation of a segment and the update of the manifest leaves behind a
+/// segment the manifest doesn't know about. The segment of the pending compaction is deleted
+/// since the segments it merges are still listed. The others are adopted if they can be read,
+/// the compacted segments as the oldest ones and the flushed segments as the most recent ones,
+/// otherwise they're deleted along with the temporary files. Everything is reported in the
+/// events log.
+///
+/// When `validate` is set the footer and index of the listed segments are checked, the invalid
+/// segments are moved to the quarantine directory and the database opens without them.
+pub(crate) fn recover(
+    root: &Path,
+    layout: &Layout,
+    pool: &Arc<BufferPool>,
+    validate: bool,
+    identity: u128,
+    events: &mut EventLog,
+) -> Result<Recovered> {
+    let Manifest {
+        mut listed,
+        mut live_keys,
+        limits,
+        clock,
+        mut sequence,
+        next_id,
+        mut holes,
+        pending_compaction,
+    } = read(root, layout)?;
+
+    let known: HashSet<_> = listed.iter().map(|(_, path)| path.clone()).collect();
+    let mut quarantined = Vec::new();
+    // The ids of the quarantined segments aren't reused so their files are never overwritten
+    let mut next_id = next_id;
+    if validate {
+        let mut valid = Vec::with_capacity(listed.len());
+        for (id, path) in listed {
+            match Segment::validate(&path) {
+                Ok(()) => valid.push((id, path)),
+                Err(e) => {
+                    let dir = layout.quarantine_dir(root);
+                    fs::create_dir_all(&dir)?;
+                    let to = dir.join(path.file_name().unwrap());
+                    fs::rename(&path, &to)?;
+                    sync_dir(&dir)?;
+                    events.log(format_args!(
+                        "recovery: invalid segment {} quarantined to {}: {e}",
+                        path.display(),
+                        to.display()
+                    ));
+                    quarantined.push(to);
+                    next_id = next_id.max(id + 1);
+                }
+            }
+        }
+        listed = valid;
+    }
+    let mut compacted = Vec::new();
+    let mut flushed = Vec::new();
+    // The manifest is written in the database directory, it may not hold any segment
+    let mut dirs = vec![(root.to_owned(), None)];
+    for level in 0..=1 {
+        let dir = layout.level_dir(root, level);
+        match dirs.iter_mut().find(|(other, _)| *other == dir) {
+            Some((_, other)) => *other = other.or(Some(level)),
+            None => dirs.push((dir, Some(level))),
+        }
+    }
+    let temp_prefix = layout.temp_prefix();
+    for (dir, level) in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = file_name(&path) else {
+                continue;
+            };
+            if name.starts_with(&temp_prefix) {
+                fs::remove_file(&path)?;
+                events.log(format_args!(
+                    "recovery: temporary file {} deleted",
+                    path.display()
+                ));
+                continue;
+            }
+            let Some(level) = level else {
+                continue;
+            };
+            let Some(id) = layout.parse_segment(name) else {
+                continue;
+            };
+            if known.contains(&path) {
+                continue;
+            }
+            // Its compaction crashed before replacing its segments, which are still listed
+            if pending_compaction == Some(id) {
+                fs::remove_file(&path)?;
+                events.log(format_args!(
+                    "recovery: segment {} of an unfinished compaction deleted",
+                    path.display()
+                ));
+                continue;
+            }
+            // Left in place, it's most likely a file copied by mistake
+            let valid = SegmentIter::open(&path, Bound::Unbounded, 0)
+                .and_then(|_| segment::database(&path));
+            if let Ok(Some(other)) = valid {
+                if other != identity {
+                    return Err(Error::ForeignSegment(path));
+                }
+            }
+            match valid {
+                Ok(_) => {
+                    events.log(format_args!(
+                        "recovery: orphaned segment {} adopted",
+                        path.display()
+                    ));
+                    // Without level directories only the flushes can leave a segment behind
+                    match level == 1 && layout.has_level_dirs() {
+                        true => compacted.push((id, path)),
+                        false => flushed.push((id, path)),
+                    }
+                }
+                Err(e) => {
+                    fs::remove_file(&path)?;
+                    events.log(format_args!(
+                        "recovery: invalid orphaned segment {} deleted: {e}",
+                        path.display()
+                    ));
+                }
+            }
+        }
+    }
+    compacted.sort_unstable();
+    flushed.sort_unstable();
+
+    let changed = !compacted.is_empty() || !flushed.is_empty() || !quarantined.is_empty();
+    let segments: VecDeque<_> = compacted
+        .into_iter()
+        .chain(listed)
+        .chain(flushed)
+        .map(|(id, path)| {
+            let mut segment = Segment::new(id, path, pool.clone());
+            segment.holes = holes.remove(&segment.path).unwrap_or_default();
+            segment
+        })
+        .collect();
+    // The manifests written before the id was recorded only know the ids of their segments
+    let next_id = segments
+        .iter()
+        .map(|segment| segment.id + 1)
+        .fold(next_id, usize::max);
+    if changed {
+        // The count and the sequence number don't match the segments anymore
+        live_keys = None;
+        sequence = None;
+        write(
+            root,
+            layout,
+            &segments,
+            live_keys,
+            limits,
+            clock,
+            sequence.unwrap_or(0),
+            next_id,
+            None,
+        )?;
+    }
+    Ok(Recovered {
+        segments,
+        next_id,
+        quarantined,
+        live_keys,
+        limits,
+        clock,
+        sequence,
+    })
+}
+
+/// Split a line of holes into the path of the segment and the runs following it, the path may
+/// contain spaces.
+fn parse_holes(line: &str) -> Option<(&str, Vec<Range<u64>>)> {
+    let mut runs = Vec::new();
+    let mut path = line;
+    while let Some((head, run)) = path.rsplit_once(' ') {
+        let Some((start, end)) = run.split_once("..") else {
+            break;
+        };
+        match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) => runs.push(start..end),
+            _ => break,
+        }
+        path = head;
+    }
+    (!runs.is_empty()).then_some((path, runs))
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()
+}
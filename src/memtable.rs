@@ -0,0 +1,163 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{iter::RangeTombstone, read_bytes, read_entry_to_vec, read_u32, read_u64, Layout};
+
+/// Identifies the files of saved memtables, followed by the version of their format.
+const MAGIC: &[u8; 8] = b"memtable";
+const VERSION: u32 = 3;
+
+/// The memtable saved on a clean shutdown, so the next open doesn't replay the dirty segment.
+pub(crate) struct Saved {
+    pub memtable: BTreeMap<Vec<u8>, u64>,
+    pub sequence: u64,
+    /// How many live keys the memtable adds to the segments.
+    pub live_keys: i64,
+    /// The largest timestamp given to a write.
+    pub clock: u64,
+    /// The ranges deleted by the dirty segment.
+    pub range_tombstones: Vec<RangeTombstone>,
+}
+
+/// Save the memtable next to the files of the dirty segment.
+///
+/// The number and size of the files of the dirty segment are saved along with it, any write
+/// after the save makes it stale. A checksum covers the whole file.
+pub(crate) fn save(
+    root: &Path,
+    layout: &Layout,
+    wal_files: &[(u64, u64)],
+    saved: &Saved,
+) -> io::Result<()> {
+    let dir = layout.wal_files_dir(root);
+    let file = layout.temp_file(&dir)?;
+    let mut writer = Checksummed {
+        inner: BufWriter::new(file),
+        hasher: crc32fast::Hasher::new(),
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+    writer.write_all(&(wal_files.len() as u32).to_be_bytes())?;
+    for (number, size) in wal_files {
+        writer.write_all(&number.to_be_bytes())?;
+        writer.write_all(&size.to_be_bytes())?;
+    }
+    writer.write_all(&saved.sequence.to_be_bytes())?;
+    writer.write_all(&saved.live_keys.to_be_bytes())?;
+    writer.write_all(&saved.clock.to_be_bytes())?;
+    writer.write_all(&(saved.memtable.len() as u64).to_be_bytes())?;
+    for (key, index) in &saved.memtable {
+        writer.write_all(&(key.len() as u32).to_be_bytes())?;
+        writer.write_all(key)?;
+        writer.write_all(&index.to_be_bytes())?;
+    }
+    writer.write_all(&(saved.range_tombstones.len() as u64).to_be_bytes())?;
+    for range in &saved.range_tombstones {
+        for bound in [&range.start, &range.end] {
+            writer.write_all(&(bound.len() as u32).to_be_bytes())?;
+            writer.write_all(bound)?;
+        }
+        writer.write_all(&range.seq.to_be_bytes())?;
+    }
+    let checksum = writer.hasher.finalize();
+    let mut writer = writer.inner;
+    writer.write_all(&checksum.to_be_bytes())?;
+    let file = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.persist(layout.memtable_path(root))?;
+    Ok(())
+}
+
+/// Load and delete the saved memtable, returns `None` if there is none or if it doesn't match
+/// the files of the dirty segment anymore.
+pub(crate) fn load(
+    root: &Path,
+    layout: &Layout,
+    wal_files: &[(u64, u64)],
+) -> io::Result<Option<Saved>> {
+    let path = layout.memtable_path(root);
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // The dirty segment is written to right after the open, it would be stale anyway
+    fs::remove_file(&path)?;
+
+    let mut content = Vec::new();
+    BufReader::new(file).read_to_end(&mut content)?;
+    let checksum_start = content.len().checked_sub(4).ok_or_else(invalid)?;
+    let checksum = read_u32(&mut &content[checksum_start..])?;
+    content.truncate(checksum_start);
+    if crc32fast::hash(&content) != checksum {
+        return Err(invalid());
+    }
+
+    let mut reader = content.as_slice();
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+        return Err(invalid());
+    }
+    let count = read_u32(&mut reader)?;
+    let mut saved_files = Vec::with_capacity(wal_files.len());
+    for _ in 0..count {
+        saved_files.push((read_u64(&mut reader)?, read_u64(&mut reader)?));
+    }
+    if saved_files != wal_files {
+        return Ok(None);
+    }
+
+    let sequence = read_u64(&mut reader)?;
+    let live_keys = read_u64(&mut reader)? as i64;
+    let clock = read_u64(&mut reader)?;
+    let mut memtable = BTreeMap::new();
+    let mut key = Vec::new();
+    for _ in 0..read_u64(&mut reader)? {
+        let size = read_u32(&mut reader)?;
+        read_bytes(&mut reader, size as usize, &mut key)?;
+        memtable.insert(key.clone(), read_u64(&mut reader)?);
+    }
+    let mut range_tombstones = Vec::new();
+    for _ in 0..read_u64(&mut reader)? {
+        range_tombstones.push(RangeTombstone {
+            start: read_entry_to_vec(&mut reader)?,
+            end: read_entry_to_vec(&mut reader)?,
+            seq: read_u64(&mut reader)?,
+        });
+    }
+    Ok(Some(Saved {
+        memtable,
+        sequence,
+        live_keys,
+        clock,
+        range_tombstones,
+    }))
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted saved memtable")
+}
+
+/// Computes the checksum of everything written.
+struct Checksummed<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
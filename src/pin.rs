@@ -0,0 +1,109 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    ops::Range,
+    path::Path,
+};
+
+use crate::{sync_dir, Error, Layout, Result};
+
+/// The ranges of keys the compaction filter never sees, see
+/// [`Database::pin_range`](crate::Database::pin_range).
+///
+/// They're stored in the `PINS` file, one range per line as the hexadecimal start and end
+/// separated by a space. The file is atomically replaced each time a range is pinned or unpinned.
+#[derive(Debug, Default)]
+pub(crate) struct Pins {
+    // Sorted, the ranges may overlap
+    ranges: Vec<Range<Vec<u8>>>,
+}
+
+impl Pins {
+    /// Read the pinned ranges, a database without a `PINS` file has none.
+    pub fn load(root: &Path, layout: &Layout) -> Result<Pins> {
+        let content = match fs::read_to_string(layout.pins_path(root)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Pins::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut ranges = Vec::new();
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let range = line
+                .split_once(' ')
+                .and_then(|(start, end)| Some(from_hex(start)?..from_hex(end)?))
+                .ok_or_else(|| Error::InvalidPins(line.to_owned()))?;
+            ranges.push(range);
+        }
+        ranges.sort_unstable_by(|a, b| (&a.start, &a.end).cmp(&(&b.start, &b.end)));
+        Ok(Pins { ranges })
+    }
+
+    pub fn ranges(&self) -> &[Range<Vec<u8>>] {
+        &self.ranges
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        // Only the ranges starting before the key can hold it
+        let before = self
+            .ranges
+            .partition_point(|range| range.start.as_slice() <= key);
+        self.ranges[..before]
+            .iter()
+            .any(|range| key < range.end.as_slice())
+    }
+
+    /// Returns `false` if the range was already pinned.
+    pub fn insert(&mut self, range: Range<Vec<u8>>) -> bool {
+        match self.position(&range) {
+            Ok(_) => false,
+            Err(i) => {
+                self.ranges.insert(i, range);
+                true
+            }
+        }
+    }
+
+    /// Returns `false` if the range wasn't pinned.
+    pub fn remove(&mut self, range: &Range<Vec<u8>>) -> bool {
+        match self.position(range) {
+            Ok(i) => {
+                self.ranges.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Atomically replace the `PINS` file.
+    pub fn write(&self, root: &Path, layout: &Layout) -> io::Result<()> {
+        let mut file = layout.temp_file(root)?;
+        for range in &self.ranges {
+            writeln!(file, "{} {}", to_hex(&range.start), to_hex(&range.end))?;
+        }
+        file.as_file().sync_all()?;
+        file.persist(layout.pins_path(root))?;
+        sync_dir(root)
+    }
+
+    fn position(&self, range: &Range<Vec<u8>>) -> std::result::Result<usize, usize> {
+        self.ranges.binary_search_by(|pinned| {
+            (&pinned.start, &pinned.end).cmp(&(&range.start, &range.end))
+        })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// `None` if it's not made of pairs of hexadecimal digits.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
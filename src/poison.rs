@@ -0,0 +1,44 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{Error, Result};
+
+/// Set once a flush or a compaction panicked midway, the state in memory may then be half
+/// updated and only reopening the database brings it back in line with the files.
+#[derive(Default)]
+pub(crate) struct Poison(Arc<AtomicBool>);
+
+impl Poison {
+    pub fn is_poisoned(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        match self.is_poisoned() {
+            true => Err(Error::Poisoned),
+            false => Ok(()),
+        }
+    }
+
+    /// Mark the start of a flush or a compaction, the database is poisoned if the guard is
+    /// dropped by a panic.
+    pub fn guard(&self) -> Result<PoisonGuard> {
+        self.check()?;
+        Ok(PoisonGuard(self.0.clone()))
+    }
+}
+
+pub(crate) struct PoisonGuard(Arc<AtomicBool>);
+
+impl Drop for PoisonGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+}
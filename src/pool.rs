@@ -0,0 +1,118 @@
+use std::sync::Mutex;
+
+/// The capacity of the smallest buffers kept by the pool, each class doubles the previous one.
+const MIN_CLASS: usize = 4096;
+/// The number of size classes, from 4 KiB to 256 KiB.
+const CLASSES: usize = 7;
+
+/// Reusable buffers for the blocks read and written by the lookups, flushes and compactions.
+///
+/// The buffers are sorted by size class, a buffer is taken from the smallest class that fits
+/// and is only allocated when the class is empty. At most `max_bytes` are retained, the buffers
+/// returned past that limit or larger than the largest class are freed.
+pub(crate) struct BufferPool {
+    max_bytes: usize,
+    inner: Mutex<Classes>,
+}
+
+#[derive(Default)]
+struct Classes {
+    buffers: [Vec<Vec<u8>>; CLASSES],
+    // The capacity of all the retained buffers
+    bytes: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_bytes: usize) -> BufferPool {
+        BufferPool {
+            max_bytes,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// An empty buffer with a capacity of at least `size` bytes.
+    pub fn get(&self, size: usize) -> Vec<u8> {
+        let Some(class) = (0..CLASSES).find(|class| class_size(*class) >= size) else {
+            return Vec::with_capacity(size);
+        };
+        let mut inner = self.inner.lock().unwrap();
+        match inner.buffers[class].pop() {
+            Some(buffer) => {
+                inner.bytes -= buffer.capacity();
+                buffer
+            }
+            None => Vec::with_capacity(class_size(class)),
+        }
+    }
+
+    /// Give back a buffer for the next operations.
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        // The largest class the buffer can stand for
+        let Some(class) = (0..CLASSES)
+            .rev()
+            .find(|class| class_size(*class) <= buffer.capacity())
+        else {
+            return;
+        };
+        if buffer.capacity() > class_size(CLASSES - 1) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.bytes + buffer.capacity() > self.max_bytes {
+            return;
+        }
+        buffer.clear();
+        inner.bytes += buffer.capacity();
+        inner.buffers[class].push(buffer);
+    }
+
+    /// The number of buffers and bytes currently retained.
+    pub fn occupancy(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        let buffers = inner.buffers.iter().map(Vec::len).sum();
+        (buffers, inner.bytes)
+    }
+}
+
+impl Default for BufferPool {
+    /// Retains up to 4 MiB.
+    fn default() -> BufferPool {
+        BufferPool::new(4 * 1024 * 1024)
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    MIN_CLASS << class
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_classes() {
+        let pool = BufferPool::new(30_000);
+        let buffer = pool.get(5000);
+        assert_eq!(buffer.capacity(), 8192);
+        pool.put(buffer);
+        assert_eq!(pool.occupancy(), (1, 8192));
+
+        // The buffer is reused by the next operation of the same size class
+        let mut buffer = pool.get(6000);
+        assert_eq!(buffer.capacity(), 8192);
+        assert_eq!(pool.occupancy(), (0, 0));
+        buffer.extend_from_slice(b"hello");
+        pool.put(buffer);
+        assert!(pool.get(8000).is_empty());
+
+        // The buffers past the limit or too small or too large aren't retained
+        pool.put(Vec::with_capacity(16384));
+        pool.put(Vec::with_capacity(8192));
+        assert_eq!(pool.occupancy(), (2, 16384 + 8192));
+        pool.put(Vec::with_capacity(8192));
+        pool.put(Vec::with_capacity(100));
+        pool.put(Vec::with_capacity(1 << 20));
+        assert_eq!(pool.occupancy(), (2, 16384 + 8192));
+        assert_eq!(pool.get(1 << 20).capacity(), 1 << 20);
+    }
+}
@@ -0,0 +1,39 @@
+/// Extracts a prefix of the keys, added to the filters of the segments along with the keys,
+/// see [`DatabaseBuilder::prefix_extractor`](crate::DatabaseBuilder::prefix_extractor).
+///
+/// The filters then tell whether a segment may hold keys starting with a prefix, which
+/// [`Database::prefix`](crate::Database::prefix) and
+/// [`Database::may_contain_prefix`](crate::Database::may_contain_prefix) use to skip the segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    /// The first bytes of the keys, the shorter keys have no prefix.
+    Fixed(usize),
+    /// The bytes of the keys up to the first occurrence of the delimiter included, the keys
+    /// without it have no prefix.
+    Delimiter(u8),
+}
+
+impl PrefixExtractor {
+    /// The prefix of the key, `None` if it has none.
+    ///
+    /// All the keys starting with a prefix longer than its own prefix share that prefix, thus
+    /// the filters can answer for the longer prefixes too.
+    pub fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            PrefixExtractor::Fixed(len) => key.get(..len),
+            PrefixExtractor::Delimiter(delimiter) => {
+                let end = key.iter().position(|byte| *byte == delimiter)?;
+                Some(&key[..=end])
+            }
+        }
+    }
+
+    /// Recorded after the name of the filter policy, a filter holding the prefixes of another
+    /// extractor still filters the keys but can't answer for the prefixes.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            PrefixExtractor::Fixed(len) => format!("fixed:{len}"),
+            PrefixExtractor::Delimiter(delimiter) => format!("delimiter:{delimiter}"),
+        }
+    }
+}
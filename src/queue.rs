@@ -0,0 +1,62 @@
+use crate::{Database, Key, Result};
+
+/// Durable FIFO queues stored in the database.
+///
+/// The elements of a queue are stored under the [`Key`] made of the queue name followed by the
+/// sequence number of the write that pushed them, thus they're sorted in insertion order.
+/// These keys shouldn't be used for anything else.
+impl Database {
+    /// Push a value at the back of the queue.
+    pub fn push(&mut self, queue: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        // The sequence number the write is going to get
+        let key = Key::new().push(queue.as_ref()).push(self.sequence() + 1);
+        self.add(key, value)
+    }
+
+    /// Remove and return the value at the front of the queue.
+    pub fn pop_front(&mut self, queue: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let first = self.prefix(Key::new().push(queue.as_ref()))?.next();
+        match first {
+            Some(entry) => {
+                let (key, value) = entry?;
+                self.delete(key)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path()).unwrap();
+
+        database.push(b"jobs", b"a").unwrap();
+        database.push(b"jobs", b"b").unwrap();
+        database.push(b"other", b"z").unwrap();
+        database.push(b"jobs", b"c").unwrap();
+        assert_eq!(
+            database.pop_front(b"jobs").unwrap().as_deref(),
+            Some(&b"a"[..])
+        );
+
+        database.flush().unwrap();
+        database.push(b"jobs", b"d").unwrap();
+
+        let mut popped = Vec::new();
+        while let Some(value) = database.pop_front(b"jobs").unwrap() {
+            popped.push(value);
+        }
+        assert_eq!(popped, [b"b", b"c", b"d"]);
+        assert_eq!(
+            database.pop_front(b"other").unwrap().as_deref(),
+            Some(&b"z"[..])
+        );
+        assert_eq!(database.pop_front(b"other").unwrap(), None);
+    }
+}
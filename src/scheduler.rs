@@ -0,0 +1,108 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What a [`Scheduler`] knows about the database when it's asked for a decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerState {
+    /// The number of keys in the memtable.
+    pub memtable_entries: usize,
+    /// See [`Database::dirty_thresholds`](crate::Database::dirty_thresholds).
+    pub dirty_thresholds: usize,
+    /// The number of clean segments.
+    pub segments: usize,
+}
+
+/// Decides when the maintenance work happens and gives the time to the database.
+///
+/// The database only flushes and compacts on its own when the scheduler says so, the
+/// [`Database::flush`](crate::Database::flush) and
+/// [`Database::merge_segment`](crate::Database::merge_segment) methods can always be called
+/// explicitly.
+pub trait Scheduler: Send + Sync {
+    /// The current time, used to timestamp the events log.
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Called after each write, returns `true` to start flushing the memtable.
+    fn should_flush(&self, state: &SchedulerState) -> bool;
+
+    /// Called after each flush, returns `true` to merge the two oldest segments.
+    fn should_merge(&self, state: &SchedulerState) -> bool;
+}
+
+/// Flush the memtable once it holds more keys than the dirty threshold and merge the oldest
+/// segments once there are more than 10 segments.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultScheduler;
+
+impl Scheduler for DefaultScheduler {
+    fn should_flush(&self, state: &SchedulerState) -> bool {
+        state.memtable_entries > state.dirty_thresholds
+    }
+
+    fn should_merge(&self, state: &SchedulerState) -> bool {
+        state.segments > 10
+    }
+}
+
+/// Never flush nor compact on its own and only move the time forward when asked to.
+///
+/// It's meant for the tests that need a deterministic order of the flushes and compactions,
+/// and for the applications running the maintenance themselves. The clones share the same clock.
+///
+/// ```
+/// use std::time::Duration;
+/// use database::{Database, ManualScheduler};
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let scheduler = ManualScheduler::new();
+/// let mut database = Database::builder()
+///     .scheduler(scheduler.clone())
+///     .open(dir.path())
+///     .unwrap();
+/// database.add(b"hello", b"world").unwrap();
+/// scheduler.advance(Duration::from_secs(60));
+/// database.flush().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ManualScheduler {
+    // Nanoseconds since the unix epoch
+    now: Arc<AtomicU64>,
+}
+
+impl ManualScheduler {
+    /// A scheduler whose clock starts at the unix epoch.
+    pub fn new() -> ManualScheduler {
+        ManualScheduler::default()
+    }
+
+    pub fn set_time(&self, time: SystemTime) {
+        let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.now.store(now.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Scheduler for ManualScheduler {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.now.load(Ordering::Relaxed))
+    }
+
+    fn should_flush(&self, _state: &SchedulerState) -> bool {
+        false
+    }
+
+    fn should_merge(&self, _state: &SchedulerState) -> bool {
+        false
+    }
+}
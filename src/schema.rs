@@ -0,0 +1,122 @@
+use std::{collections::BTreeMap, mem};
+
+use crate::{Error, Result};
+
+type Upgrade = Box<dyn Fn(Vec<u8>) -> Vec<u8> + Send + Sync>;
+
+/// Describe the successive binary formats of the values stored in a database.
+///
+/// When a schema is registered every value is written with a tag containing the current
+/// schema version. The values written with an older version are upgraded on the fly when
+/// they're read, and rewritten in the current version when they go through a compaction.
+///
+/// ```
+/// use database::Schema;
+///
+/// // In the version 1 the values were stored as a little endian u32, they're now stored as a big endian u64
+/// let schema = Schema::new(2).upgrade(1, |value| {
+///     let n = u32::from_le_bytes(value.try_into().unwrap());
+///     (n as u64).to_be_bytes().to_vec()
+/// });
+/// ```
+pub struct Schema {
+    version: u32,
+    // Associate a version to the function upgrading a value from this version to the next one
+    upgrades: BTreeMap<u32, Upgrade>,
+}
+
+impl Schema {
+    pub fn new(version: u32) -> Schema {
+        Schema {
+            version,
+            upgrades: BTreeMap::new(),
+        }
+    }
+
+    /// Register the function converting a value from the version `from` to the version `from + 1`.
+    pub fn upgrade(
+        mut self,
+        from: u32,
+        upgrade: impl Fn(Vec<u8>) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Schema {
+        self.upgrades.insert(from, Box::new(upgrade));
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Prefix the value with the current version.
+    pub(crate) fn tag(&self, value: &[u8]) -> Vec<u8> {
+        let mut tagged = Vec::with_capacity(mem::size_of::<u32>() + value.len());
+        tagged.extend_from_slice(&self.version.to_be_bytes());
+        tagged.extend_from_slice(value);
+        tagged
+    }
+
+    /// Remove the tag of a stored value and upgrade it to the current version.
+    pub(crate) fn migrate(&self, stored: Vec<u8>) -> Result<Vec<u8>> {
+        if stored.len() < mem::size_of::<u32>() {
+            return Err(Error::UntaggedValue);
+        }
+        let (version, value) = stored.split_at(mem::size_of::<u32>());
+        let mut version = u32::from_be_bytes(version.try_into().unwrap());
+        let mut value = value.to_vec();
+
+        while version < self.version {
+            let upgrade = self
+                .upgrades
+                .get(&version)
+                .ok_or(Error::UnsupportedSchemaVersion(version))?;
+            value = upgrade(value);
+            version += 1;
+        }
+        if version > self.version {
+            return Err(Error::UnsupportedSchemaVersion(version));
+        }
+
+        Ok(value)
+    }
+
+    /// Upgrade a stored value to the current version while keeping it tagged.
+    pub(crate) fn retag(&self, stored: Vec<u8>) -> Result<Vec<u8>> {
+        if stored.starts_with(&self.version.to_be_bytes()) {
+            return Ok(stored);
+        }
+        Ok(self.tag(&self.migrate(stored)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn migrate() {
+        let schema = Schema::new(3)
+            .upgrade(1, |mut value| {
+                value.push(b'2');
+                value
+            })
+            .upgrade(2, |mut value| {
+                value.push(b'3');
+                value
+            });
+
+        let v1 = Schema::new(1).tag(b"v");
+        assert_eq!(schema.migrate(v1.clone()).unwrap(), b"v23");
+        assert_eq!(schema.retag(v1).unwrap(), schema.tag(b"v23"));
+        assert_eq!(schema.migrate(schema.tag(b"v")).unwrap(), b"v");
+
+        assert!(matches!(
+            schema.migrate(Schema::new(0).tag(b"v")),
+            Err(Error::UnsupportedSchemaVersion(0))
+        ));
+        assert!(matches!(
+            schema.migrate(Schema::new(4).tag(b"v")),
+            Err(Error::UnsupportedSchemaVersion(4))
+        ));
+        assert!(matches!(schema.migrate(vec![0]), Err(Error::UntaggedValue)));
+    }
+}
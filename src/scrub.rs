@@ -0,0 +1,164 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::segment::{Pinned, Scrubbed, Segment};
+
+/// What [`Database::scrub`](crate::Database::scrub) found in the clean segments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// The number of segments read.
+    pub segments: usize,
+    /// The number of blocks read and checked.
+    pub blocks: u64,
+    /// The number of bytes read.
+    pub bytes: u64,
+    /// The segments whose damaged filter was rebuilt out of their entries.
+    pub repaired: Vec<PathBuf>,
+    /// The segments whose entries are damaged, with the error found.
+    pub corrupted: Vec<(PathBuf, String)>,
+}
+
+impl ScrubReport {
+    /// Whether no damage was found.
+    pub fn is_clean(&self) -> bool {
+        self.repaired.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// The result of the scrub of each segment.
+pub(crate) type Results = Vec<(PathBuf, io::Result<Scrubbed>)>;
+
+/// Scrub the segments from time to time on a background thread, reading at most
+/// `bytes_per_second`.
+pub(crate) struct BackgroundScrub {
+    pub interval: Duration,
+    // Shared with the running scrub so it follows the changes of the rate
+    bytes_per_second: Arc<AtomicU64>,
+    /// When the last scrub started.
+    pub last: SystemTime,
+    running: Option<JoinHandle<Results>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl BackgroundScrub {
+    pub fn new(interval: Duration, bytes_per_second: u64, now: SystemTime) -> BackgroundScrub {
+        BackgroundScrub {
+            interval,
+            bytes_per_second: Arc::new(AtomicU64::new(bytes_per_second)),
+            last: now,
+            running: None,
+            stop: Arc::default(),
+        }
+    }
+
+    /// Change the interval and the rate, the running scrub is slowed down or sped up right away.
+    pub fn set_schedule(&mut self, interval: Duration, bytes_per_second: u64) {
+        self.interval = interval;
+        self.bytes_per_second
+            .store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Start reading the segments, the files stay available until the scrub is done.
+    pub fn start(&mut self, segments: Vec<Pinned>, now: SystemTime) {
+        self.last = now;
+        let stop = self.stop.clone();
+        let bytes_per_second = self.bytes_per_second.clone();
+        self.running = Some(thread::spawn(move || {
+            let mut throttle = Throttle::new(bytes_per_second, stop);
+            segments
+                .into_iter()
+                .map(|segment| {
+                    let scrubbed =
+                        Segment::scrub(&segment.path, &segment.holes, |bytes| throttle.wait(bytes));
+                    (segment.path, scrubbed)
+                })
+                .collect()
+        }));
+    }
+
+    /// The results of the scrub once it's done.
+    pub fn finished(&mut self) -> Option<Results> {
+        match &self.running {
+            Some(running) if running.is_finished() => {
+                let running = self.running.take().unwrap();
+                Some(
+                    running
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e)),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Drop for BackgroundScrub {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(running) = self.running.take() {
+            let _ = running.join();
+        }
+    }
+}
+
+/// Spread the reads so they don't go over the rate, and interrupt them once stopped.
+struct Throttle {
+    bytes_per_second: Arc<AtomicU64>,
+    // The rate the bytes were counted at since `start`
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+    stop: Arc<AtomicBool>,
+}
+
+impl Throttle {
+    fn new(bytes_per_second: Arc<AtomicU64>, stop: Arc<AtomicBool>) -> Throttle {
+        Throttle {
+            rate: bytes_per_second.load(Ordering::Relaxed).max(1),
+            bytes_per_second,
+            start: Instant::now(),
+            bytes: 0,
+            stop,
+        }
+    }
+
+    /// Wait until `bytes` more can be read.
+    fn wait(&mut self, bytes: u64) -> io::Result<()> {
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "scrub interrupted",
+                ));
+            }
+            // The bytes read before a change of the rate were already spread at the previous one
+            let rate = self.bytes_per_second.load(Ordering::Relaxed).max(1);
+            if rate != self.rate {
+                self.rate = rate;
+                self.start = Instant::now();
+                self.bytes = 0;
+            }
+            let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+            match due.checked_sub(self.start.elapsed()) {
+                // Wake up regularly so the database isn't kept waiting when it's closed, and the
+                // changes of the rate apply
+                Some(wait) => thread::sleep(wait.min(Duration::from_millis(100))),
+                None => break,
+            }
+        }
+        self.bytes += bytes;
+        Ok(())
+    }
+}
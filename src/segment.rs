@@ -0,0 +1,3014 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::{Bound, Range, RangeBounds, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, Weak,
+    },
+    vec,
+};
+
+use crate::{
+    batch::BatchRead,
+    blob::BlobStore,
+    cache::BlockCache,
+    encoding::StoredLen,
+    files::FilePool,
+    iter::{range_deleted, Entry, KeyFilter, MergeIter, RangeTombstone, Source},
+    pin::Pins,
+    pool::BufferPool,
+    read_bytes, read_u32, read_u64, read_u8,
+    uncached::{self, SegmentFile},
+    vlog::{ValueLog, ValuePointer},
+    write_segment, CompactionFilter, Encoding, Error, Filter, FilterPolicy, PrefixExtractor,
+    Result, Schema, Written,
+};
+
+/// By default a block is closed once its encoded size reaches this size, see [`BlockSizes`].
+const BLOCK_SIZE: usize = 4096;
+/// The default number of entries between two restart points of a block.
+const RESTART_INTERVAL: usize = 16;
+/// The number of data blocks read at once by [`Segment::warm`].
+const WARM_BATCH: usize = 64;
+/// Ends all the segments, it's used to detect the truncated segments.
+const MAGIC: u64 = u64::from_be_bytes(*b"irevoire");
+/// Prefixes the values stored in the blocks, or in blobs, read with [`SegmentIter::stored_values`].
+const STORED_INLINE: u8 = 0;
+/// Prefixes the pointers in the value log read with [`SegmentIter::stored_values`].
+const STORED_POINTER: u8 = 1;
+
+/// A clean segment is a sequence of data blocks followed by the filter, the range tombstones,
+/// the blobs referenced, the bytes of the value log referenced, the index blocks, the
+/// top-level index and the footer.
+///
+/// The entries of a block share the prefix of their key with the previous entry, except for
+/// the restart points where the full key is stored. A block ends with the offsets of its
+/// restart points followed by their count, thus it can be decoded without the rest of the segment.
+///
+/// The index blocks associate the first key of each data block to its offset and size, and the
+/// top-level index does the same for the index blocks. The versions of a key are never split
+/// between two data blocks, thus a point lookup only reads one data block.
+///
+/// The filter starts with the name of the policy that created it.
+///
+/// The lengths and sequence numbers of the entries of the blocks are written with the [`Encoding`]
+/// recorded by the format version of the footer.
+pub(crate) struct Segment {
+    pub id: usize,
+    pub path: PathBuf,
+    // Shared with the iterators reading the segment
+    lease: Arc<Lease>,
+    // Gives the buffers the blocks are read in
+    pool: Arc<BufferPool>,
+    // Loaded on the first lookup, `None` if the segment has no filter usable by the current policy
+    filter: OnceLock<Option<LoadedFilter>>,
+    // The smallest and largest keys, loaded on the first lookup, `None` if the segment is empty
+    fence: OnceLock<Option<(Vec<u8>, Vec<u8>)>>,
+    // Loaded once, `None` if the segment was written before they were recorded
+    counters: OnceLock<Option<SegmentCounters>>,
+    // Loaded on the first lookup
+    range_tombstones: OnceLock<Vec<RangeTombstone>>,
+    // The hashes of the blobs referenced, loaded by the first collection of the blobs
+    blob_refs: OnceLock<Vec<u128>>,
+    // The bytes referenced in each file of the value log, loaded by the first collection
+    value_refs: OnceLock<Vec<(u32, u64)>>,
+    /// The data blocks punched out of the file.
+    pub holes: Holes,
+    /// Where the values of the entries referencing a blob are read, they can't be read without it.
+    pub blob_store: Option<Arc<BlobStore>>,
+    /// Where the values of the entries holding a value pointer are read.
+    pub value_log: Option<Arc<ValueLog>>,
+}
+
+/// The filter of a segment once read.
+struct LoadedFilter {
+    filter: Box<dyn Filter>,
+    // The size of the filter in the segment
+    bytes: usize,
+    // The name of the extractor whose prefixes were added along with the keys, empty if none
+    prefixes: String,
+}
+
+/// The runs of data blocks punched out of the file of a segment, see
+/// [`Database::punch_holes`](crate::Database::punch_holes).
+///
+/// They're sorted by offset and recorded in the manifest, the readers skip the blocks starting
+/// in them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Holes(Vec<Range<u64>>);
+
+impl Holes {
+    pub fn new(mut runs: Vec<Range<u64>>) -> Holes {
+        runs.sort_unstable_by_key(|run| run.start);
+        Holes(runs)
+    }
+
+    pub fn runs(&self) -> &[Range<u64>] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether the block starting at `offset` was punched out.
+    pub fn contains(&self, offset: u64) -> bool {
+        let run = self.0.partition_point(|run| run.end <= offset);
+        self.0.get(run).is_some_and(|run| run.start <= offset)
+    }
+
+    pub fn extend(&mut self, runs: impl IntoIterator<Item = Range<u64>>) {
+        self.0.extend(runs);
+        self.0.sort_unstable_by_key(|run| run.start);
+    }
+}
+
+/// What a segment holds, recorded in its footer since the format version 14.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentCounters {
+    /// The number of entries, each version of a key counts.
+    pub entries: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// The number of deleted entries, they have no value.
+    pub tombstones: u64,
+}
+
+impl SegmentCounters {
+    const SIZE: usize = 32;
+
+    fn add(&mut self, key: &[u8], value_len: Option<usize>) {
+        self.entries += 1;
+        self.key_bytes += key.len() as u64;
+        match value_len {
+            Some(len) => self.value_bytes += len as u64,
+            None => self.tombstones += 1,
+        }
+    }
+
+    fn encode(&self) -> [u8; SegmentCounters::SIZE] {
+        let mut buf = [0; SegmentCounters::SIZE];
+        let counters = [
+            self.entries,
+            self.key_bytes,
+            self.value_bytes,
+            self.tombstones,
+        ];
+        for (chunk, counter) in buf.chunks_mut(8).zip(counters) {
+            chunk.copy_from_slice(&counter.to_be_bytes());
+        }
+        buf
+    }
+
+    fn decode(mut bytes: &[u8]) -> io::Result<SegmentCounters> {
+        Ok(SegmentCounters {
+            entries: read_u64(&mut bytes)?,
+            key_bytes: read_u64(&mut bytes)?,
+            value_bytes: read_u64(&mut bytes)?,
+            tombstones: read_u64(&mut bytes)?,
+        })
+    }
+}
+
+impl Segment {
+    pub fn new(id: usize, path: PathBuf, pool: Arc<BufferPool>) -> Segment {
+        Segment {
+            id,
+            pool,
+            lease: Arc::new(Lease {
+                path: path.clone(),
+                obsolete: AtomicBool::new(false),
+            }),
+            path,
+            filter: OnceLock::new(),
+            fence: OnceLock::new(),
+            counters: OnceLock::new(),
+            range_tombstones: OnceLock::new(),
+            blob_refs: OnceLock::new(),
+            value_refs: OnceLock::new(),
+            holes: Holes::default(),
+            blob_store: None,
+            value_log: None,
+        }
+    }
+
+    /// Iterate over the entries of the segment, the file stays available until the iterator is
+    /// dropped even if the segment is replaced by a compaction.
+    ///
+    /// Once the blocks are read sequentially the next `read_ahead` bytes are prefetched.
+    pub fn iter(&self, start: Bound<Vec<u8>>, read_ahead: u64) -> io::Result<SegmentIter> {
+        let mut iter = SegmentIter::open(&self.path, start, read_ahead)?;
+        iter.lease = Some(self.lease.clone());
+        iter.pool = Some(self.pool.clone());
+        iter.holes = self.holes.clone();
+        iter.blob_store = self.blob_store.clone();
+        iter.value_log = self.value_log.clone();
+        Ok(iter)
+    }
+
+    /// Iterate over all the entries of the segment without keeping it in the page cache.
+    pub fn iter_uncached(&self, read_ahead: u64) -> io::Result<SegmentIter> {
+        let mut iter = SegmentIter::open_uncached(&self.path, read_ahead)?;
+        iter.pool = Some(self.pool.clone());
+        iter.holes = self.holes.clone();
+        iter.blob_store = self.blob_store.clone();
+        iter.value_log = self.value_log.clone();
+        Ok(iter)
+    }
+
+    /// Whether an iterator or a [`Pinned`] path is reading the file of the segment.
+    pub fn in_use(&self) -> bool {
+        Arc::strong_count(&self.lease) > 1
+    }
+
+    /// Tells whether the file is still read once the segment is retired.
+    pub fn watch(&self) -> Watch {
+        Watch(Arc::downgrade(&self.lease))
+    }
+
+    /// The path of the segment, its file stays available until the returned value is dropped
+    /// even if the segment is replaced by a compaction.
+    pub fn pin(&self) -> Pinned {
+        Pinned {
+            path: self.path.clone(),
+            holes: self.holes.clone(),
+            _lease: self.lease.clone(),
+        }
+    }
+
+    /// Delete the file of a segment replaced by a compaction, or let the last iterator reading it
+    /// delete it.
+    pub fn retire(self) -> io::Result<()> {
+        match Arc::try_unwrap(self.lease) {
+            Ok(lease) => fs::remove_file(&lease.path),
+            Err(lease) => {
+                lease.obsolete.store(true, Ordering::Release);
+                Ok(())
+            }
+        }
+    }
+
+    /// Check the footer and the index of a segment without reading its data blocks.
+    ///
+    /// The data blocks must follow each other from the start of the file up to the filter and
+    /// the index blocks, and their first keys must be sorted.
+    pub fn validate(path: &Path) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let footer = read_footer(&mut file)?;
+        let len = file.metadata()?.len();
+        let out_of_place = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted segment, {what} out of place"),
+            )
+        };
+        let end = |handle: BlockHandle| handle.offset + handle.size as u64;
+        if end(footer.index) > len - Footer::MAX_SIZE.min(len) {
+            return Err(out_of_place("top index"));
+        }
+
+        let top = read_index(&mut file, footer.index, footer.format, None)?;
+        let mut offset = 0;
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut index_blocks = Vec::with_capacity(top.len());
+        for (key, handle) in &top {
+            let index = read_index(&mut file, *handle, footer.format, None)?;
+            if index.first().map(|(first, _)| first) != Some(key) {
+                return Err(out_of_place("index block"));
+            }
+            for (key, handle) in index {
+                if handle.offset != offset || last_key.as_ref().is_some_and(|last| *last >= key) {
+                    return Err(out_of_place("data block"));
+                }
+                offset = end(handle);
+                last_key = Some(key);
+            }
+            index_blocks.push(*handle);
+        }
+
+        // Then comes the filter, the range tombstones, the blobs and the bytes of the value log
+        // referenced, the index blocks and the top index
+        if let Some(filter) = footer.filter {
+            if filter.offset != offset {
+                return Err(out_of_place("filter"));
+            }
+            offset = end(filter);
+        }
+        if let Some(range_tombstones) = footer.range_tombstones {
+            if range_tombstones.offset != offset {
+                return Err(out_of_place("range tombstones"));
+            }
+            offset = end(range_tombstones);
+        }
+        if let Some(blob_refs) = footer.blob_refs {
+            if blob_refs.offset != offset {
+                return Err(out_of_place("blobs referenced"));
+            }
+            offset = end(blob_refs);
+        }
+        if let Some(value_refs) = footer.value_refs {
+            if value_refs.offset != offset {
+                return Err(out_of_place("value log referenced"));
+            }
+            offset = end(value_refs);
+        }
+        for handle in index_blocks.into_iter().chain([footer.index]) {
+            if handle.offset != offset {
+                return Err(out_of_place("index block"));
+            }
+            offset = end(handle);
+        }
+        Ok(())
+    }
+
+    /// Read every block of a segment, except the ones punched out of the file, check their
+    /// checksum and decode their entries.
+    ///
+    /// `throttle` is called with the size of each block before it's read. A damaged filter is
+    /// reported in the result since it can be rebuilt out of the entries, any other damage is
+    /// returned as an error.
+    pub fn scrub(
+        path: &Path,
+        holes: &Holes,
+        mut throttle: impl FnMut(u64) -> io::Result<()>,
+    ) -> io::Result<Scrubbed> {
+        // The whole segment is read once, it doesn't need to stay in the page cache
+        let mut file = SegmentFile::new(File::open(path)?, true, 0);
+        let footer = read_footer(&mut file)?;
+        let mut scrubbed = Scrubbed {
+            blocks: 0,
+            bytes: 0,
+            damaged_filter: None,
+        };
+        let mut read = |file: &mut SegmentFile, handle: BlockHandle| {
+            throttle(handle.size as u64)?;
+            scrubbed.blocks += 1;
+            scrubbed.bytes += handle.size as u64;
+            handle.read(file, footer.format, None)
+        };
+
+        let top = decode_index(read(&mut file, footer.index)?)?;
+        for (_, handle) in top {
+            let index = decode_index(read(&mut file, handle)?)?;
+            for (_, handle) in index {
+                if holes.contains(handle.offset) {
+                    continue;
+                }
+                // The blobs and the value log aren't read
+                for entry in read(&mut file, handle)?.keys() {
+                    entry?;
+                }
+            }
+        }
+
+        if let Some(handle) = footer.filter {
+            throttle(handle.size as u64)?;
+            scrubbed.blocks += 1;
+            scrubbed.bytes += handle.size as u64;
+            file.seek(SeekFrom::Start(handle.offset))?;
+            let mut buf = Vec::new();
+            read_bytes(&mut file, handle.size as usize, &mut buf)?;
+            scrubbed.damaged_filter = handle.verify(&buf).err();
+        }
+        if let Some(handle) = footer.range_tombstones {
+            throttle(handle.size as u64)?;
+            scrubbed.blocks += 1;
+            scrubbed.bytes += handle.size as u64;
+            read_range_tombstones(&mut file, handle)?;
+        }
+        if let Some(handle) = footer.blob_refs {
+            throttle(handle.size as u64)?;
+            scrubbed.blocks += 1;
+            scrubbed.bytes += handle.size as u64;
+            read_blob_refs(&mut file, handle)?;
+        }
+        if let Some(handle) = footer.value_refs {
+            throttle(handle.size as u64)?;
+            scrubbed.blocks += 1;
+            scrubbed.bytes += handle.size as u64;
+            read_value_refs(&mut file, handle)?;
+        }
+        Ok(scrubbed)
+    }
+
+    /// Returns the filter of the segment if it was written with the same policy.
+    pub fn filter(
+        &self,
+        files: &mut FilePool,
+        policy: &dyn FilterPolicy,
+    ) -> Result<Option<&dyn Filter>> {
+        if self.filter.get().is_none() {
+            let file = files.get(&self.path)?;
+            let footer = read_footer(file)?;
+            let _ = self.filter.set(load_filter(file, &footer, policy)?);
+        }
+        let filter = self.filter.get().and_then(Option::as_ref);
+        Ok(filter.map(|loaded| loaded.filter.as_ref()))
+    }
+
+    /// Read the counters and the range deletions of the segment, and its filter when a policy
+    /// is given, so the first lookups don't have to.
+    ///
+    /// The file is opened apart from the [`FilePool`] so several segments can be loaded at
+    /// once, see [`DatabaseBuilder::open_threads`](crate::DatabaseBuilder::open_threads).
+    pub fn preload(&self, policy: Option<&dyn FilterPolicy>, database: u128) -> Result<()> {
+        let mut file = File::open(&self.path)?;
+        let footer = read_footer(&mut file)?;
+        if footer.database.is_some_and(|other| other != database) {
+            return Err(Error::ForeignSegment(self.path.clone()));
+        }
+        let _ = self.counters.set(footer.counters);
+        if self.range_tombstones.get().is_none() {
+            let range_tombstones = match footer.range_tombstones {
+                Some(handle) => read_range_tombstones(&mut file, handle)?,
+                None => Vec::new(),
+            };
+            let _ = self.range_tombstones.set(range_tombstones);
+        }
+        if let Some(policy) = policy {
+            if self.filter.get().is_none() {
+                let _ = self.filter.set(load_filter(&mut file, &footer, policy)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the filter of the segment if it was written with the same policy and holds the
+    /// prefixes of the same extractor.
+    pub fn prefix_filter(
+        &self,
+        files: &mut FilePool,
+        policy: &dyn FilterPolicy,
+        extractor: &PrefixExtractor,
+    ) -> Result<Option<&dyn Filter>> {
+        self.filter(files, policy)?;
+        let filter = self.filter.get().and_then(Option::as_ref);
+        Ok(filter
+            .filter(|loaded| loaded.prefixes == extractor.name())
+            .map(|loaded| loaded.filter.as_ref()))
+    }
+
+    /// The size of the filter once loaded, the filters are assumed to take as much memory as
+    /// they take in the segment.
+    pub fn filter_bytes(&self) -> usize {
+        match self.filter.get() {
+            Some(Some(loaded)) => loaded.bytes,
+            _ => 0,
+        }
+    }
+
+    /// Whether the key is between the smallest and the largest key of the segment.
+    ///
+    /// It's checked before the filter since it only costs two comparisons once loaded.
+    pub fn in_fence(&self, files: &mut FilePool, key: &[u8]) -> Result<bool> {
+        Ok(match self.fence(files)? {
+            Some((first, last)) => first.as_slice() <= key && key <= last.as_slice(),
+            None => false,
+        })
+    }
+
+    /// The smallest and largest keys of the segment, `None` if it's empty.
+    pub fn fence(&self, files: &mut FilePool) -> Result<Option<&(Vec<u8>, Vec<u8>)>> {
+        if self.fence.get().is_none() {
+            let file = files.get(&self.path)?;
+            let footer = read_footer(file)?;
+            let fence = read_fence(file, &footer, Some(&self.pool))?;
+            let _ = self.fence.set(fence);
+        }
+        Ok(self.fence.get().unwrap().as_ref())
+    }
+
+    /// The counters recorded in the footer, `None` if the segment was written before they were.
+    pub fn counters(&self, files: &mut FilePool) -> Result<Option<SegmentCounters>> {
+        if self.counters.get().is_none() {
+            let counters = read_footer(files.get(&self.path)?)?.counters;
+            let _ = self.counters.set(counters);
+        }
+        Ok(self.loaded_counters())
+    }
+
+    /// The ranges of keys deleted by the segment, they hide the entries of the older segments.
+    pub fn range_tombstones(&self, files: &mut FilePool) -> Result<&[RangeTombstone]> {
+        if self.range_tombstones.get().is_none() {
+            let file = files.get(&self.path)?;
+            let range_tombstones = match read_footer(file)?.range_tombstones {
+                Some(handle) => read_range_tombstones(file, handle)?,
+                None => Vec::new(),
+            };
+            let _ = self.range_tombstones.set(range_tombstones);
+        }
+        Ok(self.range_tombstones.get().unwrap())
+    }
+
+    /// The hashes of the blobs referenced by the entries of the segment.
+    pub fn blob_refs(&self, files: &mut FilePool) -> Result<&[u128]> {
+        if self.blob_refs.get().is_none() {
+            let file = files.get(&self.path)?;
+            let blob_refs = match read_footer(file)?.blob_refs {
+                Some(handle) => read_blob_refs(file, handle)?,
+                None => Vec::new(),
+            };
+            let _ = self.blob_refs.set(blob_refs);
+        }
+        Ok(self.blob_refs.get().unwrap())
+    }
+
+    /// The ids of the files of the value log referenced by the entries of the segment, with
+    /// the number of bytes of their values.
+    pub fn value_refs(&self, files: &mut FilePool) -> Result<&[(u32, u64)]> {
+        if self.value_refs.get().is_none() {
+            let file = files.get(&self.path)?;
+            let value_refs = match read_footer(file)?.value_refs {
+                Some(handle) => read_value_refs(file, handle)?,
+                None => Vec::new(),
+            };
+            let _ = self.value_refs.set(value_refs);
+        }
+        Ok(self.value_refs.get().unwrap())
+    }
+
+    /// The counters if they were already loaded by [`Segment::counters`].
+    pub fn loaded_counters(&self) -> Option<SegmentCounters> {
+        self.counters.get().copied().flatten()
+    }
+
+    /// The smallest and largest sequence numbers of the segment, `None` if it's empty.
+    ///
+    /// They're recorded in the footer since the format version 8, the entries of the older
+    /// segments are scanned.
+    pub fn seqs(&self, files: &mut FilePool) -> Result<Option<RangeInclusive<u64>>> {
+        let file = files.get(&self.path)?;
+        if let Some(seqs) = read_footer(file)?.seqs {
+            return Ok(Some(seqs).filter(|seqs| !seqs.is_empty()));
+        }
+        let mut seqs: Option<RangeInclusive<u64>> = None;
+        let mut iter = SegmentIter::new(file, Bound::Unbounded)?;
+        iter.holes = self.holes.clone();
+        iter.keys_only();
+        for entry in iter {
+            let seq = entry?.seq;
+            seqs = Some(match seqs {
+                Some(seqs) => *seqs.start().min(&seq)..=*seqs.end().max(&seq),
+                None => seq..=seq,
+            });
+        }
+        Ok(seqs)
+    }
+
+    /// Return up to `limit` versions of the key from the most recent to the oldest one.
+    pub fn versions(
+        &self,
+        files: &mut FilePool,
+        key: &[u8],
+        limit: usize,
+    ) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        let file = files.get(&self.path)?;
+        let mut iter = SegmentIter::new(file, Bound::Included(key.to_vec()))?;
+        iter.pool = Some(self.pool.clone());
+        iter.holes = self.holes.clone();
+        iter.blob_store = self.blob_store.clone();
+        iter.value_log = self.value_log.clone();
+        let mut versions = Vec::new();
+        // All the versions are stored in the block that may contain the key
+        let Some(block) = iter.next_block()? else {
+            return Ok(versions);
+        };
+
+        for entry in block.seek_exact(key)? {
+            let entry = entry?;
+            // the keys are sorted, it can't be further
+            if entry.key != key || versions.len() == limit {
+                break;
+            }
+            versions.push((entry.seq, entry.value));
+        }
+
+        Ok(versions)
+    }
+
+    /// Look up the most recent version of each key, its value is `None` if it was deleted in this
+    /// segment.
+    ///
+    /// Each level of the index is read for all the keys at once with `reads`, the blocks found
+    /// in the `cache` aren't read again.
+    pub fn multi_get(
+        &self,
+        files: &mut FilePool,
+        keys: &[&[u8]],
+        reads: &mut dyn BatchRead,
+        cache: &mut BlockCache,
+    ) -> Result<Vec<Option<Entry>>> {
+        Ok(self.lookup(files, keys, reads, cache)?.entries)
+    }
+
+    /// Look up the key like [`Segment::multi_get`] and report what was read to find it.
+    pub fn traced_get(
+        &self,
+        files: &mut FilePool,
+        key: &[u8],
+        reads: &mut dyn BatchRead,
+        cache: &mut BlockCache,
+    ) -> Result<(Option<Entry>, LookupTrace)> {
+        let mut lookup = self.lookup(files, &[key], reads, cache)?;
+        let trace = LookupTrace {
+            block: lookup.blocks[0],
+            blocks_read: lookup.blocks_read,
+        };
+        Ok((lookup.entries.pop().flatten(), trace))
+    }
+
+    /// Read the index and data blocks that may hold the keys of `bounds` through the `cache`,
+    /// until `budget` bytes were read. Returns the number of bytes read.
+    ///
+    /// The index blocks are read first, then the data blocks from the start of the range by
+    /// batches of [`WARM_BATCH`] blocks.
+    pub fn warm(
+        &self,
+        files: &mut FilePool,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        budget: u64,
+        reads: &mut dyn BatchRead,
+        cache: &mut BlockCache,
+    ) -> Result<u64> {
+        let mut blocks = BlockReads {
+            reads,
+            cache,
+            segment: self.id,
+            pool: &self.pool,
+            from_disk: 0,
+        };
+        let file = files.get(&self.path)?;
+        let len = file.metadata()?.len();
+        let footer = Footer::decode(
+            &blocks
+                .reads
+                .read_batch(file, &[Footer::handle(len)], &self.pool)?[0],
+        )?;
+        let format = footer.format;
+        let mut read = 0;
+        // Takes the handles fitting in what remains of the budget
+        let mut within_budget = |handles: Vec<BlockHandle>| {
+            let mut kept = Vec::with_capacity(handles.len());
+            for handle in handles {
+                if read + handle.size as u64 > budget {
+                    break;
+                }
+                read += handle.size as u64;
+                kept.push(handle);
+            }
+            kept
+        };
+
+        let top = within_budget(vec![footer.index]);
+        let Some(top) = blocks.read(file, &top, format)?.pop() else {
+            return Ok(read);
+        };
+        let top = decode_index(top)?;
+        let handles = within_budget(blocks_in(&top, bounds));
+        let mut data = Vec::new();
+        for index in blocks.read(file, &handles, format)? {
+            let index = decode_index(index)?;
+            let handles = blocks_in(&index, bounds);
+            // The entries of the punched blocks were all deleted
+            data.extend(
+                handles
+                    .into_iter()
+                    .filter(|h| !self.holes.contains(h.offset)),
+            );
+        }
+        for handles in within_budget(data).chunks(WARM_BATCH) {
+            blocks.read(file, handles, format)?;
+        }
+        Ok(read)
+    }
+
+    fn lookup(
+        &self,
+        files: &mut FilePool,
+        keys: &[&[u8]],
+        reads: &mut dyn BatchRead,
+        cache: &mut BlockCache,
+    ) -> Result<Lookup> {
+        let mut blocks = BlockReads {
+            reads,
+            cache,
+            segment: self.id,
+            pool: &self.pool,
+            from_disk: 0,
+        };
+        let file = files.get(&self.path)?;
+        let len = file.metadata()?.len();
+        let pool = &self.pool;
+        let footer = Footer::decode(
+            &blocks
+                .reads
+                .read_batch(file, &[Footer::handle(len)], pool)?[0],
+        )?;
+        let format = footer.format;
+        let top = blocks.read(file, &[footer.index], format)?.remove(0);
+        let top = decode_index(top)?;
+        if top.is_empty() {
+            return Ok(Lookup {
+                entries: vec![None; keys.len()],
+                blocks: vec![None; keys.len()],
+                blocks_read: blocks.from_disk,
+            });
+        }
+
+        // The index block and then the data block that may contain each key
+        let handles: Vec<_> = keys
+            .iter()
+            .map(|key| top[find_block(&top, key)].1)
+            .collect();
+        let mut indexes = Vec::new();
+        for block in blocks.read(file, &handles, format)? {
+            indexes.push(decode_index(block)?);
+        }
+        let handles: Vec<_> = keys
+            .iter()
+            .zip(&indexes)
+            .map(|(key, index)| index.get(find_block(index, key)).map(|(_, handle)| *handle))
+            // The entries of the punched blocks were all deleted
+            .map(|handle| handle.filter(|handle| !self.holes.contains(handle.offset)))
+            .collect();
+        let data = blocks.read(
+            file,
+            &handles.iter().flatten().copied().collect::<Vec<_>>(),
+            format,
+        )?;
+
+        let mut data = data.into_iter();
+        let mut values = Vec::with_capacity(keys.len());
+        for (key, handle) in keys.iter().zip(&handles) {
+            let value = match handle.and_then(|_| data.next()) {
+                Some(mut block) => {
+                    block.blob_store = self.blob_store.clone();
+                    block.value_log = self.value_log.clone();
+                    match block.seek_exact(key)?.next().transpose()? {
+                        Some(entry) if entry.key == *key => Some(entry),
+                        _ => None,
+                    }
+                }
+                None => None,
+            };
+            values.push(value);
+        }
+        Ok(Lookup {
+            entries: values,
+            blocks: handles.iter().map(|h| h.map(|h| h.offset)).collect(),
+            blocks_read: blocks.from_disk,
+        })
+    }
+
+    /// The largest key of the segment contained in `..end`, including the deleted ones.
+    ///
+    /// The index is read from the end, only the last data block that may hold such a key is read.
+    pub fn last_key(&self, files: &mut FilePool, end: Bound<&[u8]>) -> Result<Option<Vec<u8>>> {
+        let file = files.get(&self.path)?;
+        let footer = read_footer(file)?;
+        let pool = Some(&self.pool);
+        let before_end = |key: &[u8]| match end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+
+        let top = read_index(file, footer.index, footer.format, pool)?;
+        // The blocks whose first key is out of the bounds can't hold a key in the bounds
+        let last_index = top.partition_point(|(first, _)| before_end(first));
+        for (_, handle) in top[..last_index].iter().rev() {
+            let index = read_index(file, *handle, footer.format, pool)?;
+            let last_block = index.partition_point(|(first, _)| before_end(first));
+            for (_, handle) in index[..last_block].iter().rev() {
+                if self.holes.contains(handle.offset) {
+                    continue;
+                }
+                let mut last = None;
+                for entry in handle.read(file, footer.format, pool)?.keys() {
+                    let entry = entry?;
+                    if !before_end(&entry.key) {
+                        break;
+                    }
+                    last = Some(entry.key);
+                }
+                if last.is_some() {
+                    return Ok(last);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the first key of up to `count` data blocks evenly spread across the segment.
+    ///
+    /// Only the index is read, the blocks having about the same size each key stands for the same
+    /// share of the segment.
+    pub fn sample_keys(&self, files: &mut FilePool, count: usize) -> Result<Vec<Vec<u8>>> {
+        let file = files.get(&self.path)?;
+        let footer = read_footer(file)?;
+        let mut index = Vec::new();
+        let pool = Some(&self.pool);
+        for (_, handle) in read_index(file, footer.index, footer.format, pool)? {
+            index.extend(read_index(file, handle, footer.format, pool)?);
+        }
+        let step = index.len().div_ceil(count.max(1)).max(1);
+        Ok(index
+            .into_iter()
+            .step_by(step)
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    /// The size of the data blocks that may hold the keys of `bounds`, and of all the data
+    /// blocks of the segment. Only the index is read, the punched blocks aren't counted.
+    pub fn block_bytes(
+        &self,
+        files: &mut FilePool,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Result<(u64, u64)> {
+        let file = files.get(&self.path)?;
+        let footer = read_footer(file)?;
+        let pool = Some(&self.pool);
+        let (mut in_range, mut total) = (0, 0);
+        let top = read_index(file, footer.index, footer.format, pool)?;
+        let in_bounds = blocks_in(&top, bounds);
+        for (_, handle) in top {
+            let index = read_index(file, handle, footer.format, pool)?;
+            let live = |handle: &BlockHandle| !self.holes.contains(handle.offset);
+            let size = |handle: BlockHandle| handle.size as u64;
+            total += index
+                .iter()
+                .map(|(_, h)| *h)
+                .filter(live)
+                .map(size)
+                .sum::<u64>();
+            if in_bounds.contains(&handle) {
+                let blocks = blocks_in(&index, bounds).into_iter().filter(live);
+                in_range += blocks.map(size).sum::<u64>();
+            }
+        }
+        Ok((in_range, total))
+    }
+
+    /// The runs of data blocks whose entries are all deleted by `range_tombstones`, as the part
+    /// of the file they span.
+    ///
+    /// The blocks already punched out end the runs, and so does the last block since the fence
+    /// is read from it. The runs that don't cover a whole page are left out, punching them
+    /// wouldn't release anything.
+    pub fn dead_runs(
+        &self,
+        files: &mut FilePool,
+        range_tombstones: &[RangeTombstone],
+    ) -> Result<Vec<Range<u64>>> {
+        let file = files.get(&self.path)?;
+        let footer = read_footer(file)?;
+        let pool = Some(&self.pool);
+        let mut blocks = Vec::new();
+        for (_, handle) in read_index(file, footer.index, footer.format, pool)? {
+            let index = read_index(file, handle, footer.format, pool)?;
+            blocks.extend(index.into_iter().map(|(_, handle)| handle));
+        }
+        blocks.pop();
+
+        let mut runs = Vec::new();
+        let mut run: Option<Range<u64>> = None;
+        for handle in blocks {
+            let mut dead = !self.holes.contains(handle.offset);
+            if dead {
+                for entry in handle.read(file, footer.format, pool)?.keys() {
+                    let entry = entry?;
+                    if !range_deleted(range_tombstones, &entry.key, entry.seq) {
+                        dead = false;
+                        break;
+                    }
+                }
+            }
+            let end = handle.offset + handle.size as u64;
+            match (&mut run, dead) {
+                (Some(run), true) => run.end = end,
+                (None, true) => run = Some(handle.offset..end),
+                (_, false) => runs.extend(run.take()),
+            }
+        }
+        runs.extend(run);
+        runs.retain(|run| uncached::whole_pages(run).is_some());
+        Ok(runs)
+    }
+
+    /// Write in `writer` the entries of both segments, keeping the `versions` most recent versions of each key.
+    /// The deleted entries, including the ones deleted by the `range_tombstones` of both segments,
+    /// are dropped along with the range tombstones since the compacted segments are always the
+    /// oldest ones.
+    ///
+    /// When `uncached` is set the segments are evicted from the page cache as they're read.
+    /// The values in the value log stay there, unless the compaction filter or the schema
+    /// has to read them. Returns the number of live keys written.
+    pub fn merge(
+        writer: impl Write,
+        new: &Self,
+        old: &Self,
+        range_tombstones: &[RangeTombstone],
+        options: &SegmentOptions,
+        uncached: bool,
+        read_ahead: u64,
+    ) -> Result<Written> {
+        let stored_values = options.compaction_filter.is_none() && options.schema.is_none();
+        // The merge reads both segments from start to end, it doesn't need to go through the pool
+        let mut sources = Vec::new();
+        for segment in [new, old] {
+            let mut iter = match uncached {
+                true => segment.iter_uncached(read_ahead)?,
+                false => segment.iter(Bound::Unbounded, read_ahead)?,
+            };
+            if stored_values {
+                iter.stored_values();
+            }
+            sources.push(Source::Segment(Box::new(iter)));
+        }
+
+        let entries = MergeIter::new(sources)?;
+        let options = SegmentOptions {
+            stored_values,
+            ..*options
+        };
+        write_segment(writer, entries, range_tombstones, true, &options)
+    }
+
+    #[cfg(test)]
+    pub fn dump(&self, files: &mut FilePool, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.clear();
+        let file = files.get(&self.path)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(buf)?;
+        Ok(())
+    }
+}
+
+/// What [`Segment::scrub`] went through.
+pub(crate) struct Scrubbed {
+    pub blocks: u64,
+    pub bytes: u64,
+    pub damaged_filter: Option<io::Error>,
+}
+
+/// See [`Segment::pin`].
+pub(crate) struct Pinned {
+    pub path: PathBuf,
+    pub holes: Holes,
+    _lease: Arc<Lease>,
+}
+
+/// See [`Segment::watch`].
+pub(crate) struct Watch(Weak<Lease>);
+
+impl Watch {
+    /// Whether an iterator or a [`Pinned`] path is still reading the file of the segment.
+    pub fn in_use(&self) -> bool {
+        self.0.strong_count() > 0
+    }
+}
+
+/// The blobs and the bytes of the value log referenced by a retired segment, they're kept
+/// while its file is still read.
+pub(crate) struct RetiredRefs {
+    pub watch: Watch,
+    pub blob_refs: Vec<u128>,
+    pub value_refs: Vec<(u32, u64)>,
+}
+
+/// Keeps the file of a segment alive while it's read.
+///
+/// The file of a segment replaced by a compaction is deleted once the last iterator reading it
+/// is dropped, the segment ids are never reused so the path can't be taken by a new segment
+/// in the meantime.
+struct Lease {
+    path: PathBuf,
+    obsolete: AtomicBool,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Acquire) {
+            // Nobody can be told about the failure, the file is left behind
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The sizes the blocks of the clean segments are written with, see
+/// [`DatabaseBuilder::block_size`](crate::DatabaseBuilder::block_size).
+///
+/// The segments don't need to record them: each block ends with its restart points and the
+/// index holds the offset and size of each block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockSizes {
+    /// A data block is closed once its encoded size reaches it.
+    pub data: usize,
+    /// An index block is closed once its encoded size reaches it.
+    pub index: usize,
+    /// The number of entries between two restart points of a block.
+    pub restart_interval: usize,
+}
+
+impl Default for BlockSizes {
+    fn default() -> Self {
+        BlockSizes {
+            data: BLOCK_SIZE,
+            index: BLOCK_SIZE,
+            restart_interval: RESTART_INTERVAL,
+        }
+    }
+}
+
+/// How the clean segments are written.
+pub(crate) struct SegmentOptions<'a> {
+    /// How many versions of each key are kept.
+    pub versions: usize,
+    /// Retag the values written with an older version of the schema.
+    pub schema: Option<&'a Schema>,
+    pub filter: Option<&'a dyn FilterPolicy>,
+    /// Only set by the compactions.
+    pub compaction_filter: Option<&'a dyn CompactionFilter>,
+    /// The keys the compaction filter isn't called on, only set by the compactions.
+    pub pins: Option<&'a Pins>,
+    /// The prefixes it extracts are added to the filter along with the keys.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    pub encoding: Encoding,
+    pub block_sizes: BlockSizes,
+    pub pool: &'a Arc<BufferPool>,
+    /// The identity of the database, see [`SegmentWriter::database`].
+    pub database: u128,
+    /// The blob store and the size from which the values are stored in it, see
+    /// [`SegmentWriter::blobs`].
+    pub blobs: Option<(&'a Arc<BlobStore>, usize)>,
+    /// The value log and the size from which the values are appended to it, see
+    /// [`SegmentWriter::value_log`].
+    pub value_log: Option<(&'a Arc<ValueLog>, usize)>,
+    /// The entries carry their values as stored, see [`SegmentIter::stored_values`]. Only set
+    /// by the compactions.
+    pub stored_values: bool,
+    /// The files of the value log whose values are moved, see [`SegmentWriter::relocate`].
+    pub relocate: Option<(&'a Arc<ValueLog>, &'a HashSet<u32>)>,
+}
+
+/// Where [`SegmentWriter::add`] stores a value.
+enum StoredValue<'a> {
+    Inline(&'a [u8]),
+    Blob(u128),
+    Pointer(ValuePointer),
+    Deleted,
+}
+
+/// Write the entries of a clean segment, they must be sorted.
+///
+/// Nothing but the entries and the options goes in the file, no timestamp nor random seed, so
+/// the same entries written for the same database always give the same bytes.
+pub(crate) struct SegmentWriter<W: Write> {
+    writer: W,
+    // The number of bytes written so far
+    offset: u64,
+    block: BlockBuilder,
+    sizes: BlockSizes,
+    // The first key of the current block
+    first_key: Vec<u8>,
+    // The first key and handle of all the data blocks
+    index: Vec<(Vec<u8>, BlockHandle)>,
+    // The name of the policy and the filter of the keys
+    filter: Option<(String, Box<dyn Filter>)>,
+    // Extracts the prefixes added to the filter, along with the last one added
+    prefixes: Option<(PrefixExtractor, Vec<u8>)>,
+    // Gives the buffer of the blocks and gets it back once the segment is written
+    pool: Arc<BufferPool>,
+    // The smallest and largest sequence numbers of the entries
+    seqs: Option<(u64, u64)>,
+    counters: SegmentCounters,
+    range_tombstones: Vec<RangeTombstone>,
+    // The identity of the database, 0 for none
+    database: u128,
+    // Stores the values of at least the given size
+    blobs: Option<(Arc<BlobStore>, usize)>,
+    // The hashes of the blobs referenced by the entries
+    blob_refs: BTreeSet<u128>,
+    // Appends the values of at least the given size
+    value_log: Option<(Arc<ValueLog>, usize)>,
+    // The bytes referenced in each file of the value log
+    value_refs: BTreeMap<u32, u64>,
+    // Whether values were appended to the value log, it's synced before the footer is written
+    appended: bool,
+    // The values of these files of the value log are moved
+    relocate: Option<(Arc<ValueLog>, HashSet<u32>)>,
+}
+
+impl<W: Write> SegmentWriter<W> {
+    pub fn new(
+        writer: W,
+        filter: Option<&dyn FilterPolicy>,
+        encoding: Encoding,
+        pool: Arc<BufferPool>,
+    ) -> SegmentWriter<W> {
+        SegmentWriter {
+            writer,
+            offset: 0,
+            block: BlockBuilder::new(BlockFormat::new(encoding), pool.get(2 * BLOCK_SIZE)),
+            sizes: BlockSizes::default(),
+            first_key: Vec::new(),
+            index: Vec::new(),
+            filter: filter.map(|policy| (policy.name().to_string(), policy.new_filter())),
+            prefixes: None,
+            pool,
+            seqs: None,
+            counters: SegmentCounters::default(),
+            range_tombstones: Vec::new(),
+            database: 0,
+            blobs: None,
+            blob_refs: BTreeSet::new(),
+            value_log: None,
+            value_refs: BTreeMap::new(),
+            appended: false,
+            relocate: None,
+        }
+    }
+
+    /// Store the values of at least `threshold` bytes in the blob store, the entries only
+    /// reference them.
+    pub fn blobs(&mut self, store: Arc<BlobStore>, threshold: usize) {
+        self.blobs = Some((store, threshold));
+    }
+
+    /// Append the values of at least `threshold` bytes to the value log, the entries only hold
+    /// their pointer. A database never enables both, but the blob store would take precedence
+    /// and the values whose hash is taken by another blob would go to the value log.
+    pub fn value_log(&mut self, log: Arc<ValueLog>, threshold: usize) {
+        self.value_log = Some((log, threshold));
+    }
+
+    /// Read the values of the given files of the value log back, they're then written like the
+    /// values added with [`SegmentWriter::add`].
+    pub fn relocate(&mut self, log: Arc<ValueLog>, files: HashSet<u32>) {
+        self.relocate = Some((log, files));
+    }
+
+    /// Record the identity of the database in the footer, so the segment can't be opened by
+    /// another database.
+    pub fn database(&mut self, identity: u128) {
+        self.database = identity;
+    }
+
+    /// Write the blocks with `sizes` instead of the default ones.
+    pub fn block_sizes(&mut self, sizes: BlockSizes) {
+        self.sizes = sizes;
+        self.block.restart_interval = sizes.restart_interval.max(1);
+    }
+
+    /// Add the prefixes extracted from the keys to the filter, nothing is done without filter.
+    pub fn prefix_extractor(&mut self, extractor: PrefixExtractor) {
+        if let Some((name, _)) = &mut self.filter {
+            name.push('+');
+            name.push_str(&extractor.name());
+            self.prefixes = Some((extractor, Vec::new()));
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        key: &[u8],
+        seq: u64,
+        meta: u8,
+        timestamp: u64,
+        value: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let Some(value) = value else {
+            return self.add_entry(key, seq, meta, timestamp, None, StoredValue::Deleted);
+        };
+        if let Some((store, threshold)) = &self.blobs {
+            if value.len() >= *threshold {
+                if let Some(hash) = store.put(value)? {
+                    let stored = StoredValue::Blob(hash);
+                    return self.add_entry(key, seq, meta, timestamp, Some(value.len()), stored);
+                }
+            }
+        }
+        let stored = match &self.value_log {
+            Some((log, threshold)) if value.len() >= *threshold => {
+                self.appended = true;
+                StoredValue::Pointer(log.append(value)?)
+            }
+            _ => StoredValue::Inline(value),
+        };
+        self.add_entry(key, seq, meta, timestamp, Some(value.len()), stored)
+    }
+
+    /// Add an entry whose value was read with [`SegmentIter::stored_values`], the values in the
+    /// value log stay where they are unless their file is relocated.
+    pub fn add_stored(
+        &mut self,
+        key: &[u8],
+        seq: u64,
+        meta: u8,
+        timestamp: u64,
+        stored: Option<&[u8]>,
+    ) -> io::Result<()> {
+        match stored.map(<[u8]>::split_first) {
+            None => self.add(key, seq, meta, timestamp, None),
+            Some(Some((&STORED_POINTER, pointer))) => {
+                let pointer = ValuePointer::decode(pointer)?;
+                match &self.relocate {
+                    Some((log, files)) if files.contains(&pointer.file) => {
+                        let value = log.read(pointer)?;
+                        self.add(key, seq, meta, timestamp, Some(&value))
+                    }
+                    _ => {
+                        let (len, stored) = (pointer.len as usize, StoredValue::Pointer(pointer));
+                        self.add_entry(key, seq, meta, timestamp, Some(len), stored)
+                    }
+                }
+            }
+            Some(Some((_, value))) => self.add(key, seq, meta, timestamp, Some(value)),
+            Some(None) => Err(corrupted()),
+        }
+    }
+
+    fn add_entry(
+        &mut self,
+        key: &[u8],
+        seq: u64,
+        meta: u8,
+        timestamp: u64,
+        value_len: Option<usize>,
+        stored: StoredValue,
+    ) -> io::Result<()> {
+        // The versions of a key follow each other in the same block
+        let new_key = self.block.is_empty() || self.block.last_key != key;
+        if let Some((_, filter)) = self.filter.as_mut().filter(|_| new_key) {
+            filter.add(key);
+            // The keys sharing a prefix follow each other, each prefix is added once
+            if let Some((extractor, last)) = &mut self.prefixes {
+                match extractor.extract(key) {
+                    Some(prefix) if prefix != last.as_slice() => {
+                        filter.add(prefix);
+                        *last = prefix.to_vec();
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.add_seq(seq);
+        self.counters.add(key, value_len);
+
+        // The versions of a key stay in the same block
+        if self.block.size() >= self.sizes.data && self.block.last_key != key {
+            let handle = self.write_block()?;
+            self.index.push((mem::take(&mut self.first_key), handle));
+        }
+        if self.block.is_empty() {
+            self.first_key = key.to_vec();
+        }
+        match stored {
+            StoredValue::Inline(value) => self.block.add(key, seq, meta, timestamp, Some(value)),
+            StoredValue::Blob(hash) => {
+                self.block.add_blob(key, seq, meta, timestamp, hash);
+                self.blob_refs.insert(hash);
+            }
+            StoredValue::Pointer(pointer) => {
+                self.block.add_pointer(key, seq, meta, timestamp, pointer);
+                *self.value_refs.entry(pointer.file).or_default() += pointer.record_len();
+            }
+            StoredValue::Deleted => self.block.add(key, seq, meta, timestamp, None),
+        }
+        Ok(())
+    }
+
+    /// Add a range tombstone, they're written after the entries.
+    pub fn delete_range(&mut self, range_tombstone: RangeTombstone) {
+        self.add_seq(range_tombstone.seq);
+        self.range_tombstones.push(range_tombstone);
+    }
+
+    fn add_seq(&mut self, seq: u64) {
+        self.seqs = Some(match self.seqs {
+            Some((min, max)) => (min.min(seq), max.max(seq)),
+            None => (seq, seq),
+        });
+    }
+
+    /// Write the last block, the filter, the range tombstones, the index and the footer, and
+    /// return the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            let handle = self.write_block()?;
+            self.index.push((mem::take(&mut self.first_key), handle));
+        }
+
+        let filter = match self.filter.take() {
+            Some((name, filter)) => {
+                let mut buf = (name.len() as u32).to_be_bytes().to_vec();
+                buf.extend_from_slice(name.as_bytes());
+                buf.extend_from_slice(&filter.serialize());
+                self.writer.write_all(&buf)?;
+                let handle = BlockHandle::new(self.offset, &buf);
+                self.offset += buf.len() as u64;
+                handle
+            }
+            // An empty filter handle means there is no filter
+            None => BlockHandle::new(0, &[]),
+        };
+        let range_tombstones = match self.range_tombstones.is_empty() {
+            true => BlockHandle::new(0, &[]),
+            false => {
+                let buf = encode_range_tombstones(&self.range_tombstones);
+                self.writer.write_all(&buf)?;
+                let handle = BlockHandle::new(self.offset, &buf);
+                self.offset += buf.len() as u64;
+                handle
+            }
+        };
+        let blob_refs = match self.blob_refs.is_empty() {
+            true => BlockHandle::new(0, &[]),
+            false => {
+                let buf: Vec<u8> = (self.blob_refs.iter())
+                    .flat_map(|hash| hash.to_be_bytes())
+                    .collect();
+                self.writer.write_all(&buf)?;
+                let handle = BlockHandle::new(self.offset, &buf);
+                self.offset += buf.len() as u64;
+                handle
+            }
+        };
+        let value_refs = match self.value_refs.is_empty() {
+            true => BlockHandle::new(0, &[]),
+            false => {
+                let mut buf = Vec::with_capacity(self.value_refs.len() * 12);
+                for (file, bytes) in &self.value_refs {
+                    buf.extend_from_slice(&file.to_be_bytes());
+                    buf.extend_from_slice(&bytes.to_be_bytes());
+                }
+                self.writer.write_all(&buf)?;
+                let handle = BlockHandle::new(self.offset, &buf);
+                self.offset += buf.len() as u64;
+                handle
+            }
+        };
+        // The segment can't be durable before the values it points to
+        if let Some((log, _)) = self.value_log.as_ref().filter(|_| self.appended) {
+            log.sync()?;
+        }
+
+        // The index is split in blocks referenced by the top-level index
+        let mut top = Vec::new();
+        for (key, handle) in mem::take(&mut self.index) {
+            if self.block.is_empty() {
+                self.first_key = key.clone();
+            }
+            self.block.add(&key, 0, 0, 0, Some(&handle.encode()));
+            if self.block.size() >= self.sizes.index {
+                let handle = self.write_block()?;
+                top.push((mem::take(&mut self.first_key), handle));
+            }
+        }
+        if !self.block.is_empty() {
+            let handle = self.write_block()?;
+            top.push((mem::take(&mut self.first_key), handle));
+        }
+
+        for (key, handle) in top {
+            self.block.add(&key, 0, 0, 0, Some(&handle.encode()));
+        }
+        let top = self.write_block()?;
+
+        let mut footer = Vec::with_capacity(Footer::MAX_SIZE as usize);
+        footer.extend_from_slice(&top.encode());
+        footer.extend_from_slice(&filter.encode());
+        footer.extend_from_slice(&range_tombstones.encode());
+        footer.extend_from_slice(&blob_refs.encode());
+        footer.extend_from_slice(&value_refs.encode());
+        // An empty segment has an empty range
+        let (min, max) = self.seqs.unwrap_or((1, 0));
+        footer.extend_from_slice(&min.to_be_bytes());
+        footer.extend_from_slice(&max.to_be_bytes());
+        footer.extend_from_slice(&self.counters.encode());
+        footer.extend_from_slice(&self.database.to_be_bytes());
+        let version = self.block.format.encoding.format_version();
+        let checksum = footer_checksum(&footer, version);
+        footer.extend_from_slice(&checksum.to_be_bytes());
+        footer.extend_from_slice(&version.to_be_bytes());
+        footer.extend_from_slice(&MAGIC.to_be_bytes());
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        self.pool.put(mem::take(&mut self.block.buf));
+        Ok(self.writer)
+    }
+
+    fn write_block(&mut self) -> io::Result<BlockHandle> {
+        let mut block = self.block.finish();
+        self.writer.write_all(&block)?;
+        let handle = BlockHandle::new(self.offset, &block);
+        self.offset += block.len() as u64;
+        // The next block is built in the same buffer
+        block.clear();
+        self.block.buf = block;
+        Ok(handle)
+    }
+}
+
+/// The position of a block in a segment and the checksum of its content.
+///
+/// The handles written before the format version 6 have no checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct BlockHandle {
+    offset: u64,
+    size: u32,
+    checksum: Option<u32>,
+}
+
+impl BlockHandle {
+    /// The size of an encoded handle with and without checksum.
+    const SIZE: usize = 16;
+    const LEGACY_SIZE: usize = 12;
+
+    fn new(offset: u64, block: &[u8]) -> BlockHandle {
+        BlockHandle {
+            offset,
+            size: block.len() as u32,
+            checksum: Some(crc32fast::hash(block)),
+        }
+    }
+
+    fn encode(&self) -> [u8; BlockHandle::SIZE] {
+        let mut buf = [0; BlockHandle::SIZE];
+        buf[..8].copy_from_slice(&self.offset.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.size.to_be_bytes());
+        buf[12..].copy_from_slice(&self.checksum.unwrap_or_default().to_be_bytes());
+        buf
+    }
+
+    fn decode(mut bytes: &[u8]) -> io::Result<BlockHandle> {
+        let checksum = bytes.len() == BlockHandle::SIZE;
+        Ok(BlockHandle {
+            offset: read_u64(&mut bytes)?,
+            size: read_u32(&mut bytes)?,
+            checksum: checksum.then(|| read_u32(&mut bytes)).transpose()?,
+        })
+    }
+
+    /// Check the content of the block read through the handle.
+    fn verify(&self, block: &[u8]) -> io::Result<()> {
+        match self.checksum {
+            Some(checksum) if checksum != crc32fast::hash(block) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "corrupted segment block at offset {}, checksum mismatch",
+                    self.offset
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn read(
+        &self,
+        reader: &mut (impl Read + Seek),
+        format: BlockFormat,
+        pool: Option<&Arc<BufferPool>>,
+    ) -> io::Result<Block> {
+        reader.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = match pool {
+            Some(pool) => pool.get(self.size as usize),
+            None => Vec::new(),
+        };
+        read_bytes(reader, self.size as usize, &mut buf)?;
+        self.verify(&buf)?;
+        Block::decode(buf, format, pool.cloned())
+    }
+}
+
+/// What a [`Segment::traced_get`] read in the segment.
+pub(crate) struct LookupTrace {
+    /// The offset of the data block that may hold the key, `None` if it's after the last key.
+    pub block: Option<u64>,
+    /// The number of blocks read from the file, the ones found in the block cache aren't counted.
+    pub blocks_read: usize,
+}
+
+/// The entries found by a lookup and what it read to find them.
+struct Lookup {
+    entries: Vec<Option<Entry>>,
+    // The offset of the data block of each key
+    blocks: Vec<Option<u64>>,
+    blocks_read: usize,
+}
+
+/// Reads the blocks of a segment through the block cache.
+struct BlockReads<'a> {
+    reads: &'a mut dyn BatchRead,
+    cache: &'a mut BlockCache,
+    segment: usize,
+    pool: &'a Arc<BufferPool>,
+    // The number of blocks that weren't in the cache
+    from_disk: usize,
+}
+
+impl BlockReads<'_> {
+    /// Read and decode the blocks at once, the blocks requested several times are only read once.
+    fn read(
+        &mut self,
+        file: &File,
+        handles: &[BlockHandle],
+        format: BlockFormat,
+    ) -> io::Result<Vec<Block>> {
+        let mut unique = handles.to_vec();
+        unique.sort_unstable();
+        unique.dedup();
+
+        let cached: Vec<_> = unique
+            .iter()
+            .map(|handle| self.cache.get(self.segment, handle.offset, self.pool))
+            .collect();
+        let parts: Vec<_> = unique
+            .iter()
+            .zip(&cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(handle, _)| (handle.offset, handle.size as usize))
+            .collect();
+        self.from_disk += parts.len();
+        let mut read = self.reads.read_batch(file, &parts, self.pool)?.into_iter();
+
+        let mut blocks = Vec::with_capacity(unique.len());
+        for (handle, cached) in unique.iter().zip(cached) {
+            let buf = match cached {
+                Some(buf) => buf,
+                None => {
+                    let buf = read.next().ok_or_else(corrupted)?;
+                    handle.verify(&buf)?;
+                    self.cache.insert(self.segment, handle.offset, &buf);
+                    buf
+                }
+            };
+            blocks.push(Block::decode(buf, format, Some(self.pool.clone()))?);
+        }
+        Ok(handles
+            .iter()
+            .map(|handle| blocks[unique.binary_search(handle).unwrap()].clone())
+            .collect())
+    }
+}
+
+/// The handles of the blocks of an index that may hold the keys of `bounds`.
+fn blocks_in(
+    index: &[(Vec<u8>, BlockHandle)],
+    (start, end): (Bound<&[u8]>, Bound<&[u8]>),
+) -> Vec<BlockHandle> {
+    let first = match start {
+        Bound::Included(key) | Bound::Excluded(key) => find_block(index, key),
+        Bound::Unbounded => 0,
+    };
+    // The blocks whose first key is out of the bounds can't hold a key in the bounds
+    let last = index.partition_point(|(first, _)| match end {
+        Bound::Included(end) => first.as_slice() <= end,
+        Bound::Excluded(end) => first.as_slice() < end,
+        Bound::Unbounded => true,
+    });
+    index[first..last.max(first)]
+        .iter()
+        .map(|(_, handle)| *handle)
+        .collect()
+}
+
+/// The hash stored before the keys of the blocks, to compare them in 4 bytes.
+fn key_hash(key: &[u8]) -> u32 {
+    crc32fast::hash(key)
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted segment")
+}
+
+struct Footer {
+    version: u32,
+    // The top-level index
+    index: BlockHandle,
+    filter: Option<BlockHandle>,
+    range_tombstones: Option<BlockHandle>,
+    blob_refs: Option<BlockHandle>,
+    value_refs: Option<BlockHandle>,
+    format: BlockFormat,
+    // The smallest and largest sequence numbers, `None` if they weren't recorded
+    seqs: Option<RangeInclusive<u64>>,
+    counters: Option<SegmentCounters>,
+    // The identity of the database that wrote the segment, `None` if it wasn't recorded
+    database: Option<u128>,
+}
+
+impl Footer {
+    /// The size of the largest footer.
+    const MAX_SIZE: u64 =
+        5 * BlockHandle::SIZE as u64 + 8 + 8 + SegmentCounters::SIZE as u64 + 16 + 4 + 4 + 8;
+
+    /// Decode the footer out of the end of the segment, at most [`Footer::MAX_SIZE`] bytes.
+    ///
+    /// The footer ends with the format version and the magic number, what precedes them depends on the version.
+    /// Since the version 4 the handles are followed by their checksum, since the version 6
+    /// the handles hold the checksum of their block, and since the version 8 the handles are
+    /// followed by the range of the sequence numbers. Since the version 10 the entries of the
+    /// blocks start with the hash of their key, and since the version 12 their sequence number
+    /// is followed by their metadata. Since the version 14 the range of the sequence numbers is
+    /// followed by the [`SegmentCounters`], and since the version 16 the handle of the filter is
+    /// followed by the handle of the range tombstones. Since the version 18 the metadata of the
+    /// entries is followed by their timestamp, and since the version 20 the counters are
+    /// followed by the identity of the database. Since the version 22 the values may be stored
+    /// in blobs and the handle of the range tombstones is followed by the handle of the list of
+    /// the blobs referenced. Since the version 24 the values may be stored in the value log
+    /// and that handle is followed by the handle of the bytes of the value log referenced.
+    fn decode(tail: &[u8]) -> io::Result<Footer> {
+        let (handles, mut end) = tail
+            .split_at_checked(tail.len().saturating_sub(12))
+            .ok_or_else(corrupted)?;
+        let version = read_u32(&mut end)?;
+        if read_u64(&mut end)? != MAGIC {
+            return Err(corrupted());
+        }
+
+        let (count, encoding) = match version {
+            // The segments without filter
+            1 => (1, Encoding::Fixed),
+            2 | 4 | 6 | 8 | 10 | 12 | 14 => (2, Encoding::Fixed),
+            3 | 5 | 7 | 9 | 11 | 13 | 15 => (2, Encoding::Varint),
+            16 | 18 | 20 => (3, Encoding::Fixed),
+            17 | 19 | 21 => (3, Encoding::Varint),
+            22 => (4, Encoding::Fixed),
+            23 => (4, Encoding::Varint),
+            24 => (5, Encoding::Fixed),
+            25 => (5, Encoding::Varint),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported segment format version {version}"),
+                ))
+            }
+        };
+        // Each version keeps what the previous ones added
+        let checksum = version >= 4;
+        let width = match version >= 6 {
+            true => BlockHandle::SIZE,
+            false => BlockHandle::LEGACY_SIZE,
+        };
+        let seqs_len = if version >= 8 { 16 } else { 0 };
+        let counters_len = match version >= 14 {
+            true => SegmentCounters::SIZE,
+            false => 0,
+        };
+        let database_len = if version >= 20 { 16 } else { 0 };
+        let handles = match checksum {
+            true => {
+                let (handles, mut checksum) = handles
+                    .split_at_checked(handles.len().saturating_sub(4))
+                    .ok_or_else(corrupted)?;
+                let start = handles
+                    .len()
+                    .checked_sub(count * width + seqs_len + counters_len + database_len)
+                    .ok_or_else(corrupted)?;
+                if read_u32(&mut checksum)? != footer_checksum(&handles[start..], version) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "corrupted segment footer, checksum mismatch",
+                    ));
+                }
+                handles
+            }
+            false => handles,
+        };
+        let (handles, database) = handles.split_at(handles.len().saturating_sub(database_len));
+        let database = match database.try_into() {
+            Ok(database) => Some(u128::from_be_bytes(database)).filter(|database| *database != 0),
+            Err(_) => None,
+        };
+        let (handles, counters) = handles.split_at(handles.len().saturating_sub(counters_len));
+        let counters = match counters.is_empty() {
+            true => None,
+            false => Some(SegmentCounters::decode(counters)?),
+        };
+        let (handles, mut seqs) = handles.split_at(handles.len().saturating_sub(seqs_len));
+        let seqs = match seqs.is_empty() {
+            true => None,
+            false => Some(read_u64(&mut seqs)?..=read_u64(&mut seqs)?),
+        };
+        let start = handles
+            .len()
+            .checked_sub(count * width)
+            .ok_or_else(corrupted)?;
+        let mut handles = handles[start..].chunks(width);
+        let index = BlockHandle::decode(handles.next().unwrap())?;
+        let filter = match handles.next() {
+            Some(handle) => Some(BlockHandle::decode(handle)?).filter(|handle| handle.size != 0),
+            None => None,
+        };
+        let range_tombstones = match handles.next() {
+            Some(handle) => Some(BlockHandle::decode(handle)?).filter(|handle| handle.size != 0),
+            None => None,
+        };
+        let blob_refs = match handles.next() {
+            Some(handle) => Some(BlockHandle::decode(handle)?).filter(|handle| handle.size != 0),
+            None => None,
+        };
+        let value_refs = match handles.next() {
+            Some(handle) => Some(BlockHandle::decode(handle)?).filter(|handle| handle.size != 0),
+            None => None,
+        };
+
+        Ok(Footer {
+            version,
+            index,
+            filter,
+            range_tombstones,
+            blob_refs,
+            value_refs,
+            format: BlockFormat {
+                encoding,
+                key_hashes: version >= 10,
+                meta: version >= 12,
+                timestamps: version >= 18,
+                blobs: version >= 22,
+                value_log: version >= 24,
+            },
+            seqs,
+            counters,
+            database,
+        })
+    }
+
+    /// The part of a segment of `len` bytes to read to decode its footer.
+    fn handle(len: u64) -> (u64, usize) {
+        let size = Footer::MAX_SIZE.min(len);
+        (len - size, size as usize)
+    }
+}
+
+/// How the entries of the blocks are written, it's given by the format version of the footer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockFormat {
+    encoding: Encoding,
+    // Whether each entry starts with the hash of its key
+    key_hashes: bool,
+    // Whether the sequence number of each entry is followed by its metadata byte
+    meta: bool,
+    // Whether the metadata of each entry is followed by its timestamp, encoded like the sequence numbers
+    timestamps: bool,
+    // Whether the values may be stored in blobs, the sizes are then written as a `StoredLen`
+    blobs: bool,
+    // Whether the values may be stored in the value log
+    value_log: bool,
+}
+
+impl BlockFormat {
+    /// The format of the segments written with the encoding.
+    fn new(encoding: Encoding) -> BlockFormat {
+        BlockFormat {
+            encoding,
+            key_hashes: true,
+            meta: true,
+            timestamps: true,
+            blobs: true,
+            value_log: true,
+        }
+    }
+
+    fn write_value_len(self, buf: &mut Vec<u8>, len: StoredLen) {
+        match (self.blobs, len) {
+            (true, len) => self.encoding.write_stored_len(buf, len),
+            (false, StoredLen::Inline(len)) => self.encoding.write_value_len(buf, Some(len)),
+            (false, StoredLen::Deleted) => self.encoding.write_value_len(buf, None),
+            (false, StoredLen::Blob | StoredLen::Pointer) => {
+                unreachable!("values out of the segment written with an older format")
+            }
+        }
+    }
+
+    fn read_value_len(self, cursor: &mut &[u8]) -> io::Result<StoredLen> {
+        match self.blobs {
+            true => self.encoding.read_stored_len(cursor, self.value_log),
+            false => Ok(match self.encoding.read_value_len(cursor)? {
+                Some(len) => StoredLen::Inline(len),
+                None => StoredLen::Deleted,
+            }),
+        }
+    }
+}
+
+/// The checksum of the handles of the footer and of the format version.
+fn footer_checksum(handles: &[u8], version: u32) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(handles);
+    hasher.update(&version.to_be_bytes());
+    hasher.finalize()
+}
+
+/// The range tombstones are stored in a single block, each of them as its start and end keys
+/// prefixed by their size, followed by its sequence number.
+fn encode_range_tombstones(range_tombstones: &[RangeTombstone]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for RangeTombstone { start, end, seq } in range_tombstones {
+        buf.extend_from_slice(&(start.len() as u32).to_be_bytes());
+        buf.extend_from_slice(start);
+        buf.extend_from_slice(&(end.len() as u32).to_be_bytes());
+        buf.extend_from_slice(end);
+        buf.extend_from_slice(&seq.to_be_bytes());
+    }
+    buf
+}
+
+fn read_range_tombstones(
+    reader: &mut (impl Read + Seek),
+    handle: BlockHandle,
+) -> io::Result<Vec<RangeTombstone>> {
+    let buf = read_raw_block(reader, handle)?;
+    let mut cursor = buf.as_slice();
+    let mut range_tombstones = Vec::new();
+    while !cursor.is_empty() {
+        let mut start = Vec::new();
+        let size = read_u32(&mut cursor)?;
+        read_bytes(&mut cursor, size as usize, &mut start)?;
+        let mut end = Vec::new();
+        let size = read_u32(&mut cursor)?;
+        read_bytes(&mut cursor, size as usize, &mut end)?;
+        let seq = read_u64(&mut cursor)?;
+        range_tombstones.push(RangeTombstone { start, end, seq });
+    }
+    Ok(range_tombstones)
+}
+
+/// The bytes of the value log referenced by a segment are stored in a single block, as the
+/// sorted list of the ids of the files with their number of bytes.
+fn read_value_refs(
+    reader: &mut (impl Read + Seek),
+    handle: BlockHandle,
+) -> io::Result<Vec<(u32, u64)>> {
+    let buf = read_raw_block(reader, handle)?;
+    let mut cursor = buf.as_slice();
+    let mut value_refs = Vec::with_capacity(buf.len() / 12);
+    while !cursor.is_empty() {
+        value_refs.push((read_u32(&mut cursor)?, read_u64(&mut cursor)?));
+    }
+    Ok(value_refs)
+}
+
+/// The blobs referenced by a segment are stored in a single block, as the sorted list of their
+/// hashes.
+fn read_blob_refs(reader: &mut (impl Read + Seek), handle: BlockHandle) -> io::Result<Vec<u128>> {
+    let buf = read_raw_block(reader, handle)?;
+    let hashes = buf.chunks_exact(16);
+    if !hashes.remainder().is_empty() {
+        return Err(corrupted());
+    }
+    Ok(hashes
+        .map(|hash| u128::from_be_bytes(hash.try_into().unwrap()))
+        .collect())
+}
+
+/// Read a block written as is rather than in the format of the data blocks, like the filter.
+fn read_raw_block(reader: &mut (impl Read + Seek), handle: BlockHandle) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(handle.offset))?;
+    let mut buf = Vec::new();
+    read_bytes(reader, handle.size as usize, &mut buf)?;
+    handle.verify(&buf)?;
+    Ok(buf)
+}
+
+/// The filter of the segment, `None` if it has none or if it wasn't written by the policy.
+fn load_filter(
+    reader: &mut (impl Read + Seek),
+    footer: &Footer,
+    policy: &dyn FilterPolicy,
+) -> Result<Option<LoadedFilter>> {
+    let Some(handle) = footer.filter else {
+        return Ok(None);
+    };
+    let block = read_raw_block(reader, handle)?;
+    // The name of the extractor follows the name of the policy
+    let split = split_filter(&block)?.and_then(|(name, bytes)| {
+        let name = std::str::from_utf8(name).ok()?;
+        match name.strip_prefix(policy.name())? {
+            "" => Some((String::new(), bytes)),
+            prefixes => Some((prefixes.strip_prefix('+')?.to_string(), bytes)),
+        }
+    });
+    match split {
+        Some((prefixes, bytes)) => Ok(Some(LoadedFilter {
+            filter: policy.read_filter(bytes)?,
+            bytes: bytes.len(),
+            prefixes,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Split the filter block in the name of the policy that created it and the filter itself,
+/// `None` if the name is truncated.
+fn split_filter(block: &[u8]) -> io::Result<Option<(&[u8], &[u8])>> {
+    let mut cursor = block;
+    let name_len = read_u32(&mut cursor)? as usize;
+    Ok(cursor.get(..name_len).zip(cursor.get(name_len..)))
+}
+
+/// The smallest and largest keys of the segment, `None` if it's empty.
+fn read_fence(
+    reader: &mut (impl Read + Seek),
+    footer: &Footer,
+    pool: Option<&Arc<BufferPool>>,
+) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let top = read_index(reader, footer.index, footer.format, pool)?;
+    let (Some((first, _)), Some((_, handle))) = (top.first(), top.last()) else {
+        return Ok(None);
+    };
+    // The first key of the top index is the first key of the segment while the last one is in
+    // the last data block
+    let index = read_index(reader, *handle, footer.format, pool)?;
+    let (_, handle) = index.last().ok_or_else(corrupted)?;
+    let block = handle.read(reader, footer.format, pool)?;
+    let last = block.keys().last().ok_or_else(corrupted)??.key;
+    Ok(Some((first.clone(), last)))
+}
+
+fn read_footer(reader: &mut (impl Read + Seek)) -> io::Result<Footer> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let (offset, size) = Footer::handle(len);
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut tail = Vec::new();
+    read_bytes(reader, size, &mut tail)?;
+    Footer::decode(&tail)
+}
+
+/// The identity of the database that wrote the segment, `None` if it wasn't recorded.
+pub(crate) fn database(path: &Path) -> io::Result<Option<u128>> {
+    Ok(read_footer(&mut File::open(path)?)?.database)
+}
+
+/// Whether the file ends with the magic number of the segments, the segments written by the
+/// first version of the crate have no footer.
+pub(crate) fn has_footer(path: &Path) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 8 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(len - 8))?;
+    Ok(read_u64(&mut file)? == MAGIC)
+}
+
+/// Read an index block, returns the first key and handle of each block it references.
+fn read_index(
+    reader: &mut (impl Read + Seek),
+    handle: BlockHandle,
+    format: BlockFormat,
+    pool: Option<&Arc<BufferPool>>,
+) -> io::Result<Vec<(Vec<u8>, BlockHandle)>> {
+    decode_index(handle.read(reader, format, pool)?)
+}
+
+fn decode_index(block: Block) -> io::Result<Vec<(Vec<u8>, BlockHandle)>> {
+    block
+        .into_iter()
+        .map(|entry| {
+            let entry = entry?;
+            let handle = BlockHandle::decode(entry.value.as_deref().ok_or_else(corrupted)?)?;
+            Ok((entry.key, handle))
+        })
+        .collect()
+}
+
+/// The position of the only block of the index that may contain `key`.
+fn find_block(index: &[(Vec<u8>, BlockHandle)], key: &[u8]) -> usize {
+    index
+        .partition_point(|(first, _)| first.as_slice() <= key)
+        .saturating_sub(1)
+}
+
+struct BlockBuilder {
+    format: BlockFormat,
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    last_key: Vec<u8>,
+    // The number of entries written since the last restart point
+    counter: usize,
+    restart_interval: usize,
+}
+
+impl BlockBuilder {
+    fn new(format: BlockFormat, buf: Vec<u8>) -> BlockBuilder {
+        BlockBuilder {
+            format,
+            buf,
+            restarts: Vec::new(),
+            last_key: Vec::new(),
+            counter: 0,
+            restart_interval: RESTART_INTERVAL,
+        }
+    }
+
+    fn add(&mut self, key: &[u8], seq: u64, meta: u8, timestamp: u64, value: Option<&[u8]>) {
+        self.add_key(key, seq, meta, timestamp);
+        let len = match value {
+            Some(value) => StoredLen::Inline(value.len()),
+            None => StoredLen::Deleted,
+        };
+        self.format.write_value_len(&mut self.buf, len);
+        if let Some(value) = value {
+            self.buf.extend_from_slice(value);
+        }
+    }
+
+    /// Add an entry whose value is stored in the blob of the given hash.
+    fn add_blob(&mut self, key: &[u8], seq: u64, meta: u8, timestamp: u64, hash: u128) {
+        self.add_key(key, seq, meta, timestamp);
+        self.format.write_value_len(&mut self.buf, StoredLen::Blob);
+        self.buf.extend_from_slice(&hash.to_be_bytes());
+    }
+
+    /// Add an entry whose value is stored in the value log.
+    fn add_pointer(
+        &mut self,
+        key: &[u8],
+        seq: u64,
+        meta: u8,
+        timestamp: u64,
+        pointer: ValuePointer,
+    ) {
+        self.add_key(key, seq, meta, timestamp);
+        self.format
+            .write_value_len(&mut self.buf, StoredLen::Pointer);
+        self.buf.extend_from_slice(&pointer.encode());
+    }
+
+    /// Write an entry up to the size of its value.
+    fn add_key(&mut self, key: &[u8], seq: u64, meta: u8, timestamp: u64) {
+        let shared = if self.restarts.is_empty() || self.counter == self.restart_interval {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        self.counter += 1;
+
+        if self.format.key_hashes {
+            self.buf.extend_from_slice(&key_hash(key).to_be_bytes());
+        }
+        let encoding = self.format.encoding;
+        encoding.write_len(&mut self.buf, shared);
+        encoding.write_len(&mut self.buf, key.len() - shared);
+        self.buf.extend_from_slice(&key[shared..]);
+        encoding.write_seq(&mut self.buf, seq);
+        if self.format.meta {
+            self.buf.push(meta);
+        }
+        if self.format.timestamps {
+            encoding.write_seq(&mut self.buf, timestamp);
+        }
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.restarts.is_empty()
+    }
+
+    /// The size of the block once finished.
+    fn size(&self) -> usize {
+        self.buf.len() + (self.restarts.len() + 1) * mem::size_of::<u32>()
+    }
+
+    /// Returns the encoded block and reset the builder.
+    fn finish(&mut self) -> Vec<u8> {
+        for restart in &self.restarts {
+            self.buf.extend_from_slice(&restart.to_be_bytes());
+        }
+        self.buf
+            .extend_from_slice(&(self.restarts.len() as u32).to_be_bytes());
+
+        self.restarts.clear();
+        self.last_key.clear();
+        self.counter = 0;
+        mem::take(&mut self.buf)
+    }
+}
+
+/// A decoded block of a clean segment.
+#[derive(Clone)]
+pub(crate) struct Block {
+    format: BlockFormat,
+    // The entries without the restart points
+    data: Vec<u8>,
+    restarts: Vec<u32>,
+    // Gets the buffer of the data back once the block is dropped
+    pool: Option<Arc<BufferPool>>,
+    // Reads the values stored in blobs
+    blob_store: Option<Arc<BlobStore>>,
+    // Reads the values stored in the value log
+    value_log: Option<Arc<ValueLog>>,
+}
+
+impl Block {
+    pub fn decode(
+        mut data: Vec<u8>,
+        format: BlockFormat,
+        pool: Option<Arc<BufferPool>>,
+    ) -> io::Result<Block> {
+        let count_start = data.len().checked_sub(4).ok_or_else(corrupted)?;
+        let count = read_u32(&mut &data[count_start..])? as usize;
+        let restarts_start = count
+            .checked_mul(4)
+            .and_then(|size| count_start.checked_sub(size))
+            .ok_or_else(corrupted)?;
+
+        let mut restarts = Vec::with_capacity(count);
+        let mut cursor = &data[restarts_start..count_start];
+        for _ in 0..count {
+            let restart = read_u32(&mut cursor)?;
+            if restart as usize >= restarts_start {
+                return Err(corrupted());
+            }
+            restarts.push(restart);
+        }
+        data.truncate(restarts_start);
+
+        Ok(Block {
+            format,
+            data,
+            restarts,
+            pool,
+            blob_store: None,
+            value_log: None,
+        })
+    }
+
+    /// Iterate over the entries of the block with their values returned empty.
+    pub fn keys(self) -> BlockIter {
+        let mut iter = self.into_iter();
+        iter.skip_values = true;
+        iter
+    }
+
+    /// Iterate over the entries of the block starting from the first one whose key is greater
+    /// or equal to `key`.
+    pub fn seek(self, key: &[u8]) -> io::Result<BlockIter> {
+        let left = self.find_restart(key)?;
+        let offset = match left.checked_sub(1) {
+            Some(restart) => self.restarts[restart] as usize,
+            None => 0,
+        };
+
+        let mut iter = BlockIter {
+            block: self,
+            offset,
+            key: Vec::new(),
+            peeked: None,
+            skip_values: false,
+            stored_values: false,
+        };
+        while iter.read_key()?.is_some() {
+            if iter.key.as_slice() >= key {
+                let (seq, meta, timestamp, value) = iter.read_value(false)?;
+                iter.peeked = Some(Entry {
+                    key: iter.key.clone(),
+                    seq,
+                    meta,
+                    timestamp,
+                    value,
+                });
+                break;
+            }
+            iter.read_value(true)?;
+        }
+        Ok(iter)
+    }
+
+    /// Iterate over the versions of `key`, the iterator is empty if the block doesn't contain it.
+    ///
+    /// When the block stores the hashes of the keys, the entries are skipped once their hash
+    /// doesn't match and only the keys with the same hash are compared.
+    pub fn seek_exact(self, key: &[u8]) -> io::Result<BlockIter> {
+        if !self.format.key_hashes {
+            let mut iter = self.seek(key)?;
+            if iter.peeked.as_ref().is_some_and(|entry| entry.key != key) {
+                iter.peeked = None;
+                iter.offset = iter.block.data.len();
+            }
+            return Ok(iter);
+        }
+
+        let left = self.find_restart(key)?;
+        // The first version of the key can't be past the restart point following `left`
+        let end = self
+            .restarts
+            .get(left + 1)
+            .map_or(self.data.len(), |restart| *restart as usize);
+        let offset = match left.checked_sub(1) {
+            Some(restart) => self.restarts[restart] as usize,
+            None => 0,
+        };
+        let hash = key_hash(key);
+        let mut iter = BlockIter {
+            block: self,
+            offset,
+            key: Vec::new(),
+            peeked: None,
+            skip_values: false,
+            stored_values: false,
+        };
+        while iter.offset < end {
+            let Some(stored) = iter.read_key()? else {
+                break;
+            };
+            if stored == Some(hash) && iter.key == key {
+                let (seq, meta, timestamp, value) = iter.read_value(false)?;
+                iter.peeked = Some(Entry {
+                    key: iter.key.clone(),
+                    seq,
+                    meta,
+                    timestamp,
+                    value,
+                });
+                return Ok(iter);
+            }
+            iter.read_value(true)?;
+        }
+        iter.offset = iter.block.data.len();
+        Ok(iter)
+    }
+
+    /// The first restart point whose key is not lower than `key`, the entries between it and the
+    /// previous restart point may still match.
+    fn find_restart(&self, key: &[u8]) -> io::Result<usize> {
+        let (mut left, mut right) = (0, self.restarts.len());
+        while left < right {
+            let mid = (left + right) / 2;
+            if self.restart_key(mid)? < key {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+        Ok(left)
+    }
+
+    fn restart_key(&self, restart: usize) -> io::Result<&[u8]> {
+        let mut cursor = &self.data[self.restarts[restart] as usize..];
+        if self.format.key_hashes {
+            let _hash = read_u32(&mut cursor)?;
+        }
+        // the key isn't shared with the previous entry on the restart points
+        let _shared = self.format.encoding.read_len(&mut cursor)?;
+        let size = self.format.encoding.read_len(&mut cursor)?;
+        cursor
+            .get(..size)
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.put(mem::take(&mut self.data));
+        }
+    }
+}
+
+impl IntoIterator for Block {
+    type Item = io::Result<Entry>;
+    type IntoIter = BlockIter;
+
+    fn into_iter(self) -> BlockIter {
+        BlockIter {
+            block: self,
+            offset: 0,
+            key: Vec::new(),
+            peeked: None,
+            skip_values: false,
+            stored_values: false,
+        }
+    }
+}
+
+pub(crate) struct BlockIter {
+    block: Block,
+    offset: usize,
+    // The key of the last entry read, the next one may share a prefix with it
+    key: Vec<u8>,
+    // The entry found while seeking
+    peeked: Option<Entry>,
+    // Whether the values are returned empty rather than copied
+    skip_values: bool,
+    // Whether the values are returned as stored, see `SegmentIter::stored_values`
+    stored_values: bool,
+}
+
+impl BlockIter {
+    fn read_entry(&mut self) -> io::Result<Option<Entry>> {
+        if self.read_key()?.is_none() {
+            return Ok(None);
+        }
+        let (seq, meta, timestamp, value) = self.read_value(self.skip_values)?;
+        Ok(Some(Entry {
+            key: self.key.clone(),
+            seq,
+            meta,
+            timestamp,
+            value,
+        }))
+    }
+
+    /// Read the next entry whose key is accepted by `filter`, the values of the other entries
+    /// are skipped without being copied.
+    fn next_filtered(&mut self, filter: &KeyFilter) -> io::Result<Option<Entry>> {
+        if let Some(entry) = self.peeked.take() {
+            if filter(&entry.key) {
+                return Ok(Some(entry));
+            }
+        }
+        while self.read_key()?.is_some() {
+            if !filter(&self.key) {
+                self.read_value(true)?;
+                continue;
+            }
+            let (seq, meta, timestamp, value) = self.read_value(self.skip_values)?;
+            return Ok(Some(Entry {
+                key: self.key.clone(),
+                seq,
+                meta,
+                timestamp,
+                value,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Read the key of the next entry, returns the hash stored before it if the block has them.
+    ///
+    /// The entry must then be finished with [`BlockIter::read_value`].
+    fn read_key(&mut self) -> io::Result<Option<Option<u32>>> {
+        if self.offset >= self.block.data.len() {
+            return Ok(None);
+        }
+        let mut cursor = &self.block.data[self.offset..];
+
+        let format = self.block.format;
+        let hash = match format.key_hashes {
+            true => Some(read_u32(&mut cursor)?),
+            false => None,
+        };
+        let shared = format.encoding.read_len(&mut cursor)?;
+        let unshared = format.encoding.read_len(&mut cursor)?;
+        if shared > self.key.len() {
+            return Err(corrupted());
+        }
+        self.key.truncate(shared);
+        self.key.resize(shared + unshared, 0);
+        cursor.read_exact(&mut self.key[shared..])?;
+
+        self.offset = self.block.data.len() - cursor.len();
+        Ok(Some(hash))
+    }
+
+    /// Read the sequence number, the metadata, the timestamp and the value of the entry whose
+    /// key was just read, the value isn't copied and is returned empty when `skip` is set.
+    ///
+    /// The values stored in blobs or in the value log are read from the stores of the block.
+    fn read_value(&mut self, skip: bool) -> io::Result<(u64, u8, u64, Option<Vec<u8>>)> {
+        let mut cursor = &self.block.data[self.offset..];
+        let format = self.block.format;
+        let encoding = format.encoding;
+        let seq = encoding.read_seq(&mut cursor)?;
+        let meta = match format.meta {
+            true => read_u8(&mut cursor)?,
+            false => 0,
+        };
+        let timestamp = match format.timestamps {
+            true => encoding.read_seq(&mut cursor)?,
+            false => 0,
+        };
+        let value = match format.read_value_len(&mut cursor)? {
+            StoredLen::Inline(len) if skip => {
+                cursor = cursor.get(len..).ok_or(io::ErrorKind::UnexpectedEof)?;
+                Some(Vec::new())
+            }
+            StoredLen::Inline(len) if self.stored_values => {
+                let mut value = vec![STORED_INLINE; len + 1];
+                cursor.read_exact(&mut value[1..])?;
+                Some(value)
+            }
+            StoredLen::Inline(len) => {
+                let mut value = vec![0; len];
+                cursor.read_exact(&mut value)?;
+                Some(value)
+            }
+            StoredLen::Blob => {
+                let mut hash = [0; 16];
+                cursor.read_exact(&mut hash)?;
+                let hash = u128::from_be_bytes(hash);
+                match &self.block.blob_store {
+                    _ if skip => Some(Vec::new()),
+                    Some(store) if self.stored_values => {
+                        let mut value = store.get(hash)?;
+                        value.insert(0, STORED_INLINE);
+                        Some(value)
+                    }
+                    Some(store) => Some(store.get(hash)?),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "the value is stored in a blob of the database",
+                        ))
+                    }
+                }
+            }
+            StoredLen::Pointer => {
+                let pointer = cursor
+                    .get(..ValuePointer::SIZE)
+                    .ok_or(io::ErrorKind::UnexpectedEof)?;
+                cursor = &cursor[ValuePointer::SIZE..];
+                match &self.block.value_log {
+                    _ if skip => Some(Vec::new()),
+                    _ if self.stored_values => Some([&[STORED_POINTER], pointer].concat()),
+                    Some(log) => Some(log.read(ValuePointer::decode(pointer)?)?),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "the value is stored in the value log of the database",
+                        ))
+                    }
+                }
+            }
+            StoredLen::Deleted => None,
+        };
+        self.offset = self.block.data.len() - cursor.len();
+        Ok((seq, meta, timestamp, value))
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peeked.take() {
+            Some(entry) => Some(Ok(entry)),
+            None => self.read_entry().transpose(),
+        }
+    }
+}
+
+/// Read the entries of a clean segment in order, starting from the first key contained in `start`.
+pub(crate) struct SegmentIter<R = SegmentFile> {
+    reader: R,
+    // The index blocks that weren't read yet
+    index: vec::IntoIter<BlockHandle>,
+    // The data blocks of the current index block that weren't read yet
+    blocks: vec::IntoIter<BlockHandle>,
+    block: Option<BlockIter>,
+    start: Bound<Vec<u8>>,
+    format: BlockFormat,
+    // Prevents the deletion of the segment while it's read
+    lease: Option<Arc<Lease>>,
+    // Gives the buffers the blocks are read in
+    pool: Option<Arc<BufferPool>>,
+    // The data blocks skipped since they were punched out of the file
+    holes: Holes,
+    // When set the entries whose key it rejects are skipped before their value is read
+    filter: Option<KeyFilter>,
+    // Whether the values are returned empty rather than copied
+    keys_only: bool,
+    // Reads the values stored in blobs
+    pub blob_store: Option<Arc<BlobStore>>,
+    // Reads the values stored in the value log
+    pub value_log: Option<Arc<ValueLog>>,
+    // Whether the values are returned as stored
+    stored_values: bool,
+}
+
+impl SegmentIter {
+    pub fn open(path: &Path, start: Bound<Vec<u8>>, read_ahead: u64) -> io::Result<SegmentIter> {
+        let file = SegmentFile::new(File::open(path)?, false, read_ahead);
+        SegmentIter::new(file, start)
+    }
+
+    /// Read the whole segment without keeping it in the page cache.
+    pub fn open_uncached(path: &Path, read_ahead: u64) -> io::Result<SegmentIter> {
+        let file = SegmentFile::new(File::open(path)?, true, read_ahead);
+        SegmentIter::new(file, Bound::Unbounded)
+    }
+}
+
+impl<R: Read + Seek> SegmentIter<R> {
+    pub fn new(mut reader: R, start: Bound<Vec<u8>>) -> io::Result<SegmentIter<R>> {
+        let handles = |index: &[(Vec<u8>, BlockHandle)]| {
+            let handles: Vec<_> = index.iter().map(|(_, handle)| *handle).collect();
+            handles.into_iter()
+        };
+
+        let footer = read_footer(&mut reader)?;
+        let format = footer.format;
+        let top = read_index(&mut reader, footer.index, format, None)?;
+        let (index, blocks) = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                // Skip the blocks that can't contain the start of the range
+                let mut index = handles(&top[find_block(&top, key)..]);
+                let blocks = match index.next() {
+                    Some(handle) => {
+                        let blocks = read_index(&mut reader, handle, format, None)?;
+                        handles(&blocks[find_block(&blocks, key)..])
+                    }
+                    None => Vec::new().into_iter(),
+                };
+                (index, blocks)
+            }
+            Bound::Unbounded => (handles(&top), Vec::new().into_iter()),
+        };
+
+        Ok(SegmentIter {
+            reader,
+            index,
+            blocks,
+            block: None,
+            start,
+            format,
+            lease: None,
+            pool: None,
+            holes: Holes::default(),
+            filter: None,
+            keys_only: false,
+            blob_store: None,
+            value_log: None,
+            stored_values: false,
+        })
+    }
+
+    /// Only return the entries whose key is accepted by `filter`.
+    pub fn filter_keys(&mut self, filter: KeyFilter) {
+        self.filter = Some(filter);
+    }
+
+    /// Return the entries with an empty value, the values are skipped without being copied.
+    /// The deletions still have no value.
+    pub fn keys_only(&mut self) {
+        self.keys_only = true;
+    }
+
+    /// Return the values as they're stored, for the compactions to write them back without
+    /// reading the value log: prefixed with `STORED_INLINE`, or with `STORED_POINTER` and
+    /// followed by the pointer in the value log. See [`SegmentWriter::add_stored`].
+    pub fn stored_values(&mut self) {
+        self.stored_values = true;
+    }
+
+    /// Read the next data block, `None` once the whole segment was read.
+    fn next_block(&mut self) -> io::Result<Option<Block>> {
+        loop {
+            if let Some(handle) = self.blocks.next() {
+                if self.holes.contains(handle.offset) {
+                    continue;
+                }
+                let pool = self.pool.as_ref();
+                let mut block = handle.read(&mut self.reader, self.format, pool)?;
+                block.blob_store = self.blob_store.clone();
+                block.value_log = self.value_log.clone();
+                return Ok(Some(block));
+            }
+            let Some(index) = self.index.next() else {
+                return Ok(None);
+            };
+            let blocks = read_index(&mut self.reader, index, self.format, self.pool.as_ref())?;
+            let blocks: Vec<_> = blocks.into_iter().map(|(_, handle)| handle).collect();
+            self.blocks = blocks.into_iter();
+        }
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<Entry>> {
+        loop {
+            let next = match (&mut self.block, &self.filter) {
+                (Some(block), Some(filter)) => block.next_filtered(filter).transpose(),
+                (Some(block), None) => block.next(),
+                (None, _) => None,
+            };
+            if let Some(entry) = next {
+                let entry = entry?;
+                let range = (self.start.as_ref(), Bound::Unbounded);
+                if RangeBounds::<Vec<u8>>::contains(&range, &entry.key) {
+                    // The following entries are all in the range
+                    self.start = Bound::Unbounded;
+                    return Ok(Some(entry));
+                }
+                continue;
+            }
+
+            // We went through the whole segment
+            let Some(block) = self.next_block()? else {
+                return Ok(None);
+            };
+            let mut block = match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => block.seek(key)?,
+                Bound::Unbounded => block.into_iter(),
+            };
+            block.skip_values = self.keys_only;
+            block.stored_values = self.stored_values;
+            self.block = Some(block);
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for SegmentIter<R> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+/// Read a clean segment file without the database it belongs to, e.g. for the tools merging or
+/// inspecting the segments.
+///
+/// The entries are returned as they're stored: sorted by key and then from the most recent to
+/// the oldest version, the deletions being entries without a value. The more recent segments of
+/// the database are unknown, their entries and range tombstones may hide the ones of the segment.
+pub struct SegmentReader {
+    path: PathBuf,
+    metadata: SegmentMetadata,
+}
+
+/// What the footer of a segment records, see [`SegmentReader::metadata`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentMetadata {
+    /// The version of the format the segment was written with.
+    pub format_version: u32,
+    /// How the entries of the blocks are encoded.
+    pub encoding: Encoding,
+    /// The smallest and largest keys, `None` if the segment is empty.
+    pub fence: Option<(Vec<u8>, Vec<u8>)>,
+    /// The smallest and largest sequence numbers, `None` if the segment is empty or was written
+    /// before they were recorded.
+    pub seqs: Option<RangeInclusive<u64>>,
+    /// `None` if the segment was written before they were recorded.
+    pub counters: Option<SegmentCounters>,
+    /// The name of the policy that created the filter, `None` if the segment has no filter. It's
+    /// followed by `+` and the name of the prefix extractor when the filter holds prefixes.
+    pub filter: Option<String>,
+    /// The ranges deleted by the segment, they hide the entries of the older segments.
+    pub range_tombstones: Vec<RangeTombstone>,
+    /// The identity of the database that wrote the segment, see
+    /// [`Database::identity`](crate::Database::identity). `None` if the segment was written
+    /// before it was recorded or outside of a database.
+    pub database: Option<u128>,
+}
+
+impl SegmentReader {
+    /// Open the segment and read its footer.
+    pub fn open(path: impl AsRef<Path>) -> Result<SegmentReader> {
+        let path = path.as_ref().to_owned();
+        let mut file = File::open(&path)?;
+        let footer = read_footer(&mut file)?;
+        let filter = match footer.filter {
+            Some(handle) => {
+                let block = read_raw_block(&mut file, handle)?;
+                let name = split_filter(&block)?.map(|(name, _)| name);
+                name.map(|name| String::from_utf8_lossy(name).into_owned())
+            }
+            None => None,
+        };
+        let range_tombstones = match footer.range_tombstones {
+            Some(handle) => read_range_tombstones(&mut file, handle)?,
+            None => Vec::new(),
+        };
+        let metadata = SegmentMetadata {
+            format_version: footer.version,
+            encoding: footer.format.encoding,
+            fence: read_fence(&mut file, &footer, None)?,
+            seqs: footer.seqs.filter(|seqs| !seqs.is_empty()),
+            counters: footer.counters,
+            filter,
+            range_tombstones,
+            database: footer.database,
+        };
+        Ok(SegmentReader { path, metadata })
+    }
+
+    pub fn metadata(&self) -> &SegmentMetadata {
+        &self.metadata
+    }
+
+    /// Iterate over the entries whose key is contained in `start..`, the file is opened again.
+    pub fn iter(&self, start: Bound<&[u8]>) -> Result<SegmentEntries> {
+        let start = start.map(<[u8]>::to_vec);
+        Ok(SegmentEntries(SegmentIter::open(&self.path, start, 0)?))
+    }
+}
+
+/// The entries of a segment, see [`SegmentReader::iter`].
+pub struct SegmentEntries(SegmentIter);
+
+impl Iterator for SegmentEntries {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.0.next()?.map_err(Into::into))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{batch::SequentialReads, Bloom};
+
+    struct Ribbon;
+
+    impl FilterPolicy for Ribbon {
+        fn name(&self) -> &str {
+            "ribbon"
+        }
+
+        fn new_filter(&self) -> Box<dyn Filter> {
+            unimplemented!()
+        }
+
+        fn read_filter(&self, _bytes: &[u8]) -> Result<Box<dyn Filter>> {
+            unimplemented!()
+        }
+    }
+
+    fn write(entries: &[Entry], encoding: Encoding) -> Vec<u8> {
+        let mut writer =
+            SegmentWriter::new(Vec::new(), Some(&Bloom::new(10)), encoding, Arc::default());
+        for entry in entries {
+            writer
+                .add(
+                    &entry.key,
+                    entry.seq,
+                    entry.meta,
+                    entry.timestamp,
+                    entry.value.as_deref(),
+                )
+                .unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read(segment: &[u8], start: Bound<Vec<u8>>) -> Vec<Entry> {
+        SegmentIter::new(io::Cursor::new(segment), start)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn entry(key: &[u8], seq: u64, value: Option<&[u8]>) -> Entry {
+        Entry {
+            key: key.to_vec(),
+            seq,
+            meta: 0,
+            timestamp: 0,
+            value: value.map(<[u8]>::to_vec),
+        }
+    }
+
+    #[test]
+    fn block() {
+        let entries = [
+            entry(b"hello", 3, Some(b"world")),
+            entry(b"help", 2, None),
+            entry(b"help", 1, Some(b"me")),
+        ];
+        let segment = write(&entries, Encoding::Varint);
+        insta::assert_snapshot!(format!("{segment:?}"), @"[54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 3, 0, 0, 8, 119, 111, 114, 108, 100, 8, 135, 92, 172, 3, 1, 112, 2, 0, 0, 0, 8, 135, 92, 172, 4, 0, 1, 0, 0, 5, 109, 101, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 0, 160, 10, 2, 0, 4, 85, 0, 6, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 51, 229, 65, 246, 116, 0, 0, 0, 0, 0, 0, 0, 1, 54, 16, 166, 134, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 69, 0, 0, 0, 39, 151, 57, 58, 188, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 108, 0, 0, 0, 39, 167, 99, 156, 42, 0, 0, 0, 0, 0, 0, 0, 51, 0, 0, 0, 18, 95, 22, 128, 80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 44, 52, 177, 96, 0, 0, 0, 25, 105, 114, 101, 118, 111, 105, 114, 101]");
+        let segment = write(&entries, Encoding::Fixed);
+        insta::assert_snapshot!(format!("{segment:?}"), @"[54, 16, 166, 134, 0, 0, 0, 0, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 119, 111, 114, 108, 100, 8, 135, 92, 172, 0, 0, 0, 3, 0, 0, 0, 1, 112, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 8, 135, 92, 172, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 109, 101, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 98, 108, 111, 111, 109, 0, 160, 10, 2, 0, 4, 85, 0, 6, 54, 16, 166, 134, 0, 0, 0, 0, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 120, 47, 77, 121, 115, 0, 0, 0, 0, 0, 0, 0, 1, 54, 16, 166, 134, 0, 0, 0, 0, 0, 0, 0, 5, 104, 101, 108, 108, 111, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 138, 0, 0, 0, 62, 231, 214, 188, 220, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 200, 0, 0, 0, 62, 123, 11, 182, 61, 0, 0, 0, 0, 0, 0, 0, 120, 0, 0, 0, 18, 95, 22, 128, 80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 244, 89, 147, 0, 0, 0, 24, 105, 114, 101, 118, 111, 105, 114, 101]");
+    }
+
+    #[test]
+    fn seek_exact() {
+        // The versions of the keys cross the restart points
+        let entries: Vec<_> = (0..300u32)
+            .map(|i| {
+                entry(
+                    format!("key-{:03}", i / 3 * 2).as_bytes(),
+                    i as u64,
+                    Some(b"v"),
+                )
+            })
+            .collect();
+        for key_hashes in [true, false] {
+            let format = BlockFormat {
+                encoding: Encoding::Varint,
+                key_hashes,
+                meta: true,
+                timestamps: true,
+                blobs: true,
+                value_log: true,
+            };
+            let mut builder = BlockBuilder::new(format, Vec::new());
+            for entry in &entries {
+                builder.add(
+                    &entry.key,
+                    entry.seq,
+                    entry.meta,
+                    entry.timestamp,
+                    entry.value.as_deref(),
+                );
+            }
+            let block = Block::decode(builder.finish(), format, None).unwrap();
+
+            for i in [0, 2, 10, 64, 198] {
+                let key = format!("key-{i:03}").into_bytes();
+                let found: Vec<_> = (block.clone().seek_exact(&key).unwrap())
+                    .map(Result::unwrap)
+                    .take_while(|e| e.key == key)
+                    .collect();
+                let expected: Vec<_> = entries.iter().filter(|e| e.key == key).cloned().collect();
+                assert_eq!(found, expected);
+            }
+            for key in [&b"a"[..], b"key-001", b"key-063", b"z"] {
+                assert!(block.clone().seek_exact(key).unwrap().next().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn seek_through_blocks() {
+        let entries: Vec<_> = (0..2000u32)
+            .map(|i| {
+                let key = format!("key-{:05}", i / 2).into_bytes();
+                let value = (i % 7 != 0).then(|| i.to_be_bytes().repeat(i as usize % 5));
+                entry(&key, 2000 - i as u64, value.as_deref())
+            })
+            .collect();
+        for encoding in [Encoding::Fixed, Encoding::Varint] {
+            let segment = write(&entries, encoding);
+            assert_eq!(read(&segment, Bound::Unbounded), entries);
+
+            for i in [0, 1, 15, 16, 17, 500, 999] {
+                let key = format!("key-{i:05}").into_bytes();
+                let from = entries.iter().position(|e| e.key == key).unwrap();
+                assert_eq!(
+                    read(&segment, Bound::Included(key.clone())),
+                    entries[from..]
+                );
+                assert_eq!(read(&segment, Bound::Excluded(key)), entries[from + 2..],);
+            }
+            assert_eq!(read(&segment, Bound::Included(b"key-".to_vec())), entries);
+            assert!(read(&segment, Bound::Included(b"z".to_vec())).is_empty());
+        }
+    }
+
+    #[test]
+    fn last_key() {
+        let entries: Vec<_> = (0..4000u32)
+            .map(|i| entry(format!("key-{:05}", i * 2).as_bytes(), 0, Some(b"value")))
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("segment");
+        std::fs::write(&path, write(&entries, Encoding::Varint)).unwrap();
+        let segment = Segment::new(0, path, Arc::default());
+        let mut files = FilePool::new(1);
+
+        let mut last_key = |end: Bound<&str>| {
+            let key = segment
+                .last_key(&mut files, end.map(str::as_bytes))
+                .unwrap();
+            key.map(|key| String::from_utf8(key).unwrap())
+        };
+        assert_eq!(last_key(Bound::Unbounded).as_deref(), Some("key-07998"));
+        assert_eq!(last_key(Bound::Included("z")).as_deref(), Some("key-07998"));
+        assert_eq!(
+            last_key(Bound::Included("key-05000")).as_deref(),
+            Some("key-05000")
+        );
+        assert_eq!(
+            last_key(Bound::Excluded("key-05000")).as_deref(),
+            Some("key-04998")
+        );
+        assert_eq!(
+            last_key(Bound::Included("key-05001")).as_deref(),
+            Some("key-05000")
+        );
+        assert_eq!(
+            last_key(Bound::Included("key-00000")).as_deref(),
+            Some("key-00000")
+        );
+        assert_eq!(last_key(Bound::Excluded("key-00000")), None);
+        assert_eq!(last_key(Bound::Included("a")), None);
+    }
+
+    #[test]
+    fn segment_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("segment");
+        let mut writer = SegmentWriter::new(
+            Vec::new(),
+            Some(&Bloom::new(10)),
+            Encoding::Fixed,
+            Arc::default(),
+        );
+        writer.add(b"hello", 3, 0, 0, Some(b"world")).unwrap();
+        writer.add(b"help", 2, 0, 0, None).unwrap();
+        writer.add(b"help", 1, 7, 42, Some(b"me")).unwrap();
+        let range = RangeTombstone {
+            start: b"a".to_vec(),
+            end: b"b".to_vec(),
+            seq: 4,
+        };
+        writer.delete_range(range.clone());
+        std::fs::write(&path, writer.finish().unwrap()).unwrap();
+
+        let reader = SegmentReader::open(&path).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.format_version, 24);
+        assert_eq!(metadata.encoding, Encoding::Fixed);
+        assert_eq!(metadata.fence, Some((b"hello".to_vec(), b"help".to_vec())));
+        assert_eq!(metadata.seqs, Some(1..=4));
+        assert_eq!(metadata.counters.unwrap().tombstones, 1);
+        assert_eq!(metadata.filter.as_deref(), Some("bloom"));
+        assert_eq!(metadata.range_tombstones, [range]);
+
+        let entries: Vec<_> = reader
+            .iter(Bound::Excluded(b"hello"))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                entry(b"help", 2, None),
+                Entry {
+                    meta: 7,
+                    timestamp: 42,
+                    ..entry(b"help", 1, Some(b"me"))
+                }
+            ]
+        );
+        assert!(SegmentReader::open(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn point_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("segment");
+        // The versions of a key are large enough to fill several blocks and the
+        // other keys need more than one index block
+        let mut entries: Vec<_> = (0..100u64)
+            .map(|seq| entry(b"hot", 100 - seq, Some(&[seq as u8; 100])))
+            .collect();
+        entries.extend((0..20_000u32).map(|i| entry(&i.to_be_bytes(), 0, Some(b"value"))));
+        entries.sort_by(|a, b| a.key.cmp(&b.key).then(b.seq.cmp(&a.seq)));
+        let bytes = write(&entries, Encoding::Varint);
+        std::fs::write(&path, &bytes).unwrap();
+        Segment::validate(&path).unwrap();
+        // The blocks are shifted but the footer is intact
+        let shifted = dir.path().join("shifted");
+        std::fs::write(&shifted, [&[0][..], &bytes].concat()).unwrap();
+        assert!(Segment::validate(&shifted).is_err());
+
+        let pool = Arc::new(BufferPool::default());
+        let segment = Segment::new(0, path, pool.clone());
+        let mut files = FilePool::new(1);
+        let versions = segment.versions(&mut files, b"hot", usize::MAX).unwrap();
+        assert_eq!(versions.len(), 100);
+        assert_eq!(versions[0], (100, Some(vec![0; 100])));
+        assert_eq!(versions[99], (1, Some(vec![99; 100])));
+
+        let keys = [0u32, 1, 2000, 19_999, 20_000].map(u32::to_be_bytes);
+        let mut keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+        keys.extend([&b""[..], b"hot"]);
+        let mut cache = BlockCache::new(1024 * 1024, 0);
+        let mut multi_get = |cache: &mut BlockCache| {
+            let values = segment
+                .multi_get(&mut files, &keys, &mut SequentialReads, cache)
+                .unwrap();
+            values
+                .into_iter()
+                .map(|entry| entry.map(|entry| entry.value))
+                .collect::<Vec<_>>()
+        };
+        let values = multi_get(&mut cache);
+        let value = || Some(Some(b"value".to_vec()));
+        let hot = Some(Some(vec![0; 100]));
+        assert_eq!(
+            values,
+            [value(), value(), value(), value(), None, None, hot]
+        );
+        // The second lookup only reads the footer
+        let misses = cache.stats().misses;
+        assert_eq!(multi_get(&mut cache), values);
+        assert_eq!(cache.stats().misses, misses);
+
+        let policy = Bloom::new(10);
+        let filter = segment.filter(&mut files, &policy).unwrap().unwrap();
+        assert!(filter.contains(b"hot"));
+        assert!(!filter.contains(b"cold"));
+        // The filter was written by another policy
+        let other = Segment::new(0, segment.path.clone(), pool);
+        assert!(other.filter(&mut files, &Ribbon).unwrap().is_none());
+    }
+}
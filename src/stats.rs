@@ -0,0 +1,318 @@
+/// A snapshot of the internal state of a database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of file handles currently held by the database, including the dirty segment.
+    pub open_files: usize,
+    /// The number of buffers kept for reuse, see
+    /// [`DatabaseBuilder::buffer_pool_size`](crate::DatabaseBuilder::buffer_pool_size).
+    pub pool_buffers: usize,
+    /// The capacity of the buffers kept for reuse.
+    pub pool_bytes: usize,
+    /// The number of segment lookups skipped because the filter of the segment didn't contain the key.
+    pub filter_negatives: u64,
+    /// The number of segment lookups allowed by a filter that found the key.
+    pub filter_positives: u64,
+    /// The number of segment lookups allowed by a filter that didn't find the key.
+    pub filter_false_positives: u64,
+    /// The number of segment lookups skipped because the key was smaller than the first key or
+    /// greater than the last key of the segment, they're checked before the filter.
+    pub fence_negatives: u64,
+    /// The number of lookups answered by the database filter without going through the segments,
+    /// see [`DatabaseBuilder::database_filter`](crate::DatabaseBuilder::database_filter).
+    pub database_filter_negatives: u64,
+    /// The number of blocks found in the block cache, see
+    /// [`DatabaseBuilder::block_cache_size`](crate::DatabaseBuilder::block_cache_size).
+    pub block_cache_hits: u64,
+    /// The number of blocks looked up in the block cache and not found.
+    pub block_cache_misses: u64,
+    /// The size of the blocks held by the block cache.
+    pub block_cache_bytes: usize,
+    /// The number of blocks missing from the block cache found in the compressed cache, see
+    /// [`DatabaseBuilder::compressed_block_cache_size`](crate::DatabaseBuilder::compressed_block_cache_size).
+    pub compressed_block_cache_hits: u64,
+    /// The number of blocks looked up in the compressed cache and read from the segments.
+    pub compressed_block_cache_misses: u64,
+    /// The compressed size of the blocks held by the compressed cache.
+    pub compressed_block_cache_bytes: usize,
+    /// The estimated memory used by the memtable and the previous one while it's flushed, their
+    /// keys, the values written without the dirty segment and the deleted ranges included.
+    pub memtable_bytes: usize,
+    /// The memory used by the filters of the segments loaded by the lookups, and by the
+    /// [database filter](crate::DatabaseBuilder::database_filter).
+    pub filter_bytes: usize,
+    /// The memory counted by the
+    /// [memory budget](crate::DatabaseBuilder::memory_budget): the memtables, both block
+    /// caches and the filters.
+    pub memory_bytes: usize,
+    /// The number of entries of the clean segments, each version of a key and each deletion
+    /// counts, read from the footers of the segments.
+    pub segment_entries: u64,
+    /// The size of the keys of all the entries of the clean segments.
+    pub segment_key_bytes: u64,
+    /// The size of the values of all the entries of the clean segments.
+    pub segment_value_bytes: u64,
+    /// The number of deletions in the clean segments.
+    pub segment_tombstones: u64,
+    /// The number of segments written by a version that didn't record their counters, they're
+    /// left out of the counters until they're compacted.
+    pub uncounted_segments: usize,
+    /// The number of keys of the memtable triggering a flush, it follows the writes when
+    /// [`DatabaseBuilder::adaptive_dirty_thresholds`](crate::DatabaseBuilder::adaptive_dirty_thresholds)
+    /// is set.
+    pub dirty_thresholds: usize,
+    /// The size of the keys and values written by the application since the database was opened,
+    /// the deleted ranges count their bounds and the appends the appended bytes.
+    pub user_bytes: u64,
+    /// The size of the segments written by the flushes and the imports since the database was opened.
+    pub flush_bytes: u64,
+    /// The size of the segments written by the compactions since the database was opened.
+    pub compaction_bytes: u64,
+}
+
+/// Where [`Database::get_traced`](crate::Database::get_traced) found the most recent version
+/// of a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provenance {
+    /// The memtable, or the previous one while it's flushed.
+    Memtable,
+    /// A clean segment.
+    Segment {
+        id: usize,
+        /// The offset of the data block holding the entry in the file of the segment.
+        offset: u64,
+        /// Whether the filter of the segment was checked before reading it.
+        bloom_checked: bool,
+        /// The number of blocks read from the files of all the segments looked into, the blocks
+        /// found in the block cache aren't counted.
+        blocks_read: usize,
+    },
+}
+
+/// An estimation of the entries stored under a prefix, see
+/// [`Database::prefix_stats`](crate::Database::prefix_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// The number of entries, each version of a key and each deletion counts.
+    pub entries: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// The size of the data blocks holding the entries in the segments.
+    pub disk_bytes: u64,
+}
+
+/// An estimation of the space used by the clean segments, see
+/// [`Database::space_amplification`](crate::Database::space_amplification).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceAmplification {
+    /// The bytes used by the keys that weren't overwritten or deleted since.
+    pub live_bytes: u64,
+    /// The size of all the clean segments.
+    pub total_bytes: u64,
+}
+
+impl SpaceAmplification {
+    /// How many bytes are stored for each live byte, a full compaction brings it close to 1.
+    pub fn ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+        self.total_bytes as f64 / self.live_bytes.max(1) as f64
+    }
+}
+
+impl Stats {
+    /// The ratio of the absent keys the filters failed to exclude.
+    pub fn filter_false_positive_rate(&self) -> f64 {
+        let absent = self.filter_negatives + self.filter_false_positives;
+        if absent == 0 {
+            return 0.0;
+        }
+        self.filter_false_positives as f64 / absent as f64
+    }
+
+    /// How many bytes were written to the segments for each byte written by the application,
+    /// 0 before the first write.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes == 0 {
+            return 0.0;
+        }
+        (self.flush_bytes + self.compaction_bytes) as f64 / self.user_bytes as f64
+    }
+
+    /// The ratio of the blocks looked up in the block cache that were found.
+    pub fn block_cache_hit_rate(&self) -> f64 {
+        hit_rate(self.block_cache_hits, self.block_cache_misses)
+    }
+
+    /// The ratio of the blocks looked up in the compressed cache that were found.
+    pub fn compressed_block_cache_hit_rate(&self) -> f64 {
+        hit_rate(
+            self.compressed_block_cache_hits,
+            self.compressed_block_cache_misses,
+        )
+    }
+}
+
+fn hit_rate(hits: u64, misses: u64) -> f64 {
+    if hits + misses == 0 {
+        return 0.0;
+    }
+    hits as f64 / (hits + misses) as f64
+}
+
+#[cfg(feature = "prometheus")]
+impl Stats {
+    /// Render the stats in the Prometheus text exposition format, see
+    /// [`Database::prometheus_metrics`](crate::Database::prometheus_metrics).
+    pub(crate) fn prometheus(&self, segments: usize, live_keys: u64) -> String {
+        use std::fmt::Write;
+
+        let counters = [
+            (
+                "filter_negatives_total",
+                "Segment lookups skipped by the filters.",
+                self.filter_negatives,
+            ),
+            (
+                "filter_positives_total",
+                "Segment lookups allowed by the filters that found the key.",
+                self.filter_positives,
+            ),
+            (
+                "filter_false_positives_total",
+                "Segment lookups allowed by the filters that didn't find the key.",
+                self.filter_false_positives,
+            ),
+            (
+                "fence_negatives_total",
+                "Segment lookups skipped by the first and last keys of the segments.",
+                self.fence_negatives,
+            ),
+            (
+                "database_filter_negatives_total",
+                "Lookups answered by the database filter.",
+                self.database_filter_negatives,
+            ),
+            (
+                "block_cache_hits_total",
+                "Blocks found in the block cache.",
+                self.block_cache_hits,
+            ),
+            (
+                "block_cache_misses_total",
+                "Blocks looked up in the block cache and not found.",
+                self.block_cache_misses,
+            ),
+            (
+                "compressed_block_cache_hits_total",
+                "Blocks found in the compressed cache.",
+                self.compressed_block_cache_hits,
+            ),
+            (
+                "compressed_block_cache_misses_total",
+                "Blocks looked up in the compressed cache and not found.",
+                self.compressed_block_cache_misses,
+            ),
+            (
+                "user_bytes_total",
+                "Bytes written by the application.",
+                self.user_bytes,
+            ),
+            (
+                "flush_bytes_total",
+                "Bytes of the segments written by the flushes and the imports.",
+                self.flush_bytes,
+            ),
+            (
+                "compaction_bytes_total",
+                "Bytes of the segments written by the compactions.",
+                self.compaction_bytes,
+            ),
+        ];
+        let gauges = [
+            ("segments", "Clean segments.", segments as u64),
+            ("live_keys", "Keys with a value.", live_keys),
+            (
+                "open_files",
+                "File handles held by the database.",
+                self.open_files as u64,
+            ),
+            (
+                "pool_buffers",
+                "Buffers kept for reuse.",
+                self.pool_buffers as u64,
+            ),
+            (
+                "pool_bytes",
+                "Capacity of the buffers kept for reuse.",
+                self.pool_bytes as u64,
+            ),
+            (
+                "block_cache_bytes",
+                "Size of the blocks held by the block cache.",
+                self.block_cache_bytes as u64,
+            ),
+            (
+                "compressed_block_cache_bytes",
+                "Compressed size of the blocks held by the compressed cache.",
+                self.compressed_block_cache_bytes as u64,
+            ),
+            (
+                "memtable_bytes",
+                "Estimated memory used by the memtables.",
+                self.memtable_bytes as u64,
+            ),
+            (
+                "filter_bytes",
+                "Memory used by the loaded filters.",
+                self.filter_bytes as u64,
+            ),
+            (
+                "memory_bytes",
+                "Memory counted by the memory budget.",
+                self.memory_bytes as u64,
+            ),
+            (
+                "segment_entries",
+                "Entries of the clean segments.",
+                self.segment_entries,
+            ),
+            (
+                "segment_key_bytes",
+                "Size of the keys of the clean segments.",
+                self.segment_key_bytes,
+            ),
+            (
+                "segment_value_bytes",
+                "Size of the values of the clean segments.",
+                self.segment_value_bytes,
+            ),
+            (
+                "segment_tombstones",
+                "Deletions in the clean segments.",
+                self.segment_tombstones,
+            ),
+            (
+                "uncounted_segments",
+                "Segments without counters.",
+                self.uncounted_segments as u64,
+            ),
+            (
+                "dirty_thresholds",
+                "Keys of the memtable triggering a flush.",
+                self.dirty_thresholds as u64,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (kind, metrics) in [("counter", &counters[..]), ("gauge", &gauges[..])] {
+            for (name, help, value) in metrics {
+                // Writing to a String can't fail
+                let _ = writeln!(text, "# HELP database_{name} {help}");
+                let _ = writeln!(text, "# TYPE database_{name} {kind}");
+                let _ = writeln!(text, "database_{name} {value}");
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+/// The weight of each new entry in the moving average of their size.
+const ENTRY_SMOOTHING: f64 = 1.0 / 64.0;
+/// The weight of each new flush in the moving average of their duration.
+const FLUSH_SMOOTHING: f64 = 0.5;
+/// The duration the flushes shouldn't exceed, the writes wait for the previous flush once the
+/// next memtable is full.
+const FLUSH_TARGET: Duration = Duration::from_secs(1);
+/// The bounds of the threshold, whatever the size of the entries.
+const MIN_THRESHOLD: usize = 64;
+const MAX_THRESHOLD: usize = 16 * 1024 * 1024;
+
+/// Adjusts the dirty threshold to the writes, see
+/// [`DatabaseBuilder::adaptive_dirty_thresholds`](crate::DatabaseBuilder::adaptive_dirty_thresholds).
+///
+/// The threshold is the number of entries of the average size fitting in the memtable budget,
+/// reduced when the flushes take longer than [`FLUSH_TARGET`].
+#[derive(Debug, Clone)]
+pub(crate) struct AdaptiveThreshold {
+    memtable_bytes: usize,
+    // `None` until the first write
+    entry_size: Option<f64>,
+    // `None` until the first flush
+    flush_secs: Option<f64>,
+}
+
+impl AdaptiveThreshold {
+    pub fn new(memtable_bytes: usize) -> AdaptiveThreshold {
+        AdaptiveThreshold {
+            memtable_bytes,
+            entry_size: None,
+            flush_secs: None,
+        }
+    }
+
+    pub fn record_entry(&mut self, size: usize) {
+        self.entry_size = Some(average(self.entry_size, size as f64, ENTRY_SMOOTHING));
+    }
+
+    pub fn record_flush(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        self.flush_secs = Some(average(self.flush_secs, secs, FLUSH_SMOOTHING));
+    }
+
+    /// The current threshold, `None` until the first write.
+    pub fn threshold(&self) -> Option<usize> {
+        let entry_size = self.entry_size?;
+        let mut threshold = self.memtable_bytes as f64 / entry_size.max(1.0);
+        if let Some(flush_secs) = self.flush_secs {
+            threshold *= (FLUSH_TARGET.as_secs_f64() / flush_secs).min(1.0);
+        }
+        Some((threshold.round() as usize).clamp(MIN_THRESHOLD, MAX_THRESHOLD))
+    }
+}
+
+fn average(previous: Option<f64>, value: f64, smoothing: f64) -> f64 {
+    match previous {
+        Some(previous) => previous + (value - previous) * smoothing,
+        None => value,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adaptive_threshold() {
+        let mut adaptive = AdaptiveThreshold::new(1024 * 1024);
+        assert_eq!(adaptive.threshold(), None);
+        adaptive.record_entry(1024);
+        assert_eq!(adaptive.threshold(), Some(1024));
+
+        // The average moves slowly toward the new size
+        for _ in 0..1000 {
+            adaptive.record_entry(64);
+        }
+        assert_eq!(adaptive.threshold(), Some(16384));
+        adaptive.record_entry(1024 * 1024);
+        assert!(adaptive.threshold().unwrap() < 1024);
+
+        // A slow flush shrinks the memtable, a fast one doesn't grow it
+        let mut adaptive = AdaptiveThreshold::new(1024 * 1024);
+        adaptive.record_entry(64);
+        adaptive.record_flush(Duration::from_millis(10));
+        assert_eq!(adaptive.threshold(), Some(16384));
+        adaptive.record_flush(Duration::from_millis(3990));
+        assert_eq!(adaptive.threshold(), Some(8192));
+
+        // Whatever the entries
+        adaptive.record_entry(usize::MAX);
+        assert_eq!(adaptive.threshold(), Some(MIN_THRESHOLD));
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+/// The granularity at which the file systems release the space of the holes punched in a file.
+const PAGE_SIZE: u64 = 4096;
+
+/// The number of consecutive reads starting where the previous one ended before the reads are
+/// considered sequential.
+const SEQUENTIAL_READS: u32 = 2;
+
+/// A segment file whose pages can be evicted from the page cache as soon as they're read,
+/// so a large compaction doesn't evict the pages serving the foreground reads.
+///
+/// When the file is read sequentially the kernel is asked to prefetch the next `read_ahead` bytes.
+pub(crate) struct SegmentFile {
+    file: File,
+    drop_cache: bool,
+    position: u64,
+    read_ahead: u64,
+    // The number of consecutive sequential reads
+    sequential: u32,
+    // The end of the part of the file already prefetched
+    prefetched: u64,
+}
+
+impl SegmentFile {
+    pub fn new(file: File, drop_cache: bool, read_ahead: u64) -> SegmentFile {
+        SegmentFile {
+            file,
+            drop_cache,
+            position: 0,
+            read_ahead,
+            sequential: 0,
+            prefetched: 0,
+        }
+    }
+
+    fn prefetch(&mut self) {
+        if self.read_ahead == 0 || self.sequential < SEQUENTIAL_READS {
+            return;
+        }
+        // Prefetch the next part once half of the previous one was consumed
+        if self.position + self.read_ahead / 2 >= self.prefetched {
+            let offset = self.position.max(self.prefetched);
+            will_need(&self.file, offset, self.position + self.read_ahead - offset);
+            self.prefetched = self.position + self.read_ahead;
+        }
+    }
+}
+
+impl Read for SegmentFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.prefetch();
+        let read = self.file.read(buf)?;
+        if self.drop_cache {
+            drop_cache(&self.file, self.position, read as u64);
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SegmentFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.file.seek(pos)?;
+        match position == self.position {
+            true => self.sequential += 1,
+            false => self.sequential = 0,
+        }
+        self.position = position;
+        Ok(self.position)
+    }
+}
+
+/// Advise the kernel to evict the pages of the given part of the file from the page cache.
+///
+/// It's only an optimization, the errors are ignored. The dirty pages must be synced first.
+#[cfg(target_os = "linux")]
+pub(crate) fn drop_cache(file: &File, offset: u64, len: u64) {
+    use std::os::fd::AsRawFd;
+
+    // Safety: the file descriptor is valid as long as the file is borrowed
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn drop_cache(_file: &File, _offset: u64, _len: u64) {}
+
+/// Advise the kernel to load the given part of the file in the page cache in the background.
+///
+/// Like [`drop_cache`] the errors are ignored.
+#[cfg(target_os = "linux")]
+fn will_need(file: &File, offset: u64, len: u64) {
+    use std::os::fd::AsRawFd;
+
+    // Safety: the file descriptor is valid as long as the file is borrowed
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn will_need(_file: &File, _offset: u64, _len: u64) {}
+
+/// Reserve the blocks of the first `len` bytes of a file before writing it, so it's less
+/// fragmented and running out of space fails right away instead of in the middle of the write.
+///
+/// The file systems that can't preallocate are written to as usual. The file is extended to
+/// `len`, it must be truncated with [`truncate_to_written`] once complete.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // Safety: the file descriptor is valid as long as the file is borrowed
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS) => Ok(()),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// The part of `range` made of whole pages, `None` if it doesn't cover one.
+pub(crate) fn whole_pages(range: &Range<u64>) -> Option<Range<u64>> {
+    let start = range.start.next_multiple_of(PAGE_SIZE);
+    let end = range.end / PAGE_SIZE * PAGE_SIZE;
+    (start < end).then_some(start..end)
+}
+
+/// Release the space of the whole pages of `range`, they're read as zeros afterwards and the
+/// size of the file doesn't change. The file must be open for writing.
+///
+/// Returns the number of bytes released, 0 when the file system can't punch holes.
+#[cfg(target_os = "linux")]
+pub(crate) fn punch_hole(file: &File, range: &Range<u64>) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    let Some(pages) = whole_pages(range) else {
+        return Ok(0);
+    };
+    let len = pages.end - pages.start;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // Safety: the file descriptor is valid as long as the file is borrowed
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            pages.start as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(len);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS) => Ok(0),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn punch_hole(_file: &File, _range: &Range<u64>) -> io::Result<u64> {
+    Ok(0)
+}
+
+/// Drop the part of a preallocated file after the current position, where the write ended.
+pub(crate) fn truncate_to_written(file: &mut File) -> io::Result<()> {
+    let len = file.stream_position()?;
+    file.set_len(len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_ahead() {
+        let mut file = tempfile::tempfile().unwrap();
+        io::Write::write_all(&mut file, &[0; 64 * 1024]).unwrap();
+        let mut file = SegmentFile::new(file, false, 8192);
+        let mut buf = [0; 1024];
+
+        // The blocks are read one after the other
+        for offset in (0..16 * 1024).step_by(1024) {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+        }
+        // Always ahead of the reads
+        let prefetched = file.prefetched;
+        assert!(
+            prefetched > 16 * 1024 && prefetched <= 24 * 1024,
+            "{prefetched}"
+        );
+
+        // A random read doesn't prefetch anything
+        file.seek(SeekFrom::Start(40 * 1024)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(file.prefetched, prefetched);
+    }
+
+    #[test]
+    fn preallocate() {
+        let mut file = tempfile::tempfile().unwrap();
+        super::preallocate(&file, 64 * 1024).unwrap();
+        io::Write::write_all(&mut file, &[1; 1000]).unwrap();
+        truncate_to_written(&mut file).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1000);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{read_u32, sync_dir, Layout};
+
+/// A file of the value log is sealed once it reaches this size, the next values go to a new one.
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// The size and the checksum of the value precede it in the value log.
+const HEADER_SIZE: u64 = 8;
+
+/// Where a value is stored in the value log, the entries of the segments hold it rather than
+/// the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ValuePointer {
+    pub file: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl ValuePointer {
+    pub const SIZE: usize = 16;
+
+    pub fn encode(&self) -> [u8; ValuePointer::SIZE] {
+        let mut buf = [0; ValuePointer::SIZE];
+        buf[..4].copy_from_slice(&self.file.to_be_bytes());
+        buf[4..12].copy_from_slice(&self.offset.to_be_bytes());
+        buf[12..].copy_from_slice(&self.len.to_be_bytes());
+        buf
+    }
+
+    /// The size of the value in the value log, its header included.
+    pub fn record_len(&self) -> u64 {
+        HEADER_SIZE + self.len as u64
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<ValuePointer> {
+        let bytes: &[u8; ValuePointer::SIZE] = bytes
+            .try_into()
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+        Ok(ValuePointer {
+            file: u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+            offset: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+            len: u32::from_be_bytes(bytes[12..].try_into().unwrap()),
+        })
+    }
+}
+
+/// The large values appended out of the segments, see
+/// [`DatabaseBuilder::value_log_threshold`](crate::DatabaseBuilder::value_log_threshold).
+///
+/// The log is a sequence of files in the `vlog` directory named by their increasing number.
+/// Each value is preceded by its size and its checksum, and only the last file is appended to.
+/// Each segment lists how many bytes of each file its entries reference: a file no segment
+/// references is deleted by the compactions, and
+/// [`Database::collect_value_log`](crate::Database::collect_value_log) moves the values still
+/// referenced out of the files made mostly of garbage.
+pub(crate) struct ValueLog {
+    dir: PathBuf,
+    head: Mutex<Head>,
+}
+
+/// The file appended to, created by the first append.
+struct Head {
+    id: u32,
+    file: Option<File>,
+    offset: u64,
+}
+
+impl ValueLog {
+    /// The values are appended to a new file, the files written before a crash may end with a
+    /// torn value no segment references.
+    pub fn open(root: &Path, layout: &Layout) -> io::Result<ValueLog> {
+        let mut log = ValueLog {
+            dir: layout.value_log_dir(root),
+            head: Mutex::new(Head {
+                id: 0,
+                file: None,
+                offset: 0,
+            }),
+        };
+        let last = log.files()?.last().map_or(0, |(id, _)| *id);
+        log.head.get_mut().unwrap().id = last + 1;
+        Ok(log)
+    }
+
+    pub fn path(&self, id: u32) -> PathBuf {
+        self.dir.join(format!("{id:06}"))
+    }
+
+    /// Append a value, it's durable once [`ValueLog::sync`] returns.
+    pub fn append(&self, value: &[u8]) -> io::Result<ValuePointer> {
+        let mut head = self.head.lock().unwrap();
+        if head.file.is_some() && head.offset + HEADER_SIZE + value.len() as u64 > FILE_SIZE {
+            self.seal_head(&mut head)?;
+        }
+        if head.file.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.path(head.id))?;
+            sync_dir(&self.dir)?;
+            head.file = Some(file);
+            head.offset = 0;
+        }
+        let pointer = ValuePointer {
+            file: head.id,
+            offset: head.offset,
+            len: value.len() as u32,
+        };
+        let mut record = Vec::with_capacity(HEADER_SIZE as usize + value.len());
+        record.extend_from_slice(&pointer.len.to_be_bytes());
+        record.extend_from_slice(&crc32fast::hash(value).to_be_bytes());
+        record.extend_from_slice(value);
+        head.file.as_mut().unwrap().write_all(&record)?;
+        head.offset += record.len() as u64;
+        Ok(pointer)
+    }
+
+    /// Make the values appended so far durable.
+    pub fn sync(&self) -> io::Result<()> {
+        match &self.head.lock().unwrap().file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop appending to the current file, so it can be collected like the others.
+    pub fn seal(&self) -> io::Result<()> {
+        let mut head = self.head.lock().unwrap();
+        match head.file {
+            Some(_) => self.seal_head(&mut head),
+            None => Ok(()),
+        }
+    }
+
+    fn seal_head(&self, head: &mut Head) -> io::Result<()> {
+        if let Some(file) = head.file.take() {
+            file.sync_data()?;
+        }
+        head.id += 1;
+        Ok(())
+    }
+
+    /// Read a value and check it against its checksum.
+    pub fn read(&self, pointer: ValuePointer) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(pointer.file))?;
+        file.seek(SeekFrom::Start(pointer.offset))?;
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        let mut cursor = header.as_slice();
+        let (len, checksum) = (read_u32(&mut cursor)?, read_u32(&mut cursor)?);
+        let mut value = vec![0; pointer.len as usize];
+        file.read_exact(&mut value)?;
+        match len == pointer.len && crc32fast::hash(&value) == checksum {
+            true => Ok(value),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "corrupted value at {} of the value log file {}",
+                    pointer.offset, pointer.file
+                ),
+            )),
+        }
+    }
+
+    /// The ids and sizes of the files no value will be appended to anymore.
+    pub fn sealed_files(&self) -> io::Result<Vec<(u32, u64)>> {
+        let head = self.head.lock().unwrap().id;
+        let mut files = self.files()?;
+        files.retain(|(id, _)| *id < head);
+        Ok(files)
+    }
+
+    pub fn delete(&self, id: u32) -> io::Result<()> {
+        fs::remove_file(self.path(id))?;
+        sync_dir(&self.dir)
+    }
+
+    /// The ids and sizes of all the files, sorted by id.
+    fn files(&self) -> io::Result<Vec<(u32, u64)>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let id = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+            if let Some(id) = id {
+                files.push((id, entry.metadata()?.len()));
+            }
+        }
+        files.sort_unstable();
+        Ok(files)
+    }
+}
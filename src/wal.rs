@@ -0,0 +1,549 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use crate::{read_bytes, read_u32, sync_dir, Error, Layout, Result};
+
+/// The size of the files of a [`Wal`] before it continues in a new one.
+const WAL_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// The size of the record and its checksum precede each record of a [`Wal`].
+const RECORD_HEADER: u64 = 2 * mem::size_of::<u32>() as u64;
+
+/// How a single write goes through the dirty segment, see [`Database::add_opt`](crate::Database::add_opt).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Sync the dirty segment once the entry is written, the write is durable when it returns.
+    pub sync: bool,
+    /// Only keep the entry in memory until it's flushed to a segment. It's lost if the process
+    /// crashes before, and isn't streamed by
+    /// [`Database::stream_wal_since`](crate::Database::stream_wal_since).
+    pub disable_wal: bool,
+}
+
+/// The dirty segment, a write-ahead log split in numbered files of about `max_size` bytes.
+///
+/// The files are read as if they were concatenated, thus the positions of the entries are
+/// counted from the start of the oldest file. An entry is never split between two files, a new
+/// file is only started between two entries with [`WalFiles::rotate_if_full`].
+///
+/// The writes always append at the end of the log, whatever the position of the reads.
+pub(crate) struct WalFiles {
+    root: PathBuf,
+    layout: Layout,
+    max_size: u64,
+    // From the oldest to the current one
+    files: Vec<WalFile>,
+    len: u64,
+    // The position of the reads
+    position: u64,
+    // Whether the cursor of the current file is at its end, so the writes don't need to seek
+    appending: bool,
+}
+
+struct WalFile {
+    number: u64,
+    file: File,
+    // The position of its first byte in the log
+    start: u64,
+}
+
+impl WalFiles {
+    /// Open all the files of the log in order, a first file is created if there is none.
+    pub fn open(root: &Path, layout: &Layout, max_size: u64) -> io::Result<WalFiles> {
+        // The databases created before the rotation have a single dirty file
+        let legacy = layout.dirty_path(root);
+        if legacy.exists() {
+            fs::rename(&legacy, layout.wal_path(root, 0))?;
+        }
+
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(layout.wal_files_dir(root))? {
+            let name = entry?.file_name();
+            if let Some(number) = name.to_str().and_then(|name| layout.parse_wal(name)) {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+
+        let mut wal = WalFiles {
+            root: root.to_owned(),
+            layout: layout.clone(),
+            max_size: max_size.max(1),
+            files: Vec::new(),
+            len: 0,
+            position: 0,
+            appending: false,
+        };
+        for number in numbers {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .open(layout.wal_path(root, number))?;
+            let size = file.metadata()?.len();
+            wal.files.push(WalFile {
+                number,
+                file,
+                start: wal.len,
+            });
+            wal.len += size;
+        }
+        if wal.files.is_empty() {
+            let file = wal.create_file(1, 0)?;
+            wal.files.push(file);
+        }
+        Ok(wal)
+    }
+
+    /// The size of the log, it's where the next entry will be written.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// The number and size of each file of the log.
+    pub fn file_sizes(&self) -> Vec<(u64, u64)> {
+        let ends = self.files.iter().skip(1).map(|file| file.start);
+        self.files
+            .iter()
+            .zip(ends.chain([self.len]))
+            .map(|(file, end)| (file.number, end - file.start))
+            .collect()
+    }
+
+    /// The number of files of the log, they're all kept open.
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Applies from the next rotation, the current file isn't rotated right away.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size.max(1);
+    }
+
+    /// Start a new file if the current one reached the maximum size, must be called between two entries.
+    pub fn rotate_if_full(&mut self) -> io::Result<()> {
+        let current = self.files.last().unwrap();
+        if self.len - current.start >= self.max_size {
+            let file = self.create_file(current.number + 1, self.len)?;
+            self.files.push(file);
+            self.appending = true;
+        }
+        Ok(())
+    }
+
+    /// Continue the log in a new file and returns the numbers of the previous ones,
+    /// they're not read anymore but kept until [`WalFiles::remove`] is called.
+    ///
+    /// The previous files are synced, so [`WalFiles::sync`] doesn't need them to make all the
+    /// entries durable.
+    pub fn seal(&mut self) -> io::Result<Vec<u64>> {
+        for file in &self.files {
+            file.file.sync_data()?;
+        }
+        // The new file is created first, so the numbers keep increasing even after a crash
+        let next = self.create_file(self.files.last().unwrap().number + 1, 0)?;
+        let files = std::mem::replace(&mut self.files, vec![next]);
+        self.len = 0;
+        self.position = 0;
+        self.appending = true;
+        Ok(files.into_iter().map(|file| file.number).collect())
+    }
+
+    /// Make the entries written so far and the files of the log durable.
+    pub fn sync(&self) -> io::Result<()> {
+        for file in &self.files {
+            file.file.sync_data()?;
+        }
+        sync_dir(&self.layout.wal_files_dir(&self.root))
+    }
+
+    /// Cut the log at `len`, which must be in the current file, e.g. to drop a torn entry.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let current = self.files.last().unwrap();
+        assert!(
+            len >= current.start,
+            "the log can only be cut in its current file"
+        );
+        current.file.set_len(len - current.start)?;
+        self.len = len;
+        self.position = self.position.min(len);
+        self.appending = false;
+        Ok(())
+    }
+
+    /// Delete sealed files once their entries were durably written in a segment.
+    pub fn remove(&self, numbers: &[u64]) -> io::Result<()> {
+        for number in numbers {
+            fs::remove_file(self.layout.wal_path(&self.root, *number))?;
+        }
+        Ok(())
+    }
+
+    fn create_file(&self, number: u64, start: u64) -> io::Result<WalFile> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.layout.wal_path(&self.root, number))?;
+        Ok(WalFile {
+            number,
+            file,
+            start,
+        })
+    }
+}
+
+impl Read for WalFiles {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len {
+            return Ok(0);
+        }
+        // The file holding the current position, the reads stop at its end
+        let i = self
+            .files
+            .partition_point(|file| file.start <= self.position)
+            - 1;
+        let end = self.files.get(i + 1).map_or(self.len, |file| file.start);
+        if i == self.files.len() - 1 {
+            self.appending = false;
+        }
+        let file = &mut self.files[i];
+        file.file
+            .seek(SeekFrom::Start(self.position - file.start))?;
+        let len = buf.len().min((end - self.position) as usize);
+        let read = file.file.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for WalFiles {
+    /// Append to the current file, whatever the position.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = self.files.last_mut().unwrap();
+        if !self.appending {
+            file.file.seek(SeekFrom::Start(self.len - file.start))?;
+            self.appending = true;
+        }
+        let written = file
+            .file
+            .write(buf)
+            .inspect_err(|_| self.appending = false)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.last_mut().unwrap().file.flush()
+    }
+}
+
+impl Seek for WalFiles {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+/// A standalone write-ahead log of records, for the applications needing a durable log next to
+/// their database, e.g. to record the intents of a multi-step operation.
+///
+/// It's written through the same numbered files as the dirty segment of a
+/// [`Database`](crate::Database), so it must have its own directory. Each record is preceded by
+/// its size and its checksum: a record torn by a crash is dropped when the log is opened, a
+/// checksum mismatch in the older files is returned as [`Error::CorruptedWalRecord`].
+///
+/// ```
+/// use database::Wal;
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let mut wal = Wal::open(dir.path()).unwrap();
+/// wal.append(b"transfer 10 from a to b").unwrap();
+/// wal.sync().unwrap();
+///
+/// let records: Vec<_> = wal.records().map(|record| record.unwrap().1).collect();
+/// assert_eq!(records, [b"transfer 10 from a to b"]);
+/// ```
+pub struct Wal {
+    files: WalFiles,
+}
+
+impl Wal {
+    /// Open the log stored in `dir`, it's created if it doesn't exist.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Wal> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let files = WalFiles::open(dir, &Layout::flat(), WAL_MAX_SIZE)?;
+        let mut wal = Wal { files };
+
+        // A crash can only tear the record being appended, at the end of the current file
+        let mut records = wal.records();
+        let mut torn = None;
+        while let Some(record) = records.next() {
+            match record {
+                Err(Error::CorruptedWalRecord(position)) if position >= records.last_file_start => {
+                    torn = Some(position)
+                }
+                record => {
+                    record?;
+                }
+            }
+        }
+        if let Some(position) = torn {
+            wal.files.truncate(position)?;
+        }
+        Ok(wal)
+    }
+
+    /// The size of the files of the log before it continues in a new one, 64MiB by default.
+    /// It applies from the next file.
+    pub fn set_max_file_size(&mut self, bytes: u64) {
+        self.files.set_max_size(bytes);
+    }
+
+    /// Append a record to the log and returns its position. It's only durable once
+    /// [`Wal::sync`] returns.
+    ///
+    /// The size of the records is stored on 4 bytes, an [`Error::ValueTooLarge`] is returned
+    /// for the records of 4GiB or more and nothing is written.
+    pub fn append(&mut self, record: &[u8]) -> Result<u64> {
+        let header = record_header(record.len(), crc32fast::hash(record))?;
+        self.files.rotate_if_full()?;
+        let position = self.files.len();
+        let written = self
+            .files
+            .write_all(&header)
+            .and_then(|()| self.files.write_all(record));
+        if let Err(e) = written {
+            // A partially written record would be read as corrupted
+            self.files.truncate(position)?;
+            return Err(e.into());
+        }
+        Ok(position)
+    }
+
+    /// Make the records appended so far durable.
+    pub fn sync(&self) -> Result<()> {
+        Ok(self.files.sync()?)
+    }
+
+    /// Iterate over the records from the oldest one, along with their position.
+    pub fn records(&mut self) -> WalRecords<'_> {
+        let len = self.files.len();
+        let last_file_start = self.files.files.last().unwrap().start;
+        // The writes don't depend on the position of the reads
+        self.files.position = 0;
+        WalRecords {
+            reader: BufReader::new(&mut self.files),
+            position: 0,
+            len,
+            last_file_start,
+        }
+    }
+
+    /// Drop all the records, e.g. once the application applied them. The positions of the
+    /// records appended afterward start again from 0.
+    pub fn truncate(&mut self) -> Result<()> {
+        let sealed = self.files.seal()?;
+        self.files.remove(&sealed)?;
+        self.files.sync()?;
+        Ok(())
+    }
+
+    /// The size of the log, it's the position of the next record.
+    pub fn len(&self) -> u64 {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.len() == 0
+    }
+}
+
+/// The records of a [`Wal`] along with their position, see [`Wal::records`].
+pub struct WalRecords<'a> {
+    reader: BufReader<&'a mut WalFiles>,
+    position: u64,
+    len: u64,
+    // The position of the first byte of the current file of the log
+    last_file_start: u64,
+}
+
+impl WalRecords<'_> {
+    /// Read the record at the current position, a record whose size goes past the end of the
+    /// log or whose checksum doesn't match is corrupted.
+    fn read_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let size = read_u32(&mut self.reader)? as u64;
+        let checksum = read_u32(&mut self.reader)?;
+        if self.position + RECORD_HEADER + size > self.len {
+            return Ok(None);
+        }
+        let mut record = Vec::new();
+        read_bytes(&mut self.reader, size as usize, &mut record)?;
+        Ok((crc32fast::hash(&record) == checksum).then_some(record))
+    }
+}
+
+impl Iterator for WalRecords<'_> {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.len {
+            return None;
+        }
+        let position = self.position;
+        match self.read_record() {
+            Ok(Some(record)) => {
+                self.position += RECORD_HEADER + record.len() as u64;
+                Some(Ok((position, record)))
+            }
+            result => {
+                // Nothing can be read past a corrupted record
+                self.position = self.len;
+                match result {
+                    Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => Some(Err(e.into())),
+                    _ => Some(Err(Error::CorruptedWalRecord(position))),
+                }
+            }
+        }
+    }
+}
+
+/// The size and the checksum preceding a record.
+fn record_header(len: usize, checksum: u32) -> Result<[u8; RECORD_HEADER as usize]> {
+    let size = u32::try_from(len).map_err(|_| Error::ValueTooLarge {
+        size: len,
+        max: u32::MAX as usize,
+    })?;
+    let mut header = [0; RECORD_HEADER as usize];
+    header[..4].copy_from_slice(&size.to_be_bytes());
+    header[4..].copy_from_slice(&checksum.to_be_bytes());
+    Ok(header)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layout::default();
+        let mut wal = WalFiles::open(dir.path(), &layout, 10).unwrap();
+        for entry in [&b"hello"[..], b" world", b"!"] {
+            wal.rotate_if_full().unwrap();
+            wal.write_all(entry).unwrap();
+        }
+        assert_eq!(wal.file_count(), 2);
+
+        // The files are read back in order
+        let mut wal = WalFiles::open(dir.path(), &layout, 10).unwrap();
+        let mut content = String::new();
+        wal.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world!");
+        wal.seek(SeekFrom::Start(6)).unwrap();
+        content.clear();
+        wal.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "world!");
+
+        // The reads don't move the end of the log
+        wal.seek(SeekFrom::Start(0)).unwrap();
+        let mut hello = [0; 5];
+        wal.read_exact(&mut hello).unwrap();
+        assert_eq!(wal.len(), 12);
+        wal.write_all(b"?").unwrap();
+        assert_eq!(wal.stream_position().unwrap(), 5);
+        content.clear();
+        wal.seek(SeekFrom::Start(6)).unwrap();
+        wal.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "world!?");
+
+        let sealed = wal.seal().unwrap();
+        assert_eq!(sealed, [1, 2]);
+        wal.remove(&sealed).unwrap();
+        let mut files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["wal-000003"]);
+    }
+
+    #[test]
+    fn legacy_dirty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("dirty"), "hello").unwrap();
+        let mut wal = WalFiles::open(dir.path(), &Layout::default(), 10).unwrap();
+        wal.write_all(b" world").unwrap();
+
+        let mut content = String::new();
+        wal.seek(SeekFrom::Start(0)).unwrap();
+        wal.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world");
+        assert!(dir.path().join("wal-000000").exists());
+    }
+
+    #[test]
+    fn records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path()).unwrap();
+        wal.set_max_file_size(20);
+        for record in [&b"first"[..], b"second", b"third"] {
+            wal.append(record).unwrap();
+        }
+        wal.sync().unwrap();
+        drop(wal);
+
+        // The last record is torn by a crash
+        let path = dir.path().join("wal-000002");
+        let len = fs::metadata(&path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        let mut wal = Wal::open(dir.path()).unwrap();
+        let records: Vec<_> = wal.records().map(Result::unwrap).collect();
+        assert_eq!(records, [(0, b"first".to_vec()), (13, b"second".to_vec())]);
+        assert_eq!(wal.append(b"fourth").unwrap(), 27);
+        let records: Vec<_> = wal.records().map(|record| record.unwrap().1).collect();
+        assert_eq!(records, [&b"first"[..], b"second", b"fourth"]);
+
+        wal.truncate().unwrap();
+        assert!(wal.is_empty());
+        assert_eq!(wal.records().count(), 0);
+        assert_eq!(wal.append(b"fifth").unwrap(), 0);
+        drop(wal);
+
+        // A corrupted record of a previous file isn't dropped
+        let mut wal = Wal::open(dir.path()).unwrap();
+        wal.set_max_file_size(1);
+        wal.append(b"sixth").unwrap();
+        drop(wal);
+        let path = dir.path().join("wal-000003");
+        let mut content = fs::read(&path).unwrap();
+        content[9] ^= 1;
+        fs::write(&path, content).unwrap();
+        let error = Wal::open(dir.path()).err().unwrap();
+        assert!(matches!(error, Error::CorruptedWalRecord(0)));
+    }
+
+    #[test]
+    fn record_size() {
+        assert_eq!(record_header(5, 7).unwrap(), [0, 0, 0, 5, 0, 0, 0, 7]);
+        let max = u32::MAX as usize;
+        assert!(record_header(max, 0).is_ok());
+        let error = record_header(max + 1, 0).unwrap_err();
+        assert!(matches!(error, Error::ValueTooLarge { size, .. } if size == max + 1));
+        assert_eq!(error.kind(), crate::ErrorKind::LimitExceeded);
+    }
+}